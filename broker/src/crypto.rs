@@ -132,6 +132,29 @@ impl GetCertsFromPki {
         }
     }
 
+    /// Vault answers LIST requests without any results with 404, so this is treated as an empty list.
+    async fn vault_list(&self, api_path: &str) -> Result<Vec<String>, SamplyBeamError> {
+        let resp = self
+            .resilient_vault_request(
+                &Method::from_bytes("LIST".as_bytes()).unwrap(),
+                api_path,
+                Some(100),
+            )
+            .await?;
+        if resp.status() == StatusCode::NOT_FOUND {
+            return Ok(Vec::new());
+        }
+        let body_bytes = body::to_bytes(resp.into_body()).await.map_err(|e| {
+            SamplyBeamError::VaultOtherError(format!("Cannot retrieve vault list {api_path}: {e}"))
+        })?;
+        let body: PkiListResponse = serde_json::from_slice(&body_bytes).map_err(|e| {
+            SamplyBeamError::VaultOtherError(format!(
+                "Cannot deserialize vault list {api_path}: {e}"
+            ))
+        })?;
+        Ok(body.data.keys)
+    }
+
     async fn resilient_vault_request(
         &self,
         method: &Method,
@@ -154,7 +177,11 @@ impl GetCertsFromPki {
                 .unwrap(); //TODO Unwrap
             let resp = self.hyper_client.request(req).await;
             let Ok(resp) = resp else {
-                warn!("Samply.PKI: Unable to communicate to vault: {}; retrying (failed attempt #{})", resp.unwrap_err(), tries+2);
+                warn!(
+                    "Samply.PKI: Unable to communicate to vault: {}; retrying (failed attempt #{})",
+                    resp.unwrap_err(),
+                    tries + 2
+                );
                 self.report_vault_health(VaultStatus::Unreachable).await;
                 continue;
             };
@@ -163,6 +190,10 @@ impl GetCertsFromPki {
                     self.report_vault_health(VaultStatus::Ok).await;
                     return Ok(resp);
                 }
+                StatusCode::NOT_FOUND if method.as_str() == "LIST" => {
+                    self.report_vault_health(VaultStatus::Ok).await;
+                    return Ok(resp);
+                }
                 code if code.is_client_error() || code.is_redirection() => {
                     error!(
                         "Samply.PKI: Vault reported client-side Error (code {}), not retrying.",
@@ -213,27 +244,17 @@ impl GetCertsFromPki {
 impl GetCerts for GetCertsFromPki {
     async fn certificate_list(&self) -> Result<Vec<String>, SamplyBeamError> {
         debug!("Getting Cert List");
-        let resp = self
-            .resilient_vault_request(
-                &Method::from_bytes("LIST".as_bytes()).unwrap(),
-                &format!("{}/certs", &config::CONFIG_CENTRAL.pki_realm),
-                Some(100),
-            )
+        let list = self
+            .vault_list(&format!("{}/certs", &config::CONFIG_CENTRAL.pki_realm))
             .await?;
-        let body_bytes = body::to_bytes(resp.into_body()).await.map_err(|e| {
-            SamplyBeamError::VaultOtherError(format!(
-                "Cannot retrieve vault certificate list: {}",
-                e
-            ))
-        })?;
-        let body: PkiListResponse = serde_json::from_slice(&body_bytes).map_err(|e| {
-            SamplyBeamError::VaultOtherError(format!(
-                "Cannot deserialize vault certificate list: {}",
-                e
-            ))
-        })?;
-        debug!("Got cert list with {} elements", body.data.keys.len());
-        return Ok(body.data.keys);
+        debug!("Got cert list with {} elements", list.len());
+        Ok(list)
+    }
+
+    async fn revoked_certificate_list(&self) -> Result<Vec<String>, SamplyBeamError> {
+        debug!("Getting list of revoked certs");
+        self.vault_list(&format!("{}/certs/revoked", &self.pki_realm))
+            .await
     }
 
     async fn certificate_by_serial_as_pem(&self, serial: &str) -> Result<String, SamplyBeamError> {
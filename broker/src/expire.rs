@@ -11,6 +11,8 @@ use tokio::{
 };
 use tracing::{debug, error, info, warn};
 
+use crate::serve_tasks::ResultIds;
+
 struct Latest {
     id: Option<MsgId>,
     expire: Option<SystemTime>,
@@ -31,6 +33,7 @@ async fn get_soonest(tasks: &HashMap<MyUuid, MsgSigned<EncryptedMsgTaskRequest>>
 
 pub(crate) async fn watch(
     tasks: Arc<RwLock<HashMap<MyUuid, MsgSigned<EncryptedMsgTaskRequest>>>>,
+    result_ids: Arc<RwLock<HashMap<MyUuid, ResultIds>>>,
     mut new_task_rx: Receiver<MsgSigned<EncryptedMsgTaskRequest>>,
 ) -> Result<(), SystemTimeError> {
     let mut soonest = {
@@ -79,6 +82,7 @@ pub(crate) async fn watch(
             _ = tokio::time::sleep(until) => {
                 let mut tasks = tasks.write().await;
                 let removed = tasks.remove(&soonest.id.unwrap());
+                result_ids.write().await.remove(&soonest.id.unwrap());
                 if let Some(removed) = removed {
                     info!("Removed expired task {}.", removed.msg.id);
                 } else {
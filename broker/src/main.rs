@@ -4,9 +4,12 @@ mod banner;
 mod crypto;
 mod expire;
 mod health;
+mod quota;
 mod serve;
+mod serve_blobs;
 mod serve_health;
 mod serve_pki;
+mod serve_sockets;
 mod serve_tasks;
 
 use std::{collections::HashMap, sync::Arc, time::Duration};
@@ -32,7 +35,7 @@ pub async fn main() -> anyhow::Result<()> {
     tokio::task::spawn(retry_notify(
         ExponentialBackoff::default(),
         || async {
-            shared::crypto::init_ca_chain()
+            shared::crypto::init_ca_chain(None)
                 .await
                 .map_err(|e| backoff::Error::transient(e))
         },
@@ -0,0 +1,580 @@
+//! Per-proxy and per-app limits on request rate, outstanding tasks, stored bytes and task lifetime
+
+use std::{
+    collections::HashMap,
+    path::Path,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant, SystemTime},
+};
+
+use axum::{
+    async_trait,
+    body::Body,
+    extract::{FromRef, FromRequest},
+    http::{header, Request, StatusCode},
+    response::{IntoResponse, Response},
+};
+use serde::{de::DeserializeOwned, Deserialize, Deserializer, Serialize};
+use shared::{
+    beam_id::AppOrProxyId, errors::SamplyBeamError, policy::BeamIdPattern, EncryptedMsgTaskRequest,
+    EncryptedMsgTaskResult, Msg, MsgId, MsgSigned,
+};
+use tokio::sync::RwLock;
+use tracing::warn;
+
+/// Limits applying to a single proxy (including all of its apps) or app. Unset limits are not enforced.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct Limits {
+    /// Sustained number of requests per second
+    requests_per_second: Option<f64>,
+    /// Number of requests that may be made in short succession; defaults to one second's worth
+    burst: Option<f64>,
+    /// Number of tasks created that have not expired yet
+    outstanding_tasks: Option<usize>,
+    /// Total size of the signed tasks and results as well as the blobs kept by the Broker
+    stored_bytes: Option<usize>,
+    /// Maximum time until a task expires, e.g. "1h"
+    #[serde(default, deserialize_with = "deserialize_duration")]
+    max_ttl: Option<Duration>,
+}
+
+fn deserialize_duration<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<Duration>, D::Error> {
+    Option::<String>::deserialize(deserializer)?
+        .map(|s| fundu::parse_duration(&s).map_err(serde::de::Error::custom))
+        .transpose()
+}
+
+/// Limits for the proxies/apps matching `for`, replacing the default limits
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Override {
+    #[serde(rename = "for")]
+    for_ids: Vec<BeamIdPattern>,
+    limits: Limits,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct QuotaConfig {
+    /// Default limits for each proxy, covering the proxy and all of its apps
+    #[serde(default)]
+    proxy: Limits,
+    /// Default limits for each app
+    #[serde(default)]
+    app: Limits,
+    /// The first matching override is used instead of the default limits
+    #[serde(default)]
+    overrides: Vec<Override>,
+}
+
+impl QuotaConfig {
+    pub(crate) fn load(file: &Path) -> Result<Self, SamplyBeamError> {
+        let err = |e: String| {
+            SamplyBeamError::ConfigurationFailed(format!(
+                "Unable to load quota file {}: {e}",
+                file.to_string_lossy()
+            ))
+        };
+        let content = std::fs::read_to_string(file).map_err(|e| err(e.to_string()))?;
+        let config: Self = serde_json::from_str(&content).map_err(|e| err(e.to_string()))?;
+        let all_limits = [&config.proxy, &config.app]
+            .into_iter()
+            .chain(config.overrides.iter().map(|o| &o.limits));
+        for limits in all_limits {
+            if limits.requests_per_second.is_some_and(|rate| rate <= 0.0) {
+                return Err(err("requests_per_second must be positive".into()));
+            }
+        }
+        Ok(config)
+    }
+
+    fn limits_for(&self, subject: &AppOrProxyId) -> &Limits {
+        self.overrides
+            .iter()
+            .find(|o| BeamIdPattern::any_matches(&o.for_ids, subject))
+            .map(|o| &o.limits)
+            .unwrap_or(match subject {
+                AppOrProxyId::AppId(_) => &self.app,
+                AppOrProxyId::ProxyId(_) => &self.proxy,
+            })
+    }
+}
+
+/// The proxy/app a request is accounted to: An app's requests count against both the app and its proxy.
+fn subjects(sender: &AppOrProxyId) -> Vec<AppOrProxyId> {
+    match sender {
+        AppOrProxyId::AppId(app) => vec![sender.clone(), AppOrProxyId::ProxyId(app.proxy_id())],
+        AppOrProxyId::ProxyId(_) => vec![sender.clone()],
+    }
+}
+
+/// Whether messages by `id` count against `subject`
+fn accounted_to(subject: &AppOrProxyId, id: &AppOrProxyId) -> bool {
+    match subject {
+        AppOrProxyId::AppId(_) => subject == id,
+        AppOrProxyId::ProxyId(proxy) => id.get_proxy_id() == *proxy,
+    }
+}
+
+#[derive(Debug)]
+pub(crate) enum QuotaExceeded {
+    TooManyRequests {
+        subject: AppOrProxyId,
+        retry_after: Duration,
+    },
+    Exhausted {
+        subject: AppOrProxyId,
+        what: &'static str,
+        retry_after: Duration,
+    },
+    TtlTooLong {
+        max_ttl: Duration,
+    },
+}
+
+impl IntoResponse for QuotaExceeded {
+    fn into_response(self) -> Response {
+        let (message, retry_after) = match self {
+            QuotaExceeded::TooManyRequests {
+                subject,
+                retry_after,
+            } => (
+                format!("Request rate limit of {subject} exceeded."),
+                retry_after,
+            ),
+            QuotaExceeded::Exhausted {
+                subject,
+                what,
+                retry_after,
+            } => (
+                format!("Quota of {what} for {subject} exceeded."),
+                retry_after,
+            ),
+            QuotaExceeded::TtlTooLong { max_ttl } => {
+                return (
+                    StatusCode::BAD_REQUEST,
+                    format!(
+                        "Task TTL exceeds the Broker's limit of {} seconds.",
+                        max_ttl.as_secs()
+                    ),
+                )
+                    .into_response()
+            }
+        };
+        warn!("Quota: {message}");
+        // Retry-After is given in whole seconds; never advise to retry immediately
+        let retry_after = retry_after.as_secs_f64().ceil().max(1.0) as u64;
+        (
+            StatusCode::TOO_MANY_REQUESTS,
+            [(header::RETRY_AFTER, retry_after.to_string())],
+            message,
+        )
+            .into_response()
+    }
+}
+
+#[derive(Debug)]
+struct TokenBucket {
+    tokens: f64,
+    last_refill: Instant,
+}
+
+#[derive(Debug)]
+struct StoredBlob {
+    owner: AppOrProxyId,
+    size: usize,
+    expire: SystemTime,
+}
+
+/// The configured limits along with the state needed to enforce them
+#[derive(Clone, Default)]
+pub(crate) struct Quotas {
+    config: Arc<RwLock<QuotaConfig>>,
+    buckets: Arc<Mutex<HashMap<AppOrProxyId, TokenBucket>>>,
+    blobs: Arc<Mutex<HashMap<MsgId, StoredBlob>>>,
+}
+
+impl Quotas {
+    pub(crate) fn new(config: Arc<RwLock<QuotaConfig>>) -> Self {
+        Self {
+            config,
+            buckets: Default::default(),
+            blobs: Default::default(),
+        }
+    }
+
+    /// Takes a token from the buckets of `sender` and its proxy if both have one left
+    pub(crate) async fn check_rate(&self, sender: &AppOrProxyId) -> Result<(), QuotaExceeded> {
+        let config = self.config.read().await;
+        let mut buckets = self.buckets.lock().unwrap();
+        let now = Instant::now();
+        let subjects = subjects(sender);
+        for subject in &subjects {
+            let limits = config.limits_for(subject);
+            let Some(rate) = limits.requests_per_second else {
+                continue;
+            };
+            let burst = limits.burst.unwrap_or(rate).max(1.0);
+            let bucket = buckets.entry(subject.clone()).or_insert(TokenBucket {
+                tokens: burst,
+                last_refill: now,
+            });
+            bucket.tokens = (bucket.tokens
+                + now.duration_since(bucket.last_refill).as_secs_f64() * rate)
+                .min(burst);
+            bucket.last_refill = now;
+            if bucket.tokens < 1.0 {
+                return Err(QuotaExceeded::TooManyRequests {
+                    subject: subject.clone(),
+                    retry_after: Duration::from_secs_f64((1.0 - bucket.tokens) / rate),
+                });
+            }
+        }
+        for subject in &subjects {
+            if config.limits_for(subject).requests_per_second.is_some() {
+                if let Some(bucket) = buckets.get_mut(subject) {
+                    bucket.tokens -= 1.0;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Checks whether storing `task` keeps its sender within their limits
+    pub(crate) async fn check_task(
+        &self,
+        task: &MsgSigned<EncryptedMsgTaskRequest>,
+        tasks: &HashMap<MsgId, MsgSigned<EncryptedMsgTaskRequest>>,
+    ) -> Result<(), QuotaExceeded> {
+        let config = self.config.read().await;
+        let ttl = task
+            .msg
+            .expire
+            .duration_since(SystemTime::now())
+            .unwrap_or_default();
+        for subject in subjects(&task.msg.from) {
+            let limits = config.limits_for(&subject);
+            if let Some(max_ttl) = limits.max_ttl {
+                if ttl > max_ttl {
+                    return Err(QuotaExceeded::TtlTooLong { max_ttl });
+                }
+            }
+            if let Some(max) = limits.outstanding_tasks {
+                let outstanding = tasks
+                    .values()
+                    .filter(|t| accounted_to(&subject, &t.msg.from))
+                    .count();
+                if outstanding >= max {
+                    return Err(exhausted(subject, "outstanding tasks", tasks));
+                }
+            }
+            if let Some(max) = limits.stored_bytes {
+                if stored_bytes(&subject, tasks) + self.blob_bytes(&subject) + task.jwt.len() > max
+                {
+                    return Err(exhausted(subject, "stored bytes", tasks));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Checks whether storing `result`, replacing `previous`, keeps its sender within their limits
+    pub(crate) async fn check_result(
+        &self,
+        result: &MsgSigned<EncryptedMsgTaskResult>,
+        previous: Option<&MsgSigned<EncryptedMsgTaskResult>>,
+        tasks: &HashMap<MsgId, MsgSigned<EncryptedMsgTaskRequest>>,
+    ) -> Result<(), QuotaExceeded> {
+        let config = self.config.read().await;
+        let previous = previous.map(|p| p.jwt.len()).unwrap_or_default();
+        for subject in subjects(result.get_from()) {
+            if let Some(max) = config.limits_for(&subject).stored_bytes {
+                if stored_bytes(&subject, tasks) + self.blob_bytes(&subject) + result.jwt.len()
+                    - previous
+                    > max
+                {
+                    return Err(exhausted(subject, "stored bytes", tasks));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// The shortest `max_ttl` applying to `sender`, if any
+    pub(crate) async fn max_ttl(&self, sender: &AppOrProxyId) -> Option<Duration> {
+        let config = self.config.read().await;
+        subjects(sender)
+            .iter()
+            .filter_map(|subject| config.limits_for(subject).max_ttl)
+            .min()
+    }
+
+    /// How many bytes of blobs `owner` may still store, if limited. Tasks and results are not
+    /// counted here, as they are kept apart from blobs; they are when storing tasks and results.
+    pub(crate) async fn blob_allowance(&self, owner: &AppOrProxyId) -> Option<usize> {
+        let config = self.config.read().await;
+        subjects(owner)
+            .iter()
+            .filter_map(|subject| {
+                let max = config.limits_for(subject).stored_bytes?;
+                Some(max.saturating_sub(self.blob_bytes(subject)))
+            })
+            .min()
+    }
+
+    /// Accounts the blob `id` of `size` bytes to `owner` until it expires, unless that exceeds their limits
+    pub(crate) async fn add_blob(
+        &self,
+        id: MsgId,
+        owner: &AppOrProxyId,
+        size: usize,
+        expire: SystemTime,
+    ) -> Result<(), QuotaExceeded> {
+        let config = self.config.read().await;
+        let mut blobs = self.blobs.lock().unwrap();
+        for subject in subjects(owner) {
+            if let Some(max) = config.limits_for(&subject).stored_bytes {
+                if blob_bytes(&subject, &blobs) + size > max {
+                    let now = SystemTime::now();
+                    let retry_after = blobs
+                        .values()
+                        .filter(|blob| accounted_to(&subject, &blob.owner))
+                        .map(|blob| blob.expire.duration_since(now).unwrap_or_default())
+                        .min()
+                        .unwrap_or_default();
+                    return Err(QuotaExceeded::Exhausted {
+                        subject,
+                        what: "stored bytes",
+                        retry_after,
+                    });
+                }
+            }
+        }
+        blobs.insert(
+            id,
+            StoredBlob {
+                owner: owner.clone(),
+                size,
+                expire,
+            },
+        );
+        Ok(())
+    }
+
+    /// Stops accounting the blob `id` once it has been deleted
+    pub(crate) fn remove_blob(&self, id: &MsgId) {
+        self.blobs.lock().unwrap().remove(id);
+    }
+
+    /// Total size of the blobs accounted to `subject`
+    fn blob_bytes(&self, subject: &AppOrProxyId) -> usize {
+        blob_bytes(subject, &self.blobs.lock().unwrap())
+    }
+}
+
+fn blob_bytes(subject: &AppOrProxyId, blobs: &HashMap<MsgId, StoredBlob>) -> usize {
+    blobs
+        .values()
+        .filter(|blob| accounted_to(subject, &blob.owner))
+        .map(|blob| blob.size)
+        .sum()
+}
+
+/// Total size of the tasks and results accounted to `subject`
+fn stored_bytes(
+    subject: &AppOrProxyId,
+    tasks: &HashMap<MsgId, MsgSigned<EncryptedMsgTaskRequest>>,
+) -> usize {
+    tasks
+        .values()
+        .map(|task| {
+            let results: usize = task
+                .msg
+                .results
+                .values()
+                .filter(|r| accounted_to(subject, &r.msg.from))
+                .map(|r| r.jwt.len())
+                .sum();
+            if accounted_to(subject, &task.msg.from) {
+                results + task.jwt.len()
+            } else {
+                results
+            }
+        })
+        .sum()
+}
+
+/// Quota exhaustion, to be retried once the next of `subject`'s tasks (or tasks it answered) expires
+fn exhausted(
+    subject: AppOrProxyId,
+    what: &'static str,
+    tasks: &HashMap<MsgId, MsgSigned<EncryptedMsgTaskRequest>>,
+) -> QuotaExceeded {
+    let now = SystemTime::now();
+    let retry_after = tasks
+        .values()
+        .filter(|task| {
+            accounted_to(&subject, &task.msg.from)
+                || task.msg.results.keys().any(|r| accounted_to(&subject, r))
+        })
+        .map(|task| task.msg.expire.duration_since(now).unwrap_or_default())
+        .min()
+        .unwrap_or_default();
+    QuotaExceeded::Exhausted {
+        subject,
+        what,
+        retry_after,
+    }
+}
+
+/// A signed message whose sender has not exceeded their request rate limit
+pub(crate) struct RateLimited<M: Msg>(pub MsgSigned<M>);
+
+#[async_trait]
+impl<S, M> FromRequest<S, Body> for RateLimited<M>
+where
+    Quotas: FromRef<S>,
+    S: Send + Sync,
+    M: Serialize + DeserializeOwned + Msg + Send,
+{
+    type Rejection = Response;
+
+    async fn from_request(req: Request<Body>, state: &S) -> Result<Self, Self::Rejection> {
+        let msg = MsgSigned::<M>::from_request(req, state)
+            .await
+            .map_err(IntoResponse::into_response)?;
+        Quotas::from_ref(state)
+            .check_rate(msg.get_from())
+            .await
+            .map_err(IntoResponse::into_response)?;
+        Ok(Self(msg))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::Value;
+    use shared::{beam_id::BeamId, beam_id::BrokerId, Encrypted, FailureStrategy, MsgTaskRequest};
+
+    use super::*;
+
+    const BROKER_ID: &str = "broker.samply.de";
+
+    fn id(id: &str) -> AppOrProxyId {
+        AppOrProxyId::new(&format!("{id}.{BROKER_ID}")).unwrap()
+    }
+
+    fn task(from: &str, ttl: Duration) -> MsgSigned<EncryptedMsgTaskRequest> {
+        MsgSigned {
+            msg: MsgTaskRequest {
+                id: MsgId::new(),
+                from: id(from),
+                to: vec![],
+                body: Encrypted::default(),
+                bodies: HashMap::new(),
+                expire: SystemTime::now() + ttl,
+                failure_strategy: FailureStrategy::Discard,
+                results: HashMap::new(),
+                metadata: Value::Null,
+            },
+            jwt: "x".repeat(100),
+        }
+    }
+
+    #[tokio::test]
+    async fn quotas_are_enforced_per_app_and_proxy() {
+        BrokerId::set_broker_id(BROKER_ID.to_string());
+        let config: QuotaConfig = serde_json::from_str(&format!(
+            r#"{{
+                "proxy": {{ "outstanding_tasks": 2, "stored_bytes": 1000 }},
+                "app": {{ "requests_per_second": 1, "burst": 2, "max_ttl": "1h" }},
+                "overrides": [ {{ "for": ["proxy2.{BROKER_ID}"], "limits": {{}} }} ]
+            }}"#
+        ))
+        .unwrap();
+        let quotas = Quotas::new(Arc::new(RwLock::new(config)));
+
+        assert!(quotas.check_rate(&id("app1.proxy1")).await.is_ok());
+        assert!(quotas.check_rate(&id("app1.proxy1")).await.is_ok());
+        assert!(matches!(
+            quotas.check_rate(&id("app1.proxy1")).await,
+            Err(QuotaExceeded::TooManyRequests { .. })
+        ));
+        assert!(quotas.check_rate(&id("app2.proxy1")).await.is_ok());
+        assert!(quotas.check_rate(&id("proxy1")).await.is_ok());
+
+        let hour = Duration::from_secs(3600);
+        assert!(matches!(
+            quotas
+                .check_task(&task("app1.proxy1", 2 * hour), &HashMap::new())
+                .await,
+            Err(QuotaExceeded::TtlTooLong { .. })
+        ));
+        let mut tasks = HashMap::new();
+        for from in ["app1.proxy1", "app2.proxy1"] {
+            let task = task(from, hour);
+            quotas.check_task(&task, &tasks).await.unwrap();
+            tasks.insert(task.msg.id, task);
+        }
+        let response = quotas
+            .check_task(&task("proxy1", hour), &tasks)
+            .await
+            .unwrap_err()
+            .into_response();
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        let retry_after: u64 = response.headers()[header::RETRY_AFTER]
+            .to_str()
+            .unwrap()
+            .parse()
+            .unwrap();
+        assert!(retry_after > 3500 && retry_after <= 3600);
+        assert!(quotas
+            .check_task(&task("proxy2", 2 * hour), &tasks)
+            .await
+            .is_ok());
+    }
+
+    #[tokio::test]
+    async fn blobs_count_against_stored_bytes() {
+        BrokerId::set_broker_id(BROKER_ID.to_string());
+        let config: QuotaConfig = serde_json::from_str(
+            r#"{ "proxy": { "stored_bytes": 1000 }, "app": { "max_ttl": "1h" } }"#,
+        )
+        .unwrap();
+        let quotas = Quotas::new(Arc::new(RwLock::new(config)));
+        let app = id("app1.proxy1");
+        let hour = Duration::from_secs(3600);
+        assert_eq!(quotas.max_ttl(&app).await, Some(hour));
+        assert_eq!(quotas.max_ttl(&id("proxy1")).await, None);
+
+        let expire = SystemTime::now() + hour;
+        let blob = MsgId::new();
+        assert_eq!(quotas.blob_allowance(&app).await, Some(1000));
+        quotas.add_blob(blob, &app, 950, expire).await.unwrap();
+        assert_eq!(quotas.blob_allowance(&id("app2.proxy1")).await, Some(50));
+        assert!(matches!(
+            quotas
+                .add_blob(MsgId::new(), &id("app2.proxy1"), 51, expire)
+                .await,
+            Err(QuotaExceeded::Exhausted { .. })
+        ));
+        // The blob leaves too little room for a task
+        assert!(matches!(
+            quotas
+                .check_task(&task("app1.proxy1", hour), &HashMap::new())
+                .await,
+            Err(QuotaExceeded::Exhausted { .. })
+        ));
+        assert_eq!(quotas.blob_allowance(&id("proxy2")).await, Some(1000));
+
+        quotas.remove_blob(&blob);
+        assert_eq!(quotas.blob_allowance(&app).await, Some(1000));
+        assert!(quotas
+            .check_task(&task("app1.proxy1", hour), &HashMap::new())
+            .await
+            .is_ok());
+    }
+}
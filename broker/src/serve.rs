@@ -9,8 +9,8 @@ use axum::{
 };
 use serde::Deserialize;
 use shared::{
-    config, EncryptedMsgTaskRequest, EncryptedMsgTaskResult, HasWaitId, HowLongToBlock, Msg,
-    MsgEmpty, MsgId, MsgSigned, EMPTY_VEC_APPORPROXYID,
+    config, policy::RoutingPolicy, websocket::Api, EncryptedMsgTaskRequest, EncryptedMsgTaskResult,
+    HasWaitId, HowLongToBlock, Msg, MsgEmpty, MsgId, MsgSigned, EMPTY_VEC_APPORPROXYID,
 };
 use tokio::{
     sync::{
@@ -21,22 +21,74 @@ use tokio::{
 };
 use tracing::{debug, info, trace, warn};
 
-use crate::{banner, crypto, health::Health, serve_health, serve_pki, serve_tasks};
+use crate::{
+    banner, crypto,
+    health::Health,
+    quota::{QuotaConfig, Quotas},
+    serve_blobs, serve_health, serve_pki, serve_sockets, serve_tasks,
+};
 
 pub(crate) async fn serve(health: Arc<RwLock<Health>>) -> anyhow::Result<()> {
-    let app = serve_tasks::router()
+    let policy = match &config::CONFIG_CENTRAL.policy_file {
+        Some(file) => {
+            let policy = Arc::new(RwLock::new(RoutingPolicy::load(file)?));
+            shared::reload::reload_on_sighup("routing policy", policy.clone(), || {
+                RoutingPolicy::load(file)
+            });
+            info!("Enforcing routing policy from {}", file.to_string_lossy());
+            Some(policy)
+        }
+        None => None,
+    };
+    let quotas = match &config::CONFIG_CENTRAL.quota_file {
+        Some(file) => {
+            let quotas = Arc::new(RwLock::new(QuotaConfig::load(file)?));
+            shared::reload::reload_on_sighup("quotas", quotas.clone(), || QuotaConfig::load(file));
+            info!("Enforcing quotas from {}", file.to_string_lossy());
+            Quotas::new(quotas)
+        }
+        None => Quotas::default(),
+    };
+    let mut api = serve_tasks::router(policy.clone(), quotas.clone())
+        .merge(serve_sockets::router(policy.clone(), quotas.clone()));
+    if let Some(dir) = &config::CONFIG_CENTRAL.blob_dir {
+        api = api.merge(serve_blobs::router(
+            dir,
+            config::CONFIG_CENTRAL.max_blob_size,
+            policy,
+            quotas,
+        )?);
+        info!("Storing blobs in {}", dir.to_string_lossy());
+    }
+    let api = api
         .merge(serve_pki::router())
         .merge(serve_health::router(health))
-        .layer(axum::middleware::from_fn(shared::middleware::log))
+        .layer(axum::middleware::from_fn(shared::middleware::log));
+    // Requests tunneled via WebSockets are served by the same API
+    let app = api
+        .clone()
+        .layer(Extension(Api::new(api)))
         .layer(axum::middleware::map_response(banner::set_server_header));
 
     info!(
         "Startup complete. Listening for requests on {}",
         config::CONFIG_CENTRAL.bind_addr
     );
-    axum::Server::bind(&config::CONFIG_CENTRAL.bind_addr)
-        .serve(app.into_make_service_with_connect_info::<SocketAddr>())
-        .with_graceful_shutdown(shared::graceful_shutdown::wait_for_signal())
-        .await?;
+    match &config::CONFIG_CENTRAL.tls {
+        Some(tls) => {
+            shared::tls_server::serve(
+                config::CONFIG_CENTRAL.bind_addr,
+                tls.reloadable_acceptor()?,
+                app,
+            )
+            .await?
+        }
+        None => {
+            axum::Server::bind(&config::CONFIG_CENTRAL.bind_addr)
+                .serve(app.into_make_service_with_connect_info::<SocketAddr>())
+                .with_graceful_shutdown(shared::graceful_shutdown::wait_for_signal())
+                .await?
+        }
+    }
     Ok(())
 }
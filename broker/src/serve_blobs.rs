@@ -0,0 +1,286 @@
+//! Blobs: The broker stores large payloads, which the sending proxy has encrypted end-to-end (see
+//! [`shared::blobs`]), on disk until their recipients' proxies have downloaded them or they expire.
+//! Uploads and downloads are streamed, so a blob is never held in memory as a whole.
+
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    path::{Path as FsPath, PathBuf},
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+
+use axum::{
+    body::StreamBody,
+    extract::{ConnectInfo, Query, State},
+    http::{header, StatusCode},
+    response::{IntoResponse, Response},
+    routing::{get, post},
+    Json, Router,
+};
+use hyper::{body::HttpBody, Body, Request};
+use serde::Deserialize;
+use serde_json::json;
+use shared::{
+    beam_id::{AppOrProxyId, BeamId},
+    crypto_jwt::HeaderSigned,
+    policy::RoutingPolicy,
+    Msg, MsgEmpty, MsgId,
+};
+use tokio::{
+    fs,
+    io::{AsyncReadExt, AsyncWriteExt},
+    sync::RwLock,
+    time,
+};
+use tracing::{debug, info, warn};
+
+use crate::quota::Quotas;
+
+/// Blobs are kept this long unless the sender asks otherwise
+const DEFAULT_BLOB_TTL: Duration = Duration::from_secs(60 * 60);
+
+const ERR_TOO_LARGE: (StatusCode, &str) = (
+    StatusCode::PAYLOAD_TOO_LARGE,
+    "Blob exceeds the Broker's size limit or your quota of stored bytes.",
+);
+
+const ERR_STORAGE: (StatusCode, &str) = (
+    StatusCode::INTERNAL_SERVER_ERROR,
+    "Unable to store blob; see server logs.",
+);
+
+struct BlobInfo {
+    owner: AppOrProxyId,
+    to: Vec<AppOrProxyId>,
+}
+
+#[derive(Clone)]
+struct BlobState {
+    dir: Arc<PathBuf>,
+    max_size: u64,
+    blobs: Arc<RwLock<HashMap<MsgId, BlobInfo>>>,
+    policy: Option<Arc<RwLock<RoutingPolicy>>>,
+    quotas: Quotas,
+}
+
+impl BlobState {
+    fn path(&self, id: &MsgId) -> PathBuf {
+        self.dir.join(format!("{id}.blob"))
+    }
+}
+
+pub(crate) fn router(
+    dir: &FsPath,
+    max_size: u64,
+    policy: Option<Arc<RwLock<RoutingPolicy>>>,
+    quotas: Quotas,
+) -> std::io::Result<Router> {
+    std::fs::create_dir_all(dir)?;
+    // Blobs are only known in memory, so those left over from a previous run can no longer be served
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path
+            .extension()
+            .is_some_and(|ext| ext == "blob" || ext == "part")
+        {
+            std::fs::remove_file(&path)?;
+        }
+    }
+    let state = BlobState {
+        dir: Arc::new(dir.to_owned()),
+        max_size,
+        blobs: Default::default(),
+        policy,
+        quotas,
+    };
+    Ok(Router::new()
+        .route("/v1/blobs", post(post_blob))
+        .route("/v1/blobs/:id", get(get_blob))
+        .with_state(state))
+}
+
+#[derive(Deserialize)]
+struct BlobParams {
+    /// Recipients, separated by commas
+    to: String,
+    ttl: Option<String>,
+}
+
+// POST /v1/blobs?to=...&ttl=...
+/// Stores an encrypted blob for the given recipients. As the body is streamed, the signed message
+/// is sent in a header; the query, and thus the recipients, are covered by its signature.
+async fn post_blob(
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    State(state): State<BlobState>,
+    Query(params): Query<BlobParams>,
+    HeaderSigned(msg): HeaderSigned<MsgEmpty>,
+    req: Request<Body>,
+) -> Result<(StatusCode, impl IntoResponse), Response> {
+    let owner = msg.get_from().clone();
+    state
+        .quotas
+        .check_rate(&owner)
+        .await
+        .map_err(IntoResponse::into_response)?;
+    let to = params
+        .to
+        .split(',')
+        .map(AppOrProxyId::new)
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|_| (StatusCode::BAD_REQUEST, "Invalid recipients.").into_response())?;
+    let ttl = match &params.ttl {
+        Some(ttl) => fundu::parse_duration(ttl)
+            .map_err(|_| (StatusCode::BAD_REQUEST, "Invalid TTL.").into_response())?,
+        None => DEFAULT_BLOB_TTL,
+    };
+    let ttl = match state.quotas.max_ttl(&owner).await {
+        Some(max_ttl) => ttl.min(max_ttl),
+        None => ttl,
+    };
+    if let Some(policy) = &state.policy {
+        let denied = policy.read().await.denied_recipients(&owner, &to);
+        if !denied.is_empty() {
+            warn!("Routing policy: Denied blob from {owner} to {denied:?}");
+            return Err((
+                StatusCode::FORBIDDEN,
+                "According to the Broker's routing policy, you may not address this recipient.",
+            )
+                .into_response());
+        }
+    }
+    let max_size = match state.quotas.blob_allowance(&owner).await {
+        Some(allowance) => state.max_size.min(allowance as u64),
+        None => state.max_size,
+    };
+    if req
+        .body()
+        .size_hint()
+        .exact()
+        .is_some_and(|len| len > max_size)
+    {
+        return Err(ERR_TOO_LARGE.into_response());
+    }
+    let id = MsgId::new();
+    let path = state.path(&id);
+    let part = path.with_extension("part");
+    let mut body = req.into_body();
+    let store = async {
+        let mut file = fs::File::create(&part).await?;
+        let mut size = 0u64;
+        while let Some(data) = body.data().await {
+            let data = data.map_err(std::io::Error::other)?;
+            size += data.len() as u64;
+            if size > max_size {
+                return Ok(None);
+            }
+            file.write_all(&data).await?;
+        }
+        file.sync_all().await?;
+        Ok::<_, std::io::Error>(Some(size))
+    };
+    let size = match store.await {
+        Ok(Some(size)) => size,
+        Ok(None) => {
+            let _ = fs::remove_file(&part).await;
+            return Err(ERR_TOO_LARGE.into_response());
+        }
+        Err(e) => {
+            warn!("Unable to store blob {id} from {owner}: {e}");
+            let _ = fs::remove_file(&part).await;
+            return Err(ERR_STORAGE.into_response());
+        }
+    };
+    let expire = SystemTime::now() + ttl;
+    // Concurrent uploads may have used up the allowance in the meantime
+    if let Err(e) = state
+        .quotas
+        .add_blob(id, &owner, size as usize, expire)
+        .await
+    {
+        let _ = fs::remove_file(&part).await;
+        return Err(e.into_response());
+    }
+    if let Err(e) = fs::rename(&part, &path).await {
+        warn!("Unable to store blob {id} from {owner}: {e}");
+        let _ = fs::remove_file(&part).await;
+        state.quotas.remove_blob(&id);
+        return Err(ERR_STORAGE.into_response());
+    }
+    info!("{owner} with IP {addr} uploaded blob {id} ({size} bytes) for {to:?}");
+    state.blobs.write().await.insert(id, BlobInfo { owner, to });
+    tokio::task::spawn(async move {
+        time::sleep(expire.duration_since(SystemTime::now()).unwrap_or_default()).await;
+        state.blobs.write().await.remove(&id);
+        state.quotas.remove_blob(&id);
+        match fs::remove_file(state.path(&id)).await {
+            Ok(()) => debug!("Blob {id} has expired"),
+            Err(e) => warn!("Unable to delete expired blob {id}: {e}"),
+        }
+    });
+    Ok((
+        StatusCode::CREATED,
+        (
+            [(header::LOCATION, format!("/v1/blobs/{id}"))],
+            Json(json!({ "id": id })),
+        ),
+    ))
+}
+
+// GET /v1/blobs/:id
+/// Streams an encrypted blob to its owner or one of its recipients. Like for uploads, the signed
+/// message is sent in a header.
+async fn get_blob(
+    id: MsgId,
+    State(state): State<BlobState>,
+    HeaderSigned(msg): HeaderSigned<MsgEmpty>,
+) -> Result<Response, Response> {
+    const ERR_NOT_FOUND: (StatusCode, &str) = (StatusCode::NOT_FOUND, "Blob not found or expired.");
+    let requester = msg.get_from();
+    state
+        .quotas
+        .check_rate(requester)
+        .await
+        .map_err(IntoResponse::into_response)?;
+    match state.blobs.read().await.get(&id) {
+        Some(blob) if &blob.owner == requester || blob.to.contains(requester) => {}
+        Some(_) => {
+            return Err((
+                StatusCode::FORBIDDEN,
+                "This blob is neither from you nor addressed to you.",
+            )
+                .into_response())
+        }
+        None => return Err(ERR_NOT_FOUND.into_response()),
+    }
+    let mut file = fs::File::open(state.path(&id)).await.map_err(|e| {
+        warn!("Unable to open blob {id}: {e}");
+        ERR_NOT_FOUND.into_response()
+    })?;
+    let len = file.metadata().await.map(|meta| meta.len()).ok();
+    debug!("{requester} is downloading blob {id}");
+    let stream = async_stream::stream! {
+        let mut buf = vec![0; shared::blobs::CHUNK_SIZE];
+        loop {
+            match file.read(&mut buf).await {
+                Ok(0) => break,
+                Ok(n) => yield Ok(hyper::body::Bytes::copy_from_slice(&buf[..n])),
+                Err(e) => {
+                    warn!("Unable to read blob {id}: {e}");
+                    yield Err(e);
+                    break;
+                }
+            }
+        }
+    };
+    let mut resp = StreamBody::new(stream).into_response();
+    if let Some(len) = len {
+        resp.headers_mut()
+            .insert(header::CONTENT_LENGTH, len.into());
+    }
+    resp.headers_mut().insert(
+        header::CONTENT_TYPE,
+        header::HeaderValue::from_static("application/octet-stream"),
+    );
+    Ok(resp)
+}
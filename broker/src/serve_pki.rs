@@ -48,6 +48,7 @@ pub(crate) fn router() -> Router {
     Router::new()
         .route("/v1/pki/certs", get(get_certificate_list))
         .route("/v1/pki/certs/im-ca", get(get_im_cert))
+        .route("/v1/pki/certs/revoked", get(get_revoked_certificate_list))
         .route(
             "/v1/pki/certs/by_serial/:serial",
             get(get_certificate_by_serial),
@@ -111,3 +112,15 @@ async fn get_certificate_list(
     let json = Json(list);
     Ok(json)
 }
+
+#[tracing::instrument(name = "/v1/pki/certs/revoked")]
+async fn get_revoked_certificate_list(
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    _: Authorized,
+) -> Result<Json<Vec<String>>, PkiError> {
+    debug!("Asked for revoked certificates by {addr}");
+    let list = shared::crypto::get_revoked_serial_list()
+        .await
+        .map_err(|e| PkiError::CommunicationWithVault(e.to_string()))?;
+    Ok(Json(list))
+}
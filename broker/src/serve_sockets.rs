@@ -0,0 +1,256 @@
+//! Socket connections between apps: The broker keeps the socket requests until the recipient's
+//! proxy picks them up, and relays the bytes between both proxies once they have connected.
+
+use std::{collections::HashMap, net::SocketAddr, sync::Arc, time::SystemTime};
+
+use axum::{
+    extract::{ConnectInfo, FromRef, State},
+    http::{header, StatusCode},
+    response::{IntoResponse, Response},
+    routing::get,
+    Json, Router,
+};
+use hyper::upgrade::Upgraded;
+use shared::{
+    policy::RoutingPolicy,
+    sockets::{self, Side, SocketUpgrade},
+    EncryptedMsgSocketRequest, HowLongToBlock, Msg, MsgEmpty, MsgId, MsgSigned,
+};
+use tokio::{
+    sync::{broadcast, oneshot, RwLock},
+    time,
+};
+use tracing::{debug, info, warn};
+
+use crate::quota::{Quotas, RateLimited};
+
+/// Long-polls without `wait_time` wait (almost) forever, like for tasks
+const WAIT_FOREVER: time::Duration = time::Duration::from_secs(31536000);
+
+/// The side of a connection waiting for the other end, which hands over its connection here
+type WaitingEnd = (Side, oneshot::Sender<Upgraded>);
+
+#[derive(Clone)]
+struct SocketState {
+    requests: Arc<RwLock<HashMap<MsgId, MsgSigned<EncryptedMsgSocketRequest>>>>,
+    new_request_tx: Arc<broadcast::Sender<MsgSigned<EncryptedMsgSocketRequest>>>,
+    /// Connections waiting for the other end of their socket
+    waiting: Arc<std::sync::Mutex<HashMap<MsgId, WaitingEnd>>>,
+    policy: Option<Arc<RwLock<RoutingPolicy>>>,
+    quotas: Quotas,
+}
+
+impl FromRef<SocketState> for Quotas {
+    fn from_ref(state: &SocketState) -> Self {
+        state.quotas.clone()
+    }
+}
+
+pub(crate) fn router(policy: Option<Arc<RwLock<RoutingPolicy>>>, quotas: Quotas) -> Router {
+    let state = SocketState {
+        requests: Default::default(),
+        new_request_tx: Arc::new(broadcast::channel(512).0),
+        waiting: Default::default(),
+        policy,
+        quotas,
+    };
+    Router::new()
+        .route(
+            "/v1/sockets",
+            get(get_socket_requests).post(post_socket_request),
+        )
+        .route("/v1/sockets/:id", get(connect_socket))
+        .with_state(state)
+}
+
+// POST /v1/sockets
+async fn post_socket_request(
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    State(state): State<SocketState>,
+    RateLimited(msg): RateLimited<EncryptedMsgSocketRequest>,
+) -> Result<(StatusCode, impl IntoResponse), Response> {
+    debug!(
+        "Client {} with IP {addr} is requesting socket {:?}",
+        msg.msg.from, msg
+    );
+    if let Some(policy) = &state.policy {
+        let denied = policy
+            .read()
+            .await
+            .denied_recipients(&msg.msg.from, &msg.msg.to);
+        if !denied.is_empty() {
+            warn!(
+                "Routing policy: Denied socket {} from {} to {denied:?}",
+                msg.msg.id, msg.msg.from
+            );
+            return Err((
+                StatusCode::FORBIDDEN,
+                "According to the Broker's routing policy, you may not address this recipient.",
+            )
+                .into_response());
+        }
+    }
+    let id = msg.msg.id;
+    let expire = msg.msg.expire;
+    {
+        let mut requests = state.requests.write().await;
+        if requests.contains_key(&id) {
+            return Err(
+                (StatusCode::CONFLICT, format!("ID {id} is already taken.")).into_response()
+            );
+        }
+        requests.insert(id, msg.clone());
+    }
+    // Nobody might be waiting right now
+    let _ = state.new_request_tx.send(msg);
+    // Forget the request and any connection waiting for the other end once the request expires
+    tokio::task::spawn(async move {
+        let ttl = expire.duration_since(SystemTime::now()).unwrap_or_default();
+        time::sleep(ttl).await;
+        if state.requests.write().await.remove(&id).is_some() {
+            debug!("Socket request {id} has expired");
+        }
+        state.waiting.lock().unwrap().remove(&id);
+    });
+    Ok((
+        StatusCode::CREATED,
+        [(header::LOCATION, format!("/v1/sockets/{id}"))],
+    ))
+}
+
+// GET /v1/sockets
+/// Lists the socket requests addressed to the requesting app or proxy, supporting long-polling
+async fn get_socket_requests(
+    block: HowLongToBlock,
+    State(state): State<SocketState>,
+    RateLimited(msg): RateLimited<MsgEmpty>,
+) -> (StatusCode, Json<Vec<MsgSigned<EncryptedMsgSocketRequest>>>) {
+    let requester = msg.get_from();
+    let addressed = |req: &MsgSigned<EncryptedMsgSocketRequest>| req.get_to().contains(requester);
+    let mut new_request_rx = state.new_request_tx.subscribe();
+    let mut requests: Vec<_> = state
+        .requests
+        .read()
+        .await
+        .values()
+        .filter(|req| addressed(req))
+        .cloned()
+        .collect();
+    let wait_count = usize::from(block.wait_count.unwrap_or(0));
+    let deadline = time::Instant::now() + block.wait_time.unwrap_or(WAIT_FOREVER);
+    while requests.len() < wait_count {
+        match time::timeout_at(deadline, new_request_rx.recv()).await {
+            Ok(Ok(req)) if addressed(&req) => requests.push(req),
+            Ok(Ok(_)) => {}
+            Ok(Err(broadcast::error::RecvError::Lagged(n))) => {
+                warn!("Socket request listener is too slow; skipped {n} requests.")
+            }
+            Ok(Err(broadcast::error::RecvError::Closed)) | Err(_) => break,
+        }
+    }
+    let status = if requests.len() < wait_count {
+        StatusCode::PARTIAL_CONTENT
+    } else {
+        StatusCode::OK
+    };
+    (status, Json(requests))
+}
+
+// GET /v1/sockets/:id
+/// Connects the sender's or the recipient's proxy to the socket. Once both ends have connected,
+/// the bytes are relayed between them.
+async fn connect_socket(
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    id: MsgId,
+    State(state): State<SocketState>,
+    upgrade: SocketUpgrade,
+    RateLimited(msg): RateLimited<MsgEmpty>,
+) -> Result<Response, (StatusCode, &'static str)> {
+    const ERR_NOT_FOUND: (StatusCode, &str) = (
+        StatusCode::NOT_FOUND,
+        "Socket request not found or expired.",
+    );
+    let requester = msg.get_from();
+    let (side, expire) = {
+        let requests = state.requests.read().await;
+        let request = requests.get(&id).ok_or(ERR_NOT_FOUND)?;
+        let side = if request.get_from() == requester {
+            Side::Initiator
+        } else if request.get_to().contains(requester) {
+            Side::Recipient
+        } else {
+            return Err((
+                StatusCode::FORBIDDEN,
+                "This socket is neither from you nor addressed to you.",
+            ));
+        };
+        (side, request.msg.expire)
+    };
+    let other_end = {
+        let mut waiting = state.waiting.lock().unwrap();
+        match waiting.remove(&id) {
+            Some((waiting_side, other_end)) if waiting_side != side => Some(other_end),
+            Some(same_side) => {
+                waiting.insert(id, same_side);
+                return Err((
+                    StatusCode::CONFLICT,
+                    "You are already connected to this socket.",
+                ));
+            }
+            None => None,
+        }
+    };
+    info!("{requester} with IP {addr} connected to socket {id}");
+    let resp = match other_end {
+        Some(other_end) => {
+            // Both ends are connected, so the socket cannot be used again
+            state.requests.write().await.remove(&id);
+            upgrade.accept(move |io| async move {
+                if other_end.send(io).is_err() {
+                    warn!("Socket {id}: The other end has gone away");
+                }
+            })
+        }
+        None => {
+            let (tx, rx) = oneshot::channel();
+            state.waiting.lock().unwrap().insert(id, (side, tx));
+            let ttl = expire.duration_since(SystemTime::now()).unwrap_or_default();
+            upgrade.accept(move |io| relay_when_connected(id, io, rx, ttl))
+        }
+    };
+    Ok(resp)
+}
+
+/// Waits for the other end of the socket, sending keep-alives meanwhile, and relays between both
+async fn relay_when_connected(
+    id: MsgId,
+    mut io: Upgraded,
+    mut other_end: oneshot::Receiver<Upgraded>,
+    ttl: time::Duration,
+) {
+    let deadline = time::sleep(ttl);
+    tokio::pin!(deadline);
+    let mut keep_alive = time::interval(shared::websocket::PING_INTERVAL);
+    let mut other_end = loop {
+        tokio::select! {
+            other_end = &mut other_end => match other_end {
+                Ok(other_end) => break other_end,
+                // The request has expired
+                Err(_) => return,
+            },
+            _ = &mut deadline => return,
+            _ = keep_alive.tick() => {
+                if let Err(e) = sockets::keep_alive(&mut io).await {
+                    debug!("Socket {id}: Waiting end has gone away: {e}");
+                    return;
+                }
+            }
+        }
+    };
+    match tokio::io::copy_bidirectional(&mut io, &mut other_end).await {
+        Ok((sent, received)) => {
+            debug!("Socket {id} closed after relaying {sent} and {received} bytes")
+        }
+        Err(e) => debug!("Socket {id} closed: {e}"),
+    }
+}
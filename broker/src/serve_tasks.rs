@@ -5,50 +5,111 @@ use std::{
 
 use axum::{
     extract::ConnectInfo,
-    extract::{Path, Query, State},
-    http::{header, HeaderValue, StatusCode},
-    response::{sse::Event, IntoResponse, Response, Sse},
+    extract::{FromRef, Path, Query, State},
+    http::{header, HeaderValue, Request, StatusCode},
+    response::{
+        sse::{Event, KeepAlive},
+        IntoResponse, Response, Sse,
+    },
     routing::{get, post, put},
-    Json, Router,
+    Extension, Json, Router,
 };
 use futures_core::{stream, Stream};
-use hyper::HeaderMap;
+use hyper::{service::Service, Body, HeaderMap};
 use serde::Deserialize;
 use shared::{
-    beam_id::AppOrProxyId, config, errors::SamplyBeamError, sse_event::SseEventType,
-    EncryptedMsgTaskRequest, EncryptedMsgTaskResult, HasWaitId, HowLongToBlock, Msg, MsgEmpty,
-    MsgId, MsgSigned, MsgTaskRequest, MsgTaskResult, WorkStatus, EMPTY_VEC_APPORPROXYID,
+    beam_id::{AppOrProxyId, ProxyId},
+    config,
+    errors::SamplyBeamError,
+    policy::RoutingPolicy,
+    sse_event::SseEventType,
+    websocket::{self, Api, Frame, Message, Upgrade},
+    wire, EncryptedMsgTaskRequest, EncryptedMsgTaskResult, HasWaitId, HowLongToBlock, Msg,
+    MsgEmpty, MsgId, MsgSigned, MsgTaskRequest, MsgTaskResult, WorkStatus, EMPTY_VEC_APPORPROXYID,
 };
 use tokio::{
     sync::{
-        broadcast::{Receiver, Sender},
+        broadcast::{error::RecvError, Receiver, Sender},
         RwLock,
     },
     time,
 };
 use tracing::{debug, error, info, trace, warn};
 
-use crate::expire;
+use crate::{
+    expire,
+    quota::{Quotas, RateLimited},
+};
 
 #[derive(Clone)]
 struct TasksState {
     tasks: Arc<RwLock<HashMap<MsgId, MsgSigned<EncryptedMsgTaskRequest>>>>,
     new_task_tx: Arc<Sender<MsgSigned<EncryptedMsgTaskRequest>>>,
     new_result_tx: Arc<RwLock<HashMap<MsgId, Sender<MsgSigned<EncryptedMsgTaskResult>>>>>,
+    /// New results of all tasks, along with the task's creator
+    new_result_any_tx: Arc<Sender<(AppOrProxyId, MsgSigned<EncryptedMsgTaskResult>)>>,
     removed_task_rx: Arc<Sender<MsgId>>,
+    /// Event IDs of each task's results, for resuming result streams
+    result_ids: Arc<RwLock<HashMap<MsgId, ResultIds>>>,
+    policy: Option<Arc<RwLock<RoutingPolicy>>>,
+    quotas: Quotas,
 }
 
-pub(crate) fn router() -> Router {
-    let state = TasksState::default();
+/// Results are numbered in the order they are stored, so a client resuming a result stream with
+/// `Last-Event-ID` only gets the results it has missed
+#[derive(Default)]
+pub(crate) struct ResultIds {
+    latest: u64,
+    /// The IDs of the first and the latest version of each worker's result
+    results: HashMap<AppOrProxyId, (u64, u64)>,
+}
+
+impl ResultIds {
+    fn record(&mut self, worker: &AppOrProxyId) {
+        self.latest += 1;
+        let (_, latest) = self
+            .results
+            .entry(worker.clone())
+            .or_insert((self.latest, self.latest));
+        *latest = self.latest;
+    }
+}
+
+/// How long clients of result streams should wait before reconnecting
+const SSE_RETRY: time::Duration = time::Duration::from_secs(3);
+
+impl FromRef<TasksState> for Quotas {
+    fn from_ref(state: &TasksState) -> Self {
+        state.quotas.clone()
+    }
+}
+
+pub(crate) fn router(policy: Option<Arc<RwLock<RoutingPolicy>>>, quotas: Quotas) -> Router {
+    let state = TasksState {
+        policy,
+        quotas,
+        ..Default::default()
+    };
     let state2 = state.clone();
     tokio::task::spawn(async move {
-        let err = expire::watch(state2.tasks.clone(), state2.new_task_tx.subscribe()).await;
+        let err = expire::watch(
+            state2.tasks.clone(),
+            state2.result_ids.clone(),
+            state2.new_task_tx.subscribe(),
+        )
+        .await;
         error!("Internal error: expire() returned with error {:?}", err);
     });
     Router::new()
         .route("/v1/tasks", get(get_tasks).post(post_task))
         .route("/v1/tasks/:task_id/results", get(get_results_for_task))
-        .route("/v1/tasks/:task_id/results/:app_id", put(put_result))
+        .route("/v1/tasks/:task_id", get(get_task))
+        .route(
+            "/v1/tasks/:task_id/results/:app_id",
+            get(get_result).put(put_result),
+        )
+        .route("/v1/events", get(get_events))
+        .route("/v1/ws", get(get_ws))
         .with_state(state)
 }
 
@@ -64,7 +125,11 @@ impl Default for TasksState {
             tasks,
             new_task_tx,
             new_result_tx: Arc::new(RwLock::new(HashMap::new())),
+            new_result_any_tx: Arc::new(tokio::sync::broadcast::channel(512).0),
             removed_task_rx: Arc::new(tokio::sync::broadcast::channel(512).0),
+            result_ids: Default::default(),
+            policy: None,
+            quotas: Quotas::default(),
         }
     }
 }
@@ -75,7 +140,7 @@ async fn get_results_for_task(
     block: HowLongToBlock,
     task_id: MsgId,
     headers: HeaderMap,
-    msg: MsgSigned<MsgEmpty>,
+    RateLimited(msg): RateLimited<MsgEmpty>,
 ) -> Result<Response, (StatusCode, &'static str)> {
     let found = &headers
         .get(header::ACCEPT)
@@ -88,13 +153,16 @@ async fn get_results_for_task(
         .is_some();
 
     let result = if *found {
-        get_results_for_task_stream(addr, state, block, task_id, msg)
+        let last_event_id = headers
+            .get(LAST_EVENT_ID)
+            .and_then(|id| id.to_str().ok()?.parse().ok());
+        get_results_for_task_stream(addr, state, block, task_id, msg, last_event_id)
             .await?
             .into_response()
     } else {
-        get_results_for_task_nostream(addr, state, block, task_id, msg)
-            .await?
-            .into_response()
+        let (statuscode, results) =
+            get_results_for_task_nostream(addr, state, block, task_id, msg).await?;
+        (statuscode, wire::signed_response(&headers, &results)).into_response()
     };
     Ok(result)
 }
@@ -106,8 +174,7 @@ async fn get_results_for_task_nostream(
     block: HowLongToBlock,
     task_id: MsgId,
     msg: MsgSigned<MsgEmpty>,
-) -> Result<(StatusCode, Json<Vec<MsgSigned<EncryptedMsgTaskResult>>>), (StatusCode, &'static str)>
-{
+) -> Result<(StatusCode, Vec<MsgSigned<EncryptedMsgTaskResult>>), (StatusCode, &'static str)> {
     debug!(
         "get_results_for_task(task={}) called by {} with IP {addr}, wait={:?}",
         task_id.to_string(),
@@ -129,7 +196,8 @@ async fn get_results_for_task_nostream(
         }
         let results: Vec<MsgSigned<EncryptedMsgTaskResult>> =
             task.msg.results.values().cloned().collect();
-        let rx_new_result = match would_wait_for_elements(results.len(), &block) {
+        let finished = count_results(&results, &block);
+        let rx_new_result = match would_wait_for_elements(finished, &block) {
             true => Some(
                 state
                     .new_result_tx
@@ -159,8 +227,8 @@ async fn get_results_for_task_nostream(
         )
         .await;
     }
-    let statuscode = wait_get_statuscode(&results, &block);
-    Ok((statuscode, Json(results)))
+    let statuscode = wait_get_statuscode(count_results(&results, &block), &block);
+    Ok((statuscode, results))
 }
 
 // GET /v1/tasks/:task_id/results/stream
@@ -170,14 +238,16 @@ async fn get_results_for_task_stream(
     block: HowLongToBlock,
     task_id: MsgId,
     msg: MsgSigned<MsgEmpty>,
+    last_event_id: Option<u64>,
 ) -> Result<Sse<impl Stream<Item = Result<Event, Infallible>>>, (StatusCode, &'static str)> {
     debug!(
-        "get_results_for_task_stream(task={}) called by {} with IP {addr}, wait={:?}",
+        "get_results_for_task_stream(task={}) called by {} with IP {addr}, wait={:?}, last event={:?}",
         task_id.to_string(),
         msg.get_from(),
-        block
+        block,
+        last_event_id
     );
-    let (mut results, rx_new_result, rx_deleted_task) = {
+    let (mut rx_new_result, mut rx_deleted_task) = {
         let tasks = state.tasks.read().await;
         let Some(task) = tasks.get(&task_id) else {
             return Err((StatusCode::NOT_FOUND, "Task not found"));
@@ -185,166 +255,137 @@ async fn get_results_for_task_stream(
         if task.get_from() != msg.get_from() {
             return Err((StatusCode::UNAUTHORIZED, "Not your task."));
         }
-        let results = task.msg.results.clone();
-        let rx_new_result = match would_wait_for_elements(results.len(), &block) {
-            true => Some(
-                state
-                    .new_result_tx
-                    .read()
-                    .await
-                    .get(&task_id)
-                    .unwrap_or_else(|| {
-                        panic!(
-                            "Internal error: No new_result_tx found for task {}",
-                            task_id
-                        )
-                    })
-                    .subscribe(),
-            ),
-            false => None,
-        };
-        (results, rx_new_result, state.removed_task_rx.subscribe())
+        let rx_new_result = state
+            .new_result_tx
+            .read()
+            .await
+            .get(&task_id)
+            .unwrap_or_else(|| {
+                panic!(
+                    "Internal error: No new_result_tx found for task {}",
+                    task_id
+                )
+            })
+            .subscribe();
+        (rx_new_result, state.removed_task_rx.subscribe())
     };
+    let wait_until = time::Instant::now()
+        + block
+            .wait_time
+            .unwrap_or(time::Duration::from_secs(31536000));
 
     let stream = async_stream::stream! {
-        for (_from, result) in &results {
-            let event = Event::default()
-                .event(SseEventType::NewResult)
-                .json_data(result);
-            yield match event {
-                Ok(event) => Ok(event),
-                Err(err) => {
-                    error!("Unable to serialize message: {}; offending message was {:?}", err, result);
-                    Ok(Event::default()
-                        .event(SseEventType::Error)
-                        .data("Internal error: Unable to serialize message.")
-                    )
+        yield Ok(Event::default().retry(SSE_RETRY));
+        let from = msg.get_from();
+        let filter_for_me = MsgFilterNoTask { from: None, to: Some(from), mode: MsgFilterMode::Or };
+        // Results are sent from the stored task whenever they change, so none are missed
+        let mut sent_until = last_event_id.unwrap_or(0);
+        loop {
+            let (changes, count) = {
+                let tasks = state.tasks.read().await;
+                let Some(task) = tasks.get(&task_id) else {
+                    break;
+                };
+                let result_ids = state.result_ids.read().await;
+                let ids = result_ids.get(&task_id);
+                let mut changes = Vec::new();
+                let mut count = 0;
+                for (worker, result) in task.msg.results.iter().filter(|(_, result)| filter_for_me.matches(*result)) {
+                    if !block.wait_final || result.msg.status.is_final() {
+                        count += 1;
+                    }
+                    let (first, latest) = ids.and_then(|ids| ids.results.get(worker)).copied().unwrap_or_default();
+                    if latest > sent_until {
+                        let event_type = match first > sent_until {
+                            true => SseEventType::NewResult,
+                            false => SseEventType::UpdatedResult,
+                        };
+                        changes.push((latest, event_type, result.clone()));
+                    }
                 }
+                changes.sort_by_key(|(id, ..)| *id);
+                (changes, count)
             };
-        }
-        if let Some(rx_new_result) = rx_new_result {
-            let from = msg.get_from();
-            let filter_for_me = MsgFilterNoTask { from: None, to: Some(&from), mode: MsgFilterMode::Or };
-            let other_stream = wait_for_results_for_task_stream(&mut results, &block, rx_new_result, &filter_for_me, rx_deleted_task, &task_id).await;
-            for await event in other_stream {
-                yield event;
+            for (id, event_type, result) in changes {
+                sent_until = id;
+                let event = Event::default()
+                    .event(event_type)
+                    .id(id.to_string())
+                    .json_data(&result);
+                yield match event {
+                    Ok(event) => Ok(event),
+                    Err(err) => {
+                        error!("Unable to serialize message: {}; offending message was {:?}", err, result);
+                        Ok(Event::default()
+                            .event(SseEventType::Error)
+                            .data("Internal error: Unable to serialize message.")
+                        )
+                    }
+                };
+            }
+            if !would_wait_for_elements(count, &block) {
+                break;
+            }
+            tokio::select! {
+                _ = tokio::time::sleep_until(wait_until) => {
+                    debug!("SSE: Wait expired.");
+                    yield Ok(Event::default()
+                        .event(SseEventType::WaitExpired)
+                        .data("{}"));
+                    break;
+                },
+                // Missed notifications do not matter, as the results are read from the task anyway
+                Ok(_) | Err(RecvError::Lagged(_)) = rx_new_result.recv() => {},
+                deleted_task_id = rx_deleted_task.recv() => {
+                    if deleted_task_id.is_ok_and(|deleted_task_id| deleted_task_id == task_id) {
+                        warn!("Task {} was just deleted while someone was waiting for results.", task_id);
+                        yield Ok(Event::default()
+                            .event(SseEventType::DeletedTask)
+                            .data(format!("{{ \"task_id\": \"{task_id}\" }}")));
+                        break;
+                    }
+                }
             }
         }
     };
 
-    let sse = Sse::new(stream);
-
-    Ok(sse)
+    // Keep-alive comments prevent the connection from timing out while there are no new results
+    Ok(Sse::new(stream).keep_alive(KeepAlive::default()))
 }
 
 fn would_wait_for_elements(existing_elements: usize, block: &HowLongToBlock) -> bool {
     usize::from(block.wait_count.unwrap_or(0)) > existing_elements
 }
 
-fn wait_get_statuscode<S>(vec: &Vec<S>, block: &HowLongToBlock) -> StatusCode {
-    if usize::from(block.wait_count.unwrap_or(0)) > vec.len() {
+fn wait_get_statuscode(existing_elements: usize, block: &HowLongToBlock) -> StatusCode {
+    if would_wait_for_elements(existing_elements, block) {
         StatusCode::PARTIAL_CONTENT
     } else {
         StatusCode::OK
     }
 }
 
-async fn wait_for_results_for_task_stream<'a, M: Msg, I: PartialEq>(
-    results: &'a mut HashMap<AppOrProxyId, M>,
-    block: &'a HowLongToBlock,
-    mut new_result_rx: Receiver<M>,
-    filter: &'a MsgFilterNoTask<'a>,
-    mut deleted_task_rx: Receiver<MsgId>,
-    task_id: &'a MsgId,
-) -> impl Stream<Item = Result<Event, Infallible>> + 'a
-where
-    M: Clone + HasWaitId<I> + Debug,
-{
-    let wait_until = time::Instant::now()
-        + block
-            .wait_time
-            .unwrap_or(time::Duration::from_secs(31536000));
-    trace!(
-        "Now is {:?}. Will wait until {:?}",
-        time::Instant::now(),
-        wait_until
-    );
-    let mut running = true;
-    let stream = async_stream::stream! {
-    while usize::from(block.wait_count.unwrap_or(0)) > results.len()
-        && time::Instant::now() < wait_until
-        && running {
-        trace!(
-            "Items in vec: {}, time remaining: {:?}",
-            results.len(),
-            wait_until - time::Instant::now()
-        );
-            tokio::select! {
-                _ = tokio::time::sleep_until(wait_until) => {
-                    debug!("SSE: Wait expired.");
-                    yield Ok(Event::default()
-                        .event(SseEventType::WaitExpired)
-                        .data("{}"));
-                    running = false;
-                },
-                result = new_result_rx.recv() => {
-                    match result {
-                        Ok(req) => {
-                            if filter.matches(&req) {
-                                let previous = results.insert(req.get_from().clone(), req.clone());
-                                let event_type = match previous {
-                                    Some(_) => SseEventType::UpdatedResult,
-                                    None => SseEventType::NewResult
-                                };
-                                let event = Event::default()
-                                    .event(event_type)
-                                    .json_data(&req);
-                                yield match event {
-                                    Ok(event) => Ok(event),
-                                    Err(err) => {
-                                        error!("Unable to serialize message: {}; offending message was {:?}", err, req);
-                                        Ok(Event::default()
-                                            .event(SseEventType::Error)
-                                            .data("Internal error: Unable to serialize message.")
-                                        )
-                                    }
-                                };
-                            }
-                        },
-                        Err(e) => { panic!("Unable to receive from queue new_result_rx: {}", e); }
-                    }
-                },
-                deleted_task_id = deleted_task_rx.recv() => {
-                    match deleted_task_id {
-                        Ok(deleted_task_id) => {
-                            if deleted_task_id == *task_id {
-                                warn!("Task {} was just deleted while someone was waiting for results. Returning the {} results up to now.", task_id, results.len());
-                                yield Ok(Event::default()
-                                    .event(SseEventType::DeletedTask)
-                                    .data("{ \"task_id\": \"{deleted_task_id}\" }"));
-                                running = false;
-                            }
-                        },
-                        Err(e) => { panic!("Unable to receive from queue deleted_task_rx: {}", e); }
-                    }
-                }
-            }
-        }
-    };
-    stream
+/// The number of results counting towards `wait_count`, i.e. only final ones if asked for
+fn count_results(results: &[MsgSigned<EncryptedMsgTaskResult>], block: &HowLongToBlock) -> usize {
+    if block.wait_final {
+        results
+            .iter()
+            .filter(|result| result.msg.status.is_final())
+            .count()
+    } else {
+        results.len()
+    }
 }
 
 // TODO: Is there a way to write this function in a generic way? (1/2)
-async fn wait_for_results_for_task<'a, M: Msg, I: PartialEq>(
-    vec: &mut Vec<M>,
+async fn wait_for_results_for_task<'a>(
+    vec: &mut Vec<MsgSigned<EncryptedMsgTaskResult>>,
     block: &HowLongToBlock,
-    mut new_result_rx: Receiver<M>,
+    mut new_result_rx: Receiver<MsgSigned<EncryptedMsgTaskResult>>,
     filter: &MsgFilterNoTask<'a>,
     mut deleted_task_rx: Receiver<MsgId>,
     task_id: &MsgId,
-) where
-    M: Clone + HasWaitId<I>,
-{
+) {
     let wait_until = time::Instant::now()
         + block
             .wait_time
@@ -354,7 +395,7 @@ async fn wait_for_results_for_task<'a, M: Msg, I: PartialEq>(
         time::Instant::now(),
         wait_until
     );
-    while usize::from(block.wait_count.unwrap_or(0)) > vec.len()
+    while would_wait_for_elements(count_results(vec, block), block)
         && time::Instant::now() < wait_until
     {
         trace!(
@@ -464,7 +505,8 @@ async fn get_tasks(
     block: HowLongToBlock,
     Query(taskfilter): Query<TaskFilter>,
     State(state): State<TasksState>,
-    msg: MsgSigned<MsgEmpty>,
+    headers: HeaderMap,
+    RateLimited(msg): RateLimited<MsgEmpty>,
 ) -> Result<(StatusCode, impl IntoResponse), (StatusCode, impl IntoResponse)> {
     let from = taskfilter.from;
     let mut to = taskfilter.to;
@@ -529,8 +571,47 @@ async fn get_tasks(
         state.removed_task_rx.subscribe(),
     )
     .await;
-    let statuscode = wait_get_statuscode(&vec, &block);
-    Ok((statuscode, Json(vec)))
+    let statuscode = wait_get_statuscode(vec.len(), &block);
+    Ok((statuscode, wire::signed_response(&headers, &vec)))
+}
+
+// GET /v1/tasks/:task_id
+/// Retrieves a single task created by or addressed to the requester
+async fn get_task(
+    task_id: MsgId,
+    State(state): State<TasksState>,
+    headers: HeaderMap,
+    RateLimited(msg): RateLimited<MsgEmpty>,
+) -> Result<Response, (StatusCode, &'static str)> {
+    let tasks = state.tasks.read().await;
+    let Some(task) = tasks.get(&task_id) else {
+        return Err((StatusCode::NOT_FOUND, "Task not found"));
+    };
+    if task.get_from() != msg.get_from() && !task.get_to().contains(msg.get_from()) {
+        return Err((StatusCode::UNAUTHORIZED, "Not your task."));
+    }
+    Ok(wire::signed_message_response(&headers, task))
+}
+
+// GET /v1/tasks/:task_id/results/:app_id
+/// Retrieves a single result created by or addressed to the requester
+async fn get_result(
+    Path((task_id, app_id)): Path<(MsgId, AppOrProxyId)>,
+    State(state): State<TasksState>,
+    headers: HeaderMap,
+    RateLimited(msg): RateLimited<MsgEmpty>,
+) -> Result<Response, (StatusCode, &'static str)> {
+    let tasks = state.tasks.read().await;
+    let Some(task) = tasks.get(&task_id) else {
+        return Err((StatusCode::NOT_FOUND, "Task not found"));
+    };
+    let Some(result) = task.msg.results.get(&app_id) else {
+        return Err((StatusCode::NOT_FOUND, "Result not found"));
+    };
+    if result.get_from() != msg.get_from() && !result.get_to().contains(msg.get_from()) {
+        return Err((StatusCode::UNAUTHORIZED, "Not your result."));
+    }
+    Ok(wire::signed_message_response(&headers, result))
 }
 
 trait MsgFilterTrait<M: Msg> {
@@ -658,8 +739,8 @@ impl<'a, M: Msg> MsgFilterTrait<M> for MsgFilterNoTask<'a> {
 async fn post_task(
     ConnectInfo(addr): ConnectInfo<SocketAddr>,
     State(state): State<TasksState>,
-    msg: MsgSigned<EncryptedMsgTaskRequest>,
-) -> Result<(StatusCode, impl IntoResponse), (StatusCode, String)> {
+    RateLimited(msg): RateLimited<EncryptedMsgTaskRequest>,
+) -> Result<(StatusCode, impl IntoResponse), Response> {
     // let id = MsgId::new();
     // msg.id = id;
     // TODO: Check if ID is taken
@@ -667,6 +748,28 @@ async fn post_task(
         "Client {} with IP {addr} is creating task {:?}",
         msg.msg.from, msg
     );
+    if let Some(policy) = &state.policy {
+        let denied = policy
+            .read()
+            .await
+            .denied_recipients(&msg.msg.from, &msg.msg.to);
+        if !denied.is_empty() {
+            let denied = denied
+                .iter()
+                .map(ToString::to_string)
+                .collect::<Vec<_>>()
+                .join(", ");
+            warn!(
+                "Routing policy: Denied task {} from {} to {denied}",
+                msg.msg.id, msg.msg.from
+            );
+            return Err((
+                StatusCode::FORBIDDEN,
+                format!("According to the Broker's routing policy, you may not address: {denied}"),
+            )
+                .into_response());
+        }
+    }
     let (new_tx, _) = tokio::sync::broadcast::channel(256);
     {
         let mut tasks = state.tasks.write().await;
@@ -675,10 +778,21 @@ async fn post_task(
             return Err((
                 StatusCode::CONFLICT,
                 format!("ID {} is already taken.", msg.msg.id),
-            ));
+            )
+                .into_response());
         }
+        state
+            .quotas
+            .check_task(&msg, &tasks)
+            .await
+            .map_err(IntoResponse::into_response)?;
         tasks.insert(msg.msg.id, msg.clone());
         txes.insert(msg.msg.id, new_tx);
+        state
+            .result_ids
+            .write()
+            .await
+            .insert(msg.msg.id, ResultIds::default());
         if let Err(e) = state.new_task_tx.send(msg.clone()) {
             debug!("Unable to send notification: {}. Ignoring since probably noone is currently waiting for tasks.", e);
         }
@@ -694,56 +808,296 @@ async fn put_result(
     ConnectInfo(addr): ConnectInfo<SocketAddr>,
     Path((task_id, app_id)): Path<(MsgId, AppOrProxyId)>,
     State(state): State<TasksState>,
-    result: MsgSigned<EncryptedMsgTaskResult>,
-) -> Result<StatusCode, (StatusCode, &'static str)> {
+    RateLimited(result): RateLimited<EncryptedMsgTaskResult>,
+) -> Result<StatusCode, Response> {
     debug!("Called: Task {:?}, {:?} by {addr}", task_id, result);
     if task_id != result.msg.task {
         return Err((
             StatusCode::BAD_REQUEST,
             "Task IDs supplied in path and payload do not match.",
-        ));
+        )
+            .into_response());
     }
     let worker_id = result.msg.from.clone();
     if app_id != worker_id {
         return Err((
             StatusCode::BAD_REQUEST,
             "AppID supplied in URL and signed message do not match.",
-        ));
+        )
+            .into_response());
     }
 
     // Step 1: Check prereqs.
     let mut tasks = state.tasks.write().await;
 
     // TODO: Check if this can be written nicer using .entry()
-    let task = match tasks.get_mut(&task_id) {
-        Some(task) => &mut task.msg,
-        None => return Err((StatusCode::NOT_FOUND, "Task not found")),
+    let task = match tasks.get(&task_id) {
+        Some(task) => &task.msg,
+        None => return Err((StatusCode::NOT_FOUND, "Task not found").into_response()),
     };
     debug!(?task, ?worker_id, "Checking if task is in worker ID: ");
     if !task.to.contains(&worker_id) {
         return Err((
             StatusCode::UNAUTHORIZED,
             "Your result is not requested for this task.",
-        ));
+        )
+            .into_response());
+    }
+    if let Some(policy) = &state.policy {
+        // Answering the task's creator is allowed if it may address the worker in the first place;
+        // any other recipient has to be allowed for the worker itself
+        let policy = policy.read().await;
+        let answers_creator = policy.may_address(&task.from, &worker_id);
+        let denied = policy.denied_recipients(
+            &worker_id,
+            result
+                .msg
+                .to
+                .iter()
+                .filter(|to| !answers_creator || **to != task.from),
+        );
+        if !denied.is_empty() {
+            let denied = denied
+                .iter()
+                .map(ToString::to_string)
+                .collect::<Vec<_>>()
+                .join(", ");
+            warn!("Routing policy: Denied result of {worker_id} to task {task_id} to {denied}");
+            return Err((
+                StatusCode::FORBIDDEN,
+                format!("According to the Broker's routing policy, you may not address: {denied}"),
+            )
+                .into_response());
+        }
     }
+    state
+        .quotas
+        .check_result(&result, task.results.get(&worker_id), &tasks)
+        .await
+        .map_err(IntoResponse::into_response)?;
 
     // Step 2: Insert.
+    let task = &mut tasks
+        .get_mut(&task_id)
+        .expect("Task to exist since we hold the lock")
+        .msg;
     let statuscode = match task.results.insert(worker_id.clone(), result.clone()) {
         Some(_) => StatusCode::NO_CONTENT,
         None => StatusCode::CREATED,
     };
+    state
+        .result_ids
+        .write()
+        .await
+        .entry(task_id)
+        .or_default()
+        .record(&worker_id);
 
     // Step 3: Notify. This has to happen while the lock for tasks is still held since otherwise results could get lost.
     let sender = state.new_result_tx.read().await;
     let sender = sender
         .get(&task_id)
         .unwrap_or_else(|| panic!("Internal error: No result_tx found for task {}", task_id));
+    if state
+        .new_result_any_tx
+        .send((task.from.clone(), result.clone()))
+        .is_err()
+    {
+        trace!("No proxy is subscribed to events.");
+    }
     if let Err(e) = sender.send(result) {
         debug!("Unable to send notification: {}. Ignoring since probably noone is currently waiting for tasks.", e);
     }
     Ok(statuscode)
 }
 
+/// New tasks and results concerning a proxy or its apps
+struct ProxyEvents {
+    proxy: ProxyId,
+    new_task_rx: Receiver<MsgSigned<EncryptedMsgTaskRequest>>,
+    new_result_rx: Receiver<(AppOrProxyId, MsgSigned<EncryptedMsgTaskResult>)>,
+}
+
+impl ProxyEvents {
+    fn subscribe(state: &TasksState, proxy: ProxyId) -> Self {
+        Self {
+            proxy,
+            new_task_rx: state.new_task_tx.subscribe(),
+            new_result_rx: state.new_result_any_tx.subscribe(),
+        }
+    }
+
+    fn concerns_proxy(&self, id: &AppOrProxyId) -> bool {
+        id.get_proxy_id() == self.proxy
+    }
+
+    /// The next event, with the signed message as data, or `None` once the broker shuts down
+    async fn next(&mut self) -> Option<(SseEventType, serde_json::Value)> {
+        loop {
+            let event = tokio::select! {
+                task = self.new_task_rx.recv() => match task {
+                    Ok(task) if self.concerns_proxy(task.get_from()) || task.get_to().iter().any(|id| self.concerns_proxy(id)) => {
+                        serde_json::to_value(&task).map(|task| (SseEventType::NewTask, task))
+                    },
+                    Ok(_) => continue,
+                    Err(RecvError::Lagged(n)) => return Some(lagged_event(n)),
+                    Err(RecvError::Closed) => return None,
+                },
+                result = self.new_result_rx.recv() => match result {
+                    Ok((task_creator, result)) if self.concerns_proxy(&task_creator) || result.get_to().iter().any(|id| self.concerns_proxy(id)) => {
+                        serde_json::to_value(&result).map(|result| (SseEventType::NewResult, result))
+                    },
+                    Ok(_) => continue,
+                    Err(RecvError::Lagged(n)) => return Some(lagged_event(n)),
+                    Err(RecvError::Closed) => return None,
+                },
+            };
+            return Some(event.unwrap_or_else(|err| {
+                error!("Unable to serialize message: {err}");
+                (
+                    SseEventType::Error,
+                    "Internal error: Unable to serialize message.".into(),
+                )
+            }));
+        }
+    }
+}
+
+fn lagged_event(missed: u64) -> (SseEventType, serde_json::Value) {
+    warn!("Subscriber is too slow; skipped {missed} events.");
+    (
+        SseEventType::Error,
+        format!("Missed {missed} events.").into(),
+    )
+}
+
+// GET /v1/events
+/// Notifies a proxy of all new tasks and results concerning it or its apps, so it can serve its
+/// apps' long-polls using a single connection to the broker.
+async fn get_events(
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    State(state): State<TasksState>,
+    RateLimited(msg): RateLimited<MsgEmpty>,
+) -> Result<Sse<impl Stream<Item = Result<Event, Infallible>>>, (StatusCode, &'static str)> {
+    let AppOrProxyId::ProxyId(proxy) = msg.get_from().clone() else {
+        return Err(ERR_ONLY_PROXIES);
+    };
+    info!("Proxy {proxy} with IP {addr} subscribed to events");
+    let mut events = ProxyEvents::subscribe(&state, proxy);
+    let stream = async_stream::stream! {
+        while let Some((event_type, data)) = events.next().await {
+            let event = Event::default().event(event_type);
+            yield Ok(match data {
+                serde_json::Value::String(text) => event.data(text),
+                data => event.json_data(data).expect("Serializing JSON to succeed"),
+            });
+        }
+    };
+    // Keep-alive comments prevent HTTP proxies from closing the connection while there are no events
+    Ok(Sse::new(stream).keep_alive(KeepAlive::default()))
+}
+
+const LAST_EVENT_ID: header::HeaderName = header::HeaderName::from_static("last-event-id");
+const X_FORWARDED_FOR: header::HeaderName = header::HeaderName::from_static("x-forwarded-for");
+
+const ERR_ONLY_PROXIES: (StatusCode, &str) = (
+    StatusCode::FORBIDDEN,
+    "Only proxies may subscribe to events.",
+);
+
+// GET /v1/ws
+/// Like `/v1/events`, but via a WebSocket through which the proxy may also send requests. These
+/// are signed like regular HTTP requests and processed by the broker's API as such.
+async fn get_ws(
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    State(state): State<TasksState>,
+    upgrade: Upgrade,
+    Extension(api): Extension<Api>,
+    headers: HeaderMap,
+    RateLimited(msg): RateLimited<MsgEmpty>,
+) -> Result<Response, (StatusCode, &'static str)> {
+    let AppOrProxyId::ProxyId(proxy) = msg.get_from().clone() else {
+        return Err(ERR_ONLY_PROXIES);
+    };
+    info!("Proxy {proxy} with IP {addr} connected via WebSocket");
+    let mut events = ProxyEvents::subscribe(&state, proxy.clone());
+    // Tunneled requests are logged with the address the WebSocket has been opened from
+    let forwarded_for = headers.get(X_FORWARDED_FOR).cloned();
+    Ok(upgrade.accept(move |sender, mut receiver| async move {
+        let sender2 = sender.clone();
+        let notify = tokio::task::spawn(async move {
+            let mut ping = time::interval(websocket::PING_INTERVAL);
+            loop {
+                let sent = tokio::select! {
+                    event = events.next() => match event {
+                        Some((event_type, data)) => sender2.send_json(&Frame::Event {
+                            event: event_type.to_string(),
+                            subscription: None,
+                            data,
+                        }).await,
+                        None => break,
+                    },
+                    _ = ping.tick() => sender2.ping().await,
+                };
+                if sent.is_err() {
+                    break;
+                }
+            }
+        });
+        loop {
+            let frame = match receiver.recv().await {
+                Ok(Some(Message::Text(text))) => serde_json::from_str::<Frame>(&text),
+                Ok(Some(Message::Binary(_))) => continue,
+                Ok(None) => break,
+                Err(e) => {
+                    warn!("WebSocket of proxy {proxy} failed: {e}");
+                    break;
+                }
+            };
+            let (id, req) = match frame {
+                Ok(Frame::Request {
+                    id,
+                    method,
+                    uri,
+                    headers,
+                    body,
+                }) => (id, websocket::to_request(&method, &uri, &headers, body)),
+                Ok(_) => continue,
+                Err(e) => {
+                    warn!("Got invalid frame from proxy {proxy}: {e}");
+                    continue;
+                }
+            };
+            let mut req = match req {
+                Ok(req) => req,
+                Err(e) => {
+                    let resp = (StatusCode::BAD_REQUEST, e.to_string()).into_response();
+                    let _ = sender
+                        .send_json(&Frame::from_response(id, resp).await)
+                        .await;
+                    continue;
+                }
+            };
+            req.headers_mut().remove(X_FORWARDED_FOR);
+            if let Some(forwarded_for) = &forwarded_for {
+                req.headers_mut()
+                    .insert(X_FORWARDED_FOR, forwarded_for.clone());
+            }
+            req.extensions_mut().insert(ConnectInfo(addr));
+            let mut api = api.router();
+            let sender = sender.clone();
+            tokio::task::spawn(async move {
+                let resp = api.call(req).await.expect("Infallible");
+                let _ = sender
+                    .send_json(&Frame::from_response(id, resp).await)
+                    .await;
+            });
+        }
+        notify.abort();
+        info!("Proxy {proxy} closed its WebSocket");
+    }))
+}
+
 #[cfg(all(test, never))] // Removed until the errors down below are fixed
 mod test {
     use serde_json::Value;
@@ -827,3 +1181,335 @@ mod test {
         );
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, SystemTime};
+
+    use axum::body::BoxBody;
+    use hyper::body::HttpBody;
+    use serde_json::Value;
+    use shared::{beam_id::BeamId, beam_id::BrokerId, Encrypted, FailureStrategy, Progress};
+
+    use super::*;
+
+    const BROKER_ID: &str = "broker.samply.de";
+
+    fn id(id: &str) -> AppOrProxyId {
+        BrokerId::set_broker_id(BROKER_ID.to_string());
+        AppOrProxyId::new(&format!("{id}.{BROKER_ID}")).unwrap()
+    }
+
+    fn signed<M: Msg>(msg: M) -> MsgSigned<M> {
+        MsgSigned {
+            msg,
+            jwt: "Certainly valid".into(),
+        }
+    }
+
+    /// A task by app1.proxy1, along with the state of a Broker storing it
+    async fn state_with_task() -> (TasksState, MsgId) {
+        let state = TasksState::default();
+        let task = signed(MsgTaskRequest {
+            id: MsgId::new(),
+            from: id("app1.proxy1"),
+            to: vec![id("app1.proxy2"), id("app1.proxy3")],
+            body: Encrypted::default(),
+            bodies: HashMap::new(),
+            expire: SystemTime::now() + Duration::from_secs(3600),
+            failure_strategy: FailureStrategy::Discard,
+            results: HashMap::new(),
+            metadata: Value::Null,
+        });
+        let task_id = task.msg.id;
+        state.tasks.write().await.insert(task_id, task);
+        state
+            .new_result_tx
+            .write()
+            .await
+            .insert(task_id, tokio::sync::broadcast::channel(256).0);
+        state
+            .result_ids
+            .write()
+            .await
+            .insert(task_id, ResultIds::default());
+        (state, task_id)
+    }
+
+    /// Stores and announces a result like `put_result`
+    async fn store_result(state: &TasksState, task_id: MsgId, worker: &str, status: WorkStatus) {
+        let mut result = signed(MsgTaskResult {
+            from: id(worker),
+            to: vec![id("app1.proxy1")],
+            task: task_id,
+            status,
+            body: Encrypted::default(),
+            metadata: Value::Null,
+        });
+        result.jwt = format!("Result by {worker}");
+        let mut tasks = state.tasks.write().await;
+        let task = tasks.get_mut(&task_id).unwrap();
+        task.msg.results.insert(id(worker), result.clone());
+        state
+            .result_ids
+            .write()
+            .await
+            .get_mut(&task_id)
+            .unwrap()
+            .record(&id(worker));
+        let _ = state.new_result_tx.read().await[&task_id].send(result);
+    }
+
+    async fn stream(
+        state: &TasksState,
+        task_id: MsgId,
+        block: HowLongToBlock,
+        last_event_id: Option<u64>,
+    ) -> BoxBody {
+        let addr = SocketAddr::from(([127, 0, 0, 1], 0));
+        let msg = signed(MsgEmpty {
+            from: id("app1.proxy1"),
+        });
+        get_results_for_task_stream(addr, state.clone(), block, task_id, msg, last_event_id)
+            .await
+            .unwrap()
+            .into_response()
+            .into_body()
+    }
+
+    /// Reads the stream until `count` results have been received or it ends, returning their event types and IDs
+    async fn next_results(body: &mut BoxBody, count: usize) -> Vec<(String, u64)> {
+        let mut text = String::new();
+        let mut results = Vec::new();
+        while results.len() < count {
+            let Some(chunk) = time::timeout(Duration::from_secs(5), body.data())
+                .await
+                .expect("Result stream stalled")
+            else {
+                break;
+            };
+            let chunk = chunk.unwrap();
+            text.push_str(std::str::from_utf8(&chunk).unwrap());
+            while let Some((event, rest)) = text.split_once("\n\n") {
+                let field = |name: &str| {
+                    event
+                        .lines()
+                        .find_map(|line| line.strip_prefix(name)?.strip_prefix(':'))
+                        .map(str::to_string)
+                };
+                if let (Some(event_type), Some(id)) = (field("event"), field("id")) {
+                    results.push((event_type, id.parse().unwrap()));
+                }
+                text = rest.to_string();
+            }
+        }
+        results
+    }
+
+    fn block(wait_count: Option<u16>, wait_final: bool) -> HowLongToBlock {
+        HowLongToBlock {
+            wait_time: None,
+            wait_count,
+            wait_final,
+        }
+    }
+
+    fn event(event_type: &str, id: u64) -> (String, u64) {
+        (event_type.to_string(), id)
+    }
+
+    #[tokio::test]
+    async fn result_stream_distinguishes_new_and_updated_results() {
+        let (state, task_id) = state_with_task().await;
+        let mut body = stream(&state, task_id, block(Some(2), false), None).await;
+
+        store_result(&state, task_id, "app1.proxy2", WorkStatus::Claimed).await;
+        assert_eq!(next_results(&mut body, 1).await, [event("new_result", 1)]);
+        store_result(&state, task_id, "app1.proxy2", WorkStatus::Succeeded).await;
+        assert_eq!(
+            next_results(&mut body, 1).await,
+            [event("updated_result", 2)]
+        );
+        store_result(&state, task_id, "app1.proxy3", WorkStatus::Succeeded).await;
+        assert_eq!(next_results(&mut body, 1).await, [event("new_result", 3)]);
+        // Enough results have been sent, so the stream ends
+        assert!(time::timeout(Duration::from_secs(5), body.data())
+            .await
+            .unwrap()
+            .is_none());
+    }
+
+    fn running(percent: u8) -> WorkStatus {
+        WorkStatus::Running {
+            progress: Some(Progress {
+                percent: Some(percent),
+                ..Default::default()
+            }),
+        }
+    }
+
+    #[tokio::test]
+    async fn waiting_for_final_results_skips_running_ones() {
+        let (state, task_id) = state_with_task().await;
+        let msg = signed(MsgEmpty {
+            from: id("app1.proxy1"),
+        });
+        let block = HowLongToBlock {
+            wait_time: Some(Duration::from_secs(5)),
+            ..block(Some(1), true)
+        };
+        let waiting = tokio::spawn(get_results_for_task_nostream(
+            SocketAddr::from(([127, 0, 0, 1], 0)),
+            state.clone(),
+            block,
+            task_id,
+            msg,
+        ));
+        store_result(&state, task_id, "app1.proxy2", running(50)).await;
+        time::sleep(Duration::from_millis(100)).await;
+        assert!(!waiting.is_finished());
+
+        store_result(&state, task_id, "app1.proxy2", WorkStatus::Succeeded).await;
+        let (status, results) = time::timeout(Duration::from_secs(1), waiting)
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0].msg.status, WorkStatus::Succeeded);
+    }
+
+    #[tokio::test]
+    async fn progress_is_streamed_as_updated_results() {
+        let (state, task_id) = state_with_task().await;
+        let mut body = stream(&state, task_id, block(Some(1), true), None).await;
+
+        store_result(&state, task_id, "app1.proxy2", running(10)).await;
+        assert_eq!(next_results(&mut body, 1).await, [event("new_result", 1)]);
+        store_result(&state, task_id, "app1.proxy2", running(50)).await;
+        assert_eq!(
+            next_results(&mut body, 1).await,
+            [event("updated_result", 2)]
+        );
+        store_result(&state, task_id, "app1.proxy2", WorkStatus::Succeeded).await;
+        assert_eq!(
+            next_results(&mut body, 1).await,
+            [event("updated_result", 3)]
+        );
+        // The result is final, so the stream ends
+        assert!(next_results(&mut body, 1).await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn single_messages_are_only_given_to_their_parties() {
+        let (state, task_id) = state_with_task().await;
+        store_result(&state, task_id, "app1.proxy2", WorkStatus::Succeeded).await;
+        let requester = |app: &str| RateLimited(signed(MsgEmpty { from: id(app) }));
+        let task = |app: &'static str| {
+            get_task(
+                task_id,
+                State(state.clone()),
+                HeaderMap::new(),
+                requester(app),
+            )
+        };
+        let result = |app: &'static str, worker: &str| {
+            get_result(
+                Path((task_id, id(worker))),
+                State(state.clone()),
+                HeaderMap::new(),
+                requester(app),
+            )
+        };
+
+        let body = |resp: Response| async {
+            let bytes = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+            serde_json::from_slice::<Value>(&bytes).unwrap()
+        };
+        for app in ["app1.proxy1", "app1.proxy3"] {
+            let task = body(task(app).await.unwrap()).await;
+            assert_eq!(task["jwt"], "Certainly valid");
+        }
+        assert_eq!(
+            task("app2.proxy1").await.unwrap_err().0,
+            StatusCode::UNAUTHORIZED
+        );
+
+        for app in ["app1.proxy1", "app1.proxy2"] {
+            let result = body(result(app, "app1.proxy2").await.unwrap()).await;
+            assert_eq!(result["jwt"], "Result by app1.proxy2");
+        }
+        assert_eq!(
+            result("app1.proxy3", "app1.proxy2").await.unwrap_err().0,
+            StatusCode::UNAUTHORIZED
+        );
+        assert_eq!(
+            result("app1.proxy1", "app1.proxy3").await.unwrap_err().0,
+            StatusCode::NOT_FOUND
+        );
+    }
+
+    #[tokio::test]
+    async fn result_stream_resumes_after_last_event_id() {
+        let (state, task_id) = state_with_task().await;
+        store_result(&state, task_id, "app1.proxy2", WorkStatus::Claimed).await;
+        store_result(&state, task_id, "app1.proxy3", WorkStatus::Claimed).await;
+        store_result(&state, task_id, "app1.proxy2", WorkStatus::Succeeded).await;
+
+        let mut body = stream(&state, task_id, block(None, false), None).await;
+        assert_eq!(
+            next_results(&mut body, 2).await,
+            [event("new_result", 2), event("new_result", 3)]
+        );
+        let mut body = stream(&state, task_id, block(None, false), Some(1)).await;
+        assert_eq!(
+            next_results(&mut body, 2).await,
+            [event("new_result", 2), event("updated_result", 3)]
+        );
+        let mut body = stream(&state, task_id, block(None, false), Some(2)).await;
+        assert_eq!(
+            next_results(&mut body, 1).await,
+            [event("updated_result", 3)]
+        );
+        // Nothing has been missed, so the stream ends right away
+        let mut body = stream(&state, task_id, block(None, false), Some(3)).await;
+        assert!(next_results(&mut body, 1).await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn results_only_go_to_recipients_allowed_by_the_policy() {
+        let (mut state, task_id) = state_with_task().await;
+        let policy: RoutingPolicy = serde_json::from_str(&format!(
+            r#"{{ "rules": [ {{ "from": ["*.proxy1.{BROKER_ID}"], "to": ["*.proxy2.{BROKER_ID}"] }} ] }}"#
+        ))
+        .unwrap();
+        state.policy = Some(Arc::new(RwLock::new(policy)));
+        let put = |to: Vec<AppOrProxyId>| {
+            let result = signed(MsgTaskResult {
+                from: id("app1.proxy2"),
+                to,
+                task: task_id,
+                status: WorkStatus::Succeeded,
+                body: Encrypted::default(),
+                metadata: Value::Null,
+            });
+            put_result(
+                ConnectInfo(SocketAddr::from(([127, 0, 0, 1], 0))),
+                Path((task_id, id("app1.proxy2"))),
+                State(state.clone()),
+                RateLimited(result),
+            )
+        };
+
+        // The worker may not address anyone else along with the task's creator
+        let resp = put(vec![id("app1.proxy1"), id("app1.proxy3")])
+            .await
+            .unwrap_err();
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+        assert_eq!(
+            put(vec![id("app1.proxy1")]).await.ok(),
+            Some(StatusCode::CREATED)
+        );
+    }
+}
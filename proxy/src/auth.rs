@@ -12,10 +12,13 @@ use hyper::{
 use shared::{
     beam_id::{AppId, BeamId},
     config, config_proxy,
+    tls_server::ClientCertificate,
 };
 
 use tracing::debug;
 
+use crate::oidc;
+
 pub(crate) struct AuthenticatedApp(pub(crate) AppId);
 
 #[async_trait]
@@ -31,23 +34,54 @@ impl<S: Send + Sync> FromRequestParts<S> for AuthenticatedApp {
         if let Some(auth) = parts.headers.get(header::AUTHORIZATION) {
             let auth = auth.to_str().map_err(|_| UNAUTH_ERR)?;
             let mut auth = auth.split(' ');
-            if auth.next().unwrap_or("") != SCHEME {
-                return Err(UNAUTH_ERR);
+            match auth.next().unwrap_or("") {
+                SCHEME => {}
+                oidc::SCHEME => {
+                    let token = auth.next().ok_or(UNAUTH_ERR)?;
+                    let client_id = oidc::authenticate(token).await.ok_or(UNAUTH_ERR)?;
+                    debug!(
+                        "Request authenticated via bearer token (ClientID {})",
+                        client_id
+                    );
+                    return Ok(Self(client_id));
+                }
+                _ => return Err(UNAUTH_ERR),
             }
             let client_id = auth.next().unwrap_or("");
             let client_id = AppId::new(client_id).map_err(|_| UNAUTH_ERR)?;
-            let api_key_actual = config::CONFIG_PROXY
-                .api_keys
-                .get(&client_id)
-                .ok_or(UNAUTH_ERR)?;
             let api_key_claimed = auth.next().ok_or(UNAUTH_ERR)?;
-            if api_key_claimed != api_key_actual {
+            if !config::CONFIG_PROXY
+                .api_keys
+                .read()
+                .await
+                .verify(&client_id, api_key_claimed)
+                .await
+            {
                 return Err(UNAUTH_ERR);
             }
             debug!("Request authenticated (ClientID {})", client_id);
             Ok(Self(client_id))
+        } else if let Some(Some(cert)) = parts.extensions.get::<Option<ClientCertificate>>() {
+            let client_id = cert
+                .common_name()
+                .and_then(|cn| app_id_from_name(&cn))
+                .ok_or(UNAUTH_ERR)?;
+            debug!(
+                "Request authenticated via client certificate (ClientID {})",
+                client_id
+            );
+            Ok(Self(client_id))
         } else {
             Err(UNAUTH_ERR)
         }
     }
 }
+
+/// Maps an app's name (e.g. `app1`) or AppId, as given by a bearer token or client certificate, to the AppId of this proxy's app
+pub(crate) fn app_id_from_name(name: &str) -> Option<AppId> {
+    let proxy_id = &config::CONFIG_PROXY.proxy_id;
+    let name = name.strip_suffix(&format!(".{proxy_id}")).unwrap_or(name);
+    AppId::new(&format!("{name}.{proxy_id}"))
+        .map_err(|e| debug!("Invalid app name {name}: {e}"))
+        .ok()
+}
@@ -95,6 +95,10 @@ impl GetCerts for GetCertsFromBroker {
             .await
     }
 
+    async fn revoked_certificate_list(&self) -> Result<Vec<String>, SamplyBeamError> {
+        self.query_vec("/v1/pki/certs/revoked").await
+    }
+
     async fn im_certificate_as_pem(&self) -> Result<String, SamplyBeamError> {
         debug!("Retrieving im ca certificate ...");
         self.query("/v1/pki/certs/im-ca").await
@@ -15,9 +15,15 @@ use tracing::{debug, error, info, warn};
 mod auth;
 mod banner;
 mod crypto;
+mod multiplex;
+mod oidc;
+mod outbox;
 mod serve;
+mod serve_blobs;
 mod serve_health;
+mod serve_sockets;
 mod serve_tasks;
+mod serve_verify;
 
 #[tokio::main]
 pub async fn main() -> anyhow::Result<()> {
@@ -33,23 +39,39 @@ pub async fn main() -> anyhow::Result<()> {
     )
     .map_err(SamplyBeamError::HttpProxyProblem)?;
 
-    if let Err(err) = retry_notify(
-        ExponentialBackoff::default(),
-        || async { Ok(get_broker_health(&config, &client).await?) },
-        |err, dur: Duration| {
-            warn!(
-                "Still trying to reach Broker: {}. Retrying in {}s",
-                err,
-                dur.as_secs()
-            );
-        },
-    )
-    .await
-    {
-        error!("Giving up reaching Broker: {}", err);
-        std::process::exit(1);
+    let cert_cache_warm = config
+        .cert_cache_file
+        .as_ref()
+        .is_some_and(|file| file.is_file());
+    let broker_health = if cert_cache_warm {
+        // With persisted certificates, we can start up even if the Broker is (briefly) unreachable.
+        get_broker_health(&config, &client).await
     } else {
-        info!("Connected to Broker: {}", &config.broker_uri);
+        retry_notify(
+            ExponentialBackoff::default(),
+            || async { Ok(get_broker_health(&config, &client).await?) },
+            |err, dur: Duration| {
+                warn!(
+                    "Still trying to reach Broker: {}. Retrying in {}s",
+                    err,
+                    dur.as_secs()
+                );
+            },
+        )
+        .await
+    };
+    match broker_health {
+        Ok(()) => info!("Connected to Broker: {}", &config.broker_uri),
+        Err(err) if cert_cache_warm => {
+            warn!(
+                "Unable to reach Broker: {}. Starting up with cached certificates.",
+                err
+            )
+        }
+        Err(err) => {
+            error!("Giving up reaching Broker: {}", err);
+            std::process::exit(1);
+        }
     }
 
     if let Err(err) = retry_notify(
@@ -82,7 +104,7 @@ async fn init_crypto(config: Config, client: SamplyHttpClient) -> Result<(), Sam
         client.clone(),
         private_crypto_proxy.clone(),
     )?);
-    shared::crypto::init_ca_chain().await?;
+    shared::crypto::init_ca_chain(config.cert_cache_file.clone()).await?;
 
     let _public_info: Vec<_> =
         shared::crypto::get_all_certs_and_clients_by_cname_as_pemstr(&config.proxy_id)
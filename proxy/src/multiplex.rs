@@ -0,0 +1,322 @@
+//! A single, persistent connection to the Broker carrying the new tasks and results for all local
+//! apps, so that their long-polls do not each hold a connection to the Broker. If the Broker
+//! supports WebSockets, tasks and results are also sent through this connection.
+
+use std::{
+    collections::HashMap,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use futures::{StreamExt, TryStreamExt};
+use hyper::{header, upgrade::Upgraded, Body, Method, Request, Response, Uri};
+use serde_json::Value;
+use shared::{
+    beam_id::AppOrProxyId,
+    config_proxy,
+    errors::SamplyBeamError,
+    http_client::SamplyHttpClient,
+    sse_event::SseEventType,
+    websocket::{self, Frame, Message, WsSender},
+    EncryptedMessage, MsgEmpty, MsgId, MsgSigned,
+};
+use tokio::sync::{broadcast, oneshot};
+use tracing::{debug, info, warn};
+
+use crate::serve_tasks::sign_request;
+
+/// Reconnecting is retried with exponential backoff between these intervals
+const MIN_RECONNECT_INTERVAL: Duration = Duration::from_secs(1);
+const MAX_RECONNECT_INTERVAL: Duration = Duration::from_secs(5 * 60);
+
+/// A new task or result, as announced by the Broker
+pub(crate) struct UpstreamMessage {
+    pub(crate) event_type: SseEventType,
+    /// Verified sender and recipients, for picking the messages an app waits for
+    pub(crate) from: AppOrProxyId,
+    pub(crate) to: Vec<AppOrProxyId>,
+    /// The task's ID, or the ID of the task being answered
+    pub(crate) task: MsgId,
+    /// The signed message as sent by the Broker, i.e. `{"jwt": "..."}`
+    pub(crate) signed: Value,
+}
+
+pub(crate) enum UpstreamEvent {
+    Message(UpstreamMessage),
+    /// Events may have been missed, so waiting apps should fetch again
+    Disconnected,
+}
+
+#[derive(Clone)]
+pub(crate) struct Upstream {
+    tx: broadcast::Sender<Arc<UpstreamEvent>>,
+    connected: Arc<AtomicBool>,
+    socket: Arc<Mutex<Option<WsSender<Upgraded>>>>,
+    /// Requests sent through the WebSocket, waiting for the Broker's response
+    pending: Arc<Mutex<HashMap<u64, oneshot::Sender<Response<Body>>>>>,
+    next_id: Arc<AtomicU64>,
+}
+
+impl Upstream {
+    fn new() -> Self {
+        Self {
+            tx: broadcast::channel(512).0,
+            connected: Default::default(),
+            socket: Default::default(),
+            pending: Default::default(),
+            next_id: Default::default(),
+        }
+    }
+
+    /// Connects to the Broker's event stream in the background, reconnecting as needed
+    pub(crate) fn connect(client: SamplyHttpClient, config: config_proxy::Config) -> Self {
+        let upstream = Self::new();
+        let upstream2 = upstream.clone();
+        tokio::task::spawn(async move {
+            let mut retry_interval = MIN_RECONNECT_INTERVAL;
+            loop {
+                match upstream2.listen(&client, &config).await {
+                    Ok(()) => {
+                        debug!("Event stream from the Broker ended; reconnecting.");
+                        retry_interval = MIN_RECONNECT_INTERVAL;
+                    }
+                    Err(e) => warn!(
+                        "Unable to receive events from the Broker, using a connection per long-poll instead: {e}"
+                    ),
+                }
+                *upstream2.socket.lock().unwrap() = None;
+                upstream2.pending.lock().unwrap().clear();
+                if upstream2.connected.swap(false, Ordering::SeqCst) {
+                    let _ = upstream2.tx.send(Arc::new(UpstreamEvent::Disconnected));
+                }
+                tokio::time::sleep(retry_interval).await;
+                retry_interval = (retry_interval * 2).min(MAX_RECONNECT_INTERVAL);
+            }
+        });
+        upstream
+    }
+
+    /// Subscribes to the Broker's events if the connection is established
+    pub(crate) fn subscribe(&self) -> Option<broadcast::Receiver<Arc<UpstreamEvent>>> {
+        let rx = self.tx.subscribe();
+        self.connected.load(Ordering::SeqCst).then_some(rx)
+    }
+
+    /// Sends a signed request through the WebSocket to the Broker. Returns the request if there
+    /// is no WebSocket, so it can be sent via HTTP instead.
+    pub(crate) async fn request(
+        &self,
+        req: Request<Body>,
+    ) -> Result<Result<Response<Body>, SamplyBeamError>, Request<Body>> {
+        let Some(socket) = self.socket.lock().unwrap().clone() else {
+            return Err(req);
+        };
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        Ok(async {
+            let frame = Frame::from_request(id, req).await?;
+            let (tx, rx) = oneshot::channel();
+            self.pending.lock().unwrap().insert(id, tx);
+            if let Err(e) = socket.send_json(&frame).await {
+                self.pending.lock().unwrap().remove(&id);
+                return Err(SamplyBeamError::HttpProxyProblem(e));
+            }
+            rx.await.map_err(|_| {
+                SamplyBeamError::InternalSynchronizationError(
+                    "WebSocket to the Broker closed before its response".into(),
+                )
+            })
+        }
+        .await)
+    }
+
+    /// A signed request to open the connection
+    async fn signed_request(
+        &self,
+        config: &config_proxy::Config,
+        path: &str,
+        accept: &'static str,
+    ) -> Result<Request<Body>, SamplyBeamError> {
+        let uri = Uri::try_from(format!("{}{path}", config.broker_uri))
+            .map_err(|_| SamplyBeamError::InvalidPath)?;
+        let (parts, _) = Request::builder()
+            .method(Method::GET)
+            .uri(uri)
+            .header(header::ACCEPT, accept)
+            .header(header::VIA, env!("SAMPLY_USER_AGENT"))
+            .body(())
+            .expect("To build request successfully")
+            .into_parts();
+        let body = EncryptedMessage::MsgEmpty(MsgEmpty {
+            from: AppOrProxyId::ProxyId(config.proxy_id.clone()),
+        });
+        sign_request(body, parts, config, None)
+            .await
+            .map_err(|(_, msg)| SamplyBeamError::SignEncryptError(msg.into()))
+    }
+
+    async fn listen(
+        &self,
+        client: &SamplyHttpClient,
+        config: &config_proxy::Config,
+    ) -> Result<(), SamplyBeamError> {
+        let req = self.signed_request(config, "v1/ws", "*/*").await?;
+        let (sender, mut receiver) = match websocket::connect(client, req).await {
+            Ok(socket) => socket,
+            Err(e) => {
+                debug!("Unable to open WebSocket to the Broker, using its event stream: {e}");
+                return self.listen_sse(client, config).await;
+            }
+        };
+        info!("Exchanging tasks and results for all apps via a WebSocket to the Broker");
+        *self.socket.lock().unwrap() = Some(sender);
+        self.connected.store(true, Ordering::SeqCst);
+
+        while let Some(msg) = receiver
+            .recv()
+            .await
+            .map_err(SamplyBeamError::HttpProxyProblem)?
+        {
+            let Message::Text(text) = msg else {
+                continue;
+            };
+            match serde_json::from_str::<Frame>(&text) {
+                Ok(Frame::Event { event, data, .. }) => {
+                    let event_type = SseEventType::from_str(&event).expect("Error in Infallible");
+                    self.dispatch(event_type, Ok(data)).await;
+                }
+                Ok(Frame::Response {
+                    id,
+                    status,
+                    headers,
+                    body,
+                }) => {
+                    let Some(tx) = self.pending.lock().unwrap().remove(&id) else {
+                        continue;
+                    };
+                    match websocket::to_response(status, &headers, body) {
+                        Ok(resp) => {
+                            let _ = tx.send(resp);
+                        }
+                        Err(e) => warn!("Got invalid response from the Broker: {e}"),
+                    }
+                }
+                Ok(other) => debug!("WebSocket: Ignoring unexpected frame {other:?}"),
+                Err(e) => warn!("WebSocket: Discarding invalid frame from the Broker: {e}"),
+            }
+        }
+        Ok(())
+    }
+
+    async fn listen_sse(
+        &self,
+        client: &SamplyHttpClient,
+        config: &config_proxy::Config,
+    ) -> Result<(), SamplyBeamError> {
+        let req = self
+            .signed_request(config, "v1/events", "text/event-stream")
+            .await?;
+        let resp = client.request(req).await?;
+        if !resp.status().is_success() {
+            return Err(SamplyBeamError::VaultOtherError(format!(
+                "Broker answered with status {}",
+                resp.status()
+            )));
+        }
+        info!("Receiving tasks and results for all apps via a single connection to the Broker");
+        self.connected.store(true, Ordering::SeqCst);
+
+        let incoming = resp
+            .into_body()
+            .map(|result| result.map_err(std::io::Error::other))
+            .into_async_read();
+        let mut reader = async_sse::decode(incoming);
+        while let Some(event) = reader.next().await {
+            let event = event.map_err(|e| {
+                SamplyBeamError::VaultOtherError(format!("Unable to read event stream: {e}"))
+            })?;
+            let async_sse::Event::Message(event) = event else {
+                continue;
+            };
+            let event_type = SseEventType::from_str(event.name()).expect("Error in Infallible");
+            let data = match event_type {
+                SseEventType::Error => Ok(String::from_utf8_lossy(event.data()).into()),
+                _ => serde_json::from_slice(event.data()),
+            };
+            self.dispatch(event_type, data).await;
+        }
+        Ok(())
+    }
+
+    async fn dispatch(&self, event_type: SseEventType, data: Result<Value, serde_json::Error>) {
+        match event_type {
+            SseEventType::NewTask | SseEventType::NewResult => {}
+            SseEventType::Error => {
+                match data {
+                    Ok(Value::String(error)) => warn!("The Broker has reported an error: {error}"),
+                    _ => warn!("The Broker has reported an error."),
+                }
+                // Events may have been dropped
+                let _ = self.tx.send(Arc::new(UpstreamEvent::Disconnected));
+                return;
+            }
+            other => {
+                debug!("Ignoring \"{other}\" event.");
+                return;
+            }
+        }
+        let parsed = match data {
+            Ok(signed) => parse_message(event_type, signed).await,
+            Err(e) => Err(SamplyBeamError::JsonParseError(e.to_string())),
+        };
+        match parsed {
+            Ok(msg) => {
+                // Nobody might be waiting right now
+                let _ = self.tx.send(Arc::new(UpstreamEvent::Message(msg)));
+            }
+            Err(e) => warn!("Discarding invalid message from the Broker: {e}"),
+        }
+    }
+}
+
+#[cfg(test)]
+impl Upstream {
+    /// A connection that is up, carrying the events passed to [`Upstream::send`]
+    pub(crate) fn connected() -> Self {
+        let upstream = Self::new();
+        upstream.connected.store(true, Ordering::SeqCst);
+        upstream
+    }
+
+    pub(crate) fn send(&self, event: UpstreamEvent) {
+        let _ = self.tx.send(Arc::new(event));
+    }
+}
+
+async fn parse_message(
+    event_type: SseEventType,
+    signed: Value,
+) -> Result<UpstreamMessage, SamplyBeamError> {
+    let jwt = signed["jwt"]
+        .as_str()
+        .ok_or_else(|| SamplyBeamError::JsonParseError("Message is not signed".into()))?;
+    let (from, to, task) = match MsgSigned::<EncryptedMessage>::verify(jwt).await?.msg {
+        EncryptedMessage::MsgTaskRequest(task) => (task.from, task.to, task.id),
+        EncryptedMessage::MsgTaskResult(result) => (result.from, result.to, result.task),
+        EncryptedMessage::MsgSocketRequest(_) | EncryptedMessage::MsgEmpty(_) => {
+            return Err(SamplyBeamError::JsonParseError(
+                "Message is neither a task nor a result".into(),
+            ))
+        }
+    };
+    Ok(UpstreamMessage {
+        event_type,
+        from,
+        to,
+        task,
+        signed,
+    })
+}
@@ -0,0 +1,165 @@
+//! Authentication of apps via bearer tokens (JWTs) issued by an OAuth2/OIDC identity provider
+
+use std::{
+    collections::HashSet,
+    sync::{Arc, LazyLock},
+    time::Duration,
+};
+
+use hyper::{body, Method, Request, StatusCode};
+use jwt_simple::prelude::{Token, VerificationOptions};
+use serde_json::{Map, Value};
+use shared::{
+    beam_id::AppId,
+    config,
+    config_proxy::JwksSource,
+    crypto_keys::{Jwks, PublicKey},
+    errors::SamplyBeamError,
+    http_client::SamplyHttpClient,
+};
+use tokio::sync::{Notify, RwLock};
+use tracing::{debug, info, warn};
+
+use crate::auth::app_id_from_name;
+
+pub(crate) const SCHEME: &str = "Bearer";
+
+/// How often keys are fetched from the identity provider's JWKS endpoint
+const JWKS_REFRESH_INTERVAL: Duration = Duration::from_secs(15 * 60);
+/// Minimum time between two fetches, e.g. when tokens with unknown key IDs come in
+const JWKS_MIN_REFRESH_INTERVAL: Duration = Duration::from_secs(30);
+
+static KEYS: LazyLock<Arc<RwLock<Vec<PublicKey>>>> = LazyLock::new(Default::default);
+static UNKNOWN_KEY_ID: LazyLock<Notify> = LazyLock::new(Notify::new);
+
+/// Loads the identity provider's keys and keeps them up to date
+pub(crate) async fn init(client: &SamplyHttpClient) {
+    let Some(oidc) = &config::CONFIG_PROXY.oidc else {
+        return;
+    };
+    match &oidc.jwks {
+        JwksSource::File(file) => {
+            let load = move || {
+                let jwks = std::fs::read(file).map_err(|e| {
+                    SamplyBeamError::ConfigurationFailed(format!(
+                        "Unable to read JWKS from {}: {e}",
+                        file.to_string_lossy()
+                    ))
+                })?;
+                parse_jwks(&jwks)
+            };
+            match load() {
+                Ok(keys) => *KEYS.write().await = keys,
+                Err(e) => warn!("{e}"),
+            }
+            shared::reload::reload_on_sighup("JWKS", KEYS.clone(), load);
+            shared::reload::reload_on_file_change("JWKS", file.clone(), KEYS.clone(), load);
+        }
+        JwksSource::Url(url) => {
+            let client = client.clone();
+            tokio::task::spawn(async move {
+                loop {
+                    match fetch_jwks(&client, url).await {
+                        Ok(keys) => {
+                            debug!("Fetched {} keys from JWKS endpoint {url}", keys.len());
+                            *KEYS.write().await = keys;
+                        }
+                        Err(e) => warn!("Unable to fetch JWKS from {url}: {e}"),
+                    }
+                    tokio::time::sleep(JWKS_MIN_REFRESH_INTERVAL).await;
+                    // Refresh early if a token has been signed with a new key
+                    let _ = tokio::time::timeout(
+                        JWKS_REFRESH_INTERVAL - JWKS_MIN_REFRESH_INTERVAL,
+                        UNKNOWN_KEY_ID.notified(),
+                    )
+                    .await;
+                }
+            });
+        }
+    }
+    info!("Accepting bearer tokens issued by {}", oidc.issuer);
+}
+
+fn parse_jwks(jwks: &[u8]) -> Result<Vec<PublicKey>, SamplyBeamError> {
+    let jwks: Jwks = serde_json::from_slice(jwks)
+        .map_err(|e| SamplyBeamError::ConfigurationFailed(format!("Invalid JWKS: {e}")))?;
+    let keys = jwks
+        .keys
+        .iter()
+        .filter_map(|jwk| match PublicKey::from_jwk(jwk) {
+            Ok(key) => Some(key),
+            Err(e) => {
+                // Identity providers may publish keys for other purposes, e.g. encryption
+                debug!("Ignoring key {:?} from JWKS: {e}", jwk.kid);
+                None
+            }
+        })
+        .collect();
+    Ok(keys)
+}
+
+async fn fetch_jwks(
+    client: &SamplyHttpClient,
+    url: &hyper::Uri,
+) -> Result<Vec<PublicKey>, SamplyBeamError> {
+    let req = Request::builder()
+        .method(Method::GET)
+        .uri(url)
+        .body(body::Body::empty())
+        .expect("To build request successfully");
+    let resp = client.request(req).await?;
+    let status = resp.status();
+    let bytes = body::to_bytes(resp.into_body()).await?;
+    if status != StatusCode::OK {
+        return Err(SamplyBeamError::ConfigurationFailed(format!(
+            "JWKS endpoint returned {status}"
+        )));
+    }
+    parse_jwks(&bytes)
+}
+
+/// Validates the bearer token and returns the AppId from its configured claim
+pub(crate) async fn authenticate(token: &str) -> Option<AppId> {
+    let oidc = config::CONFIG_PROXY.oidc.as_ref()?;
+    let key_id = Token::decode_metadata(token)
+        .ok()?
+        .key_id()
+        .map(str::to_string);
+    let options = VerificationOptions {
+        allowed_issuers: Some(HashSet::from([oidc.issuer.clone()])),
+        allowed_audiences: oidc
+            .audience
+            .as_ref()
+            .map(|aud| HashSet::from([aud.clone()])),
+        time_tolerance: Some(config::CONFIG_SHARED.clock_skew.into()),
+        ..Default::default()
+    };
+    let keys = KEYS.read().await;
+    let candidates: Vec<_> = keys
+        .iter()
+        .filter(|key| key_id.is_none() || key.key_id() == key_id.as_deref())
+        .collect();
+    if candidates.is_empty() {
+        debug!("Bearer token signed with unknown key {key_id:?}");
+        UNKNOWN_KEY_ID.notify_one();
+        return None;
+    }
+    let claims = candidates.iter().find_map(|key| {
+        key.verify_token::<Map<String, Value>>(token, Some(options.clone()))
+            .map_err(|e| debug!("Rejecting bearer token: {e}"))
+            .ok()
+    })?;
+    let app = match oidc.app_claim.as_str() {
+        "sub" => claims.subject,
+        claim => claims
+            .custom
+            .get(claim)
+            .and_then(Value::as_str)
+            .map(str::to_string),
+    };
+    let Some(app) = app else {
+        debug!("Bearer token lacks claim {}", oidc.app_claim);
+        return None;
+    };
+    app_id_from_name(&app)
+}
@@ -0,0 +1,613 @@
+//! Durable outbox for tasks and results that cannot be delivered to the Broker right away
+
+use std::{
+    future::Future,
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+
+use axum::{
+    extract::{Path as UrlPath, State},
+    response::{IntoResponse, Response},
+    routing::get,
+    Json, Router,
+};
+use httpdate::fmt_http_date;
+use hyper::{body, header, http::request::Parts, Body, Method, Request, StatusCode, Uri};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use shared::{
+    beam_id::AppId, config_proxy, errors::SamplyBeamError, http_client::SamplyHttpClient,
+    EncryptedMessage, MyUuid,
+};
+use tokio::sync::{Mutex, Notify};
+use tracing::{debug, info, warn};
+
+use crate::{
+    auth::AuthenticatedApp,
+    serve_tasks::{sign_request, to_wire_format},
+};
+
+/// Delivery is retried with exponential backoff between these intervals
+const MIN_RETRY_INTERVAL: Duration = Duration::from_secs(1);
+const MAX_RETRY_INTERVAL: Duration = Duration::from_secs(5 * 60);
+
+const ERR_QUEUE: (StatusCode, &str) = (
+    StatusCode::INTERNAL_SERVER_ERROR,
+    "Unable to queue message in outbox; see server logs.",
+);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum EntryStatus {
+    /// Waiting to be delivered
+    Pending,
+    /// Rejected by the Broker; kept for the app to inspect until deleted
+    Failed,
+}
+
+/// A task or result that has been encrypted for its recipients and is waiting for delivery.
+/// It is signed upon delivery, as the Broker only accepts recent signatures.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub(crate) struct OutboxEntry {
+    id: MyUuid,
+    app: AppId,
+    method: String,
+    /// Path and query of the Broker's endpoint
+    path: String,
+    /// The encrypted message
+    msg: Value,
+    /// When the task expires, as its TTL in `msg` is relative to the time it was queued
+    #[serde(default)]
+    expire: Option<SystemTime>,
+    queued_at: SystemTime,
+    attempts: u32,
+    status: EntryStatus,
+    last_error: Option<String>,
+}
+
+impl OutboxEntry {
+    pub(crate) fn new(
+        app: &AppId,
+        parts: &Parts,
+        path: &str,
+        msg: &EncryptedMessage,
+    ) -> Result<Self, (StatusCode, &'static str)> {
+        let expire = match msg {
+            EncryptedMessage::MsgTaskRequest(task) => Some(task.expire),
+            _ => None,
+        };
+        let msg = serde_json::to_value(msg).map_err(|e| {
+            warn!("Unable to serialize message for the outbox: {e}");
+            ERR_QUEUE
+        })?;
+        Ok(Self {
+            id: MyUuid::new(),
+            app: app.clone(),
+            method: parts.method.to_string(),
+            path: path.to_string(),
+            msg,
+            expire,
+            queued_at: SystemTime::now(),
+            attempts: 0,
+            status: EntryStatus::Pending,
+            last_error: None,
+        })
+    }
+
+    /// Whether this is a task that has expired, so it must not be delivered anymore
+    fn expired(&self) -> bool {
+        self.expire.is_some_and(|expire| expire < SystemTime::now())
+    }
+}
+
+/// An outbox entry as shown to apps
+#[derive(Serialize)]
+struct EntryInfo<'a> {
+    id: MyUuid,
+    method: &'a str,
+    path: &'a str,
+    to: &'a Value,
+    queued_at: String,
+    attempts: u32,
+    status: EntryStatus,
+    last_error: &'a Option<String>,
+}
+
+impl<'a> From<&'a OutboxEntry> for EntryInfo<'a> {
+    fn from(entry: &'a OutboxEntry) -> Self {
+        Self {
+            id: entry.id,
+            method: &entry.method,
+            path: &entry.path,
+            to: &entry.msg["to"],
+            queued_at: fmt_http_date(entry.queued_at),
+            attempts: entry.attempts,
+            status: entry.status,
+            last_error: &entry.last_error,
+        }
+    }
+}
+
+enum DeliveryError {
+    /// The Broker is unreachable or asked to come back later
+    Retry {
+        error: String,
+        retry_after: Option<Duration>,
+    },
+    /// The Broker rejected the message
+    Failed(String),
+}
+
+/// Whether the Broker's answer indicates that it is (temporarily) unable to accept messages
+pub(crate) fn is_transient(status: StatusCode) -> bool {
+    matches!(
+        status,
+        StatusCode::REQUEST_TIMEOUT
+            | StatusCode::TOO_MANY_REQUESTS
+            | StatusCode::BAD_GATEWAY
+            | StatusCode::SERVICE_UNAVAILABLE
+            | StatusCode::GATEWAY_TIMEOUT
+    )
+}
+
+/// Entries are kept in memory in the order they were queued, and each one in its own file
+#[derive(Clone)]
+pub(crate) struct Outbox {
+    dir: PathBuf,
+    entries: Arc<Mutex<Vec<OutboxEntry>>>,
+    new_entry: Arc<Notify>,
+}
+
+impl Outbox {
+    /// Opens the outbox in `dir`, loading the entries queued before a restart
+    pub(crate) fn open(dir: &Path) -> Result<Self, SamplyBeamError> {
+        let err = |e: std::io::Error| {
+            SamplyBeamError::ConfigurationFailed(format!(
+                "Unable to open outbox {}: {e}",
+                dir.to_string_lossy()
+            ))
+        };
+        std::fs::create_dir_all(dir).map_err(err)?;
+        let mut entries = Vec::new();
+        for file in std::fs::read_dir(dir).map_err(err)? {
+            let file = file.map_err(err)?.path();
+            if file.extension().is_some_and(|ext| ext == "json") {
+                match std::fs::read(&file)
+                    .map_err(|e| e.to_string())
+                    .and_then(|json| serde_json::from_slice(&json).map_err(|e| e.to_string()))
+                {
+                    Ok(entry) => entries.push(entry),
+                    Err(e) => warn!(
+                        "Ignoring invalid outbox entry {}: {e}",
+                        file.to_string_lossy()
+                    ),
+                }
+            }
+        }
+        entries.sort_by_key(|entry: &OutboxEntry| entry.queued_at);
+        info!(
+            "Opened outbox {} with {} queued messages",
+            dir.to_string_lossy(),
+            entries.len()
+        );
+        Ok(Self {
+            dir: dir.to_path_buf(),
+            entries: Arc::new(Mutex::new(entries)),
+            new_entry: Default::default(),
+        })
+    }
+
+    fn file(&self, id: &MyUuid) -> PathBuf {
+        self.dir.join(format!("{id}.json"))
+    }
+
+    /// Writes the entry to a temporary file first, so a crash never leaves a truncated entry behind
+    fn persist(&self, entry: &OutboxEntry) -> Result<(), String> {
+        let file = self.file(&entry.id);
+        let tmp = file.with_extension("tmp");
+        serde_json::to_vec(entry)
+            .map_err(|e| e.to_string())
+            .and_then(|json| std::fs::write(&tmp, json).map_err(|e| e.to_string()))
+            .and_then(|()| std::fs::rename(&tmp, &file).map_err(|e| e.to_string()))
+            .map_err(|e| {
+                format!(
+                    "Unable to write outbox entry {}: {e}",
+                    file.to_string_lossy()
+                )
+            })
+    }
+
+    fn remove_file(&self, id: &MyUuid) {
+        if let Err(e) = std::fs::remove_file(self.file(id)) {
+            warn!("Unable to remove outbox entry {id}: {e}");
+        }
+    }
+
+    /// Whether messages are waiting for delivery. New messages must then be queued, too, to keep their order.
+    pub(crate) async fn has_pending(&self) -> bool {
+        self.entries
+            .lock()
+            .await
+            .iter()
+            .any(|entry| entry.status == EntryStatus::Pending)
+    }
+
+    /// Queues the entry for delivery, answering the app with `202 Accepted`
+    pub(crate) async fn accept(
+        &self,
+        entry: OutboxEntry,
+    ) -> Result<hyper::Response<Body>, Response> {
+        let mut entries = self.entries.lock().await;
+        self.persist(&entry).map_err(|e| {
+            warn!("{e}");
+            ERR_QUEUE.into_response()
+        })?;
+        info!(
+            "Queued {} {} by {} in the outbox as {}",
+            entry.method, entry.path, entry.app, entry.id
+        );
+        let location = format!("/v1/outbox/{}", entry.id);
+        entries.push(entry);
+        self.new_entry.notify_one();
+        Ok(hyper::Response::builder()
+            .status(StatusCode::ACCEPTED)
+            .header(header::LOCATION, location)
+            .body(Body::empty())
+            .expect("To build response successfully"))
+    }
+
+    /// Delivers queued messages to the Broker in order, backing off while it is unreachable
+    pub(crate) fn spawn_delivery(&self, client: SamplyHttpClient, config: config_proxy::Config) {
+        let outbox = self.clone();
+        tokio::task::spawn(async move {
+            let (client, config) = (&client, &config);
+            let mut retry_interval = MIN_RETRY_INTERVAL;
+            loop {
+                let next = outbox
+                    .deliver_next(&mut retry_interval, |entry| async move {
+                        deliver(&entry, client, config).await
+                    })
+                    .await;
+                match next {
+                    Some(wait) => tokio::time::sleep(wait).await,
+                    None => outbox.new_entry.notified().await,
+                }
+            }
+        });
+    }
+
+    /// Tries to deliver the oldest pending entry, returning how long to wait before the next
+    /// attempt, or `None` if no entry is pending
+    async fn deliver_next<F, Fut>(
+        &self,
+        retry_interval: &mut Duration,
+        deliver: F,
+    ) -> Option<Duration>
+    where
+        F: FnOnce(OutboxEntry) -> Fut,
+        Fut: Future<Output = Result<(), DeliveryError>>,
+    {
+        let entry = self
+            .entries
+            .lock()
+            .await
+            .iter()
+            .find(|entry| entry.status == EntryStatus::Pending)
+            .cloned()?;
+        let id = entry.id;
+        let outcome = if entry.expired() {
+            Err(DeliveryError::Failed(
+                "Task expired before it could be delivered".into(),
+            ))
+        } else {
+            deliver(entry).await
+        };
+        let mut entries = self.entries.lock().await;
+        let Some(stored) = entries.iter_mut().find(|e| e.id == id) else {
+            // Deleted by the app in the meantime
+            return Some(Duration::ZERO);
+        };
+        stored.attempts += 1;
+        match outcome {
+            Ok(()) => {
+                info!("Delivered {id} from the outbox");
+                entries.retain(|e| e.id != id);
+                self.remove_file(&id);
+                *retry_interval = MIN_RETRY_INTERVAL;
+                Some(Duration::ZERO)
+            }
+            Err(DeliveryError::Failed(error)) => {
+                warn!("Broker rejected {id} from the outbox: {error}");
+                stored.status = EntryStatus::Failed;
+                stored.last_error = Some(error);
+                if let Err(e) = self.persist(stored) {
+                    warn!("{e}");
+                }
+                Some(Duration::ZERO)
+            }
+            Err(DeliveryError::Retry { error, retry_after }) => {
+                debug!(
+                    "Unable to deliver {id} from the outbox, retrying in {}s: {error}",
+                    retry_interval.as_secs()
+                );
+                stored.last_error = Some(error);
+                if let Err(e) = self.persist(stored) {
+                    warn!("{e}");
+                }
+                let wait = (*retry_interval).max(retry_after.unwrap_or_default());
+                *retry_interval = (*retry_interval * 2).min(MAX_RETRY_INTERVAL);
+                Some(wait)
+            }
+        }
+    }
+}
+
+async fn deliver(
+    entry: &OutboxEntry,
+    client: &SamplyHttpClient,
+    config: &config_proxy::Config,
+) -> Result<(), DeliveryError> {
+    let mut msg: EncryptedMessage = serde_json::from_value(entry.msg.clone())
+        .map_err(|e| DeliveryError::Failed(format!("Invalid message: {e}")))?;
+    if let (EncryptedMessage::MsgTaskRequest(task), Some(expire)) = (&mut msg, entry.expire) {
+        task.expire = expire;
+    }
+    let method = Method::from_str(&entry.method)
+        .map_err(|e| DeliveryError::Failed(format!("Invalid method: {e}")))?;
+    let uri = Uri::try_from(config.broker_uri.to_string() + entry.path.trim_start_matches('/'))
+        .map_err(|e| DeliveryError::Failed(format!("Invalid path: {e}")))?;
+    let (parts, _) = Request::builder()
+        .method(method.clone())
+        .uri(uri)
+        .header(header::VIA, env!("SAMPLY_USER_AGENT"))
+        .body(())
+        .expect("To build request successfully")
+        .into_parts();
+    let req = sign_request(msg, parts, config, None)
+        .await
+        .map_err(|(_, e)| DeliveryError::Retry {
+            error: e.to_string(),
+            retry_after: None,
+        })?;
+    let req = to_wire_format(req, config)
+        .await
+        .map_err(|e| DeliveryError::Failed(e.to_string()))?;
+    let resp = client
+        .request(req)
+        .await
+        .map_err(|e| DeliveryError::Retry {
+            error: e.to_string(),
+            retry_after: None,
+        })?;
+    let status = resp.status();
+    if status.is_success() {
+        return Ok(());
+    }
+    if status == StatusCode::CONFLICT && method == Method::POST && entry.attempts > 0 {
+        // The task has been created by an earlier attempt whose answer got lost
+        return Ok(());
+    }
+    let retry_after = resp
+        .headers()
+        .get(header::RETRY_AFTER)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse().ok())
+        .map(Duration::from_secs);
+    let body = body::to_bytes(resp.into_body()).await.unwrap_or_default();
+    let error = format!(
+        "Broker answered with status {status}: {}",
+        String::from_utf8_lossy(&body)
+    );
+    if is_transient(status) {
+        Err(DeliveryError::Retry { error, retry_after })
+    } else {
+        Err(DeliveryError::Failed(error))
+    }
+}
+
+pub(crate) fn router(outbox: Outbox) -> Router {
+    Router::new()
+        .route("/v1/outbox", get(list_entries))
+        .route("/v1/outbox/:id", get(get_entry).delete(delete_entry))
+        .with_state(outbox)
+}
+
+/// GET /v1/outbox: The app's messages waiting for delivery or rejected by the Broker
+async fn list_entries(
+    AuthenticatedApp(app): AuthenticatedApp,
+    State(outbox): State<Outbox>,
+) -> Response {
+    let entries = outbox.entries.lock().await;
+    let entries: Vec<EntryInfo> = entries
+        .iter()
+        .filter(|entry| entry.app == app)
+        .map(EntryInfo::from)
+        .collect();
+    Json(entries).into_response()
+}
+
+// GET /v1/outbox/:id
+async fn get_entry(
+    AuthenticatedApp(app): AuthenticatedApp,
+    State(outbox): State<Outbox>,
+    UrlPath(id): UrlPath<MyUuid>,
+) -> Response {
+    let entries = outbox.entries.lock().await;
+    match entries
+        .iter()
+        .find(|entry| entry.id == id && entry.app == app)
+    {
+        Some(entry) => Json(EntryInfo::from(entry)).into_response(),
+        None => (StatusCode::NOT_FOUND, "Not in the outbox (anymore).").into_response(),
+    }
+}
+
+/// DELETE /v1/outbox/:id: Discards a message, e.g. after the Broker rejected it
+async fn delete_entry(
+    AuthenticatedApp(app): AuthenticatedApp,
+    State(outbox): State<Outbox>,
+    UrlPath(id): UrlPath<MyUuid>,
+) -> StatusCode {
+    let mut entries = outbox.entries.lock().await;
+    let before = entries.len();
+    entries.retain(|entry| entry.id != id || entry.app != app);
+    if entries.len() == before {
+        return StatusCode::NOT_FOUND;
+    }
+    outbox.remove_file(&id);
+    StatusCode::NO_CONTENT
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use shared::{
+        beam_id::{AppOrProxyId, BeamId, BrokerId},
+        Encrypted, FailureStrategy, MsgTaskRequest,
+    };
+
+    use super::*;
+
+    const BROKER_ID: &str = "broker.samply.de";
+
+    fn app() -> AppId {
+        BrokerId::set_broker_id(BROKER_ID.to_string());
+        AppId::new(&format!("app1.proxy1.{BROKER_ID}")).unwrap()
+    }
+
+    fn task_entry(expire: SystemTime) -> OutboxEntry {
+        let task = EncryptedMessage::MsgTaskRequest(MsgTaskRequest {
+            id: MyUuid::new(),
+            from: app().into(),
+            to: vec![AppOrProxyId::new(&format!("app1.proxy2.{BROKER_ID}")).unwrap()],
+            body: Encrypted::default(),
+            bodies: HashMap::new(),
+            expire,
+            failure_strategy: FailureStrategy::Discard,
+            results: HashMap::new(),
+            metadata: Value::Null,
+        });
+        let (parts, ()) = Request::post("/v1/tasks").body(()).unwrap().into_parts();
+        OutboxEntry::new(&app(), &parts, "/v1/tasks", &task).unwrap()
+    }
+
+    fn in_an_hour() -> SystemTime {
+        SystemTime::now() + Duration::from_secs(3600)
+    }
+
+    fn temp_outbox() -> (PathBuf, Outbox) {
+        let dir = std::env::temp_dir().join(format!("beam-outbox-{}", MyUuid::new()));
+        let outbox = Outbox::open(&dir).unwrap();
+        (dir, outbox)
+    }
+
+    async fn ids(outbox: &Outbox) -> Vec<MyUuid> {
+        outbox.entries.lock().await.iter().map(|e| e.id).collect()
+    }
+
+    fn retry(retry_after: Option<Duration>) -> Result<(), DeliveryError> {
+        Err(DeliveryError::Retry {
+            error: "Broker unreachable".into(),
+            retry_after,
+        })
+    }
+
+    #[tokio::test]
+    async fn entries_survive_restarts() {
+        let (dir, outbox) = temp_outbox();
+        let expire = in_an_hour();
+        let (first, second) = (task_entry(expire), task_entry(expire));
+        let queued = vec![first.id, second.id];
+        outbox.accept(first).await.unwrap();
+        outbox.accept(second).await.unwrap();
+        std::fs::write(dir.join("garbage.json"), "{").unwrap();
+
+        let reopened = Outbox::open(&dir).unwrap();
+        assert_eq!(ids(&reopened).await, queued);
+        assert!(reopened.has_pending().await);
+        // The expiry does not move while the task is waiting
+        assert_eq!(reopened.entries.lock().await[0].expire, Some(expire));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn entries_are_retried_and_delivered_in_order() {
+        let (dir, outbox) = temp_outbox();
+        let (first, second) = (task_entry(in_an_hour()), task_entry(in_an_hour()));
+        let (first_id, second_id) = (first.id, second.id);
+        outbox.accept(first).await.unwrap();
+        outbox.accept(second).await.unwrap();
+        let attempts = std::sync::Mutex::new(Vec::new());
+        let attempt = |outcome: Result<(), DeliveryError>| {
+            let attempts = &attempts;
+            move |entry: OutboxEntry| {
+                attempts.lock().unwrap().push(entry.id);
+                async move { outcome }
+            }
+        };
+        let mut retry_interval = MIN_RETRY_INTERVAL;
+
+        let wait = outbox
+            .deliver_next(&mut retry_interval, attempt(retry(None)))
+            .await;
+        assert_eq!(wait, Some(MIN_RETRY_INTERVAL));
+        let wait = outbox
+            .deliver_next(
+                &mut retry_interval,
+                attempt(retry(Some(Duration::from_secs(60)))),
+            )
+            .await;
+        assert_eq!(wait, Some(Duration::from_secs(60)));
+        let wait = outbox
+            .deliver_next(&mut retry_interval, attempt(retry(None)))
+            .await;
+        assert_eq!(wait, Some(4 * MIN_RETRY_INTERVAL));
+        assert_eq!(outbox.entries.lock().await[0].attempts, 3);
+
+        outbox
+            .deliver_next(&mut retry_interval, attempt(Ok(())))
+            .await;
+        assert_eq!(retry_interval, MIN_RETRY_INTERVAL);
+        assert!(!dir.join(format!("{first_id}.json")).exists());
+        outbox
+            .deliver_next(&mut retry_interval, attempt(Ok(())))
+            .await;
+        assert_eq!(
+            *attempts.lock().unwrap(),
+            [first_id, first_id, first_id, first_id, second_id]
+        );
+        assert!(ids(&outbox).await.is_empty());
+        assert_eq!(
+            outbox
+                .deliver_next(&mut retry_interval, attempt(Ok(())))
+                .await,
+            None
+        );
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn expired_tasks_are_not_delivered() {
+        let (dir, outbox) = temp_outbox();
+        let expired = task_entry(SystemTime::now() - Duration::from_secs(1));
+        let id = expired.id;
+        outbox.accept(expired).await.unwrap();
+        let mut retry_interval = MIN_RETRY_INTERVAL;
+        outbox
+            .deliver_next(&mut retry_interval, |_| async {
+                panic!("Expired task must not be delivered")
+            })
+            .await;
+        assert!(!outbox.has_pending().await);
+        let reopened = Outbox::open(&dir).unwrap();
+        let entries = reopened.entries.lock().await;
+        assert_eq!(entries[0].id, id);
+        assert_eq!(entries[0].status, EntryStatus::Failed);
+        drop(entries);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
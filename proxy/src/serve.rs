@@ -1,44 +1,102 @@
 use std::{fmt::Write, net::SocketAddr};
 
+use axum::Extension;
 use hyper::{client::HttpConnector, header, Client};
 use hyper_proxy::ProxyConnector;
 use hyper_tls::HttpsConnector;
 use shared::{
     config, config_proxy, config_shared, errors::SamplyBeamError, http_client::SamplyHttpClient,
+    websocket::Api,
 };
 use tracing::{debug, error, info, warn};
 
-use crate::{banner, serve_health, serve_tasks};
+use crate::{
+    banner,
+    multiplex::Upstream,
+    oidc,
+    outbox::{self, Outbox},
+    serve_blobs, serve_health, serve_sockets, serve_tasks, serve_verify,
+};
 
 pub(crate) async fn serve(
     config: config_proxy::Config,
     client: SamplyHttpClient,
 ) -> anyhow::Result<()> {
-    let router_tasks = serve_tasks::router(&client);
+    let outbox = match &config.outbox_dir {
+        Some(dir) => {
+            let outbox = Outbox::open(dir)?;
+            outbox.spawn_delivery(client.clone(), config.clone());
+            Some(outbox)
+        }
+        None => None,
+    };
+
+    let upstream = Upstream::connect(client.clone(), config.clone());
+
+    let router_tasks = serve_tasks::router(&client, outbox.clone(), upstream);
 
     let router_health = serve_health::router();
 
-    let app = router_tasks
+    let router_verify = serve_verify::router(config.clone());
+
+    let router_sockets = serve_sockets::router(&client, config.clone());
+
+    let router_blobs = serve_blobs::router(&client, config.clone());
+
+    let mut app = router_tasks
         .merge(router_health)
-        .layer(axum::middleware::from_fn(shared::middleware::log))
+        .merge(router_verify)
+        .merge(router_sockets)
+        .merge(router_blobs);
+    if let Some(outbox) = outbox {
+        app = app.merge(outbox::router(outbox));
+    }
+    let app = app.layer(axum::middleware::from_fn(shared::middleware::log));
+    // Requests tunneled via WebSockets are served by the same API
+    let app = app
+        .clone()
+        .layer(Extension(Api::new(app)))
         .layer(axum::middleware::map_response(banner::set_server_header));
 
+    if let Some(file) = &config::CONFIG_PROXY.api_keys_file {
+        let load =
+            || config_proxy::load_api_keys(&config::CONFIG_PROXY.proxy_id, Some(file.as_path()));
+        shared::reload::reload_on_sighup("API keys", config.api_keys.clone(), load);
+        shared::reload::reload_on_file_change(
+            "API keys",
+            file.clone(),
+            config.api_keys.clone(),
+            load,
+        );
+    }
+
+    oidc::init(&client).await;
+
+    let api_keys = config.api_keys.read().await;
     let mut apps_joined = String::new();
-    config.api_keys.keys().for_each(|k| {
+    api_keys.apps().for_each(|k| {
         write!(apps_joined, "{} ", k.to_string().split('.').next().unwrap()).unwrap()
     });
     info!(
         "Startup complete. This is Proxy {} listening on {}. {} apps are known: {}",
         config.proxy_id,
         config.bind_addr,
-        config.api_keys.len(),
+        api_keys.len(),
         apps_joined
     );
+    drop(api_keys);
 
-    axum::Server::bind(&config.bind_addr)
-        .serve(app.into_make_service_with_connect_info::<SocketAddr>())
-        .with_graceful_shutdown(shared::graceful_shutdown::wait_for_signal())
-        .await?;
+    match &config.tls {
+        Some(tls) => {
+            shared::tls_server::serve(config.bind_addr, tls.reloadable_acceptor()?, app).await?
+        }
+        None => {
+            axum::Server::bind(&config.bind_addr)
+                .serve(app.into_make_service_with_connect_info::<SocketAddr>())
+                .with_graceful_shutdown(shared::graceful_shutdown::wait_for_signal())
+                .await?
+        }
+    }
 
     Ok(())
 }
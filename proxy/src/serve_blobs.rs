@@ -0,0 +1,204 @@
+//! Blobs: Large payloads that are encrypted and uploaded to the broker by streaming, without being
+//! held in memory as a whole, see [`shared::blobs`]
+
+use axum::{
+    extract::{FromRef, Query, State},
+    response::{IntoResponse, Response},
+    routing::{get, post},
+    Json, Router,
+};
+use futures::{StreamExt, TryStreamExt};
+use hyper::{body, header, Body, HeaderMap, Method, Request, StatusCode, Uri};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use shared::{
+    beam_id::{AppId, AppOrProxyId, BeamId},
+    blobs::{self, BLOB_KEY},
+    config_proxy,
+    crypto_jwt::SIGNED_MESSAGE,
+    http_client::SamplyHttpClient,
+    EncryptedMessage, MsgEmpty, MsgId,
+};
+use tokio::{io::DuplexStream, task::JoinHandle};
+use tokio_util::io::{ReaderStream, StreamReader};
+use tracing::{info, warn};
+
+use crate::{
+    auth::AuthenticatedApp,
+    serve_tasks::{error_as_is, sign_request},
+};
+
+const ERR_UPSTREAM: (StatusCode, &str) =
+    (StatusCode::BAD_GATEWAY, "Upstream error; see server logs.");
+
+#[derive(Clone, FromRef)]
+struct BlobsState {
+    client: SamplyHttpClient,
+    config: config_proxy::Config,
+}
+
+pub(crate) fn router(client: &SamplyHttpClient, config: config_proxy::Config) -> Router {
+    let state = BlobsState {
+        client: client.clone(),
+        config,
+    };
+    Router::new()
+        .route("/v1/blobs", post(handler_upload))
+        .route("/v1/blobs/:id", get(handler_download))
+        .with_state(state)
+}
+
+#[derive(Deserialize)]
+struct BlobParams {
+    /// Recipients, separated by commas
+    to: String,
+}
+
+// POST /v1/blobs?to=...&ttl=...
+/// Encrypts the request's body and uploads it to the broker as a blob for the given recipients.
+/// Answers with the blob's ID and key, which the app passes on to the recipients in the body of a
+/// task or result.
+async fn handler_upload(
+    State(state): State<BlobsState>,
+    Query(params): Query<BlobParams>,
+    AuthenticatedApp(sender): AuthenticatedApp,
+    req: Request<Body>,
+) -> Result<(StatusCode, Json<Value>), Response> {
+    let to = params
+        .to
+        .split(',')
+        .map(AppOrProxyId::new)
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|_| (StatusCode::BAD_REQUEST, "Invalid recipients.").into_response())?;
+    if let Some(policy) = &state.config.policy {
+        if let Some(denied) = to.iter().find(|to| !policy.may_send_to(&sender, to)) {
+            warn!("Policy: App {sender} may not send a blob to {denied}");
+            return Err((
+                StatusCode::FORBIDDEN,
+                "According to the policy, you may not send to this recipient.",
+            )
+                .into_response());
+        }
+    }
+    let path = format!("v1/blobs?{}", req.uri().query().unwrap_or_default());
+    let plain = StreamReader::new(req.into_body().map_err(std::io::Error::other));
+    let key = blobs::generate_key();
+    let (encrypted, writer) = tokio::io::duplex(2 * blobs::CHUNK_SIZE);
+    let encryption = {
+        let key = key.clone();
+        tokio::task::spawn(async move { blobs::encrypt(&key, plain, writer).await })
+    };
+    let mut req = signed_request(&state.config, Method::POST, &path, &sender).await?;
+    *req.body_mut() = streamed(encrypted, encryption);
+    let resp = state.client.request(req).await.map_err(|e| {
+        warn!("Request to broker failed: {e}");
+        ERR_UPSTREAM.into_response()
+    })?;
+    if resp.status() != StatusCode::CREATED {
+        return Err(error_as_is(resp).await);
+    }
+    let bytes = body::to_bytes(resp.into_body()).await.map_err(|e| {
+        warn!("Error receiving reply from the broker: {e}");
+        ERR_UPSTREAM.into_response()
+    })?;
+    let id = serde_json::from_slice::<Value>(&bytes)
+        .ok()
+        .and_then(|json| json["id"].as_str().map(str::to_owned))
+        .ok_or_else(|| {
+            warn!("Broker answered the blob upload without an ID");
+            ERR_UPSTREAM.into_response()
+        })?;
+    info!("App {sender} uploaded blob {id}");
+    Ok((StatusCode::CREATED, Json(json!({ "id": id, "key": key }))))
+}
+
+// GET /v1/blobs/:id
+/// Downloads a blob from the broker and decrypts it with the key in the `X-Beam-Blob-Key` header
+async fn handler_download(
+    State(state): State<BlobsState>,
+    id: MsgId,
+    AuthenticatedApp(app): AuthenticatedApp,
+    headers: HeaderMap,
+) -> Result<Response, Response> {
+    let key = headers
+        .get(BLOB_KEY)
+        .and_then(|key| key.to_str().ok())
+        .ok_or_else(|| {
+            (
+                StatusCode::BAD_REQUEST,
+                "Please supply the blob's key in the X-Beam-Blob-Key header.",
+            )
+                .into_response()
+        })?
+        .to_owned();
+    let req = signed_request(&state.config, Method::GET, &format!("v1/blobs/{id}"), &app).await?;
+    let resp = state.client.request(req).await.map_err(|e| {
+        warn!("Request to broker failed: {e}");
+        ERR_UPSTREAM.into_response()
+    })?;
+    if resp.status() != StatusCode::OK {
+        return Err(error_as_is(resp).await);
+    }
+    let encrypted = StreamReader::new(resp.into_body().map_err(std::io::Error::other));
+    let (plain, writer) = tokio::io::duplex(2 * blobs::CHUNK_SIZE);
+    let decryption =
+        tokio::task::spawn(async move { blobs::decrypt(&key, encrypted, writer).await });
+    let mut resp = Response::new(axum::body::boxed(streamed(plain, decryption)));
+    resp.headers_mut().insert(
+        header::CONTENT_TYPE,
+        header::HeaderValue::from_static("application/octet-stream"),
+    );
+    Ok(resp)
+}
+
+/// Streams the output of an encryption or decryption. If it fails, the stream ends with an error
+/// instead of just ending, so the receiver does not take the data for complete.
+fn streamed(output: DuplexStream, task: JoinHandle<std::io::Result<u64>>) -> Body {
+    let stream = async_stream::stream! {
+        let mut chunks = ReaderStream::new(output);
+        while let Some(chunk) = chunks.next().await {
+            yield chunk;
+        }
+        match task.await {
+            Ok(Ok(_)) => {}
+            Ok(Err(e)) => {
+                warn!("Streaming blob failed: {e}");
+                yield Err(e);
+            }
+            Err(e) => yield Err(std::io::Error::other(e)),
+        }
+    };
+    Body::wrap_stream(stream)
+}
+
+/// Builds a request to the broker signed on behalf of the app. The signed message is sent in the
+/// [`SIGNED_MESSAGE`] header, so the body is left for streaming the blob.
+async fn signed_request(
+    config: &config_proxy::Config,
+    method: Method,
+    path: &str,
+    app: &AppId,
+) -> Result<Request<Body>, Response> {
+    let uri = Uri::try_from(format!("{}{path}", config.broker_uri))
+        .map_err(|_| (StatusCode::BAD_REQUEST, "Invalid path queried.").into_response())?;
+    let (parts, _) = Request::builder()
+        .method(method)
+        .uri(uri)
+        .header(header::VIA, env!("SAMPLY_USER_AGENT"))
+        .body(())
+        .expect("To build request successfully")
+        .into_parts();
+    let empty = EncryptedMessage::MsgEmpty(MsgEmpty { from: app.into() });
+    let (mut parts, body) = sign_request(empty, parts, config, None)
+        .await
+        .map_err(IntoResponse::into_response)?
+        .into_parts();
+    let token = body::to_bytes(body)
+        .await
+        .ok()
+        .and_then(|token| header::HeaderValue::from_maybe_shared(token).ok())
+        .ok_or_else(|| ERR_UPSTREAM.into_response())?;
+    parts.headers.insert(SIGNED_MESSAGE, token);
+    parts.headers.remove(header::CONTENT_LENGTH);
+    Ok(Request::from_parts(parts, Body::empty()))
+}
@@ -0,0 +1,292 @@
+//! Socket connections between apps, tunneled through the broker and encrypted end-to-end, see
+//! [`shared::sockets`]
+
+use std::time::{Duration, SystemTime};
+
+use axum::{
+    body::Bytes,
+    extract::{FromRef, Path, State},
+    response::{IntoResponse, Response},
+    routing::get,
+    Json, Router,
+};
+use hyper::{body, header, Body, Method, Request, StatusCode, Uri};
+use serde_json::Value;
+use shared::{
+    beam_id::{AppId, AppOrProxyId},
+    config_proxy, crypto,
+    http_client::SamplyHttpClient,
+    sockets::{self, Side, SocketUpgrade},
+    EncryptableMsg, EncryptedMessage, MsgEmpty, MsgId, MsgSigned, MsgSocketRequest,
+};
+use tracing::{debug, info, warn};
+
+use crate::{
+    auth::AuthenticatedApp,
+    serve_tasks::{decrypt_msg, error_as_is, sign_request, UnreachableRecipientsError},
+};
+
+/// The recipient has to accept a socket within this time
+const SOCKET_REQUEST_TTL: Duration = Duration::from_secs(60);
+
+const ERR_UPSTREAM: (StatusCode, &str) =
+    (StatusCode::BAD_GATEWAY, "Upstream error; see server logs.");
+
+#[derive(Clone, FromRef)]
+struct SocketsState {
+    client: SamplyHttpClient,
+    config: config_proxy::Config,
+}
+
+pub(crate) fn router(client: &SamplyHttpClient, config: config_proxy::Config) -> Router {
+    let state = SocketsState {
+        client: client.clone(),
+        config,
+    };
+    Router::new()
+        .route("/v1/sockets", get(handler_list))
+        // The parameter is the recipient for POST and the socket's ID for GET
+        .route("/v1/sockets/:id", get(handler_accept).post(handler_open))
+        .with_state(state)
+}
+
+// POST /v1/sockets/:to
+/// Opens a socket to another app. The request's body, if any, is passed on as the socket request's
+/// metadata.
+async fn handler_open(
+    State(state): State<SocketsState>,
+    Path(to): Path<AppOrProxyId>,
+    upgrade: SocketUpgrade,
+    AuthenticatedApp(sender): AuthenticatedApp,
+    body: Bytes,
+) -> Result<Response, Response> {
+    if let Some(policy) = &state.config.policy {
+        if !policy.may_send_to(&sender, &to) {
+            warn!("Policy: App {sender} may not open a socket to {to}");
+            return Err((
+                StatusCode::FORBIDDEN,
+                "According to the policy, you may not send to this recipient.",
+            )
+                .into_response());
+        }
+    }
+    let metadata = if body.is_empty() {
+        Value::Null
+    } else {
+        serde_json::from_slice(&body)
+            .map_err(|_| (StatusCode::BAD_REQUEST, "Metadata is not valid JSON.").into_response())?
+    };
+    let secret = sockets::generate_key();
+    let request = MsgSocketRequest {
+        id: MsgId::new(),
+        from: (&sender).into(),
+        to: vec![to],
+        secret: secret.clone().into(),
+        expire: SystemTime::now() + SOCKET_REQUEST_TTL,
+        metadata,
+    };
+    let id = request.id;
+    let (receivers_keys, unreachable) = crypto::get_recipients_public_keys(&request.to).await;
+    if !unreachable.is_empty() {
+        return Err(UnreachableRecipientsError {
+            error:
+                "Unable to encrypt the socket request, as the recipient has no usable certificate.",
+            unreachable,
+        }
+        .into_response());
+    }
+    let request = request.encrypt(&receivers_keys).map_err(|e| {
+        warn!("Encryption failed with: {e}");
+        (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "Cryptography failed; see server logs.",
+        )
+            .into_response()
+    })?;
+    let req = signed_request(
+        &state.config,
+        Method::POST,
+        "v1/sockets",
+        EncryptedMessage::MsgSocketRequest(request),
+    )
+    .await?;
+    let resp = state.client.request(req).await.map_err(|e| {
+        warn!("Request to broker failed: {e}");
+        ERR_UPSTREAM.into_response()
+    })?;
+    if resp.status() != StatusCode::CREATED {
+        return Err(error_as_is(resp).await);
+    }
+    let tunnel = connect(&state, &sender, &id).await?;
+    info!("App {sender} opened socket {id}");
+    Ok(upgrade.accept(move |io| relay(id, io, tunnel, secret, Side::Initiator)))
+}
+
+// GET /v1/sockets
+/// Lists the socket requests addressed to the app, without their secrets. Supports long-polling.
+async fn handler_list(
+    State(state): State<SocketsState>,
+    AuthenticatedApp(app): AuthenticatedApp,
+    req: Request<Body>,
+) -> Result<(StatusCode, Json<Vec<Value>>), Response> {
+    let (status, requests) = socket_requests(&state, &app, req.uri().query()).await?;
+    let requests = requests
+        .into_iter()
+        .map(|request| {
+            let mut json = serde_json::to_value(request).expect("Should serialize fine");
+            if let Value::Object(fields) = &mut json {
+                fields.remove("body");
+            }
+            json
+        })
+        .collect();
+    Ok((status, Json(requests)))
+}
+
+// GET /v1/sockets/:id
+/// Accepts a socket request addressed to the app and connects to the other end
+async fn handler_accept(
+    State(state): State<SocketsState>,
+    id: MsgId,
+    upgrade: SocketUpgrade,
+    AuthenticatedApp(app): AuthenticatedApp,
+) -> Result<Response, Response> {
+    let (_, requests) = socket_requests(&state, &app, None).await?;
+    let request = requests
+        .into_iter()
+        .find(|request| request.id == id)
+        .ok_or_else(|| {
+            (
+                StatusCode::NOT_FOUND,
+                "Socket request not found or expired.",
+            )
+                .into_response()
+        })?;
+    let Some(secret) = request
+        .secret
+        .body
+        .and_then(|secret| String::from_utf8(secret).ok())
+    else {
+        warn!("Socket request {id} from {} has no secret", request.from);
+        return Err(ERR_UPSTREAM.into_response());
+    };
+    let tunnel = connect(&state, &app, &id).await?;
+    info!("App {app} accepted socket {id} from {}", request.from);
+    Ok(upgrade.accept(move |io| relay(id, io, tunnel, secret, Side::Recipient)))
+}
+
+/// Fetches the socket requests addressed to the app from the broker, verifying and decrypting them.
+/// Requests that the app does not accept according to its policy are left out.
+async fn socket_requests(
+    state: &SocketsState,
+    app: &AppId,
+    query: Option<&str>,
+) -> Result<(StatusCode, Vec<MsgSocketRequest>), Response> {
+    let path = match query {
+        Some(query) => format!("v1/sockets?{query}"),
+        None => "v1/sockets".to_string(),
+    };
+    let req = signed_request(&state.config, Method::GET, &path, empty(app)).await?;
+    let resp = state.client.request(req).await.map_err(|e| {
+        warn!("Request to broker failed: {e}");
+        ERR_UPSTREAM.into_response()
+    })?;
+    let status = resp.status();
+    if !status.is_success() {
+        return Err(error_as_is(resp).await);
+    }
+    let bytes = body::to_bytes(resp.into_body()).await.map_err(|e| {
+        warn!("Error receiving reply from the broker: {e}");
+        ERR_UPSTREAM.into_response()
+    })?;
+    let signed = serde_json::from_slice::<Vec<Value>>(&bytes).map_err(|e| {
+        warn!("Unable to parse socket requests from the broker: {e}");
+        ERR_UPSTREAM.into_response()
+    })?;
+    let mut requests = Vec::with_capacity(signed.len());
+    for jwt in signed.iter().filter_map(|json| json["jwt"].as_str()) {
+        let request = match MsgSigned::<EncryptedMessage>::verify(jwt).await {
+            Ok(MsgSigned {
+                msg: EncryptedMessage::MsgSocketRequest(request),
+                ..
+            }) => decrypt_msg(request),
+            Ok(_) => continue,
+            Err(e) => Err(e),
+        };
+        match request {
+            Ok(request) if may_receive(state, app, &request) => requests.push(request),
+            Ok(request) => {
+                warn!(
+                    "Policy: App {app} does not accept sockets from {}",
+                    request.from
+                )
+            }
+            Err(e) => warn!("Discarding invalid socket request from the broker: {e}"),
+        }
+    }
+    Ok((status, requests))
+}
+
+fn may_receive(state: &SocketsState, app: &AppId, request: &MsgSocketRequest) -> bool {
+    state
+        .config
+        .policy
+        .as_ref()
+        .is_none_or(|policy| policy.may_receive_from(app, &request.from))
+}
+
+fn empty(app: &AppId) -> EncryptedMessage {
+    EncryptedMessage::MsgEmpty(MsgEmpty { from: app.into() })
+}
+
+async fn signed_request(
+    config: &config_proxy::Config,
+    method: Method,
+    path: &str,
+    body: EncryptedMessage,
+) -> Result<Request<Body>, Response> {
+    let uri = Uri::try_from(format!("{}{path}", config.broker_uri))
+        .map_err(|_| (StatusCode::BAD_REQUEST, "Invalid path queried.").into_response())?;
+    let (parts, _) = Request::builder()
+        .method(method)
+        .uri(uri)
+        .header(header::VIA, env!("SAMPLY_USER_AGENT"))
+        .body(())
+        .expect("To build request successfully")
+        .into_parts();
+    sign_request(body, parts, config, None)
+        .await
+        .map_err(IntoResponse::into_response)
+}
+
+/// Connects to the broker's end of the socket on behalf of the app
+async fn connect(
+    state: &SocketsState,
+    app: &AppId,
+    id: &MsgId,
+) -> Result<hyper::upgrade::Upgraded, Response> {
+    let req = signed_request(
+        &state.config,
+        Method::GET,
+        &format!("v1/sockets/{id}"),
+        empty(app),
+    )
+    .await?;
+    sockets::connect(&state.client, req).await.map_err(|e| {
+        warn!("Unable to connect to socket {id} via the broker: {e}");
+        ERR_UPSTREAM.into_response()
+    })
+}
+
+async fn relay(
+    id: MsgId,
+    app: hyper::upgrade::Upgraded,
+    tunnel: hyper::upgrade::Upgraded,
+    secret: String,
+    side: Side,
+) {
+    match sockets::relay(app, tunnel, &secret, side).await {
+        Ok(()) => debug!("Socket {id} closed"),
+        Err(e) => warn!("Socket {id} closed: {e}"),
+    }
+}
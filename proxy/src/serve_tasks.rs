@@ -1,16 +1,22 @@
 use std::{
+    collections::{HashMap, HashSet},
     convert::Infallible,
+    net::SocketAddr,
     str::FromStr,
+    sync::{Arc, LazyLock, Mutex},
     time::{Duration, SystemTime},
 };
 
 use axum::{
     body::Bytes,
-    extract::{BodyStream, FromRef, State},
-    http::{request::Parts, HeaderValue},
-    response::{sse::Event, IntoResponse, Response, Sse},
+    extract::{BodyStream, ConnectInfo, FromRef, Path, Query, State},
+    http::{request::Parts, HeaderName, HeaderValue},
+    response::{
+        sse::{Event, KeepAlive},
+        IntoResponse, Response, Sse,
+    },
     routing::{any, get, put},
-    Router,
+    Extension, Json, Router,
 };
 use futures::{
     stream::{StreamExt, TryStreamExt},
@@ -23,49 +29,70 @@ use hyper::{
     client::{connect::Connect, HttpConnector},
     header,
     service::Service,
-    Body, Client, HeaderMap, Request, StatusCode, Uri,
+    upgrade::Upgraded,
+    Body, Client, HeaderMap, Method, Request, StatusCode, Uri,
 };
 use hyper_proxy::ProxyConnector;
 use hyper_tls::HttpsConnector;
 use rsa::{pkcs8::DecodePublicKey, RsaPublicKey};
 use serde::{de::DeserializeOwned, Deserialize, Serialize};
-use serde_json::Value;
+use serde_json::{json, Value};
 use shared::{
     beam_id::{AppId, AppOrProxyId, ProxyId},
     config::{self, CONFIG_PROXY},
     config_proxy,
     config_shared::ConfigCrypto,
-    crypto::{self, CryptoPublicPortion},
+    crypto::{self, CryptoPublicPortion, UnreachableRecipient},
     crypto_jwt,
     errors::SamplyBeamError,
     http_client::SamplyHttpClient,
+    policy::ProxyPolicy,
     sse_event::SseEventType,
-    DecryptableMsg, EncryptableMsg, EncryptedMessage, EncryptedMsgTaskRequest,
-    EncryptedMsgTaskResult, MessageType, Msg, MsgEmpty, MsgId, MsgSigned, MsgTaskRequest,
-    MsgTaskResult, PlainMessage,
+    tls_server::ClientCertificate,
+    websocket::{self, Api, Frame, Message, Upgrade, WsSender},
+    wire, DecryptableMsg, EncryptableMsg, EncryptedMessage, EncryptedMsgTaskRequest,
+    EncryptedMsgTaskResult, HowLongToBlock, MessageType, Msg, MsgEmpty, MsgId, MsgSigned,
+    MsgTaskRequest, MsgTaskResult, Plain, PlainMessage, WorkStatus,
 };
-use tokio::io::BufReader;
+use tokio::{io::BufReader, sync::broadcast, time};
 use tracing::{debug, error, info, trace, warn};
 
-use crate::auth::AuthenticatedApp;
+use crate::{
+    auth::AuthenticatedApp,
+    multiplex::{Upstream, UpstreamEvent, UpstreamMessage},
+    outbox::{self, Outbox, OutboxEntry},
+};
 
 #[derive(Clone, FromRef)]
 struct TasksState {
     client: SamplyHttpClient,
     config: config_proxy::Config,
+    outbox: Option<Outbox>,
+    upstream: Upstream,
 }
 
-pub(crate) fn router(client: &SamplyHttpClient) -> Router {
+pub(crate) fn router(
+    client: &SamplyHttpClient,
+    outbox: Option<Outbox>,
+    upstream: Upstream,
+) -> Router {
     let config = config::CONFIG_PROXY.clone();
     let state = TasksState {
         client: client.clone(),
         config,
+        outbox,
+        upstream,
     };
     Router::new()
         // We need both path variants so the server won't send us into a redirect loop (/tasks, /tasks/, ...)
         .route("/v1/tasks", get(handler_task).post(handler_task))
+        .route("/v1/tasks/:task_id", get(handler_get_task))
         .route("/v1/tasks/:task_id/results", get(handler_task))
-        .route("/v1/tasks/:task_id/results/:app_id", put(handler_task))
+        .route(
+            "/v1/tasks/:task_id/results/:app_id",
+            get(handler_get_result).put(handler_task),
+        )
+        .route("/v1/ws", get(handler_ws))
         .with_state(state)
 }
 
@@ -84,46 +111,154 @@ const ERR_FAKED_FROM: (StatusCode, &str) = (
     StatusCode::UNAUTHORIZED,
     "You are not authorized to send on behalf of this app.",
 );
+const ERR_POLICY_REJECTED: &str = "Rejected by the recipient's policy.";
+
+/// Long-polls without `wait_time` wait (almost) forever, like on the broker
+const WAIT_FOREVER: Duration = Duration::from_secs(31536000);
+
+/// Request header by which an app allows sending a message only to those recipients
+/// that can be reached, i.e. whose proxy has a usable certificate
+const PARTIAL_DELIVERY: HeaderName = HeaderName::from_static("x-beam-partial-delivery");
+/// Request header by which an app asks for the provenance (signature and signer) of each
+/// received message, see [`crypto_jwt::Provenance`]
+const PROVENANCE: HeaderName = HeaderName::from_static("x-beam-provenance");
+/// Request header by which SSE clients resume a stream after the last event they have received
+const LAST_EVENT_ID: HeaderName = HeaderName::from_static("last-event-id");
+/// Waiting time before resuming a result stream, unless the broker says otherwise
+const DEFAULT_SSE_RETRY: Duration = Duration::from_secs(3);
+/// Resuming a result stream is given up after this many failed attempts
+const MAX_SSE_RECONNECTS: u32 = 5;
+/// Response header listing the recipients that have been dropped from a message
+const DROPPED_RECIPIENTS: HeaderName = HeaderName::from_static("x-beam-dropped-recipients");
+/// Header carrying a message's fields other than its body as JSON, if the body is sent or
+/// received as raw bytes (`application/octet-stream`)
+const MESSAGE_FIELDS: HeaderName = HeaderName::from_static("x-beam-fields");
+const OCTET_STREAM: &str = "application/octet-stream";
+
+#[derive(Serialize)]
+pub(crate) struct UnreachableRecipientsError {
+    pub(crate) error: &'static str,
+    pub(crate) unreachable: Vec<UnreachableRecipient>,
+}
+
+#[derive(Serialize)]
+struct PolicyViolationError {
+    error: &'static str,
+    denied: Vec<AppOrProxyId>,
+}
+
+impl IntoResponse for PolicyViolationError {
+    fn into_response(self) -> Response {
+        (StatusCode::FORBIDDEN, Json(self)).into_response()
+    }
+}
+
+impl IntoResponse for UnreachableRecipientsError {
+    fn into_response(self) -> Response {
+        (StatusCode::UNPROCESSABLE_ENTITY, Json(self)).into_response()
+    }
+}
 
 async fn forward_request(
     mut req: Request<Body>,
     config: &config_proxy::Config,
     sender: &AppId,
     client: &SamplyHttpClient,
-) -> Result<hyper::Response<Body>, (StatusCode, &'static str)> {
+    outbox: Option<&Outbox>,
+    upstream: Option<&Upstream>,
+) -> Result<hyper::Response<Body>, Response> {
     // Create uri to contact broker
     let path = req.uri().path();
     let path_query = req
         .uri()
         .path_and_query()
         .map(|v| v.as_str())
-        .unwrap_or(path);
+        .unwrap_or(path)
+        .to_owned();
     let target_uri =
         Uri::try_from(config.broker_uri.to_string() + path_query.trim_start_matches('/'))
-            .map_err(|_| (StatusCode::BAD_REQUEST, "Invalid path queried."))?;
+            .map_err(|_| (StatusCode::BAD_REQUEST, "Invalid path queried.").into_response())?;
     *req.uri_mut() = target_uri;
 
     req.headers_mut().append(
         header::VIA,
         HeaderValue::from_static(env!("SAMPLY_USER_AGENT")),
     );
-    let (encrypted_msg, parts) = encrypt_request(req, &sender).await?;
-    let req = sign_request(encrypted_msg, parts, &config, None).await?;
+    let (encrypted_msg, parts, dropped) =
+        encrypt_request(req, sender, config.policy.as_ref()).await?;
+    // Tasks and results are queued in the outbox (if configured) when the broker cannot take them
+    let queueable = match outbox {
+        Some(outbox) if matches!(parts.method, Method::POST | Method::PUT) => Some((
+            outbox,
+            OutboxEntry::new(sender, &parts, &path_query, &encrypted_msg)
+                .map_err(IntoResponse::into_response)?,
+        )),
+        _ => None,
+    };
+    if let Some((outbox, entry)) = &queueable {
+        // Keep the order of messages by queueing behind those waiting for delivery
+        if outbox.has_pending().await {
+            return outbox.accept(entry.clone()).await;
+        }
+    }
+    let req = sign_request(encrypted_msg, parts, config, None)
+        .await
+        .map_err(IntoResponse::into_response)?;
     trace!("Requesting: {:?}", req);
-    let resp = client.request(req).await.map_err(|e| {
-        warn!("Request to broker failed: {}", e.to_string());
-        (StatusCode::BAD_GATEWAY, "Upstream error; see server logs.")
-    })?;
+    // Tasks and results go through the WebSocket to the broker if there is one
+    let sent = match upstream {
+        Some(upstream) if matches!(req.method(), &Method::POST | &Method::PUT) => {
+            upstream.request(req).await
+        }
+        _ => Err(req),
+    };
+    let sent = match sent {
+        Ok(sent) => sent,
+        Err(req) => match to_wire_format(req, config).await {
+            Ok(req) => client.request(req).await.map_err(SamplyBeamError::from),
+            Err(e) => Err(e),
+        },
+    };
+    let mut resp = match (sent, queueable) {
+        (Ok(resp), Some((outbox, entry))) if outbox::is_transient(resp.status()) => {
+            warn!("Broker answered with {}; queueing message.", resp.status());
+            outbox.accept(entry).await?
+        }
+        (Ok(resp), _) => resp,
+        (Err(e), Some((outbox, entry))) => {
+            warn!("Request to broker failed: {e}; queueing message.");
+            outbox.accept(entry).await?
+        }
+        (Err(e), None) => {
+            warn!("Request to broker failed: {}", e.to_string());
+            return Err(
+                (StatusCode::BAD_GATEWAY, "Upstream error; see server logs.").into_response(),
+            );
+        }
+    };
+    if !dropped.is_empty() {
+        let dropped = dropped
+            .iter()
+            .map(|r| r.recipient.to_string())
+            .collect::<Vec<_>>()
+            .join(",");
+        if let Ok(value) = HeaderValue::from_str(&dropped) {
+            resp.headers_mut().insert(DROPPED_RECIPIENTS, value);
+        }
+    }
     Ok(resp)
 }
 
 async fn handler_task(
     State(client): State<SamplyHttpClient>,
     State(config): State<config_proxy::Config>,
+    State(outbox): State<Option<Outbox>>,
+    State(upstream): State<Upstream>,
     AuthenticatedApp(sender): AuthenticatedApp,
-    headers: HeaderMap,
+    block: HowLongToBlock,
     req: Request<Body>,
-) -> Result<Response, (StatusCode, String)> {
+) -> Result<Response, Response> {
+    let headers = req.headers();
     let found = &headers
         .get(header::ACCEPT)
         .unwrap_or(&HeaderValue::from_static(""))
@@ -133,43 +268,224 @@ async fn handler_task(
         .map(|part| part.trim())
         .find(|part| *part == "text/event-stream")
         .is_some();
+    let with_provenance = headers
+        .get(PROVENANCE)
+        .is_some_and(|v| v.as_bytes().eq_ignore_ascii_case(b"true"));
+
+    // Serve long-polls using the single connection to the broker if available. Result streams are
+    // served by the broker, which numbers their events so they can be resumed.
+    if req.method() == Method::GET && block.wait_count.is_some_and(|count| count > 0) && !*found {
+        let wanted = Wanted::from_request(req.uri(), &sender);
+        if let (Some(wanted), Some(events)) = (wanted, upstream.subscribe()) {
+            return handler_tasks_multiplexed(
+                client,
+                config,
+                sender,
+                req,
+                with_provenance,
+                block,
+                wanted,
+                events,
+            )
+            .await;
+        }
+    }
 
     let result = if *found {
-        handler_tasks_stream(client, config, sender, req)
-            .await?
-            .into_response()
+        handler_tasks_stream(
+            client,
+            config,
+            sender,
+            req,
+            with_provenance,
+            block.wait_time,
+        )
+        .await?
+        .into_response()
     } else {
-        handler_tasks_nostream(client, config, sender, req)
-            .await
-            .map_err(|e| (e.0, e.1.to_string()))?
-            .into_response()
+        handler_tasks_nostream(
+            client,
+            config,
+            outbox,
+            Some(upstream),
+            sender,
+            req,
+            with_provenance,
+        )
+        .await?
+        .into_response()
     };
 
     return Ok(result);
 }
 
+// GET /v1/tasks/:task_id
+/// Returns a single task sent by or addressed to the app
+async fn handler_get_task(
+    State(client): State<SamplyHttpClient>,
+    State(config): State<config_proxy::Config>,
+    AuthenticatedApp(app): AuthenticatedApp,
+    Path(task_id): Path<MsgId>,
+    headers: HeaderMap,
+) -> Result<Response, Response> {
+    let uri = format!("/v1/tasks/{task_id}");
+    single_message(client, config, app, &uri, &headers).await
+}
+
+// GET /v1/tasks/:task_id/results/:app_id
+/// Returns the result of a single app or proxy for a task
+async fn handler_get_result(
+    State(client): State<SamplyHttpClient>,
+    State(config): State<config_proxy::Config>,
+    AuthenticatedApp(app): AuthenticatedApp,
+    Path((task_id, from)): Path<(MsgId, AppOrProxyId)>,
+    headers: HeaderMap,
+) -> Result<Response, Response> {
+    let uri = format!("/v1/tasks/{task_id}/results/{from}");
+    single_message(client, config, app, &uri, &headers).await
+}
+
+/// Fetches the single message at `uri` from the broker. If the app accepts
+/// `application/octet-stream`, its body is returned as raw bytes with the other fields in the
+/// [`MESSAGE_FIELDS`] header.
+async fn single_message(
+    client: SamplyHttpClient,
+    config: config_proxy::Config,
+    app: AppId,
+    uri: &str,
+    headers: &HeaderMap,
+) -> Result<Response, Response> {
+    let with_provenance = headers
+        .get(PROVENANCE)
+        .is_some_and(|v| v.as_bytes().eq_ignore_ascii_case(b"true"));
+    let raw = headers
+        .get(header::ACCEPT)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| {
+            v.split(',')
+                .any(|part| part.trim().starts_with(OCTET_STREAM))
+        });
+    let req = Request::get(uri)
+        .body(Body::empty())
+        .expect("To build request successfully");
+    let resp =
+        handler_tasks_nostream(client, config, None, None, app, req, with_provenance).await?;
+    if !resp.status().is_success() {
+        return Ok(resp.into_response());
+    }
+    let bytes = body::to_bytes(resp.into_body()).await.map_err(|e| {
+        error!("Error receiving reply from the broker: {e}");
+        ERR_UPSTREAM.into_response()
+    })?;
+    let msg = serde_json::from_slice::<Value>(&bytes).map_err(|_| ERR_UPSTREAM.into_response())?;
+    if !raw {
+        return Ok(Json(msg).into_response());
+    }
+    let Value::Object(mut fields) = msg else {
+        return Err(ERR_UPSTREAM.into_response());
+    };
+    let mut body = serde_json::Map::new();
+    for field in ["body", "binary"] {
+        if let Some(value) = fields.remove(field) {
+            body.insert(field.to_string(), value);
+        }
+    }
+    let body = serde_json::from_value::<Plain>(Value::Object(body))
+        .map_err(|_| ERR_UPSTREAM.into_response())?
+        .body
+        .unwrap_or_default();
+    let fields = HeaderValue::from_str(&ascii_json(&Value::Object(fields)))
+        .map_err(|_| ERR_UPSTREAM.into_response())?;
+    Ok((
+        [
+            (header::CONTENT_TYPE, HeaderValue::from_static(OCTET_STREAM)),
+            (MESSAGE_FIELDS, fields),
+        ],
+        body,
+    )
+        .into_response())
+}
+
+/// Serializes JSON with all characters but printable ASCII escaped, so it can be sent in a header
+fn ascii_json(value: &Value) -> String {
+    let mut json = String::new();
+    for c in value.to_string().chars() {
+        if (' '..='~').contains(&c) {
+            json.push(c);
+        } else {
+            for unit in c.encode_utf16(&mut [0; 2]) {
+                json.push_str(&format!("\\u{unit:04x}"));
+            }
+        }
+    }
+    json
+}
+
 async fn handler_tasks_nostream(
     client: SamplyHttpClient,
     config: config_proxy::Config,
+    outbox: Option<Outbox>,
+    upstream: Option<Upstream>,
     sender: AppId,
-    req: Request<Body>,
-) -> Result<Response<Body>, (StatusCode, &'static str)> {
+    mut req: Request<Body>,
+    with_provenance: bool,
+) -> Result<Response<Body>, Response> {
     // Validate Query, forward to server, get response.
+    if config.compact_wire_format {
+        req.headers_mut()
+            .insert(header::ACCEPT, HeaderValue::from_static(wire::COMPACT));
+    }
 
-    let resp = forward_request(req, &config, &sender, &client).await?;
+    let resp = forward_request(
+        req,
+        &config,
+        &sender,
+        &client,
+        outbox.as_ref(),
+        upstream.as_ref(),
+    )
+    .await?;
+    let receiver = Receiver {
+        app: sender,
+        config,
+        client,
+        with_provenance,
+    };
 
     // Check reply's signature
 
     let (mut parts, body) = resp.into_parts();
     let mut bytes = body::to_bytes(body).await.map_err(|e| {
         error!("Error receiving reply from the broker: {}", e);
-        ERR_UPSTREAM
+        ERR_UPSTREAM.into_response()
     })?;
 
+    let answer = if wire::is_compact(&parts.headers) {
+        // Lists of messages or, e.g. for `GET /v1/tasks/:task_id`, a single one
+        let json = match wire::decode_list(&bytes) {
+            Ok(jwts) => jwts.into_iter().map(|jwt| json!({ "jwt": jwt })).collect(),
+            Err(_) => wire::decode(&bytes)
+                .map(|jwt| json!({ "jwt": jwt }))
+                .map_err(|e| {
+                    warn!("Unable to decode the broker's compact answer: {e}");
+                    ERR_UPSTREAM.into_response()
+                })?,
+        };
+        parts.headers.insert(
+            header::CONTENT_TYPE,
+            HeaderValue::from_static("application/json"),
+        );
+        Ok(json)
+    } else {
+        serde_json::from_slice::<Value>(&bytes)
+    };
+
     // TODO: Always return application/jwt from server.
     if !bytes.is_empty() {
-        if let Ok(json) = serde_json::from_slice::<Value>(&bytes) {
-            let json = to_server_error(validate_and_decrypt(json).await)?;
+        if let Ok(json) = answer {
+            let json = to_server_error(validate_and_decrypt(json, &receiver).await)
+                .map_err(IntoResponse::into_response)?
+                .ok_or_else(|| (StatusCode::FORBIDDEN, ERR_POLICY_REJECTED).into_response())?;
             trace!("Decrypted Msg: {:#?}", json);
             bytes = serde_json::to_vec(&json).unwrap().into();
             trace!(
@@ -205,119 +521,602 @@ async fn handler_tasks_stream(
     config: config_proxy::Config,
     sender: AppId,
     req: Request<Body>,
-) -> Result<Sse<impl Stream<Item = Result<Event, Infallible>>>, (StatusCode, String)> {
+    with_provenance: bool,
+    wait_time: Option<Duration>,
+) -> Result<Sse<impl Stream<Item = Result<Event, Infallible>>>, Response> {
     // Validate Query, forward to server, get response.
+    let deadline = wait_time.map(|wait_time| time::Instant::now() + wait_time);
+    let (parts, _) = req.into_parts();
+    // The request is sent again to resume the stream after losing the connection to the broker
+    let request = move |last_event_id: Option<&HeaderValue>| {
+        let wait_time =
+            deadline.map(|deadline| deadline.saturating_duration_since(time::Instant::now()));
+        let mut req = Request::new(Body::empty());
+        *req.method_mut() = parts.method.clone();
+        *req.uri_mut() = replace_query(
+            &parts.uri,
+            &["wait_time"],
+            wait_time.map(|wait_time| format!("wait_time={}ms", wait_time.as_millis())),
+        );
+        *req.headers_mut() = parts.headers.clone();
+        if let Some(last_event_id) = last_event_id {
+            req.headers_mut()
+                .insert(LAST_EVENT_ID, last_event_id.clone());
+        }
+        req
+    };
 
-    let mut resp = forward_request(req, &config, &sender, &client)
-        .await
-        .map_err(|err| (err.0, err.1.into()))?;
+    let resp = forward_request(request(None), &config, &sender, &client, None, None).await?;
+    let receiver = Receiver {
+        app: sender,
+        config,
+        client,
+        with_provenance,
+    };
 
     let code = resp.status();
     if !code.is_success() {
-        let bytes = body::to_bytes(resp.into_body()).await.ok();
-        let error_msg = bytes
-            .and_then(|v| String::from_utf8(v.into()).ok())
-            .unwrap_or("(unable to parse reply)".into());
-        warn!("Got unexpected response code from server: {code}. Returning error message as-is: \"{error_msg}\"");
-        return Err((code, error_msg));
+        return Err(error_as_is(resp).await);
     }
 
     let outgoing = async_stream::stream! {
-        let incoming = resp
-            .body_mut()
-            .map(|result| result.map_err(|error| std::io::Error::new(std::io::ErrorKind::Other, format!("IO Error: {error}"))))
-            .into_async_read();
-
-        let mut reader = async_sse::decode(incoming);
-
-        while let Some(event) = reader.next().await {
-            let event = match event {
-                Ok(event) => event,
-                Err(err) => {
-                    error!("Got error reading SSE stream: {err}");
-                    yield Ok(Event::default()
-                        .event(SseEventType::Error)
-                        .data("Error reading SSE stream from Broker (see Proxy logs for details)."));
-                    continue;
-                }
-            };
-            match event {
-                async_sse::Event::Retry(_dur) => {
-                    error!("Got a retry message from the Broker, which is not yet supported.");
-                },
-                async_sse::Event::Message(event) => {
-                    // Check if this is a message or some control event
-                    let event_type = SseEventType::from_str(event.name()).expect("Error in Infallible");
-                    let mut event_as_bytes = event.into_bytes();
-                    let event_as_str = std::str::from_utf8(&event_as_bytes).unwrap_or("(unable to parse)");
-
-                    match &event_type {
-                        SseEventType::DeletedTask | SseEventType::WaitExpired => {
-                            debug!("SSE: Got {event_type} message, forwarding to App.");
-                            yield Ok(Event::default()
-                                .event(event_type)
-                                .data(event_as_str));
-                            continue;
-                        },
-                        SseEventType::Error => {
-                            warn!("SSE: The Broker has reported an error: {event_as_str}");
-                            yield Ok(Event::default()
-                                .event(event_type)
-                                .data(event_as_str));
-                            continue;
-                        },
-                        SseEventType::Undefined => {
-                            error!("SSE: Got a message without event type -- discarding.");
-                            continue;
-                        },
-                        SseEventType::Unknown(s) => {
-                            error!("SSE: Got unknown event type: {s} -- discarding.");
-                            continue;
+        let mut resp = resp;
+        let mut last_event_id = request(None).headers().get(LAST_EVENT_ID).cloned();
+        let mut retry = DEFAULT_SSE_RETRY;
+        'connection: loop {
+            let incoming = resp
+                .into_body()
+                .map(|result| result.map_err(|error| std::io::Error::other(format!("IO Error: {error}"))))
+                .into_async_read();
+
+            let mut reader = async_sse::decode(incoming);
+            let mut interrupted = false;
+
+            while let Some(event) = reader.next().await {
+                let event = match event {
+                    Ok(event) => event,
+                    Err(err) => {
+                        warn!("Lost SSE stream from Broker: {err}; resuming.");
+                        interrupted = true;
+                        break;
+                    }
+                };
+                match event {
+                    async_sse::Event::Retry(dur) => {
+                        debug!("SSE: Broker asks to wait {dur:?} before reconnecting.");
+                        retry = dur;
+                    },
+                    async_sse::Event::Message(event) => {
+                        // Check if this is a message or some control event
+                        let event_type = SseEventType::from_str(event.name()).expect("Error in Infallible");
+                        let id = event.id().clone();
+                        let mut event_as_bytes = event.into_bytes();
+                        let event_as_str = std::str::from_utf8(&event_as_bytes).unwrap_or("(unable to parse)");
+
+                        match &event_type {
+                            SseEventType::DeletedTask | SseEventType::WaitExpired => {
+                                debug!("SSE: Got {event_type} message, forwarding to App.");
+                                yield Ok(Event::default()
+                                    .event(event_type)
+                                    .data(event_as_str));
+                                continue;
+                            },
+                            SseEventType::Error => {
+                                warn!("SSE: The Broker has reported an error: {event_as_str}");
+                                yield Ok(Event::default()
+                                    .event(event_type)
+                                    .data(event_as_str));
+                                continue;
+                            },
+                            SseEventType::Undefined => {
+                                error!("SSE: Got a message without event type -- discarding.");
+                                continue;
+                            },
+                            SseEventType::Unknown(s) => {
+                                error!("SSE: Got unknown event type: {s} -- discarding.");
+                                continue;
+                            }
+                            other => {
+                                warn!("Got \"{other}\" event -- parsing.");
+                            }
                         }
-                        other => {
-                            warn!("Got \"{other}\" event -- parsing.");
+                        if let Some(id) = id.as_ref().and_then(|id| HeaderValue::from_str(id).ok()) {
+                            last_event_id = Some(id);
                         }
+
+                        // Check reply's signature
+
+                        if !event_as_bytes.is_empty() {
+                            let Ok(json) = serde_json::from_slice::<Value>(&event_as_bytes) else {
+                                warn!("Answer is no valid JSON; discarding: \"{event_as_str}\".");
+                                // TODO: For some reason, compiler won't accept the following lines, so we can't inform the App about the problem.
+                                //
+                                // warn!("Answer is no valid JSON; returning as-is to client: \"{event_as_str}\".");
+                                // yield Ok(Event::default()
+                                //     .event(SseEventType::Error)
+                                //     .data(format!("Broker sent invalid JSON: {event_as_str}")));
+                                continue;
+                            };
+                            let json = match validate_and_decrypt(json, &receiver).await {
+                                Ok(Some(json)) => json,
+                                Ok(None) => continue,
+                                Err(err) => {
+                                    warn!("Got an error decrypting Broker's reply: {err}");
+                                    continue;
+                                }
+                            };
+                            trace!("Decrypted Msg: {:#?}",json);
+                            event_as_bytes = serde_json::to_vec(&json).unwrap();
+                            trace!(
+                                "Validated and stripped signature: \"{}\"",
+                                std::str::from_utf8(&event_as_bytes).unwrap_or("Unable to parse string as UTF-8")
+                            );
+                        }
+                        let as_string = std::str::from_utf8(&event_as_bytes).unwrap_or("(garbled_utf8)");
+                        let mut event = Event::default()
+                            .event(event_type)
+                            .data(as_string);
+                        // Apps may resume the stream with this ID, too
+                        if let Some(id) = id {
+                            event = event.id(id);
+                        }
+                        yield Ok(event);
+                    }
+                }
+            }
+            if !interrupted {
+                break;
+            }
+
+            // Keep the app's stream open while reconnecting, resuming after the last event received
+            for attempt in 1..=MAX_SSE_RECONNECTS {
+                time::sleep(retry).await;
+                if deadline.is_some_and(|deadline| deadline <= time::Instant::now()) {
+                    yield Ok(Event::default().event(SseEventType::WaitExpired).data("{}"));
+                    break 'connection;
+                }
+                match forward_request(request(last_event_id.as_ref()), &receiver.config, &receiver.app, &receiver.client, None, None).await {
+                    Ok(new) if new.status().is_success() => {
+                        info!("SSE: Resumed stream from Broker after {attempt} attempt(s).");
+                        resp = new;
+                        continue 'connection;
                     }
+                    Ok(new) => {
+                        let code = new.status();
+                        let error = error_as_is(new).await;
+                        warn!("SSE: Unable to resume stream; Broker answered {code}: {:?}", error.body());
+                        break;
+                    }
+                    Err(_) => warn!("SSE: Unable to reach Broker to resume stream (attempt {attempt})."),
+                }
+            }
+            yield Ok(Event::default()
+                .event(SseEventType::Error)
+                .data("Lost connection to the Broker; please reconnect."));
+            break;
+        }
+    };
+    // Errors of resumed streams can only be reported as events since the status code has been sent already
+    let sse = Sse::new(outgoing).keep_alive(KeepAlive::default());
+    Ok(sse)
+}
+
+/// Returns the broker's error as-is to the app
+pub(crate) async fn error_as_is(resp: hyper::Response<Body>) -> Response {
+    let code = resp.status();
+    let (parts, body) = resp.into_parts();
+    let bytes = body::to_bytes(body).await.ok();
+    let error_msg = bytes
+        .and_then(|v| String::from_utf8(v.into()).ok())
+        .unwrap_or("(unable to parse reply)".into());
+    warn!("Got unexpected response code from server: {code}. Returning error message as-is: \"{error_msg}\"");
+    let mut resp = (code, error_msg).into_response();
+    // Let apps know when to retry after hitting one of the Broker's quotas
+    if let Some(retry_after) = parts.headers.get(header::RETRY_AFTER) {
+        resp.headers_mut()
+            .insert(header::RETRY_AFTER, retry_after.clone());
+    }
+    resp
+}
+
+/// What a long-poll served via the multiplexed connection waits for
+enum Wanted {
+    /// Tasks from or to the given apps, as in `GET /v1/tasks?from=...&to=...`
+    Tasks {
+        from: Option<AppOrProxyId>,
+        to: Option<AppOrProxyId>,
+    },
+    /// Results of the given task addressed to the app
+    Results { task: MsgId },
+}
+
+#[derive(Deserialize)]
+struct TaskQuery {
+    from: Option<AppOrProxyId>,
+    to: Option<AppOrProxyId>,
+    filter: Option<String>,
+}
+
+impl Wanted {
+    fn from_request(uri: &Uri, app: &AppId) -> Option<Self> {
+        let segments: Vec<&str> = uri.path().trim_matches('/').split('/').collect();
+        match segments.as_slice() {
+            ["v1", "tasks"] => {
+                let Query(query) = Query::<TaskQuery>::try_from_uri(uri).ok()?;
+                let to = match query.filter.as_deref() {
+                    Some("todo") => query.to.or_else(|| Some(app.into())),
+                    _ => query.to,
+                };
+                Some(Self::Tasks {
+                    from: query.from,
+                    to,
+                })
+            }
+            ["v1", "tasks", task, "results"] => Some(Self::Results {
+                task: MsgId::try_from(*task).ok()?,
+            }),
+            _ => None,
+        }
+    }
+
+    /// What an app subscribes to via its WebSocket. Like the broker, only lists messages created
+    /// by the app (from) or directed to it (to).
+    fn subscription(uri: &str, app: &AppId) -> Result<Self, (StatusCode, &'static str)> {
+        let wanted = Uri::try_from(uri)
+            .ok()
+            .and_then(|uri| Self::from_request(&uri, app))
+            .ok_or((StatusCode::BAD_REQUEST, "Unable to subscribe to this URI."))?;
+        let app = AppOrProxyId::from(app);
+        if let Self::Tasks { from, to } = &wanted {
+            if (from.is_none() && to.is_none())
+                || from.as_ref().is_some_and(|from| *from != app)
+                || to.as_ref().is_some_and(|to| *to != app)
+            {
+                return Err((
+                    StatusCode::UNAUTHORIZED,
+                    "You can only subscribe to messages created by you (from) or directed to you (to).",
+                ));
+            }
+        }
+        Ok(wanted)
+    }
+
+    /// Whether the app waits for the message; only messages from or to the app are considered
+    fn matches(&self, msg: &UpstreamMessage, app: &AppOrProxyId) -> bool {
+        match (self, &msg.event_type) {
+            (Self::Tasks { from, to }, SseEventType::NewTask) => {
+                (msg.from == *app || msg.to.contains(app))
+                    && (from.as_ref() == Some(&msg.from)
+                        || to.as_ref().is_some_and(|to| msg.to.contains(to)))
+            }
+            (Self::Results { task }, SseEventType::NewResult) => {
+                msg.task == *task && msg.to.contains(app)
+            }
+            _ => false,
+        }
+    }
+
+    /// Messages with the same key replace each other, e.g. a worker's updated result
+    fn key<'a>(&self, json: &'a Value) -> &'a Value {
+        match self {
+            Self::Tasks { .. } => &json["id"],
+            Self::Results { .. } => &json["from"],
+        }
+    }
+
+    /// Adds the message, replacing a previous one with the same key
+    fn upsert(&self, messages: &mut Vec<Value>, json: Value) {
+        match messages
+            .iter_mut()
+            .find(|msg| self.key(msg) == self.key(&json))
+        {
+            Some(previous) => *previous = json,
+            None => messages.push(json),
+        }
+    }
+}
 
-                    // Check reply's signature
-
-                    if !event_as_bytes.is_empty() {
-                        let Ok(json) = serde_json::from_slice::<Value>(&event_as_bytes) else {
-                            warn!("Answer is no valid JSON; discarding: \"{event_as_str}\".");
-                            // TODO: For some reason, compiler won't accept the following lines, so we can't inform the App about the problem.
-                            //
-                            // warn!("Answer is no valid JSON; returning as-is to client: \"{event_as_str}\".");
-                            // yield Ok(Event::default()
-                            //     .event(SseEventType::Error)
-                            //     .data(format!("Broker sent invalid JSON: {event_as_str}")));
+/// The request without long-polling parameters, so the broker answers right away
+fn without_long_polling(req: Request<Body>) -> Request<Body> {
+    let (mut parts, body) = req.into_parts();
+    parts.uri = replace_query(&parts.uri, &["wait_count", "wait_time", "wait_final"], None);
+    parts
+        .headers
+        .insert(header::ACCEPT, HeaderValue::from_static("application/json"));
+    Request::from_parts(parts, body)
+}
+
+/// The URI without the query parameters named in `remove`, but with `add` (e.g. `wait_time=5s`)
+fn replace_query(uri: &Uri, remove: &[&str], add: Option<String>) -> Uri {
+    let query = uri
+        .query()
+        .unwrap_or_default()
+        .split('&')
+        .filter(|param| {
+            let name = param.split('=').next().unwrap_or_default();
+            !param.is_empty() && !remove.contains(&name)
+        })
+        .map(str::to_string)
+        .chain(add)
+        .collect::<Vec<_>>()
+        .join("&");
+    let path_and_query = match query.as_str() {
+        "" => uri.path().to_string(),
+        query => format!("{}?{query}", uri.path()),
+    };
+    Uri::try_from(path_and_query).expect("Path and query to remain valid")
+}
+
+/// Serves a long-poll via the multiplexed connection to the broker: Messages available right away
+/// are fetched without waiting, further ones are picked from the broker's events.
+#[allow(clippy::too_many_arguments)]
+async fn handler_tasks_multiplexed(
+    client: SamplyHttpClient,
+    config: config_proxy::Config,
+    sender: AppId,
+    req: Request<Body>,
+    with_provenance: bool,
+    block: HowLongToBlock,
+    wanted: Wanted,
+    mut events: broadcast::Receiver<Arc<UpstreamEvent>>,
+) -> Result<Response, Response> {
+    let resp = handler_tasks_nostream(
+        client.clone(),
+        config.clone(),
+        None,
+        None,
+        sender.clone(),
+        without_long_polling(req),
+        with_provenance,
+    )
+    .await?;
+    if !resp.status().is_success() {
+        return Ok(resp.into_response());
+    }
+    let bytes = body::to_bytes(resp.into_body())
+        .await
+        .map_err(|_| ERR_UPSTREAM.into_response())?;
+    let initial: Vec<Value> =
+        serde_json::from_slice(&bytes).map_err(|_| ERR_UPSTREAM.into_response())?;
+    let receiver = Receiver {
+        app: sender,
+        config,
+        client,
+        with_provenance,
+    };
+    let wait_count = usize::from(block.wait_count.unwrap_or(0));
+    let deadline = time::Instant::now() + block.wait_time.unwrap_or(WAIT_FOREVER);
+    let mut messages = Vec::new();
+    for json in initial {
+        wanted.upsert(&mut messages, json);
+    }
+
+    // Messages without a status are tasks, which always count
+    let count = |messages: &[Value]| {
+        messages
+            .iter()
+            .filter(|json| {
+                !block.wait_final
+                    || WorkStatus::deserialize(*json).map_or(true, |status| status.is_final())
+            })
+            .count()
+    };
+    while count(&messages) < wait_count {
+        let Some(json) = next_message(&mut events, &wanted, &receiver, deadline).await else {
+            break;
+        };
+        wanted.upsert(&mut messages, json);
+    }
+    let status = if count(&messages) < wait_count {
+        StatusCode::PARTIAL_CONTENT
+    } else {
+        StatusCode::OK
+    };
+    Ok((status, Json(messages)).into_response())
+}
+
+/// The next message from the broker's events that the long-poll waits for, or `None` once the
+/// deadline has passed or messages may have been missed
+async fn next_message(
+    events: &mut broadcast::Receiver<Arc<UpstreamEvent>>,
+    wanted: &Wanted,
+    receiver: &Receiver,
+    deadline: time::Instant,
+) -> Option<Value> {
+    let app = AppOrProxyId::from(&receiver.app);
+    loop {
+        let signed = next_wanted(events, wanted, &app, deadline).await?;
+        match validate_and_decrypt(signed, receiver).await {
+            Ok(Some(json)) => return Some(json),
+            Ok(None) => continue,
+            Err(e) => warn!("Unable to validate and decrypt message from the Broker: {e}"),
+        }
+    }
+}
+
+/// Like [`next_message`], but the signed message as sent by the broker
+async fn next_wanted(
+    events: &mut broadcast::Receiver<Arc<UpstreamEvent>>,
+    wanted: &Wanted,
+    app: &AppOrProxyId,
+    deadline: time::Instant,
+) -> Option<Value> {
+    loop {
+        let event = tokio::select! {
+            _ = time::sleep_until(deadline) => return None,
+            event = events.recv() => event,
+        };
+        match event.as_deref() {
+            Ok(UpstreamEvent::Message(msg)) if wanted.matches(msg, app) => {
+                return Some(msg.signed.clone())
+            }
+            Ok(UpstreamEvent::Message(_)) => continue,
+            Ok(UpstreamEvent::Disconnected) | Err(_) => return None,
+        }
+    }
+}
+
+// GET /v1/ws
+/// Lets an app send requests and subscribe to tasks and results through a single WebSocket.
+/// Requests carry the credentials of the WebSocket's handshake and are processed like regular
+/// HTTP requests, so each gets a response with a proper status code.
+async fn handler_ws(
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    State(state): State<TasksState>,
+    upgrade: Upgrade,
+    Extension(api): Extension<Api>,
+    AuthenticatedApp(sender): AuthenticatedApp,
+    headers: HeaderMap,
+    client_cert: Option<Extension<Option<ClientCertificate>>>,
+) -> Response {
+    let credentials = headers.get(header::AUTHORIZATION).cloned();
+    let client_cert = client_cert.and_then(|Extension(cert)| cert);
+    let receiver = Arc::new(Receiver {
+        app: sender,
+        config: state.config,
+        client: state.client,
+        with_provenance: headers
+            .get(PROVENANCE)
+            .is_some_and(|v| v.as_bytes().eq_ignore_ascii_case(b"true")),
+    });
+    let upstream = state.upstream;
+    info!("App {} connected via WebSocket", receiver.app);
+    upgrade.accept(move |ws, mut incoming| async move {
+        let ws2 = ws.clone();
+        let ping = tokio::task::spawn(async move {
+            let mut interval = time::interval(websocket::PING_INTERVAL);
+            loop {
+                interval.tick().await;
+                if ws2.ping().await.is_err() {
+                    break;
+                }
+            }
+        });
+        let mut subscriptions = HashMap::new();
+        loop {
+            let frame = match incoming.recv().await {
+                Ok(Some(Message::Text(text))) => serde_json::from_str::<Frame>(&text),
+                Ok(Some(Message::Binary(_))) => continue,
+                Ok(None) => break,
+                Err(e) => {
+                    debug!("WebSocket of app {} failed: {e}", receiver.app);
+                    break;
+                }
+            };
+            match frame {
+                Ok(Frame::Request {
+                    id,
+                    method,
+                    uri,
+                    headers,
+                    body,
+                }) => {
+                    let mut req = match websocket::to_request(&method, &uri, &headers, body) {
+                        Ok(req) => req,
+                        Err(e) => {
+                            let resp = (StatusCode::BAD_REQUEST, e.to_string()).into_response();
+                            let _ = ws.send_json(&Frame::from_response(id, resp).await).await;
                             continue;
-                        };
-                        let json = match validate_and_decrypt(json).await {
-                            Ok(json) => json,
-                            Err(err) => {
-                                warn!("Got an error decrypting Broker's reply: {err}");
-                                continue;
+                        }
+                    };
+                    req.headers_mut().remove(header::AUTHORIZATION);
+                    if let Some(credentials) = &credentials {
+                        req.headers_mut()
+                            .insert(header::AUTHORIZATION, credentials.clone());
+                    }
+                    req.extensions_mut().insert(client_cert.clone());
+                    req.extensions_mut().insert(ConnectInfo(addr));
+                    let mut api = api.router();
+                    let ws = ws.clone();
+                    tokio::task::spawn(async move {
+                        let resp = api.call(req).await.expect("Infallible");
+                        let _ = ws.send_json(&Frame::from_response(id, resp).await).await;
+                    });
+                }
+                Ok(Frame::Subscribe { id, uri }) => {
+                    let resp = match (
+                        Wanted::subscription(&uri, &receiver.app),
+                        upstream.subscribe(),
+                    ) {
+                        (Err(e), _) => e,
+                        (_, None) => (
+                            StatusCode::SERVICE_UNAVAILABLE,
+                            "Not connected to the Broker; please retry later.",
+                        ),
+                        (Ok(wanted), Some(events)) => {
+                            let subscription = tokio::task::spawn(forward_subscription(
+                                id,
+                                wanted,
+                                events,
+                                receiver.clone(),
+                                ws.clone(),
+                            ));
+                            if let Some(previous) = subscriptions.insert(id, subscription) {
+                                previous.abort();
                             }
-                        };
-                        trace!("Decrypted Msg: {:#?}",json);
-                        event_as_bytes = serde_json::to_vec(&json).unwrap();
-                        trace!(
-                            "Validated and stripped signature: \"{}\"",
-                            std::str::from_utf8(&event_as_bytes).unwrap_or("Unable to parse string as UTF-8")
-                        );
+                            (StatusCode::OK, "")
+                        }
+                    };
+                    let _ = ws
+                        .send_json(&Frame::from_response(id, resp.into_response()).await)
+                        .await;
+                }
+                Ok(Frame::Unsubscribe { id }) => {
+                    if let Some(subscription) = subscriptions.remove(&id) {
+                        subscription.abort();
                     }
-                    let as_string = std::str::from_utf8(&event_as_bytes).unwrap_or("(garbled_utf8)");
-                    let event = Event::default()
-                        .event(event_type)
-                        .data(as_string);
-                    yield Ok(event);
+                }
+                other => {
+                    let error = match other {
+                        Ok(_) => "Unexpected frame".to_string(),
+                        Err(e) => format!("Invalid frame: {e}"),
+                    };
+                    let _ = ws
+                        .send_json(&Frame::Event {
+                            event: SseEventType::Error.to_string(),
+                            subscription: None,
+                            data: error.into(),
+                        })
+                        .await;
                 }
             }
         }
+        ping.abort();
+        subscriptions.values().for_each(|s| s.abort());
+        info!("App {} closed its WebSocket", receiver.app);
+    })
+}
+
+/// Sends the tasks or results an app has subscribed to through its WebSocket
+async fn forward_subscription(
+    id: u64,
+    wanted: Wanted,
+    mut events: broadcast::Receiver<Arc<UpstreamEvent>>,
+    receiver: Arc<Receiver>,
+    ws: WsSender<Upgraded>,
+) {
+    let event_type = match wanted {
+        Wanted::Tasks { .. } => SseEventType::NewTask,
+        Wanted::Results { .. } => SseEventType::NewResult,
     };
-    // TODO: Somehow return correct error code (not always possible since headers are sent before long request)
-    let sse = Sse::new(outgoing);
-    Ok(sse)
+    loop {
+        let deadline = time::Instant::now() + WAIT_FOREVER;
+        let (event, data) = match next_message(&mut events, &wanted, &receiver, deadline).await {
+            Some(json) => (event_type.to_string(), json),
+            None => (
+                SseEventType::Error.to_string(),
+                "Lost connection to the Broker; messages may have been missed.".into(),
+            ),
+        };
+        let event = Frame::Event {
+            event,
+            subscription: Some(id),
+            data,
+        };
+        if ws.send_json(&event).await.is_err() {
+            break;
+        }
+    }
 }
 
 fn to_server_error<T>(res: Result<T, SamplyBeamError>) -> Result<T, (StatusCode, &'static str)> {
@@ -338,6 +1137,28 @@ fn to_server_error<T>(res: Result<T, SamplyBeamError>) -> Result<T, (StatusCode,
     })
 }
 
+/// Converts a signed request to the compact wire format if configured (see [`wire`]). Only applies
+/// to requests sent via HTTP, as the WebSocket to the broker carries text.
+pub async fn to_wire_format(
+    req: Request<Body>,
+    config: &config_proxy::Config,
+) -> Result<Request<Body>, SamplyBeamError> {
+    if !config.compact_wire_format {
+        return Ok(req);
+    }
+    let (mut parts, body) = req.into_parts();
+    let body = body::to_bytes(body).await?;
+    let compact = wire::encode(&wire::token_from_body(&parts.headers, &body)?)?;
+    parts.headers.insert(
+        header::CONTENT_TYPE,
+        HeaderValue::from_static(wire::COMPACT),
+    );
+    parts
+        .headers
+        .insert(header::CONTENT_LENGTH, compact.len().into());
+    Ok(Request::from_parts(parts, compact.into()))
+}
+
 // TODO: This could be a middleware
 pub async fn sign_request(
     body: EncryptedMessage,
@@ -407,8 +1228,32 @@ pub async fn sign_request(
     Ok(req)
 }
 
+/// The app on whose behalf messages are received from the broker, and how to process them
+struct Receiver {
+    app: AppId,
+    config: config_proxy::Config,
+    client: SamplyHttpClient,
+    with_provenance: bool,
+}
+
+impl Receiver {
+    /// Whether the app's policy allows it to receive the task. Tasks not addressed to the app,
+    /// e.g. its own tasks, are always passed on.
+    fn accepts(&self, task: &MsgTaskRequest) -> bool {
+        let Some(policy) = &self.config.policy else {
+            return true;
+        };
+        !task.to.contains(&(&self.app).into()) || policy.may_receive_from(&self.app, &task.from)
+    }
+}
+
+/// Verifies and decrypts messages from the broker. Tasks that the receiving app does not accept
+/// according to its policy are removed and reported back to their sender.
 #[async_recursion::async_recursion]
-async fn validate_and_decrypt(json: Value) -> Result<Value, SamplyBeamError> {
+async fn validate_and_decrypt(
+    json: Value,
+    receiver: &Receiver,
+) -> Result<Option<Value>, SamplyBeamError> {
     // It might be possible to use MsgSigned directly instead but there are issues impl Deserialize for MsgSigned<EncryptedMessage>
     #[derive(Deserialize)]
     struct MsgSignedHelper {
@@ -417,21 +1262,45 @@ async fn validate_and_decrypt(json: Value) -> Result<Value, SamplyBeamError> {
     if let Value::Array(arr) = json {
         let mut results = Vec::with_capacity(arr.len());
         for value in arr {
-            results.push(validate_and_decrypt(value).await?);
+            if let Some(value) = validate_and_decrypt(value, receiver).await? {
+                results.push(value);
+            }
         }
-        Ok(Value::Array(results))
+        Ok(Some(Value::Array(results)))
     } else if json.is_object() {
-        match serde_json::from_value::<MsgSignedHelper>(json) {
-            Ok(signed) => {
-                let msg = MsgSigned::<EncryptedMessage>::verify(&signed.jwt)
-                    .await?
-                    .msg;
-                Ok(serde_json::to_value(decrypt_msg(msg)?).expect("Should serialize fine"))
-            }
-            Err(e) => Err(SamplyBeamError::JsonParseError(format!(
+        let signed = serde_json::from_value::<MsgSignedHelper>(json).map_err(|e| {
+            SamplyBeamError::JsonParseError(format!(
                 "Failed to parse broker response as a signed encrypted message. Err is {e}"
-            ))),
+            ))
+        })?;
+        let (mut msg, provenance) = if receiver.with_provenance {
+            let (msg, provenance) =
+                crypto_jwt::verify_with_provenance::<EncryptedMessage>(&signed.jwt).await?;
+            (msg, Some(provenance))
+        } else {
+            let msg = MsgSigned::<EncryptedMessage>::verify(&signed.jwt)
+                .await?
+                .msg;
+            (msg, None)
+        };
+        if let EncryptedMessage::MsgTaskRequest(task) = &mut msg {
+            task.select_body(&(&receiver.app).into());
         }
+        let msg = decrypt_msg(msg)?;
+        if let PlainMessage::MsgTaskRequest(task) = &msg {
+            if !receiver.accepts(task) {
+                reject_task(task, receiver).await;
+                return Ok(None);
+            }
+        }
+        let mut json = serde_json::to_value(msg).expect("Should serialize fine");
+        if let (Some(provenance), Value::Object(fields)) = (provenance, &mut json) {
+            fields.insert(
+                "provenance".to_string(),
+                serde_json::to_value(provenance).expect("Should serialize fine"),
+            );
+        }
+        Ok(Some(json))
     } else {
         Err(SamplyBeamError::JsonParseError(format!(
             "Broker respondend with invalid json {json:#?}"
@@ -439,24 +1308,109 @@ async fn validate_and_decrypt(json: Value) -> Result<Value, SamplyBeamError> {
     }
 }
 
-fn decrypt_msg<M: DecryptableMsg>(msg: M) -> Result<M::Output, SamplyBeamError> {
+/// Tasks that have been rejected, until they expire
+static REJECTED_TASKS: LazyLock<Mutex<HashMap<MsgId, SystemTime>>> =
+    LazyLock::new(Default::default);
+
+/// Reports a task that the receiving app does not accept back to its sender as permanently failed.
+/// Each task is reported only once.
+async fn reject_task(task: &MsgTaskRequest, receiver: &Receiver) {
+    if !remember_rejection(task.id, task.expire) {
+        return;
+    }
+    warn!(
+        "Policy: App {} does not accept tasks from {}; rejecting task {}",
+        receiver.app, task.from, task.id
+    );
+    let result = MsgTaskResult {
+        from: (&receiver.app).into(),
+        to: vec![task.from.clone()],
+        task: task.id,
+        status: WorkStatus::PermFailed,
+        body: ERR_POLICY_REJECTED.into(),
+        metadata: Value::Null,
+    };
+    if let Err(e) = send_result(result, receiver).await {
+        warn!(
+            "Unable to report rejected task {} to {}: {e}",
+            task.id, task.from
+        );
+        REJECTED_TASKS.lock().unwrap().remove(&task.id);
+    }
+}
+
+/// Whether the task has not been rejected before; forgets tasks that have expired
+fn remember_rejection(task: MsgId, expire: SystemTime) -> bool {
+    let mut rejected = REJECTED_TASKS.lock().unwrap();
+    let now = SystemTime::now();
+    rejected.retain(|_, expire| *expire > now);
+    rejected.insert(task, expire).is_none()
+}
+
+/// Sends a result on behalf of the receiving app
+async fn send_result(result: MsgTaskResult, receiver: &Receiver) -> Result<(), SamplyBeamError> {
+    let uri = Uri::try_from(format!(
+        "{}v1/tasks/{}/results/{}",
+        receiver.config.broker_uri, result.task, result.from
+    ))
+    .map_err(|_| SamplyBeamError::InvalidPath)?;
+    let (receivers_keys, unreachable) = crypto::get_recipients_public_keys(result.get_to()).await;
+    if !unreachable.is_empty() {
+        return Err(SamplyBeamError::SignEncryptError(format!(
+            "Recipients are unreachable: {unreachable:?}"
+        )));
+    }
+    let encrypted = EncryptedMessage::MsgTaskResult(result.encrypt(&receivers_keys)?);
+    let (parts, _) = Request::builder()
+        .method(Method::PUT)
+        .uri(uri)
+        .body(())
+        .expect("To build request successfully")
+        .into_parts();
+    let req = sign_request(encrypted, parts, &receiver.config, None)
+        .await
+        .map_err(|(_, msg)| SamplyBeamError::SignEncryptError(msg.into()))?;
+    let resp = receiver.client.request(req).await?;
+    if !resp.status().is_success() {
+        return Err(SamplyBeamError::VaultOtherError(format!(
+            "Broker answered with status {}",
+            resp.status()
+        )));
+    }
+    Ok(())
+}
+
+pub(crate) fn decrypt_msg<M: DecryptableMsg>(msg: M) -> Result<M::Output, SamplyBeamError> {
     msg.decrypt(
         &AppOrProxyId::ProxyId(CONFIG_PROXY.proxy_id.to_owned()),
         crypto::get_own_privkey(),
     )
 }
 
+/// Encrypts the message for all recipients. Fails if a recipient cannot be reached, unless the app
+/// allowed partial delivery, in which case such recipients are dropped and returned.
 async fn encrypt_request(
     req: Request<Body>,
     sender: &AppId,
-) -> Result<(EncryptedMessage, Parts), (StatusCode, &'static str)> {
-    let (parts, body) = req.into_parts();
+    policy: Option<&ProxyPolicy>,
+) -> Result<(EncryptedMessage, Parts, Vec<UnreachableRecipient>), Response> {
+    let (mut parts, body) = req.into_parts();
+    let partial_delivery = parts
+        .headers
+        .remove(PARTIAL_DELIVERY)
+        .is_some_and(|v| v.as_bytes().eq_ignore_ascii_case(b"true"));
     let body = body::to_bytes(body).await.map_err(|e| {
         warn!("Unable to read message body: {e}");
-        ERR_BODY
+        ERR_BODY.into_response()
     })?;
 
-    let msg = if body.is_empty() {
+    let is_raw = parts
+        .headers
+        .get(header::CONTENT_TYPE)
+        .is_some_and(|v| v.as_bytes().starts_with(OCTET_STREAM.as_bytes()));
+    let mut msg = if is_raw {
+        parse_raw_message(&parts.headers, body.to_vec()).map_err(IntoResponse::into_response)?
+    } else if body.is_empty() {
         debug!("Body is empty, substituting MsgEmpty.");
         PlainMessage::MsgEmpty(MsgEmpty {
             from: sender.into(),
@@ -473,22 +1427,293 @@ async fn encrypt_request(
                     e,
                     std::str::from_utf8(&body).unwrap_or("(not valid UTF-8)")
                 );
-                return Err(ERR_BODY);
+                return Err(ERR_BODY.into_response());
             }
         }
     };
     // Sanity/security checks: From address sane?
     if msg.get_from() != sender {
-        return Err(ERR_FAKED_FROM);
+        return Err(ERR_FAKED_FROM.into_response());
+    }
+    if let Some(policy) = policy {
+        let denied = denied_recipients(&msg, sender, policy);
+        if !denied.is_empty() {
+            warn!("Policy: App {sender} may not send to {denied:?}");
+            return Err(PolicyViolationError {
+                error: "According to the policy, you may not send to these recipients.",
+                denied,
+            }
+            .into_response());
+        }
     }
-    let body = encrypt_msg(msg).await.map_err(|e| {
+    if let PlainMessage::MsgTaskRequest(task) = &msg {
+        if let Some(stranger) = task.bodies.keys().find(|r| !task.to.contains(r)) {
+            warn!("App {sender} supplied a body for {stranger}, who is no recipient of the task");
+            return Err((
+                StatusCode::BAD_REQUEST,
+                "Bodies may only be given for recipients of the task.",
+            )
+                .into_response());
+        }
+    }
+    let (receivers_keys, unreachable) = crypto::get_recipients_public_keys(msg.get_to()).await;
+    if !unreachable.is_empty() {
+        if !partial_delivery || unreachable.len() == msg.get_to().len() {
+            return Err(UnreachableRecipientsError {
+                error: "Unable to encrypt the message for some recipients, as they have no usable certificate.",
+                unreachable,
+            }
+            .into_response());
+        }
+        warn!(
+            "Partial delivery: Dropping unreachable recipients {:?}",
+            unreachable
+        );
+        drop_recipients(&mut msg, &unreachable);
+    }
+    let body = match msg {
+        PlainMessage::MsgTaskRequest(task) if !task.bodies.is_empty() => {
+            // Each recipient's body is encrypted only for its proxy
+            let mut recipients_keys = HashMap::new();
+            for recipient in task.bodies.keys() {
+                let (keys, _) = crypto::get_recipients_public_keys([recipient]).await;
+                recipients_keys.insert(recipient.clone(), keys);
+            }
+            task.encrypt_with_bodies(&receivers_keys, &recipients_keys)
+                .map(EncryptedMessage::MsgTaskRequest)
+        }
+        msg => msg.encrypt(&receivers_keys),
+    }
+    .map_err(|e| {
         warn!("Encryption faild with: {e}");
-        ERR_INTERNALCRYPTO
+        ERR_INTERNALCRYPTO.into_response()
+    })?;
+    Ok((body, parts, unreachable))
+}
+
+/// Parses a message whose body has been sent as raw bytes, with its other fields as JSON in the
+/// [`MESSAGE_FIELDS`] header
+fn parse_raw_message(
+    headers: &HeaderMap,
+    body: Vec<u8>,
+) -> Result<PlainMessage, (StatusCode, &'static str)> {
+    let fields = headers
+        .get(MESSAGE_FIELDS)
+        .and_then(|v| v.to_str().ok())
+        .ok_or((
+            StatusCode::BAD_REQUEST,
+            "Please supply the message's fields as JSON in the X-Beam-Fields header.",
+        ))?;
+    let mut msg: PlainMessage = serde_json::from_str(fields).map_err(|e| {
+        warn!("Received X-Beam-Fields header is invalid: {e}. Header was {fields}");
+        ERR_BODY
     })?;
-    Ok((body, parts))
+    msg.body_mut().ok_or(ERR_BODY)?.body = Some(body);
+    Ok(msg)
+}
+
+/// Recipients of the message that the sending app may not address: Tasks may be sent to the
+/// recipients allowed by `send_to`, results may be returned to those allowed by `receive_from`.
+fn denied_recipients(
+    msg: &PlainMessage,
+    sender: &AppId,
+    policy: &ProxyPolicy,
+) -> Vec<AppOrProxyId> {
+    let allowed = |recipient: &AppOrProxyId| match msg {
+        PlainMessage::MsgTaskRequest(_) | PlainMessage::MsgSocketRequest(_) => {
+            policy.may_send_to(sender, recipient)
+        }
+        PlainMessage::MsgTaskResult(_) => policy.may_receive_from(sender, recipient),
+        PlainMessage::MsgEmpty(_) => true,
+    };
+    msg.get_to()
+        .iter()
+        .filter(|recipient| !allowed(recipient))
+        .cloned()
+        .collect()
+}
+
+fn drop_recipients(msg: &mut PlainMessage, dropped: &[UnreachableRecipient]) {
+    let to = match msg {
+        PlainMessage::MsgTaskRequest(m) => &mut m.to,
+        PlainMessage::MsgTaskResult(m) => &mut m.to,
+        PlainMessage::MsgSocketRequest(m) => &mut m.to,
+        PlainMessage::MsgEmpty(_) => return,
+    };
+    to.retain(|r| !dropped.iter().any(|d| &d.recipient == r));
+    if let PlainMessage::MsgTaskRequest(m) = msg {
+        m.bodies
+            .retain(|r, _| !dropped.iter().any(|d| &d.recipient == r));
+    }
 }
 
-async fn encrypt_msg<M: EncryptableMsg>(msg: M) -> Result<M::Output, SamplyBeamError> {
-    let receivers_keys = crypto::get_proxy_public_keys(msg.get_to()).await?;
-    msg.encrypt(&receivers_keys)
+#[cfg(test)]
+mod tests {
+    use shared::beam_id::{BeamId, BrokerId};
+
+    use super::*;
+
+    const BROKER_ID: &str = "broker.samply.de";
+
+    fn id(name: &str) -> AppOrProxyId {
+        BrokerId::set_broker_id(BROKER_ID.to_string());
+        AppOrProxyId::new(&format!("{name}.{BROKER_ID}")).unwrap()
+    }
+
+    /// A task or result as announced by the broker; `signed` stands in for the JWT
+    fn message(event_type: SseEventType, to: &str, task: MsgId, signed: &str) -> UpstreamEvent {
+        UpstreamEvent::Message(UpstreamMessage {
+            event_type,
+            from: id("app1.proxy2"),
+            to: vec![id(to)],
+            task,
+            signed: Value::from(signed),
+        })
+    }
+
+    /// Long-polls of the app for `wanted`, served by the upstream connection
+    fn long_poll(
+        upstream: &Upstream,
+        app: &str,
+        wanted: Wanted,
+    ) -> tokio::task::JoinHandle<Option<Value>> {
+        let mut events = upstream.subscribe().expect("Upstream to be connected");
+        let app = id(app);
+        let deadline = time::Instant::now() + Duration::from_secs(5);
+        tokio::spawn(async move { next_wanted(&mut events, &wanted, &app, deadline).await })
+    }
+
+    #[tokio::test]
+    async fn long_polls_of_several_apps_share_one_connection() {
+        let upstream = Upstream::connected();
+        let task = MsgId::new();
+        let tasks_of = |app: &str| Wanted::Tasks {
+            from: None,
+            to: Some(id(app)),
+        };
+        let app1 = long_poll(&upstream, "app1.proxy1", tasks_of("app1.proxy1"));
+        let app2 = long_poll(&upstream, "app2.proxy1", tasks_of("app2.proxy1"));
+        let results = long_poll(&upstream, "app3.proxy1", Wanted::Results { task });
+
+        upstream.send(message(
+            SseEventType::NewResult,
+            "app3.proxy1",
+            MsgId::new(),
+            "other result",
+        ));
+        upstream.send(message(
+            SseEventType::NewTask,
+            "app2.proxy1",
+            MsgId::new(),
+            "task for app2",
+        ));
+        upstream.send(message(
+            SseEventType::NewTask,
+            "app1.proxy1",
+            MsgId::new(),
+            "task for app1",
+        ));
+        upstream.send(message(
+            SseEventType::NewResult,
+            "app3.proxy1",
+            task,
+            "result for app3",
+        ));
+
+        assert_eq!(app1.await.unwrap(), Some(Value::from("task for app1")));
+        assert_eq!(app2.await.unwrap(), Some(Value::from("task for app2")));
+        assert_eq!(results.await.unwrap(), Some(Value::from("result for app3")));
+    }
+
+    #[tokio::test]
+    async fn apps_cannot_subscribe_to_other_apps_tasks() {
+        let app2 = AppId::new(&format!("app2.proxy1.{BROKER_ID}")).unwrap();
+        for uri in ["/v1/tasks?to={app1}", "/v1/tasks?from={app1}", "/v1/tasks"] {
+            let uri = uri.replace("{app1}", &id("app1.proxy1").to_string());
+            assert_eq!(
+                Wanted::subscription(&uri, &app2)
+                    .err()
+                    .map(|(status, _)| status),
+                Some(StatusCode::UNAUTHORIZED),
+                "{uri}"
+            );
+        }
+        let own = format!("/v1/tasks?to={app2}");
+        assert!(Wanted::subscription(&own, &app2).is_ok());
+        assert!(Wanted::subscription("/v1/tasks?filter=todo", &app2).is_ok());
+
+        // Even if asked for, tasks neither from nor to the app are not passed on
+        let upstream = Upstream::connected();
+        let snooping = long_poll(
+            &upstream,
+            "app2.proxy1",
+            Wanted::Tasks {
+                from: None,
+                to: Some(id("app1.proxy1")),
+            },
+        );
+        upstream.send(message(
+            SseEventType::NewTask,
+            "app1.proxy1",
+            MsgId::new(),
+            "task for app1",
+        ));
+        upstream.send(UpstreamEvent::Disconnected);
+        assert_eq!(snooping.await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn events_reach_the_remaining_apps_when_one_disconnects() {
+        let upstream = Upstream::connected();
+        let wanted = |app: &str| Wanted::Tasks {
+            from: None,
+            to: Some(id(app)),
+        };
+        let app1 = long_poll(&upstream, "app1.proxy1", wanted("app1.proxy1"));
+        let app2 = long_poll(&upstream, "app2.proxy1", wanted("app2.proxy1"));
+        // The app closes its connection, so its long-poll is dropped
+        app1.abort();
+        assert!(app1.await.unwrap_err().is_cancelled());
+
+        upstream.send(message(
+            SseEventType::NewTask,
+            "app1.proxy1",
+            MsgId::new(),
+            "task for app1",
+        ));
+        upstream.send(message(
+            SseEventType::NewTask,
+            "app2.proxy1",
+            MsgId::new(),
+            "task for app2",
+        ));
+        assert_eq!(app2.await.unwrap(), Some(Value::from("task for app2")));
+
+        // A new long-poll of the app gets what is sent from now on
+        let app1 = long_poll(&upstream, "app1.proxy1", wanted("app1.proxy1"));
+        let app2 = long_poll(&upstream, "app2.proxy1", wanted("app2.proxy1"));
+        upstream.send(message(
+            SseEventType::NewTask,
+            "app1.proxy1",
+            MsgId::new(),
+            "next task",
+        ));
+        assert_eq!(app1.await.unwrap(), Some(Value::from("next task")));
+        // Once the connection is lost, waiting apps fetch again instead of missing messages
+        upstream.send(UpstreamEvent::Disconnected);
+        assert_eq!(app2.await.unwrap(), None);
+    }
+
+    #[test]
+    fn rejections_are_forgotten_once_tasks_expire() {
+        let expired = MsgId::new();
+        let current = MsgId::new();
+        let hour = Duration::from_secs(3600);
+        assert!(remember_rejection(expired, SystemTime::now() - hour));
+        assert!(remember_rejection(current, SystemTime::now() + hour));
+        assert!(!remember_rejection(current, SystemTime::now() + hour));
+        let rejected = REJECTED_TASKS.lock().unwrap();
+        assert!(!rejected.contains_key(&expired));
+        assert!(rejected.contains_key(&current));
+    }
 }
@@ -0,0 +1,162 @@
+use axum::{extract::State, routing::post, Json, Router};
+use hyper::StatusCode;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use shared::{
+    beam_id::{AppId, AppOrProxyId},
+    config_proxy,
+    crypto_jwt::{self, Provenance},
+    policy::ProxyPolicy,
+    EncryptedMessage, MessageType, Msg, MsgState,
+};
+use tracing::{debug, warn};
+
+use crate::{auth::AuthenticatedApp, serve_tasks::decrypt_msg};
+
+pub(crate) fn router(config: config_proxy::Config) -> Router {
+    Router::new()
+        .route("/v1/verify", post(handler_verify))
+        .with_state(config)
+}
+
+#[derive(Deserialize)]
+struct VerifyRequest {
+    jwt: String,
+}
+
+#[derive(Serialize)]
+struct VerifyResponse {
+    from: AppOrProxyId,
+    #[serde(flatten)]
+    provenance: Provenance,
+    /// The decrypted message, if the app is its sender or one of its recipients and this proxy can
+    /// decrypt it
+    msg: Option<Value>,
+}
+
+/// Verifies a signed message that an app has archived, e.g. from the `provenance` of a received message
+async fn handler_verify(
+    State(config): State<config_proxy::Config>,
+    AuthenticatedApp(sender): AuthenticatedApp,
+    Json(req): Json<VerifyRequest>,
+) -> Result<Json<VerifyResponse>, (StatusCode, String)> {
+    let (mut msg, provenance) = crypto_jwt::verify_archived::<EncryptedMessage>(&req.jwt)
+        .await
+        .map_err(|e| {
+            warn!("App {sender} asked to verify an invalid message: {e}");
+            (
+                StatusCode::UNPROCESSABLE_ENTITY,
+                format!("Unable to verify message: {e}"),
+            )
+        })?;
+    let from = msg.get_from().clone();
+    if !may_read(&sender, &msg, config.policy.as_ref()) {
+        debug!("App {sender} may not read the verified message, returning the verdict only");
+        return Ok(Json(VerifyResponse {
+            from,
+            provenance,
+            msg: None,
+        }));
+    }
+    if let EncryptedMessage::MsgTaskRequest(task) = &mut msg {
+        task.select_body(&(&sender).into());
+    }
+    let msg = match decrypt_msg(msg) {
+        Ok(msg) => serde_json::to_value(msg).ok(),
+        Err(e) => {
+            debug!("Verified message cannot be decrypted by this proxy: {e}");
+            None
+        }
+    };
+    Ok(Json(VerifyResponse {
+        from,
+        provenance,
+        msg,
+    }))
+}
+
+/// Whether the app may read the message's body: It has to be the message's sender or one of its
+/// recipients, and for the latter, its policy has to allow receiving tasks and sockets from the
+/// sender or (for results) sending tasks to it, as when receiving the message in the first place.
+fn may_read<T: MsgState>(app: &AppId, msg: &MessageType<T>, policy: Option<&ProxyPolicy>) -> bool {
+    let app_id = AppOrProxyId::from(app);
+    if *msg.get_from() == app_id {
+        return true;
+    }
+    if !msg.get_to().contains(&app_id) {
+        return false;
+    }
+    let Some(policy) = policy else {
+        return true;
+    };
+    match msg {
+        MessageType::MsgTaskRequest(_) | MessageType::MsgSocketRequest(_) => {
+            policy.may_receive_from(app, msg.get_from())
+        }
+        MessageType::MsgTaskResult(_) => policy.may_send_to(app, msg.get_from()),
+        MessageType::MsgEmpty(_) => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use shared::{
+        beam_id::{BeamId, BrokerId, ProxyId},
+        FailureStrategy, MsgTaskRequest, MsgTaskResult, PlainMessage, WorkStatus,
+    };
+
+    use super::*;
+
+    const BROKER_ID: &str = "broker.samply.de";
+
+    fn app(name: &str) -> AppId {
+        AppId::new(&format!("{name}.{BROKER_ID}")).unwrap()
+    }
+
+    #[test]
+    fn only_sender_and_recipients_may_read() {
+        BrokerId::set_broker_id(BROKER_ID.to_string());
+        let (creator, worker, third_party) =
+            (app("app1.proxy1"), app("app2.proxy2"), app("app3.proxy2"));
+        let task = MsgTaskRequest::new(
+            (&creator).into(),
+            vec![(&worker).into()],
+            "Secret parameters".to_string(),
+            FailureStrategy::Discard,
+            Value::Null,
+        );
+        let result = MsgTaskResult {
+            from: (&worker).into(),
+            to: vec![(&creator).into()],
+            task: task.id,
+            status: WorkStatus::Succeeded,
+            body: "Secret result".into(),
+            metadata: Value::Null,
+        };
+        let task = PlainMessage::MsgTaskRequest(task);
+        let result = PlainMessage::MsgTaskResult(result);
+        for msg in [&task, &result] {
+            assert!(may_read(&creator, msg, None));
+            assert!(may_read(&worker, msg, None));
+            assert!(!may_read(&third_party, msg, None));
+        }
+
+        let policy_file =
+            std::env::temp_dir().join(format!("beam-verify-policy-{}.json", std::process::id()));
+        std::fs::write(
+            &policy_file,
+            format!(
+                r#"{{ "apps": {{ "app2": {{ "receive_from": ["app1.proxy1.{BROKER_ID}"] }} }} }}"#
+            ),
+        )
+        .unwrap();
+        let proxy2 = ProxyId::new(&format!("proxy2.{BROKER_ID}")).unwrap();
+        let policy = ProxyPolicy::load(&policy_file, &proxy2).unwrap();
+        std::fs::remove_file(policy_file).unwrap();
+        assert!(may_read(&worker, &task, Some(&policy)));
+        assert!(!may_read(&third_party, &task, Some(&policy)));
+        // Without a policy allowing it, a recipient may not read the message either
+        assert!(!may_read(&worker, &task, Some(&ProxyPolicy::default())));
+        assert!(!may_read(&creator, &result, Some(&ProxyPolicy::default())));
+    }
+}
@@ -0,0 +1,158 @@
+//! API keys of the Proxy's apps, stored as salted (argon2) hashes
+
+use std::{collections::HashMap, path::Path, sync::Mutex};
+
+use argon2::{
+    password_hash::{rand_core::OsRng, PasswordHashString, SaltString},
+    Argon2, PasswordHasher, PasswordVerifier,
+};
+use sha2::{Digest, Sha256};
+
+use crate::{
+    beam_id::{AppId, BeamId, ProxyId},
+    errors::SamplyBeamError,
+};
+
+/// The apps' API keys. Each app may have several valid keys at once to allow for key rotation.
+#[derive(Debug, Default)]
+pub struct ApiKeyStore {
+    keys: HashMap<AppId, Vec<PasswordHashString>>,
+    /// SHA-256 digests of keys that have already been verified, sparing the (deliberately slow)
+    /// hash verification on subsequent requests
+    verified: Mutex<HashMap<AppId, Vec<[u8; 32]>>>,
+}
+
+impl ApiKeyStore {
+    /// Hashes a plaintext API key with a random salt, yielding a PHC string (`$argon2id$...`)
+    pub fn hash(api_key: &str) -> Result<String, SamplyBeamError> {
+        let salt = SaltString::generate(&mut OsRng);
+        Argon2::default()
+            .hash_password(api_key.as_bytes(), &salt)
+            .map(|hash| hash.to_string())
+            .map_err(|e| {
+                SamplyBeamError::ConfigurationFailed(format!("Unable to hash API key: {e}"))
+            })
+    }
+
+    /// Adds a plaintext API key for `app`
+    pub fn insert_plain(&mut self, app: AppId, api_key: &str) -> Result<(), SamplyBeamError> {
+        if api_key.is_empty() {
+            return Err(SamplyBeamError::ConfigurationFailed(format!(
+                "Unable to assign empty API key for client {app}"
+            )));
+        }
+        let hash = Self::hash(api_key)?;
+        self.insert_hash(app, &hash)
+    }
+
+    /// Adds a hashed API key (PHC string) for `app`
+    pub fn insert_hash(&mut self, app: AppId, hash: &str) -> Result<(), SamplyBeamError> {
+        let hash = PasswordHashString::new(hash).map_err(|e| {
+            SamplyBeamError::ConfigurationFailed(format!(
+                "Invalid API key hash for client {app}: {e}"
+            ))
+        })?;
+        self.keys.entry(app).or_default().push(hash);
+        Ok(())
+    }
+
+    /// Adds the hashed API keys from a JSON file mapping the apps' short names to lists of hashes, e.g.
+    /// `{ "app1": ["$argon2id$v=19$..."] }`
+    pub fn insert_from_file(
+        &mut self,
+        file: &Path,
+        proxy_id: &ProxyId,
+    ) -> Result<(), SamplyBeamError> {
+        let err = |e: String| {
+            SamplyBeamError::ConfigurationFailed(format!(
+                "Unable to read API keys from {}: {e}",
+                file.to_string_lossy()
+            ))
+        };
+        let content = std::fs::read_to_string(file).map_err(|e| err(e.to_string()))?;
+        let apps: HashMap<String, Vec<String>> =
+            serde_json::from_str(&content).map_err(|e| err(e.to_string()))?;
+        for (app, hashes) in apps {
+            let app = AppId::new(&format!("{app}.{proxy_id}"))?;
+            for hash in hashes {
+                self.insert_hash(app.clone(), &hash)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Checks in constant time whether `api_key` is one of `app`'s valid keys. Unknown keys are
+    /// verified against the hashes on a blocking thread.
+    pub async fn verify(&self, app: &AppId, api_key: &str) -> bool {
+        let Some(hashes) = self.keys.get(app) else {
+            return false;
+        };
+        let digest: [u8; 32] = Sha256::digest(api_key.as_bytes()).into();
+        if self
+            .verified
+            .lock()
+            .unwrap()
+            .get(app)
+            .is_some_and(|known| known.iter().any(|d| openssl::memcmp::eq(d, &digest)))
+        {
+            return true;
+        }
+        let (hashes, api_key) = (hashes.clone(), api_key.to_owned());
+        let valid = tokio::task::spawn_blocking(move || {
+            hashes.iter().any(|hash| {
+                Argon2::default()
+                    .verify_password(api_key.as_bytes(), &hash.password_hash())
+                    .is_ok()
+            })
+        })
+        .await
+        .unwrap_or(false);
+        if valid {
+            let mut verified = self.verified.lock().unwrap();
+            let known = verified.entry(app.clone()).or_default();
+            if !known.contains(&digest) {
+                known.push(digest);
+            }
+        }
+        valid
+    }
+
+    pub fn apps(&self) -> impl Iterator<Item = &AppId> {
+        self.keys.keys()
+    }
+
+    pub fn len(&self) -> usize {
+        self.keys.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.keys.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::beam_id::BrokerId;
+
+    #[tokio::test]
+    async fn api_keys_are_verified_against_all_hashes() {
+        const BROKER_ID: &str = "broker.samply.de";
+        BrokerId::set_broker_id(BROKER_ID.to_string());
+        let app1 = AppId::new(&format!("app1.proxy1.{BROKER_ID}")).unwrap();
+        let app2 = AppId::new(&format!("app2.proxy1.{BROKER_ID}")).unwrap();
+        let mut store = ApiKeyStore::default();
+        store.insert_plain(app1.clone(), "OldSecret").unwrap();
+        store
+            .insert_hash(app1.clone(), &ApiKeyStore::hash("NewSecret").unwrap())
+            .unwrap();
+        assert!(store.insert_hash(app2.clone(), "NotAHash").is_err());
+        assert!(store.insert_plain(app2.clone(), "").is_err());
+
+        assert!(store.verify(&app1, "OldSecret").await);
+        assert!(store.verify(&app1, "NewSecret").await);
+        assert!(store.verify(&app1, "NewSecret").await);
+        assert!(!store.verify(&app1, "WrongSecret").await);
+        assert!(!store.verify(&app2, "NewSecret").await);
+    }
+}
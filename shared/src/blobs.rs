@@ -0,0 +1,174 @@
+//! Blobs: Large payloads that are streamed through the Broker instead of being embedded in a task or
+//! result.
+//!
+//! The sending Proxy encrypts a blob with a random key using the STREAM construction (Hoang, Reyhanitabar,
+//! Rogaway and Vizár, 2015) with XChaCha20Poly1305: The plaintext is split into chunks of
+//! [`CHUNK_SIZE`] bytes, each encrypted with a nonce made up of a random prefix, the chunk's number
+//! and a flag marking the last chunk. Thus, each chunk's integrity is verified when it is received,
+//! and reordered, duplicated or missing chunks as well as truncated blobs are detected, while neither
+//! side has to hold more than a chunk in memory. The key is passed on to the recipients in the body
+//! of a task or result, so only they can decrypt the blob.
+
+use chacha20poly1305::{aead::Aead, KeyInit, XChaCha20Poly1305, XNonce};
+use hyper::header::HeaderName;
+use openssl::base64;
+use rand::Rng;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+/// Request header by which an app passes a blob's key to its Proxy for downloading the blob
+pub const BLOB_KEY: HeaderName = HeaderName::from_static("x-beam-blob-key");
+/// Plaintext is encrypted in chunks of this size; only the last chunk may be shorter
+pub const CHUNK_SIZE: usize = 64 * 1024;
+/// Format of encrypted blobs written by this version of Samply.Beam
+const VERSION: u8 = 1;
+const TAG_SIZE: usize = 16;
+const KEY_SIZE: usize = 32;
+/// The nonce consists of this prefix, a 32-bit chunk counter and the last chunk flag
+const PREFIX_SIZE: usize = 19;
+
+/// Generates the key of a new blob
+pub fn generate_key() -> String {
+    base64::encode_block(&rand::thread_rng().gen::<[u8; KEY_SIZE]>())
+}
+
+fn cipher(key: &str) -> std::io::Result<XChaCha20Poly1305> {
+    base64::decode_block(key)
+        .ok()
+        .and_then(|key| XChaCha20Poly1305::new_from_slice(&key).ok())
+        .ok_or_else(|| invalid_data("Invalid blob key"))
+}
+
+fn nonce(prefix: &[u8; PREFIX_SIZE], counter: u32, last: bool) -> XNonce {
+    let mut nonce = XNonce::default();
+    nonce[..PREFIX_SIZE].copy_from_slice(prefix);
+    nonce[PREFIX_SIZE..PREFIX_SIZE + 4].copy_from_slice(&counter.to_be_bytes());
+    nonce[PREFIX_SIZE + 4] = last.into();
+    nonce
+}
+
+fn invalid_data(msg: &'static str) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, msg)
+}
+
+/// Reads until `buf` is full or the end of the input, returning the number of bytes read
+async fn read_full(input: &mut (impl AsyncRead + Unpin), buf: &mut [u8]) -> std::io::Result<usize> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match input.read(&mut buf[filled..]).await? {
+            0 => break,
+            n => filled += n,
+        }
+    }
+    Ok(filled)
+}
+
+/// Encrypts the plaintext with the given key, returning the size of the plaintext
+pub async fn encrypt(
+    key: &str,
+    mut plain: impl AsyncRead + Unpin,
+    mut encrypted: impl AsyncWrite + Unpin,
+) -> std::io::Result<u64> {
+    let cipher = cipher(key)?;
+    let prefix = rand::thread_rng().gen::<[u8; PREFIX_SIZE]>();
+    encrypted.write_u8(VERSION).await?;
+    encrypted.write_all(&prefix).await?;
+    let mut buf = vec![0; CHUNK_SIZE];
+    let mut size = 0;
+    for counter in 0.. {
+        let n = read_full(&mut plain, &mut buf).await?;
+        size += n as u64;
+        // A full chunk is followed by another one, which may be empty
+        let last = n < CHUNK_SIZE;
+        let chunk = cipher
+            .encrypt(&nonce(&prefix, counter, last), &buf[..n])
+            .map_err(|_| invalid_data("Unable to encrypt chunk"))?;
+        encrypted.write_all(&chunk).await?;
+        if last {
+            break;
+        }
+    }
+    encrypted.shutdown().await?;
+    Ok(size)
+}
+
+/// Decrypts an encrypted blob with the given key. Fails as soon as a chunk cannot be verified, so
+/// all plaintext written before is authentic, but may be incomplete.
+pub async fn decrypt(
+    key: &str,
+    mut encrypted: impl AsyncRead + Unpin,
+    mut plain: impl AsyncWrite + Unpin,
+) -> std::io::Result<u64> {
+    let cipher = cipher(key)?;
+    if encrypted.read_u8().await? != VERSION {
+        return Err(invalid_data(
+            "Unsupported blob format; please update this Beam.Proxy",
+        ));
+    }
+    let mut prefix = [0; PREFIX_SIZE];
+    encrypted.read_exact(&mut prefix).await?;
+    let mut buf = vec![0; CHUNK_SIZE + TAG_SIZE];
+    let mut size = 0;
+    for counter in 0.. {
+        let n = read_full(&mut encrypted, &mut buf).await?;
+        let last = n < buf.len();
+        let chunk = cipher
+            .decrypt(&nonce(&prefix, counter, last), &buf[..n])
+            .map_err(|_| invalid_data("Blob is corrupted or incomplete"))?;
+        size += chunk.len() as u64;
+        plain.write_all(&chunk).await?;
+        if last {
+            break;
+        }
+    }
+    plain.shutdown().await?;
+    Ok(size)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn encrypted(key: &str, plain: &[u8]) -> Vec<u8> {
+        let mut encrypted = Vec::new();
+        encrypt(key, plain, &mut encrypted).await.unwrap();
+        encrypted
+    }
+
+    #[tokio::test]
+    async fn encrypt_decrypt_blob() {
+        let key = generate_key();
+        for size in [0, 1, CHUNK_SIZE, 2 * CHUNK_SIZE + 5] {
+            let plain: Vec<u8> = (0..size).map(|i| i as u8).collect();
+            let encrypted = encrypted(&key, &plain).await;
+            let mut decrypted = Vec::new();
+            let n = decrypt(&key, encrypted.as_slice(), &mut decrypted)
+                .await
+                .unwrap();
+            assert_eq!(n, size as u64);
+            assert_eq!(decrypted, plain);
+        }
+    }
+
+    #[tokio::test]
+    async fn truncation_and_reordering_are_detected() {
+        let key = generate_key();
+        let plain = vec![7; 3 * CHUNK_SIZE];
+        let encrypted = encrypted(&key, &plain).await;
+        let header = 1 + PREFIX_SIZE;
+        let chunk = CHUNK_SIZE + TAG_SIZE;
+
+        // Cut after the second chunk, which is then taken for the last one
+        let truncated = &encrypted[..header + 2 * chunk];
+        // Swap the first two chunks
+        let mut reordered = encrypted[..header].to_vec();
+        reordered.extend_from_slice(&encrypted[header + chunk..header + 2 * chunk]);
+        reordered.extend_from_slice(&encrypted[header..header + chunk]);
+        reordered.extend_from_slice(&encrypted[header + 2 * chunk..]);
+
+        for broken in [truncated, reordered.as_slice()] {
+            let mut decrypted = Vec::new();
+            let err = decrypt(&key, broken, &mut decrypted).await.unwrap_err();
+            assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+        }
+    }
+}
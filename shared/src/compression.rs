@@ -0,0 +1,136 @@
+//! Compression of message bodies before encryption. Bodies such as FHIR resources or CQL queries
+//! compress well, and ciphertext does not compress at all, so the sending Proxy compresses them
+//! and records the codec in the encryption envelope (see [`Encrypted`](crate::Encrypted)).
+//!
+//! Bodies are compressed as a single [zstd](https://facebook.github.io/zstd/) frame, which
+//! declares the uncompressed size. The declared size is checked against the receiver's limit
+//! before decompressing, and the output is limited while decompressing, so a small message cannot
+//! expand to exhaust the receiver's memory.
+
+use std::io::Read;
+
+use serde::{Deserialize, Serialize};
+
+use crate::errors::SamplyBeamError;
+
+/// Bodies are not decompressed beyond this size
+pub const MAX_DECOMPRESSED_SIZE: usize = 100 * 1024 * 1024;
+/// Smaller bodies are not worth compressing
+const MIN_COMPRESSED_SIZE: usize = 256;
+
+/// Codec used to compress the message body before encryption. Unknown codecs are kept as-is, see
+/// [`AeadAlgorithm`](crate::AeadAlgorithm).
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(from = "String", into = "String")]
+pub enum Compression {
+    Zstd,
+    Other(String),
+}
+
+impl Compression {
+    pub fn as_str(&self) -> &str {
+        match self {
+            Compression::Zstd => "zstd",
+            Compression::Other(other) => other,
+        }
+    }
+}
+
+impl From<String> for Compression {
+    fn from(value: String) -> Self {
+        match value.as_str() {
+            "zstd" => Compression::Zstd,
+            _ => Compression::Other(value),
+        }
+    }
+}
+
+impl From<Compression> for String {
+    fn from(value: Compression) -> Self {
+        value.as_str().to_string()
+    }
+}
+
+/// Compresses the body if it is large enough and actually gets smaller
+pub fn compress(plain: &[u8]) -> Option<(Compression, Vec<u8>)> {
+    if plain.len() < MIN_COMPRESSED_SIZE || plain.len() > MAX_DECOMPRESSED_SIZE {
+        return None;
+    }
+    let compressed = zstd::bulk::compress(plain, zstd::DEFAULT_COMPRESSION_LEVEL).ok()?;
+    (compressed.len() < plain.len()).then_some((Compression::Zstd, compressed))
+}
+
+/// Decompresses a body, failing if it would get larger than `max_size`
+pub fn decompress(
+    compression: &Compression,
+    compressed: &[u8],
+    max_size: usize,
+) -> Result<Vec<u8>, SamplyBeamError> {
+    let err = |msg: &str| SamplyBeamError::SignEncryptError(format!("Decompression error: {msg}"));
+    if let Compression::Other(other) = compression {
+        return Err(err(&format!(
+            "Unsupported compression {other}; please update this Beam.Proxy"
+        )));
+    }
+    let size = match zstd::zstd_safe::get_frame_content_size(compressed) {
+        Ok(Some(size)) => size,
+        Ok(None) => return Err(err("Body does not declare its size")),
+        Err(_) => return Err(err("Body is corrupted")),
+    };
+    if size > max_size as u64 {
+        return Err(err(&format!(
+            "Body would be {size} bytes, more than the allowed {max_size} bytes"
+        )));
+    }
+    // The declared size is not trusted for allocating, so the output grows as it is produced
+    let mut plain = Vec::new();
+    zstd::stream::read::Decoder::with_buffer(compressed)
+        .map_err(|_| err("Body is corrupted"))?
+        .take(size + 1)
+        .read_to_end(&mut plain)
+        .map_err(|_| err("Body is corrupted"))?;
+    if plain.len() as u64 != size {
+        return Err(err("Body does not match its declared size"));
+    }
+    Ok(plain)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn compress_decompress() {
+        let json = r#"{"resourceType":"Patient","gender":"female","birthDate":"1970-01-01"}"#;
+        let repetitive = json.repeat(500).into_bytes();
+        let mixed: Vec<u8> = (0..100_000u32)
+            .map(|i| (i.wrapping_mul(i) >> 7) as u8)
+            .collect();
+        for plain in [repetitive.clone(), mixed, vec![0; 70_000]] {
+            let Some((compression, compressed)) = compress(&plain) else {
+                continue;
+            };
+            assert!(compressed.len() < plain.len());
+            let decompressed =
+                decompress(&compression, &compressed, MAX_DECOMPRESSED_SIZE).unwrap();
+            assert_eq!(decompressed, plain);
+        }
+        assert!(compress(&repetitive).unwrap().1.len() < repetitive.len() / 10);
+        assert!(compress(json.as_bytes()).is_none());
+    }
+
+    #[test]
+    fn decompression_is_limited() {
+        let (compression, compressed) = compress(&vec![0; 1_000_000]).unwrap();
+        assert!(decompress(&compression, &compressed, 999_999).is_err());
+        assert!(decompress(&Compression::Other("lz4".into()), &compressed, 1_000_000).is_err());
+        // Bodies have to declare their size, so it can be checked before decompressing
+        let mut compressor = zstd::bulk::Compressor::new(zstd::DEFAULT_COMPRESSION_LEVEL).unwrap();
+        compressor
+            .set_parameter(zstd::zstd_safe::CParameter::ContentSizeFlag(false))
+            .unwrap();
+        let undeclared = compressor.compress(&vec![0; 1_000]).unwrap();
+        assert!(decompress(&compression, &undeclared, MAX_DECOMPRESSED_SIZE).is_err());
+        assert!(decompress(&compression, b"garbage", MAX_DECOMPRESSED_SIZE).is_err());
+    }
+}
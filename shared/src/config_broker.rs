@@ -1,11 +1,14 @@
-use std::{fs::read_to_string, net::SocketAddr, path::PathBuf};
+use std::{fs::read_to_string, net::SocketAddr, path::PathBuf, time::Duration};
 
 use crate::{
     beam_id::{BeamId, BrokerId},
     errors::SamplyBeamError,
+    middleware::IpNet,
+    tls_server::TlsServerConfig,
 };
 use axum::http::Uri;
 use clap::Parser;
+use fundu::parse_duration;
 use static_init::dynamic;
 use std::str::FromStr;
 use tracing::info;
@@ -50,6 +53,42 @@ struct CliArgs {
     #[clap(long, env, value_parser, default_value = "/run/secrets/root.crt.pem")]
     rootcert_file: PathBuf,
 
+    /// Validity of signed messages (JWTs), e.g. 1h
+    #[clap(long, env, value_parser = parse_duration, default_value = "1h")]
+    jwt_lifetime: Duration,
+
+    /// Maximum accepted difference between a signed request's Date header and the Broker's clock, e.g. 2m. Requests are remembered for this time to reject replays.
+    #[clap(long, env, value_parser = parse_duration, default_value = "2m")]
+    clock_skew: Duration,
+
+    /// Routing policy file specifying which proxies/apps may address which others (e.g. /etc/beam/policy.json); reloaded on SIGHUP. If unset, everybody may address everybody.
+    #[clap(long, env, value_parser)]
+    policy_file: Option<PathBuf>,
+
+    /// Quota file limiting the request rate, outstanding tasks, stored bytes and task TTL per proxy/app (e.g. /etc/beam/quotas.json); reloaded on SIGHUP. If unset, no limits are enforced.
+    #[clap(long, env, value_parser)]
+    quota_file: Option<PathBuf>,
+
+    /// Directory for storing blobs, i.e. large payloads streamed between apps (e.g. /var/lib/beam/blobs). If unset, blobs are not supported.
+    #[clap(long, env, value_parser)]
+    blob_dir: Option<PathBuf>,
+
+    /// Maximum size of a single blob in bytes
+    #[clap(long, env, value_parser, default_value_t = 10 * 1024 * 1024 * 1024)]
+    max_blob_size: u64,
+
+    /// Addresses or networks (e.g. 10.0.0.0/8) of reverse proxies whose X-Forwarded-For headers are trusted, separated by commas
+    #[clap(long, env, value_parser, value_delimiter = ',')]
+    trusted_proxies: Vec<IpNet>,
+
+    /// TLS: Serve HTTPS with this certificate (chain) file (PEM), reloaded when changed or on SIGHUP. Requires TLS_KEY_FILE.
+    #[clap(long, env, value_parser)]
+    tls_cert_file: Option<PathBuf>,
+
+    /// TLS: Private key file (PEM) for TLS_CERT_FILE
+    #[clap(long, env, value_parser)]
+    tls_key_file: Option<PathBuf>,
+
     /// (included for technical reasons)
     #[clap(long, hide(true))]
     test_threads: Option<String>,
@@ -61,6 +100,11 @@ pub struct Config {
     pub pki_realm: String,
     pub pki_token: String,
     pub tls_ca_certificates_dir: Option<PathBuf>,
+    pub policy_file: Option<PathBuf>,
+    pub quota_file: Option<PathBuf>,
+    pub blob_dir: Option<PathBuf>,
+    pub max_blob_size: u64,
+    pub tls: Option<TlsServerConfig>,
 }
 
 impl crate::config::Config for Config {
@@ -78,6 +122,20 @@ impl crate::config::Config for Config {
             .trim()
             .to_string();
 
+        let tls = match (cli_args.tls_cert_file, cli_args.tls_key_file) {
+            (Some(cert_file), Some(key_file)) => Some(TlsServerConfig {
+                cert_file,
+                key_file,
+                client_ca_file: None,
+            }),
+            (None, None) => None,
+            _ => {
+                return Err(SamplyBeamError::ConfigurationFailed(
+                    "To serve TLS, please set both TLS_CERT_FILE and TLS_KEY_FILE".into(),
+                ))
+            }
+        };
+
         info!("Successfully read config and API keys from CLI and secrets files.");
         let config = Config {
             bind_addr: cli_args.bind_addr,
@@ -85,6 +143,11 @@ impl crate::config::Config for Config {
             pki_realm: cli_args.pki_realm,
             pki_token,
             tls_ca_certificates_dir: cli_args.tls_ca_certificates_dir,
+            policy_file: cli_args.policy_file,
+            quota_file: cli_args.quota_file,
+            blob_dir: cli_args.blob_dir,
+            max_blob_size: cli_args.max_blob_size,
+            tls,
         };
         Ok(config)
     }
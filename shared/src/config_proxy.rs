@@ -1,4 +1,5 @@
 use clap::Parser;
+use fundu::parse_duration;
 use openssl::x509::X509;
 
 use std::{
@@ -8,16 +9,23 @@ use std::{
     path::{Path, PathBuf},
     process::exit,
     str::FromStr,
+    sync::Arc,
+    time::Duration,
 };
 
 use axum::http::HeaderValue;
 use hyper::Uri;
 use serde::Deserialize;
+use tokio::sync::RwLock;
 use tracing::{debug, info};
 
 use crate::{
+    api_keys::ApiKeyStore,
     beam_id::{self, AppId, BeamId, BrokerId, ProxyId},
     errors::SamplyBeamError,
+    middleware::IpNet,
+    policy::ProxyPolicy,
+    tls_server::TlsServerConfig,
 };
 
 #[derive(Clone, Debug)]
@@ -26,8 +34,46 @@ pub struct Config {
     pub broker_host_header: HeaderValue,
     pub bind_addr: SocketAddr,
     pub proxy_id: ProxyId,
-    pub api_keys: HashMap<AppId, ApiKey>,
+    pub api_keys: Arc<RwLock<ApiKeyStore>>,
+    pub api_keys_file: Option<PathBuf>,
     pub tls_ca_certificates: Vec<X509>,
+    pub cert_cache_file: Option<PathBuf>,
+    pub outbox_dir: Option<PathBuf>,
+    pub compact_wire_format: bool,
+    pub policy: Option<ProxyPolicy>,
+    pub oidc: Option<OidcConfig>,
+    pub tls: Option<TlsServerConfig>,
+}
+
+/// Authentication of apps via bearer tokens (JWTs) issued by an OAuth2/OIDC identity provider
+#[derive(Clone, Debug)]
+pub struct OidcConfig {
+    pub issuer: String,
+    pub jwks: JwksSource,
+    pub audience: Option<String>,
+    pub app_claim: String,
+}
+
+/// Where to get the identity provider's signing keys from
+#[derive(Clone, Debug)]
+pub enum JwksSource {
+    File(PathBuf),
+    Url(Uri),
+}
+
+impl FromStr for JwksSource {
+    type Err = SamplyBeamError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.starts_with("http://") || s.starts_with("https://") {
+            let uri = s.parse().map_err(|e| {
+                SamplyBeamError::ConfigurationFailed(format!("Invalid JWKS URL {s}: {e}"))
+            })?;
+            Ok(Self::Url(uri))
+        } else {
+            Ok(Self::File(PathBuf::from(s)))
+        }
+    }
 }
 
 pub type ApiKey = String;
@@ -64,6 +110,66 @@ pub struct CliArgs {
     #[clap(long, env, value_parser, default_value = "/run/secrets/root.crt.pem")]
     rootcert_file: PathBuf,
 
+    /// Validity of signed messages (JWTs), e.g. 1h. Messages fetched from the Broker after this time cannot be verified by the recipient.
+    #[clap(long, env, value_parser = parse_duration, default_value = "1h")]
+    jwt_lifetime: Duration,
+
+    /// samply.pki: File to persist verified certificates in, so they are available at startup even if the Broker is unreachable (e.g. /var/cache/beam/certs.json)
+    #[clap(long, env, value_parser)]
+    pub cert_cache_file: Option<PathBuf>,
+
+    /// Directory for a durable outbox (e.g. /var/cache/beam/outbox): If set, tasks and results that cannot be delivered to the Broker are queued, acknowledged with 202 Accepted and delivered once the Broker is reachable again
+    #[clap(long, env, value_parser)]
+    pub outbox_dir: Option<PathBuf>,
+
+    /// Exchange tasks and results with the Broker in a compact binary format (CBOR) instead of JSON. Requires a Beam.Broker 0.7 or newer.
+    #[clap(long, env, value_parser)]
+    pub compact_wire_format: bool,
+
+    /// Policy file specifying which remote apps/proxies each app may send tasks to and accept tasks from (e.g. /etc/beam/policy.json). If unset, all apps may communicate with anybody.
+    #[clap(long, env, value_parser)]
+    pub policy_file: Option<PathBuf>,
+
+    /// File (e.g. a Docker secret) with hashed API keys, mapping each app to a list of valid keys (e.g. /run/secrets/apikeys.json); reloaded on SIGHUP or when changed. Complements keys given via APP_n_ID/APP_n_KEY.
+    #[clap(long, env, value_parser)]
+    pub api_keys_file: Option<PathBuf>,
+
+    /// OAuth2/OIDC: Issuer of bearer tokens accepted from apps (e.g. https://idp.example.de/realms/beam). If unset, apps authenticate with API keys only.
+    #[clap(long, env, value_parser)]
+    pub oidc_issuer: Option<String>,
+
+    /// OAuth2/OIDC: The issuer's signing keys (JWKS), either a file or a URL (e.g. http://idp:8080/realms/beam/protocol/openid-connect/certs)
+    #[clap(long, env, value_parser)]
+    pub oidc_jwks: Option<String>,
+
+    /// OAuth2/OIDC: If set, bearer tokens must be issued for this audience
+    #[clap(long, env, value_parser)]
+    pub oidc_audience: Option<String>,
+
+    /// OAuth2/OIDC: Claim of the bearer token holding the app's name (e.g. app1) or AppId
+    #[clap(long, env, value_parser, default_value = "sub")]
+    pub oidc_app_claim: String,
+
+    /// Tolerated clock difference, e.g. when checking the validity of bearer tokens
+    #[clap(long, env, value_parser = parse_duration, default_value = "2m")]
+    clock_skew: Duration,
+
+    /// Addresses or networks (e.g. 10.0.0.0/8) of reverse proxies whose X-Forwarded-For headers are trusted, separated by commas
+    #[clap(long, env, value_parser, value_delimiter = ',')]
+    trusted_proxies: Vec<IpNet>,
+
+    /// TLS: Serve HTTPS to apps with this certificate (chain) file (PEM), reloaded when changed or on SIGHUP. Requires TLS_KEY_FILE.
+    #[clap(long, env, value_parser)]
+    pub tls_cert_file: Option<PathBuf>,
+
+    /// TLS: Private key file (PEM) for TLS_CERT_FILE
+    #[clap(long, env, value_parser)]
+    pub tls_key_file: Option<PathBuf>,
+
+    /// TLS: Let apps authenticate with client certificates issued by these CAs (PEM file). The certificate's CN names the app.
+    #[clap(long, env, value_parser)]
+    pub tls_client_ca_file: Option<PathBuf>,
+
     /// (included for technical reasons)
     #[clap(long, hide(true))]
     test_threads: Option<String>,
@@ -96,6 +202,24 @@ fn parse_apikeys(proxy_id: &ProxyId) -> Result<HashMap<AppId, ApiKey>, SamplyBea
     Ok(api_keys)
 }
 
+/// Loads the apps' API keys from the environment (see [`parse_apikeys`]) and the hashed keys in `file`
+pub fn load_api_keys(
+    proxy_id: &ProxyId,
+    file: Option<&Path>,
+) -> Result<ApiKeyStore, SamplyBeamError> {
+    let mut store = ApiKeyStore::default();
+    for (app_id, api_key) in parse_apikeys(proxy_id)? {
+        store.insert_plain(app_id, &api_key)?;
+    }
+    if let Some(file) = file {
+        store.insert_from_file(file, proxy_id)?;
+    }
+    if store.is_empty() {
+        return Err(SamplyBeamError::ConfigurationFailed(format!("No API keys have been defined. Please set environment vars à la {0}_0_ID=<clientname>, {0}_0_KEY=<key> or supply an API keys file", APP_PREFIX)));
+    }
+    Ok(store)
+}
+
 impl crate::config::Config for Config {
     fn load() -> Result<Config, SamplyBeamError> {
         let cli_args = CliArgs::parse();
@@ -106,10 +230,7 @@ impl crate::config::Config for Config {
                 cli_args.proxy_id, e
             ))
         })?;
-        let api_keys = parse_apikeys(&proxy_id)?;
-        if api_keys.is_empty() {
-            return Err(SamplyBeamError::ConfigurationFailed(format!("No API keys have been defined. Please set environment vars à la {0}_0_ID=<clientname>, {0}_0_KEY=<key>", APP_PREFIX)));
-        }
+        let api_keys = load_api_keys(&proxy_id, cli_args.api_keys_file.as_deref())?;
         let tls_ca_certificates = crate::crypto::load_certificates_from_dir(
             cli_args.tls_ca_certificates_dir,
         )
@@ -119,13 +240,51 @@ impl crate::config::Config for Config {
                 e
             ))
         })?;
+        let policy = cli_args
+            .policy_file
+            .map(|file| ProxyPolicy::load(&file, &proxy_id))
+            .transpose()?;
+        let oidc =
+            match (cli_args.oidc_issuer, cli_args.oidc_jwks) {
+                (Some(issuer), Some(jwks)) => Some(OidcConfig {
+                    issuer,
+                    jwks: jwks.parse()?,
+                    audience: cli_args.oidc_audience,
+                    app_claim: cli_args.oidc_app_claim,
+                }),
+                (None, None) => None,
+                _ => return Err(SamplyBeamError::ConfigurationFailed(
+                    "For authentication via OAuth2/OIDC, please set both OIDC_ISSUER and OIDC_JWKS"
+                        .into(),
+                )),
+            };
+        let tls = match (cli_args.tls_cert_file, cli_args.tls_key_file) {
+            (Some(cert_file), Some(key_file)) => Some(TlsServerConfig {
+                cert_file,
+                key_file,
+                client_ca_file: cli_args.tls_client_ca_file,
+            }),
+            (None, None) if cli_args.tls_client_ca_file.is_none() => None,
+            _ => {
+                return Err(SamplyBeamError::ConfigurationFailed(
+                    "To serve TLS, please set both TLS_CERT_FILE and TLS_KEY_FILE".into(),
+                ))
+            }
+        };
         let config = Config {
             broker_host_header: uri_to_host_header(&cli_args.broker_url)?,
             broker_uri: cli_args.broker_url,
             bind_addr: cli_args.bind_addr,
             proxy_id,
-            api_keys,
+            api_keys: Arc::new(RwLock::new(api_keys)),
+            api_keys_file: cli_args.api_keys_file,
             tls_ca_certificates,
+            cert_cache_file: cli_args.cert_cache_file,
+            outbox_dir: cli_args.outbox_dir,
+            compact_wire_format: cli_args.compact_wire_format,
+            policy,
+            oidc,
+            tls,
         };
         info!("Successfully read config and API keys from CLI and secrets file.");
         Ok(config)
@@ -5,20 +5,21 @@ use crate::{
         self, get_all_certs_and_clients_by_cname_as_pemstr, load_certificates_from_dir,
         CryptoPublicPortion, GetCerts,
     },
+    crypto_keys::PrivateKey,
+    middleware::IpNet,
     SamplyBeamError,
 };
 use axum::async_trait;
 use clap::Parser;
+use fundu::parse_duration;
 use hyper::Uri;
 use hyper_tls::native_tls::Certificate;
-use jwt_simple::prelude::RS256KeyPair;
 use openssl::{
     asn1::Asn1IntegerRef,
     x509::{self, X509},
 };
-use rsa::{pkcs1::DecodeRsaPrivateKey, pkcs8::DecodePrivateKey, RsaPrivateKey};
 use static_init::dynamic;
-use std::{fs::read_to_string, path::PathBuf, rc::Rc, sync::Arc};
+use std::{fs::read_to_string, path::PathBuf, rc::Rc, sync::Arc, time::Duration};
 use tracing::{debug, info};
 
 pub(crate) const CLAP_FOOTER: &str = "For proxy support, environment variables HTTP_PROXY, HTTPS_PROXY, ALL_PROXY and NO_PROXY (and their lower-case variants) are supported. Usually, you want to set HTTP_PROXY *and* HTTPS_PROXY or set ALL_PROXY if both values are the same.\n\nFor updates and detailed usage instructions, visit https://github.com/samply/beam";
@@ -43,6 +44,18 @@ struct CliArgs {
     #[clap(long, env, value_parser, default_value = "/run/secrets/root.crt.pem")]
     rootcert_file: PathBuf,
 
+    /// Validity of signed messages (JWTs), e.g. 1h. Messages fetched from the Broker after this time cannot be verified by the recipient.
+    #[clap(long, env, value_parser = parse_duration, default_value = "1h")]
+    jwt_lifetime: Duration,
+
+    /// Maximum accepted difference between a signed request's Date header and the local clock, e.g. 2m. Requests are remembered for this time to reject replays.
+    #[clap(long, env, value_parser = parse_duration, default_value = "2m")]
+    clock_skew: Duration,
+
+    /// Addresses or networks (e.g. 10.0.0.0/8) of reverse proxies whose X-Forwarded-For headers are trusted, separated by commas
+    #[clap(long, env, value_parser, value_delimiter = ',')]
+    trusted_proxies: Vec<IpNet>,
+
     // TODO: The following arguments have been added for compatibility reasons with the proxy config. Find another way to merge configs.
     /// (included for technical reasons)
     #[clap(long, env, value_parser)]
@@ -67,12 +80,14 @@ pub struct Config {
     pub(crate) broker_domain: String,
     pub root_cert: X509,
     pub tls_ca_certificates: Vec<X509>,
+    pub jwt_lifetime: Duration,
+    pub clock_skew: Duration,
+    pub trusted_proxies: Vec<IpNet>,
 }
 
 #[derive(Debug, Clone)]
 pub struct ConfigCrypto {
-    pub privkey_rs256: RS256KeyPair,
-    pub privkey_rsa: RsaPrivateKey,
+    pub privkey: PrivateKey,
     pub public: Option<CryptoPublicPortion>,
 }
 
@@ -102,6 +117,9 @@ impl crate::config::Config for Config {
             tls_ca_certificates_dir,
             root_cert,
             tls_ca_certificates,
+            jwt_lifetime: cli_args.jwt_lifetime,
+            clock_skew: cli_args.clock_skew,
+            trusted_proxies: cli_args.trusted_proxies,
         })
     }
 }
@@ -149,23 +167,14 @@ pub fn load_private_crypto_for_proxy() -> Result<ConfigCrypto, SamplyBeamError>
         })?
         .trim()
         .to_string();
-    let privkey_rsa = RsaPrivateKey::from_pkcs1_pem(&privkey_pem)
-        .or_else(|_| RsaPrivateKey::from_pkcs8_pem(&privkey_pem))
-        .map_err(|e| {
-            SamplyBeamError::ConfigurationFailed(format!(
-                "Unable to interpret private key PEM as PKCS#1 or PKCS#8: {}",
-                e
-            ))
-        })?;
-    let privkey_rs256 = RS256KeyPair::from_pem(&privkey_pem).map_err(|e| {
+    let privkey = PrivateKey::from_pem(&privkey_pem).map_err(|e| {
         SamplyBeamError::ConfigurationFailed(format!(
-            "Unable to interpret private key PEM as PKCS#1 or PKCS#8: {}",
+            "Unable to interpret private key PEM as an RSA, EC (P-256, P-384) or Ed25519 key in PKCS#1, PKCS#8 or SEC1 format: {}",
             e
         ))
     })?;
     Ok(ConfigCrypto {
-        privkey_rs256,
-        privkey_rsa,
+        privkey,
         public: None,
     })
 }
@@ -185,18 +194,18 @@ async fn load_public_crypto_for_proxy(
                 .ok()
         })
         .collect();
-    let public = crypto::get_best_own_certificate(publics, &config.privkey_rsa).ok_or(
+    let public = crypto::get_best_own_certificate(publics, &config.privkey).ok_or(
         SamplyBeamError::SignEncryptError(
             "Unable to choose valid, newest certificate for this proxy".into(),
         ),
     )?;
     let serial = asn_str_to_vault_str(public.cert.serial_number())?;
-    config.privkey_rs256 = config.privkey_rs256.with_key_id(&serial);
+    config.privkey = config.privkey.with_key_id(&serial);
     config.public = Some(public);
     Ok(config)
 }
 
-fn asn_str_to_vault_str(asn: &Asn1IntegerRef) -> Result<String, SamplyBeamError> {
+pub(crate) fn asn_str_to_vault_str(asn: &Asn1IntegerRef) -> Result<String, SamplyBeamError> {
     let mut a = asn
         .to_bn()
         .map_err(|e| {
@@ -10,9 +10,10 @@ use openssl::{
     x509::X509,
 };
 use rsa::{
-    pkcs1::DecodeRsaPublicKey, pkcs8::DecodePublicKey, PaddingScheme, PublicKey, PublicKeyParts,
-    RsaPrivateKey, RsaPublicKey,
+    pkcs1::DecodeRsaPublicKey, pkcs8::DecodePublicKey, PaddingScheme, PublicKey as _,
+    PublicKeyParts, RsaPrivateKey, RsaPublicKey,
 };
+use serde::{Deserialize, Serialize};
 use sha2::{Digest, Sha256};
 use static_init::dynamic;
 use std::{
@@ -22,7 +23,7 @@ use std::{
     fs::read_to_string,
     path::{Path, PathBuf},
     sync::Arc,
-    time::{Duration, SystemTime},
+    time::{Duration, Instant, SystemTime},
 };
 use tokio::sync::{mpsc, oneshot, RwLock};
 use tracing::{debug, error, info, warn};
@@ -30,8 +31,9 @@ use tracing::{debug, error, info, warn};
 use crate::{
     beam_id::{AppOrProxyId, BeamId, ProxyId},
     config,
-    config_shared::ConfigCrypto,
+    config_shared::{self, ConfigCrypto},
     crypto,
+    crypto_keys::{PrivateKey, PublicKey},
     errors::{CertificateInvalidReason, SamplyBeamError},
     EncryptedMsgTaskRequest, MsgTaskRequest,
 };
@@ -87,21 +89,51 @@ impl TryFrom<&X509> for ProxyCertInfo {
 #[derive(Clone)]
 pub(crate) enum CertificateCacheEntry {
     Valid(X509),
+    /// Issued by our CA, but outside of its validity period. Kept to verify archived messages.
+    InvalidDate(X509),
     Invalid(CertificateInvalidReason),
 }
 
+impl CertificateCacheEntry {
+    /// The certificate's public portion, or the reason why it may not be used. Certificates that
+    /// are only outside of their validity period are accepted if `accept_invalid_date` is set.
+    pub(crate) fn public_portion(
+        &self,
+        accept_invalid_date: bool,
+    ) -> Result<CryptoPublicPortion, CertificateInvalidReason> {
+        match self {
+            CertificateCacheEntry::Valid(cert) => extract_x509(cert),
+            CertificateCacheEntry::InvalidDate(cert) if accept_invalid_date => extract_x509(cert),
+            CertificateCacheEntry::InvalidDate(_) => Err(CertificateInvalidReason::InvalidDate),
+            CertificateCacheEntry::Invalid(reason) => Err(reason.clone()),
+        }
+    }
+}
+
 pub(crate) struct CertificateCache {
     serial_to_x509: HashMap<Serial, CertificateCacheEntry>,
     cn_to_serial: HashMap<ProxyId, Vec<Serial>>,
     update_trigger: mpsc::Sender<oneshot::Sender<Result<usize, SamplyBeamError>>>,
     root_cert: Option<X509>, // Might not be available at initialization time
     im_cert: Option<X509>,   // Might not be available at initialization time
+    persist_to: Option<PathBuf>,
+    /// When the list of revoked certificates has last been fetched
+    revoked_fetched: Option<Instant>,
+}
+
+/// On-disk representation of the certificate cache. Only certificates that passed
+/// validation are persisted; they are validated again when loaded.
+#[derive(Serialize, Deserialize, Default)]
+struct PersistedCertificateCache {
+    im_cert: Option<String>,
+    certs: HashMap<Serial, String>,
 }
 
 #[async_trait]
 pub trait GetCerts: Sync + Send {
     async fn certificate_list(&self) -> Result<Vec<String>, SamplyBeamError>;
     async fn certificate_by_serial_as_pem(&self, serial: &str) -> Result<String, SamplyBeamError>;
+    async fn revoked_certificate_list(&self) -> Result<Vec<String>, SamplyBeamError>;
     async fn im_certificate_as_pem(&self) -> Result<String, SamplyBeamError>;
 }
 
@@ -115,6 +147,8 @@ impl CertificateCache {
             update_trigger,
             root_cert: None,
             im_cert: None,
+            persist_to: None,
+            revoked_fetched: None,
         })
     }
 
@@ -144,7 +178,8 @@ impl CertificateCache {
                     let x509 = cache.serial_to_x509.get(serial);
                     if let Some(x509) = x509 {
                         match x509 {
-                            CertificateCacheEntry::Invalid(reason) => {
+                            CertificateCacheEntry::Invalid(_)
+                            | CertificateCacheEntry::InvalidDate(_) => {
                                 result.push(x509.clone());
                                 invalid += 1;
                             }
@@ -155,6 +190,8 @@ impl CertificateCache {
                                         continue;
                                     };
                                     warn!("Found x509 certificate with invalid date: CN={}, serial={}", info.common_name, info.serial);
+                                    result.push(CertificateCacheEntry::InvalidDate(x509.clone()));
+                                    invalid += 1;
                                 } else {
                                     debug!(
                                         "Certificate with serial {} successfully retrieved.",
@@ -233,8 +270,18 @@ impl CertificateCache {
     }
 
     async fn update_certificates_mut(&mut self) -> Result<usize, SamplyBeamError> {
+        self.update_certificates_from(CERT_GETTER.get().unwrap().as_ref())
+            .await
+    }
+
+    /// Fetches new certificates from `getter`. The list of revoked certificates is only fetched if
+    /// the list of certificates has changed or it is older than [`Self::REVOKED_LIST_MAX_AGE`].
+    async fn update_certificates_from(
+        &mut self,
+        getter: &dyn GetCerts,
+    ) -> Result<usize, SamplyBeamError> {
         info!("Updating certificates ...");
-        let certificate_list = CERT_GETTER.get().unwrap().certificate_list().await?;
+        let certificate_list = getter.certificate_list().await?;
         let new_certificate_serials: Vec<&String> = {
             certificate_list
                 .iter()
@@ -247,16 +294,13 @@ impl CertificateCache {
             new_certificate_serials.len()
         );
 
+        let list_changed = !new_certificate_serials.is_empty();
         let mut new_count = 0;
         //TODO Check for validity
         for serial in new_certificate_serials {
             debug!("Checking certificate with serial {serial}");
 
-            let certificate = CERT_GETTER
-                .get()
-                .unwrap()
-                .certificate_by_serial_as_pem(serial)
-                .await;
+            let certificate = getter.certificate_by_serial_as_pem(serial).await;
             if let Err(e) = certificate {
                 match e {
                     SamplyBeamError::CertificateError(err) => {
@@ -281,59 +325,231 @@ impl CertificateCache {
                     continue;
                 }
             };
-            let commonnames: Vec<ProxyId> = opensslcert
-                .subject_name()
-                .entries()
-                .map(|x| x.data().as_utf8().unwrap()) // TODO: Remove unwrap, e.g. by supplying empty _or-string
-                .collect::<Vec<OpensslString>>()
-                .iter()
-                .map(|x| {
-                    ProxyId::new(&x.to_string()).expect(&format!(
-                        "Internal error: Vault returned certificate with invalid common name: {}",
-                        x
-                    ))
-                })
-                .collect();
-
-            let err = {
-                if commonnames.is_empty() {
-                    Some(CertificateInvalidReason::NoCommonName)
-                } else if let Err(e) = verify_cert(
-                    &opensslcert,
-                    &self
-                        .im_cert
-                        .as_ref()
-                        .expect("No intermediate CA cert found"),
-                ) {
-                    Some(e)
-                } else {
-                    None
-                }
-            };
-            if let Some(err) = err {
-                warn!("Certificate with serial {} invalid: {}.", serial, err);
-                self.serial_to_x509
-                    .insert(serial.clone(), CertificateCacheEntry::Invalid(err));
-            } else {
-                let cn = commonnames
-                    .first()
-                    .expect("Internal error: common names empty; this should not happen");
-                self.serial_to_x509
-                    .insert(serial.clone(), CertificateCacheEntry::Valid(opensslcert));
-                match self.cn_to_serial.get_mut(cn) {
-                    Some(serials) => serials.push(serial.clone()),
-                    None => {
-                        let new = vec![serial.clone()];
-                        self.cn_to_serial.insert(cn.clone(), new);
-                    }
-                };
-                debug!("Added certificate {} for cname {}", serial, cn);
+            if self.insert_checked(serial, opensslcert) {
                 new_count += 1;
             }
         }
+        let revoked_outdated = self
+            .revoked_fetched
+            .is_none_or(|fetched| fetched.elapsed() >= Self::REVOKED_LIST_MAX_AGE);
+        let revoked_count = if list_changed || revoked_outdated {
+            match getter.revoked_certificate_list().await {
+                Ok(revoked) => {
+                    self.revoked_fetched = Some(Instant::now());
+                    self.mark_revoked(&revoked)
+                }
+                Err(e) => {
+                    warn!("Unable to retrieve list of revoked certificates: {e}");
+                    0
+                }
+            }
+        } else {
+            0
+        };
+        if new_count > 0 || revoked_count > 0 {
+            self.persist();
+        }
         Ok(new_count)
     }
 
+    const REVOKED_LIST_MAX_AGE: Duration = Duration::from_secs(60);
+
+    /// Adds a certificate received from the PKI, checking it against the intermediate CA certificate.
+    /// Returns whether the certificate is valid.
+    fn insert_checked(&mut self, serial: &Serial, cert: X509) -> bool {
+        let commonnames: Vec<ProxyId> = cert
+            .subject_name()
+            .entries()
+            .map(|x| x.data().as_utf8().unwrap()) // TODO: Remove unwrap, e.g. by supplying empty _or-string
+            .collect::<Vec<OpensslString>>()
+            .iter()
+            .map(|x| {
+                ProxyId::new(&x.to_string()).expect(&format!(
+                    "Internal error: Vault returned certificate with invalid common name: {}",
+                    x
+                ))
+            })
+            .collect();
+
+        let err = {
+            if commonnames.is_empty() {
+                Some(CertificateInvalidReason::NoCommonName)
+            } else if let Err(e) = verify_cert(
+                &cert,
+                &self
+                    .im_cert
+                    .as_ref()
+                    .expect("No intermediate CA cert found"),
+            ) {
+                Some(e)
+            } else {
+                None
+            }
+        };
+        if let Some(err) = err {
+            warn!("Certificate with serial {} invalid: {}.", serial, err);
+            let entry = match err {
+                CertificateInvalidReason::InvalidDate => CertificateCacheEntry::InvalidDate(cert),
+                err => CertificateCacheEntry::Invalid(err),
+            };
+            self.serial_to_x509.insert(serial.clone(), entry);
+            false
+        } else {
+            let cn = commonnames
+                .first()
+                .expect("Internal error: common names empty; this should not happen");
+            self.insert_valid(serial, cert, cn);
+            true
+        }
+    }
+
+    /// Marks the given certificates as revoked. Returns the number of certificates that were
+    /// considered valid before.
+    fn mark_revoked(&mut self, revoked_serials: &[Serial]) -> usize {
+        let mut count = 0;
+        for serial in revoked_serials {
+            let entry = self.serial_to_x509.insert(
+                serial.clone(),
+                CertificateCacheEntry::Invalid(CertificateInvalidReason::Revoked),
+            );
+            if let Some(CertificateCacheEntry::Valid(_)) = entry {
+                warn!("Certificate with serial {serial} has been revoked.");
+                count += 1;
+            }
+        }
+        count
+    }
+
+    fn insert_valid(&mut self, serial: &Serial, cert: X509, cn: &ProxyId) {
+        self.serial_to_x509
+            .insert(serial.clone(), CertificateCacheEntry::Valid(cert));
+        match self.cn_to_serial.get_mut(cn) {
+            Some(serials) => serials.push(serial.clone()),
+            None => {
+                let new = vec![serial.clone()];
+                self.cn_to_serial.insert(cn.clone(), new);
+            }
+        };
+        debug!("Added certificate {} for cname {}", serial, cn);
+    }
+
+    /// Reads the persisted cache from disk. A missing file is not an error (e.g. at first startup).
+    fn read_persisted(file: &Path) -> Result<Option<PersistedCertificateCache>, SamplyBeamError> {
+        if !file.exists() {
+            info!(
+                "Certificate cache file {} does not exist yet and will be created.",
+                file.to_string_lossy()
+            );
+            return Ok(None);
+        }
+        let content = std::fs::read(file).map_err(|e| {
+            SamplyBeamError::ConfigurationFailed(format!(
+                "Unable to read certificate cache file {}: {}",
+                file.to_string_lossy(),
+                e
+            ))
+        })?;
+        let persisted = serde_json::from_slice(&content).map_err(|e| {
+            SamplyBeamError::ConfigurationFailed(format!(
+                "Unable to parse certificate cache file {}: {}",
+                file.to_string_lossy(),
+                e
+            ))
+        })?;
+        Ok(Some(persisted))
+    }
+
+    /// Adds the persisted certificates to the cache, re-validating each of them against the
+    /// intermediate certificate, which must have been set before. Returns the number of loaded certificates.
+    fn load_persisted(&mut self, persisted: PersistedCertificateCache) -> usize {
+        let Some(im_cert) = self.im_cert.clone() else {
+            warn!("Unable to load cached certificates without an intermediate certificate.");
+            return 0;
+        };
+        let mut loaded = 0;
+        for (serial, pem) in persisted.certs {
+            if self.serial_to_x509.contains_key(&serial) {
+                continue;
+            }
+            let cert = match X509::from_pem(pem.as_bytes()) {
+                Ok(cert) => cert,
+                Err(e) => {
+                    warn!("Skipping unparseable cached certificate {serial}: {e}");
+                    continue;
+                }
+            };
+            if let Err(e) = verify_cert(&cert, &im_cert) {
+                warn!("Skipping cached certificate {serial}, as it is no longer valid: {e}");
+                continue;
+            }
+            match config_shared::asn_str_to_vault_str(cert.serial_number()) {
+                Ok(actual) if actual == serial => {}
+                Ok(actual) => {
+                    warn!("Skipping cached certificate {serial}, as its actual serial is {actual}");
+                    continue;
+                }
+                Err(e) => {
+                    warn!("Skipping cached certificate {serial}: {e}");
+                    continue;
+                }
+            }
+            let cn = match extract_x509(&cert) {
+                Ok(public) => public.beam_id,
+                Err(e) => {
+                    warn!("Skipping cached certificate {serial}: {e}");
+                    continue;
+                }
+            };
+            self.insert_valid(&serial, cert, &cn);
+            loaded += 1;
+        }
+        loaded
+    }
+
+    /// Writes all valid certificates to the cache file, if configured.
+    fn persist(&self) {
+        let Some(file) = &self.persist_to else {
+            return;
+        };
+        let to_pem = |cert: &X509| {
+            cert.to_pem()
+                .ok()
+                .and_then(|pem| String::from_utf8(pem).ok())
+        };
+        let persisted = PersistedCertificateCache {
+            im_cert: self.im_cert.as_ref().and_then(to_pem),
+            certs: self
+                .serial_to_x509
+                .iter()
+                .filter_map(|(serial, entry)| match entry {
+                    CertificateCacheEntry::Valid(cert) => {
+                        to_pem(cert).map(|pem| (serial.clone(), pem))
+                    }
+                    CertificateCacheEntry::InvalidDate(_) | CertificateCacheEntry::Invalid(_) => {
+                        None
+                    }
+                })
+                .collect(),
+        };
+        // Write to a temporary file first, so a crash never leaves a truncated cache behind.
+        let tmp = file.with_extension("tmp");
+        let result = serde_json::to_vec(&persisted)
+            .map_err(|e| e.to_string())
+            .and_then(|json| std::fs::write(&tmp, json).map_err(|e| e.to_string()))
+            .and_then(|()| std::fs::rename(&tmp, file).map_err(|e| e.to_string()));
+        match result {
+            Ok(()) => debug!(
+                "Persisted {} certificates to {}",
+                persisted.certs.len(),
+                file.to_string_lossy()
+            ),
+            Err(e) => warn!(
+                "Unable to persist certificate cache to {}: {e}",
+                file.to_string_lossy()
+            ),
+        }
+    }
+
     /*
     /// Returns all ClientIds and associated certificates currently in cache
     pub async fn get_all_cnames_and_certs() -> Vec<(ProxyId,X509)> {
@@ -361,7 +577,11 @@ impl CertificateCache {
     }
 
     pub async fn set_im_cert(&mut self) -> Result<(), SamplyBeamError> {
-        self.im_cert = Some(X509::from_pem(&get_im_cert().await.unwrap().as_bytes())?);
+        self.set_im_cert_from_pem(&get_im_cert().await?)
+    }
+
+    fn set_im_cert_from_pem(&mut self, pem: &str) -> Result<(), SamplyBeamError> {
+        self.im_cert = Some(X509::from_pem(pem.as_bytes())?);
         let _ = verify_cert(&self.im_cert.as_ref().expect("No IM certificate provided"), &self.root_cert.as_ref().expect("No root certificate set!"))
             .expect(&format!("The intermediate certificate is invalid. Please send this info to the central beam admin for debugging:\n---BEGIN DEBUG---\n{}\nroot\n{}\n---END DEBUG---", 
                              String::from_utf8(self.im_cert.as_ref().unwrap().to_text().unwrap_or("Cannot convert IM certificate to text".into())).unwrap_or("Invalid characters in IM certificate".to_string()),
@@ -371,10 +591,30 @@ impl CertificateCache {
 }
 
 /// Wrapper for initializing the CA chain. Must be called *after* config initialization
-pub async fn init_ca_chain() -> Result<(), SamplyBeamError> {
+///
+/// If `cache_file` is given, the certificates persisted there are re-validated and loaded into the cache,
+/// and the cache is persisted to this file whenever new certificates are added. If the intermediate
+/// certificate cannot be fetched, e.g. because the Broker is down, the cached one is used instead.
+pub async fn init_ca_chain(cache_file: Option<PathBuf>) -> Result<(), SamplyBeamError> {
     let mut cache = CERT_CACHE.write().await;
     cache.set_root_cert(&config::CONFIG_SHARED.root_cert);
-    cache.set_im_cert().await?;
+    let persisted = match &cache_file {
+        Some(file) => CertificateCache::read_persisted(file)?,
+        None => None,
+    };
+    if let Err(e) = cache.set_im_cert().await {
+        let Some(cached_im) = persisted.as_ref().and_then(|p| p.im_cert.as_ref()) else {
+            return Err(e);
+        };
+        warn!("Unable to fetch intermediate certificate ({e}); using cached one.");
+        cache.set_im_cert_from_pem(cached_im)?;
+    }
+    cache.persist_to = cache_file;
+    if let Some(persisted) = persisted {
+        let loaded = cache.load_persisted(persisted);
+        info!("Loaded {loaded} certificates from the certificate cache file.");
+    }
+    cache.persist();
     Ok(())
 }
 
@@ -391,6 +631,10 @@ pub async fn get_serial_list() -> Result<Vec<String>, SamplyBeamError> {
     CERT_GETTER.get().unwrap().certificate_list().await
 }
 
+pub async fn get_revoked_serial_list() -> Result<Vec<String>, SamplyBeamError> {
+    CERT_GETTER.get().unwrap().revoked_certificate_list().await
+}
+
 pub async fn get_im_cert() -> Result<String, SamplyBeamError> {
     CERT_GETTER.get().unwrap().im_certificate_as_pem().await
 }
@@ -445,21 +689,22 @@ pub async fn get_all_certs_and_clients_by_cname_as_pemstr(
     get_all_certs_by_cname(cname)
         .await
         .iter()
-        .map(|c| match c {
-            CertificateCacheEntry::Valid(c) => extract_x509(c),
-            CertificateCacheEntry::Invalid(reason) => Err(reason.clone()),
-        })
+        .map(|c| c.public_portion(false))
         .collect()
 }
 
 pub async fn get_cert_and_client_by_serial_as_pemstr(
     serial: &str,
 ) -> Option<Result<CryptoPublicPortion, CertificateInvalidReason>> {
-    match get_cert_by_serial(serial).await {
-        None => None,
-        Some(CertificateCacheEntry::Valid(valid_cert)) => Some(extract_x509(&valid_cert)),
-        Some(CertificateCacheEntry::Invalid(reason)) => Some(Err(reason)),
-    }
+    Some(get_cert_by_serial(serial).await?.public_portion(false))
+}
+
+/// Like [`get_cert_and_client_by_serial_as_pemstr`], but also accepts certificates outside of their
+/// validity period (though not revoked ones), e.g. to verify archived messages
+pub async fn get_cert_and_client_by_serial_as_pemstr_regardless_of_date(
+    serial: &str,
+) -> Option<Result<CryptoPublicPortion, CertificateInvalidReason>> {
+    Some(get_cert_by_serial(serial).await?.public_portion(true))
 }
 
 pub async fn get_newest_certs_for_cnames_as_pemstr(
@@ -548,8 +793,8 @@ pub(crate) fn hash(data: &[u8]) -> Result<[u8; 32], SamplyBeamError> {
     Ok(digest)
 }
 
-pub fn get_own_privkey() -> &'static RsaPrivateKey {
-    &config::CONFIG_SHARED_CRYPTO.get().unwrap().privkey_rsa
+pub fn get_own_privkey() -> &'static PrivateKey {
+    &config::CONFIG_SHARED_CRYPTO.get().unwrap().privkey
 }
 /* Utility Functions */
 
@@ -645,13 +890,9 @@ pub fn load_certificates_from_dir(ca_dir: Option<PathBuf>) -> Result<Vec<X509>,
     Ok(result)
 }
 
-/// Checks whether or not a x509 certificate matches a private key by comparing the (public) modulus
-pub fn is_cert_from_privkey(cert: &X509, key: &RsaPrivateKey) -> Result<bool, ErrorStack> {
-    let cert_rsa = cert.public_key()?.rsa()?;
-    let cert_mod = cert_rsa.n();
-    let key_mod = key.n();
-    let key_mod_bignum = openssl::bn::BigNum::from_slice(&key_mod.to_bytes_be())?;
-    let is_equal = cert_mod.ucmp(&key_mod_bignum) == std::cmp::Ordering::Equal;
+/// Checks whether or not a x509 certificate matches a private key by comparing the public keys
+pub fn is_cert_from_privkey(cert: &X509, key: &PrivateKey) -> Result<bool, ErrorStack> {
+    let is_equal = key.matches_cert(cert)?;
     if !is_equal {
         match ProxyCertInfo::try_from(cert) {
             Ok(x) => {
@@ -685,7 +926,7 @@ pub fn get_newest_cert(certs: &mut Vec<CryptoPublicPortion>) -> Option<CryptoPub
 /// 3) Select the newest of the remaining
 pub(crate) fn get_best_own_certificate(
     publics: impl Into<Vec<CryptoPublicPortion>>,
-    private_rsa: &RsaPrivateKey,
+    private_key: &PrivateKey,
 ) -> Option<CryptoPublicPortion> {
     let mut publics = publics.into();
     debug!(
@@ -693,7 +934,7 @@ pub(crate) fn get_best_own_certificate(
         publics.len(),
         publics
     );
-    publics.retain(|c| is_cert_from_privkey(&c.cert, private_rsa).unwrap_or(false)); // retain certs matching the private cert
+    publics.retain(|c| is_cert_from_privkey(&c.cert, private_key).unwrap_or(false)); // retain certs matching the private cert
     debug!(
         "get_best_certificate(): {} certificates match our private key.",
         publics.len()
@@ -717,27 +958,349 @@ pub fn get_best_other_certificate(
     get_newest_cert(&mut publics)
 }
 
-pub async fn get_proxy_public_keys(
+/// Public key of a certificate, identified by the certificate's serial
+pub fn public_key_of_cert(public: &CryptoPublicPortion) -> Result<PublicKey, SamplyBeamError> {
+    let serial = config_shared::asn_str_to_vault_str(public.cert.serial_number())?;
+    Ok(PublicKey::from_pem(&public.pubkey)?.with_key_id(&serial))
+}
+
+/// Why a message cannot be encrypted for a recipient
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum UnreachableReason {
+    /// No certificate is known for the recipient's proxy
+    Unknown,
+    /// All of the proxy's certificates have expired (or are not yet valid)
+    Expired,
+    /// The proxy's certificate has been revoked
+    Revoked,
+    /// The proxy's certificates are unusable for other reasons, e.g. an unsupported key
+    Invalid,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct UnreachableRecipient {
+    pub recipient: AppOrProxyId,
+    pub reason: UnreachableReason,
+}
+
+/// Looks up the public keys of all recipients' proxies. Keys are wrapped once per proxy,
+/// even if several of its apps are recipients. Recipients whose proxy has no usable
+/// certificate are returned separately, along with the reason.
+pub async fn get_recipients_public_keys(
     receivers: impl IntoIterator<Item = &AppOrProxyId>,
-) -> Result<Vec<RsaPublicKey>, SamplyBeamError> {
-    let proxy_receivers: Vec<ProxyId> = receivers
+) -> (Vec<PublicKey>, Vec<UnreachableRecipient>) {
+    let receivers: Vec<&AppOrProxyId> = receivers.into_iter().unique().collect();
+    let proxies: Vec<ProxyId> = receivers
+        .iter()
+        .map(|r| r.get_proxy_id())
+        .unique()
+        .collect();
+    let mut keys = Vec::new();
+    let mut unreachable_proxies = HashMap::new();
+    for proxy in proxies {
+        match get_proxy_public_key(&proxy).await {
+            Ok(key) => keys.push(key),
+            Err(reason) => {
+                warn!("Unable to encrypt for proxy {proxy}: {reason:?}");
+                unreachable_proxies.insert(proxy, reason);
+            }
+        }
+    }
+    let unreachable = receivers
         .into_iter()
-        .map(|app_or_proxy| match app_or_proxy {
-            AppOrProxyId::ProxyId(id) => id.to_owned(),
-            AppOrProxyId::AppId(id) => id.proxy_id(),
+        .filter_map(|recipient| {
+            unreachable_proxies
+                .get(&recipient.get_proxy_id())
+                .map(|reason| UnreachableRecipient {
+                    recipient: recipient.clone(),
+                    reason: *reason,
+                })
         })
         .collect();
-    let receivers_crypto_bundle =
-        crypto::get_newest_certs_for_cnames_as_pemstr(proxy_receivers.iter()).await;
-    let receivers_keys = match receivers_crypto_bundle {
-        Some(vec) => vec
-            .iter()
-            .map(|crypt_publ| {
-                rsa::RsaPublicKey::from_public_key_pem(&crypt_publ.pubkey)
-                    .expect("Cannot collect recipients' public keys")
-            })
-            .collect::<Vec<rsa::RsaPublicKey>>(), // TODO Expect
-        None => Vec::new(),
+    (keys, unreachable)
+}
+
+async fn get_proxy_public_key(proxy: &ProxyId) -> Result<PublicKey, UnreachableReason> {
+    let certs = get_all_certs_and_clients_by_cname_as_pemstr(proxy).await;
+    let valid = certs
+        .iter()
+        .filter_map(|c| c.as_ref().ok())
+        .cloned()
+        .collect();
+    if let Some(best) = get_best_other_certificate(&valid) {
+        return public_key_of_cert(&best).map_err(|e| {
+            warn!("Unable to use certificate of proxy {proxy}: {e}");
+            UnreachableReason::Invalid
+        });
+    }
+    let has_reason = |reason: fn(&CertificateInvalidReason) -> bool| {
+        certs.iter().any(|c| c.as_ref().is_err_and(reason))
     };
-    Ok(receivers_keys)
+    if certs.is_empty() {
+        Err(UnreachableReason::Unknown)
+    } else if has_reason(|r| matches!(r, CertificateInvalidReason::Revoked)) {
+        Err(UnreachableReason::Revoked)
+    } else if !valid.is_empty()
+        || has_reason(|r| matches!(r, CertificateInvalidReason::InvalidDate))
+    {
+        Err(UnreachableReason::Expired)
+    } else {
+        Err(UnreachableReason::Invalid)
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use openssl::{
+        asn1::Asn1Time,
+        bn::{BigNum, MsbOption},
+        hash::MessageDigest,
+        pkey::{PKey, Private},
+        rsa::Rsa,
+        x509::{extension::BasicConstraints, X509Builder, X509NameBuilder},
+    };
+
+    use super::*;
+    use crate::beam_id::BrokerId;
+
+    pub(crate) const BROKER_ID: &str = "broker.samply.de";
+
+    /// Issues a certificate for `cn` and `key`, signed by `issuer` (self-signed if `None`), valid
+    /// since a year ago until `valid_days` from now (i.e. expired if negative)
+    pub(crate) fn issue_cert(
+        cn: &str,
+        key: &PKey<Private>,
+        issuer: Option<(&X509, &PKey<Private>)>,
+        valid_days: i64,
+    ) -> X509 {
+        let mut name = X509NameBuilder::new().unwrap();
+        name.append_entry_by_text("CN", cn).unwrap();
+        let name = name.build();
+        let mut serial = BigNum::new().unwrap();
+        serial.rand(128, MsbOption::MAYBE_ZERO, false).unwrap();
+        let mut builder = X509Builder::new().unwrap();
+        builder.set_version(2).unwrap();
+        builder
+            .set_serial_number(&serial.to_asn1_integer().unwrap())
+            .unwrap();
+        builder.set_subject_name(&name).unwrap();
+        builder.set_pubkey(key).unwrap();
+        let now = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap()
+            .as_secs() as i64;
+        builder
+            .set_not_before(&Asn1Time::from_unix(now - 365 * 86400).unwrap())
+            .unwrap();
+        builder
+            .set_not_after(&Asn1Time::from_unix(now + valid_days * 86400).unwrap())
+            .unwrap();
+        let (issuer_name, signing_key) = match issuer {
+            Some((cert, key)) => (cert.subject_name(), key),
+            None => {
+                builder
+                    .append_extension(BasicConstraints::new().critical().ca().build().unwrap())
+                    .unwrap();
+                (name.as_ref(), key)
+            }
+        };
+        builder.set_issuer_name(issuer_name).unwrap();
+        builder.sign(signing_key, MessageDigest::sha256()).unwrap();
+        builder.build()
+    }
+
+    pub(crate) fn rsa_key() -> PKey<Private> {
+        PKey::from_rsa(Rsa::generate(2048).unwrap()).unwrap()
+    }
+
+    fn empty_cache() -> CertificateCache {
+        CertificateCache::new(mpsc::channel(1).0).unwrap()
+    }
+
+    #[test]
+    fn persisted_cache_is_revalidated() {
+        BrokerId::set_broker_id(BROKER_ID.to_string());
+        let root_key = rsa_key();
+        let root = issue_cert("Root CA", &root_key, None, 30);
+        let im_key = rsa_key();
+        let im = issue_cert("IM CA", &im_key, Some((&root, &root_key)), 30);
+        let other_im_key = rsa_key();
+        let other_im = issue_cert("Other CA", &other_im_key, None, 30);
+        let proxy_key = rsa_key();
+        let proxy_cn = format!("proxy1.{BROKER_ID}");
+        let good = issue_cert(&proxy_cn, &proxy_key, Some((&im, &im_key)), 30);
+        let foreign = issue_cert(&proxy_cn, &proxy_key, Some((&other_im, &other_im_key)), 30);
+
+        let file =
+            std::env::temp_dir().join(format!("beam-cert-cache-{}.json", crate::MyUuid::new()));
+        let mut cache = empty_cache();
+        cache.set_root_cert(&root);
+        cache
+            .set_im_cert_from_pem(std::str::from_utf8(&im.to_pem().unwrap()).unwrap())
+            .unwrap();
+        cache.persist_to = Some(file.clone());
+        let proxy_id = ProxyId::new(&proxy_cn).unwrap();
+        let serial = config_shared::asn_str_to_vault_str(good.serial_number()).unwrap();
+        let good_pem = String::from_utf8(good.to_pem().unwrap()).unwrap();
+        cache.insert_valid(&serial, good, &proxy_id);
+        cache.persist();
+
+        // Sneak in a certificate that is not signed by our intermediate CA, and a valid one
+        // under a serial that is not its own
+        let mut persisted = CertificateCache::read_persisted(&file).unwrap().unwrap();
+        assert_eq!(persisted.certs.len(), 1);
+        persisted.certs.insert(
+            "02".to_string(),
+            String::from_utf8(foreign.to_pem().unwrap()).unwrap(),
+        );
+        persisted.certs.insert("03".to_string(), good_pem);
+        std::fs::write(&file, serde_json::to_vec(&persisted).unwrap()).unwrap();
+
+        let mut restored = empty_cache();
+        restored.set_root_cert(&root);
+        let persisted = CertificateCache::read_persisted(&file).unwrap().unwrap();
+        restored
+            .set_im_cert_from_pem(persisted.im_cert.as_ref().unwrap())
+            .unwrap();
+        assert_eq!(restored.load_persisted(persisted), 1);
+        assert!(matches!(
+            restored.serial_to_x509.get(&serial),
+            Some(CertificateCacheEntry::Valid(_))
+        ));
+        assert!(!restored.serial_to_x509.contains_key("02"));
+        assert!(!restored.serial_to_x509.contains_key("03"));
+        assert_eq!(restored.cn_to_serial.get(&proxy_id).unwrap().len(), 1);
+        std::fs::remove_file(file).unwrap();
+    }
+
+    #[test]
+    fn revoked_certificates_are_marked() {
+        BrokerId::set_broker_id(BROKER_ID.to_string());
+        let key = rsa_key();
+        let proxy_cn = format!("proxy1.{BROKER_ID}");
+        let proxy_id = ProxyId::new(&proxy_cn).unwrap();
+        let mut cache = empty_cache();
+        cache.insert_valid(
+            &"01".to_string(),
+            issue_cert(&proxy_cn, &key, None, 30),
+            &proxy_id,
+        );
+
+        let revoked = ["01".to_string(), "02".to_string()];
+        assert_eq!(cache.mark_revoked(&revoked), 1);
+        assert_eq!(cache.mark_revoked(&revoked), 0);
+        assert!(matches!(
+            cache.serial_to_x509.get("01"),
+            Some(CertificateCacheEntry::Invalid(
+                CertificateInvalidReason::Revoked
+            ))
+        ));
+        // The proxy is still known, so the reason can be reported for it
+        assert_eq!(
+            cache.cn_to_serial.get(&proxy_id).unwrap(),
+            &vec!["01".to_string()]
+        );
+    }
+    #[test]
+    fn expired_certificates_verify_archived_messages_until_revoked() {
+        BrokerId::set_broker_id(BROKER_ID.to_string());
+        let root_key = rsa_key();
+        let root = issue_cert("Root CA", &root_key, None, 30);
+        let im_key = rsa_key();
+        let im = issue_cert("IM CA", &im_key, Some((&root, &root_key)), 30);
+        let proxy_key = rsa_key();
+        let expired = issue_cert(
+            &format!("proxy1.{BROKER_ID}"),
+            &proxy_key,
+            Some((&im, &im_key)),
+            -1,
+        );
+        let mut cache = empty_cache();
+        cache.set_root_cert(&root);
+        cache
+            .set_im_cert_from_pem(std::str::from_utf8(&im.to_pem().unwrap()).unwrap())
+            .unwrap();
+        assert!(!cache.insert_checked(&"01".to_string(), expired));
+
+        let entry = cache.serial_to_x509.get("01").unwrap();
+        assert!(matches!(
+            entry.public_portion(false),
+            Err(CertificateInvalidReason::InvalidDate)
+        ));
+        let public = entry.public_portion(true).unwrap();
+        let signer = crate::crypto_keys::PrivateKey::from_pem(
+            std::str::from_utf8(&proxy_key.private_key_to_pem_pkcs8().unwrap()).unwrap(),
+        )
+        .unwrap();
+        let token = signer
+            .sign(jwt_simple::prelude::Claims::with_custom_claims(
+                serde_json::json!({"body": "payload"}),
+                jwt_simple::prelude::Duration::from_secs(60),
+            ))
+            .unwrap();
+        assert!(crate::crypto_keys::PublicKey::from_pem(&public.pubkey)
+            .unwrap()
+            .verify_token::<serde_json::Value>(
+                &token,
+                Some(crate::crypto_jwt::ARCHIVE_VERIFICATION_OPTIONS.clone())
+            )
+            .is_ok());
+
+        cache.mark_revoked(&["01".to_string()]);
+        let entry = cache.serial_to_x509.get("01").unwrap();
+        assert!(matches!(
+            entry.public_portion(true),
+            Err(CertificateInvalidReason::Revoked)
+        ));
+    }
+
+    /// Serves the given certificate serials and counts how often the revoked list is fetched
+    #[derive(Default)]
+    struct CountingGetter {
+        serials: std::sync::Mutex<Vec<String>>,
+        revoked_calls: std::sync::atomic::AtomicUsize,
+    }
+
+    #[async_trait]
+    impl GetCerts for CountingGetter {
+        async fn certificate_list(&self) -> Result<Vec<String>, SamplyBeamError> {
+            Ok(self.serials.lock().unwrap().clone())
+        }
+        async fn certificate_by_serial_as_pem(&self, _: &str) -> Result<String, SamplyBeamError> {
+            Err(CertificateInvalidReason::NoCommonName.into())
+        }
+        async fn revoked_certificate_list(&self) -> Result<Vec<String>, SamplyBeamError> {
+            self.revoked_calls
+                .fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            Ok(Vec::new())
+        }
+        async fn im_certificate_as_pem(&self) -> Result<String, SamplyBeamError> {
+            unimplemented!()
+        }
+    }
+
+    /// Updates the cache, returning how often the revoked list has been fetched in total
+    async fn revoked_fetches_after_update(
+        cache: &mut CertificateCache,
+        getter: &CountingGetter,
+    ) -> usize {
+        cache.update_certificates_from(getter).await.unwrap();
+        getter
+            .revoked_calls
+            .load(std::sync::atomic::Ordering::SeqCst)
+    }
+
+    #[tokio::test]
+    async fn revoked_list_is_only_fetched_when_outdated_or_certificates_changed() {
+        let getter = CountingGetter::default();
+        let mut cache = empty_cache();
+        assert_eq!(revoked_fetches_after_update(&mut cache, &getter).await, 1);
+        assert_eq!(revoked_fetches_after_update(&mut cache, &getter).await, 1);
+        getter.serials.lock().unwrap().push("01".to_string());
+        assert_eq!(revoked_fetches_after_update(&mut cache, &getter).await, 2);
+        assert_eq!(revoked_fetches_after_update(&mut cache, &getter).await, 2);
+        cache.revoked_fetched = Some(Instant::now() - CertificateCache::REVOKED_LIST_MAX_AGE);
+        assert_eq!(revoked_fetches_after_update(&mut cache, &getter).await, 3);
+    }
 }
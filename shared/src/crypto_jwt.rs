@@ -1,13 +1,20 @@
 use crate::{
     beam_id::{AppOrProxyId, ProxyId},
     config,
-    config_shared::ConfigCrypto,
+    config_shared::{self, ConfigCrypto},
     crypto::{self, CryptoPublicPortion},
+    crypto_keys::PublicKey,
     errors::{CertificateInvalidReason, SamplyBeamError},
     middleware::{LoggingInfo, ProxyLogger},
-    BeamId, Msg, MsgEmpty, MsgId, MsgSigned,
+    wire, BeamId, Msg, MsgEmpty, MsgId, MsgSigned, MyUuid,
+};
+use axum::{
+    async_trait,
+    body::HttpBody,
+    extract::{FromRequest, FromRequestParts},
+    http::StatusCode,
+    BoxError,
 };
-use axum::{async_trait, body::HttpBody, extract::FromRequest, http::StatusCode, BoxError};
 use http::{request::Parts, uri::PathAndQuery, Request};
 use hyper::{
     header::{self, HeaderName},
@@ -16,16 +23,15 @@ use hyper::{
 use jwt_simple::{
     claims::JWTClaims,
     prelude::{
-        Base64, Base64UrlSafeNoPadding, Claims, Duration, KeyMetadata, RS256KeyPair,
-        RS256PublicKey, RSAKeyPairLike, RSAPublicKeyLike, Token, VerificationOptions,
+        Base64, Base64UrlSafeNoPadding, Claims, Duration, KeyMetadata, Token, VerificationOptions,
     },
     reexports::ct_codecs::Decoder,
 };
-use once_cell::unsync::Lazy;
 use openssl::base64;
 use serde::{de::DeserializeOwned, Deserialize, Serialize};
 use serde_json::Value;
 use static_init::dynamic;
+use std::{collections::HashMap, sync::Mutex, time::SystemTime};
 use tracing::{debug, error, warn};
 
 const ERR_SIG: (StatusCode, &str) = (StatusCode::UNAUTHORIZED, "Signature could not be verified");
@@ -35,6 +41,14 @@ const ERR_FROM: (StatusCode, &str) = (
     StatusCode::BAD_REQUEST,
     "\"from\" field in message does not match your certificate.",
 );
+const ERR_DATE: (StatusCode, &str) = (
+    StatusCode::UNAUTHORIZED,
+    "Date header is missing or outside of the accepted clock skew; please check your clock.",
+);
+const ERR_REPLAY: (StatusCode, &str) = (
+    StatusCode::UNAUTHORIZED,
+    "This request has already been received.",
+);
 
 #[async_trait]
 impl<S: Send + Sync, B: HttpBody + Send + Sync, T> FromRequest<S, B> for MsgSigned<T>
@@ -50,16 +64,45 @@ where
     type Rejection = (StatusCode, &'static str);
 
     async fn from_request(req: Request<B>, _state: &S) -> Result<Self, Self::Rejection> {
-        let (parts, body) = req.into_parts();
+        let (mut parts, body) = req.into_parts();
         let bytes = hyper::body::to_bytes(body).await.map_err(|_| ERR_BODY)?;
-        let token_without_extended_signature = std::str::from_utf8(&bytes).map_err(|e| {
-            warn!(
-                "Unable to parse token_without_extended_signature as UTF-8: {}",
-                e
-            );
-            ERR_SIG
-        })?;
-        verify_with_extended_header(parts, token_without_extended_signature).await
+        let token_without_extended_signature = wire::token_from_body(&parts.headers, &bytes)
+            .map_err(|e| {
+                warn!("Unable to read token_without_extended_signature: {}", e);
+                ERR_SIG
+            })?;
+        verify_with_extended_header(&mut parts, &token_without_extended_signature).await
+    }
+}
+
+/// Request header carrying the signed message (without the extended signature) instead of the body,
+/// so the body can be streamed, see [`HeaderSigned`]
+pub const SIGNED_MESSAGE: HeaderName = HeaderName::from_static("x-beam-message");
+
+/// A signed message sent in the [`SIGNED_MESSAGE`] header. As an extractor, it leaves the body to
+/// the handler, e.g. for streaming large uploads. The body itself is not covered by the signature.
+pub struct HeaderSigned<T: Msg>(pub MsgSigned<T>);
+
+#[async_trait]
+impl<S: Send + Sync, T> FromRequestParts<S> for HeaderSigned<T>
+where
+    T: Serialize + DeserializeOwned + Msg,
+{
+    type Rejection = (StatusCode, &'static str);
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        let token_without_extended_signature = parts
+            .headers
+            .get(SIGNED_MESSAGE)
+            .and_then(|token| token.to_str().ok())
+            .ok_or_else(|| {
+                warn!("Missing or invalid {SIGNED_MESSAGE} header");
+                ERR_SIG
+            })?
+            .to_owned();
+        verify_with_extended_header(parts, &token_without_extended_signature)
+            .await
+            .map(Self)
     }
 }
 
@@ -71,7 +114,24 @@ pub async fn extract_jwt<T: DeserializeOwned + Serialize>(
 ) -> Result<
     (
         crypto::CryptoPublicPortion,
-        RS256PublicKey,
+        PublicKey,
+        jwt_simple::prelude::JWTClaims<T>,
+    ),
+    SamplyBeamError,
+> {
+    extract_jwt_with_options(token, JWT_VERIFICATION_OPTIONS.clone(), false).await
+}
+
+/// Like [`extract_jwt`]; with `accept_invalid_date`, the signing certificate (given by the token's
+/// key ID) may be outside of its validity period
+async fn extract_jwt_with_options<T: DeserializeOwned + Serialize>(
+    token: &str,
+    options: VerificationOptions,
+    accept_invalid_date: bool,
+) -> Result<
+    (
+        crypto::CryptoPublicPortion,
+        PublicKey,
         jwt_simple::prelude::JWTClaims<T>,
     ),
     SamplyBeamError,
@@ -80,8 +140,12 @@ pub async fn extract_jwt<T: DeserializeOwned + Serialize>(
         SamplyBeamError::RequestValidationFailed(format!("Unable to decode JWT metadata: {}", e))
     })?;
     let public = if let Some(serial) = metadata.key_id() {
-        crypto::get_cert_and_client_by_serial_as_pemstr(serial)
-            .await
+        let public = if accept_invalid_date {
+            crypto::get_cert_and_client_by_serial_as_pemstr_regardless_of_date(serial).await
+        } else {
+            crypto::get_cert_and_client_by_serial_as_pemstr(serial).await
+        };
+        public
             .ok_or_else(|| {
                 SamplyBeamError::VaultOtherError(format!(
                     "Unable to retrieve matching certificate for serial \"{}\"",
@@ -117,11 +181,11 @@ pub async fn extract_jwt<T: DeserializeOwned + Serialize>(
             CertificateInvalidReason::NoCommonName,
         ))?
     };
-    let pubkey = RS256PublicKey::from_pem(&public.pubkey).map_err(|e| {
+    let pubkey = PublicKey::from_pem(&public.pubkey).map_err(|e| {
         SamplyBeamError::SignEncryptError(format!("Unable to initialize public key: {}", e))
     })?;
     let content = pubkey
-        .verify_token::<T>(token, Some(JWT_VERIFICATION_OPTIONS.clone()))
+        .verify_token::<T>(token, Some(options))
         .map_err(|e| {
             SamplyBeamError::RequestValidationFailed(format!(
                 "Unable to verify token and extract claims from JWT: {}",
@@ -131,11 +195,82 @@ pub async fn extract_jwt<T: DeserializeOwned + Serialize>(
     Ok((public, pubkey, content))
 }
 
-pub const JWT_VERIFICATION_OPTIONS: Lazy<VerificationOptions> = Lazy::new(|| VerificationOptions {
+/// Proof of a message's origin, which apps can archive and have verified later on (see [`verify_archived`])
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Provenance {
+    /// The message as signed by the sender's proxy
+    pub jwt: String,
+    /// The proxy that signed the message
+    pub signer: ProxyId,
+    /// Serial of the signing proxy's certificate
+    pub serial: String,
+    /// Time of signing in seconds since the Unix epoch
+    pub signed_at: Option<u64>,
+    /// Time of verification in seconds since the Unix epoch
+    pub verified_at: u64,
+}
+
+/// Verifies a signed message like [`extract_jwt`] and additionally checks that the signing proxy
+/// may sign on behalf of the message's sender.
+pub async fn verify_with_provenance<M: Msg + DeserializeOwned + Serialize>(
+    token: &str,
+) -> Result<(M, Provenance), SamplyBeamError> {
+    verify_with_options(token, JWT_VERIFICATION_OPTIONS.clone(), false).await
+}
+
+/// Verifies a signed message that has been archived by an app. In contrast to [`verify_with_provenance`],
+/// the message is accepted after its expiry and after the expiry of the signer's certificate, as long
+/// as the certificate has not been revoked.
+pub async fn verify_archived<M: Msg + DeserializeOwned + Serialize>(
+    token: &str,
+) -> Result<(M, Provenance), SamplyBeamError> {
+    verify_with_options(token, ARCHIVE_VERIFICATION_OPTIONS.clone(), true).await
+}
+
+async fn verify_with_options<M: Msg + DeserializeOwned + Serialize>(
+    token: &str,
+    options: VerificationOptions,
+    accept_invalid_date: bool,
+) -> Result<(M, Provenance), SamplyBeamError> {
+    let (public, _, claims) =
+        extract_jwt_with_options::<M>(token, options, accept_invalid_date).await?;
+    if !claims.custom.get_from().can_be_signed_by(&public.beam_id) {
+        return Err(SamplyBeamError::RequestValidationFailed(format!(
+            "Message from {} has been signed by {}, which may not sign on its behalf",
+            claims.custom.get_from(),
+            public.beam_id
+        )));
+    }
+    let provenance = Provenance {
+        jwt: token.to_string(),
+        serial: config_shared::asn_str_to_vault_str(public.cert.serial_number())?,
+        signer: public.beam_id,
+        signed_at: claims.issued_at.map(|t| t.as_secs()),
+        verified_at: SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs(),
+    };
+    Ok((claims.custom, provenance))
+}
+
+/// Signed messages (JWTs) are limited to this size
+pub const MAX_TOKEN_LENGTH: usize = 1024 * 1024 * 10; //10MB
+
+#[dynamic]
+pub static JWT_VERIFICATION_OPTIONS: VerificationOptions = VerificationOptions {
     accept_future: true,
-    max_token_length: Some(1024 * 1024 * 10), //10MB
+    max_token_length: Some(MAX_TOKEN_LENGTH),
     ..Default::default()
-});
+};
+
+/// Signatures of archived messages are verified regardless of the messages' expiry (up to 50 years,
+/// as larger tolerances overflow jwt-simple's timestamps)
+#[dynamic]
+pub(crate) static ARCHIVE_VERIFICATION_OPTIONS: VerificationOptions = VerificationOptions {
+    time_tolerance: Some(Duration::from_days(50 * 365)),
+    ..JWT_VERIFICATION_OPTIONS.clone()
+};
 
 #[tracing::instrument]
 /// This verifys a Msg from sent to the Broker
@@ -143,7 +278,7 @@ pub const JWT_VERIFICATION_OPTIONS: Lazy<VerificationOptions> = Lazy::new(|| Ver
 /// There is never really a [`MsgSigned`] involved in Deserializing the message as the signature is just copyed from the body JWT.
 /// The token is verified by a key derived from the kid of the JWT in the Header which should also match the kid of the body JWT.
 async fn verify_with_extended_header<M: Msg + DeserializeOwned>(
-    mut req: Parts,
+    req: &mut Parts,
     token_without_extended_signature: &str,
 ) -> Result<MsgSigned<M>, (StatusCode, &'static str)> {
     let token_with_extended_signature = std::str::from_utf8(
@@ -235,7 +370,28 @@ async fn verify_with_extended_header<M: Msg + DeserializeOwned>(
         );
         return Err(ERR_FROM);
     }
-    // TODO: Check if Date header makes sense (replay attacks)
+
+    // Reject replayed requests: The Date header is covered by the digest, so a captured request
+    // can only be replayed within the clock skew window, during which we remember its signed JWT ID.
+    let now = SystemTime::now();
+    let replayable_until = check_date(&req.headers, now, config::CONFIG_SHARED.clock_skew)
+        .map_err(|e| {
+            warn!("Rejecting request from {sender_actual}: {e}");
+            ERR_DATE
+        })?;
+    let Some(jwt_id) = header_claims.jwt_id else {
+        warn!("Rejecting request from {sender_actual} without a JWT ID");
+        return Err(ERR_SIG);
+    };
+    let replay_key = format!("{}/{jwt_id}", proxy_public_info.beam_id);
+    if !REPLAY_CACHE
+        .lock()
+        .unwrap()
+        .insert(&replay_key, replayable_until, now)
+    {
+        warn!("Rejecting replayed request from {sender_actual}");
+        return Err(ERR_REPLAY);
+    }
 
     let msg_signed = MsgSigned {
         msg,
@@ -256,24 +412,79 @@ pub async fn sign_to_jwt(
 ) -> Result<String, SamplyBeamError> {
     let json = serde_json::to_value(input)
         .map_err(|e| SamplyBeamError::SignEncryptError(format!("Serialization failed: {}", e)))?;
-    let privkey = if let Some(ConfigCrypto { privkey_rs256, .. }) = crypto_conf {
-        privkey_rs256
+    let privkey = if let Some(ConfigCrypto { privkey, .. }) = crypto_conf {
+        privkey
     } else {
         &config::CONFIG_SHARED_CRYPTO
             .get()
             .expect("If called by GetCertsFromBroker config needs to be provided by param")
-            .privkey_rs256
+            .privkey
     };
 
-    let claims = Claims::with_custom_claims::<Value>(json, Duration::from_hours(1)); // TODO: Make variable
+    let lifetime = Duration::from_secs(config::CONFIG_SHARED.jwt_lifetime.as_secs());
+    let claims = Claims::with_custom_claims::<Value>(json, lifetime).with_jwt_id(MyUuid::new());
 
-    let token = privkey
-        .sign(claims)
-        .map_err(|e| SamplyBeamError::SignEncryptError(format!("Unable to sign JWT: {}", e)))?;
+    privkey.sign(claims)
+}
 
-    Ok(token)
+/// Checks that the signed `Date` header is within `skew` of `now`. Returns the time until which
+/// the request would be accepted, i.e. for how long it has to be remembered to detect replays.
+fn check_date(
+    headers: &HeaderMap,
+    now: SystemTime,
+    skew: std::time::Duration,
+) -> Result<SystemTime, String> {
+    let date = headers
+        .get(header::DATE)
+        .ok_or("Missing Date header")?
+        .to_str()
+        .map_err(|e| format!("Unable to read Date header: {e}"))?;
+    let date = httpdate::parse_http_date(date)
+        .map_err(|e| format!("Unable to parse Date header \"{date}\": {e}"))?;
+    let difference = now
+        .duration_since(date)
+        .or_else(|_| date.duration_since(now))
+        .unwrap_or_default();
+    if difference > skew {
+        return Err(format!(
+            "Date header differs from local time by {}s (allowed: {}s)",
+            difference.as_secs(),
+            skew.as_secs()
+        ));
+    }
+    Ok(date + skew)
 }
 
+/// JWT IDs of recently received requests (scoped by their signer), remembered as long as their
+/// Date header is accepted
+#[derive(Default)]
+struct ReplayCache {
+    seen: HashMap<String, SystemTime>,
+    next_cleanup: Option<SystemTime>,
+}
+
+impl ReplayCache {
+    const CLEANUP_INTERVAL: std::time::Duration = std::time::Duration::from_secs(10);
+
+    /// Remembers the request's ID until `valid_until`. Returns false if it has been seen before.
+    fn insert(&mut self, id: &str, valid_until: SystemTime, now: SystemTime) -> bool {
+        if self.next_cleanup.is_none_or(|next| next <= now) {
+            self.seen.retain(|_, until| *until >= now);
+            self.next_cleanup = Some(now + Self::CLEANUP_INTERVAL);
+        }
+        match self.seen.get(id) {
+            Some(until) if *until >= now => false,
+            _ => {
+                self.seen.insert(id.to_string(), valid_until);
+                true
+            }
+        }
+    }
+}
+
+#[dynamic(lazy)]
+static REPLAY_CACHE: Mutex<ReplayCache> = Mutex::new(ReplayCache::default());
+
 #[derive(Serialize, Deserialize)]
 pub struct HeaderClaim {
     #[serde(rename = "s")] //safes 2 bytes
@@ -323,3 +534,66 @@ pub fn make_extra_fields_digest(
         from: from.to_owned(),
     })
 }
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use serde_json::json;
+
+    use super::*;
+    use crate::crypto_keys::{tests::generate_key, KeyType};
+
+    fn date_header(time: SystemTime) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(header::DATE, httpdate::fmt_http_date(time).parse().unwrap());
+        headers
+    }
+
+    #[test]
+    fn date_must_be_within_clock_skew() {
+        let now = SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        let skew = Duration::from_secs(120);
+        for offset in [0, 60, 120] {
+            assert!(check_date(&date_header(now - Duration::from_secs(offset)), now, skew).is_ok());
+            assert!(check_date(&date_header(now + Duration::from_secs(offset)), now, skew).is_ok());
+        }
+        assert_eq!(check_date(&date_header(now), now, skew), Ok(now + skew));
+        assert!(check_date(&date_header(now - Duration::from_secs(121)), now, skew).is_err());
+        assert!(check_date(&date_header(now + Duration::from_secs(121)), now, skew).is_err());
+        assert!(check_date(&HeaderMap::new(), now, skew).is_err());
+    }
+
+    #[test]
+    fn archived_messages_are_verified_after_expiry() {
+        let key = generate_key(KeyType::P256);
+        let mut claims =
+            Claims::with_custom_claims(json!({"body": "payload"}), Duration::from_secs(60).into());
+        let now = jwt_simple::prelude::Clock::now_since_epoch();
+        claims.issued_at = Some(now - jwt_simple::prelude::Duration::from_days(2));
+        claims.expires_at = Some(now - jwt_simple::prelude::Duration::from_days(1));
+        let token = key.sign(claims).unwrap();
+        let public = key.public_key().unwrap();
+        assert!(public
+            .verify_token::<Value>(&token, Some(JWT_VERIFICATION_OPTIONS.clone()))
+            .is_err());
+        assert!(public
+            .verify_token::<Value>(&token, Some(ARCHIVE_VERIFICATION_OPTIONS.clone()))
+            .is_ok());
+    }
+
+    #[test]
+    fn replays_are_detected() {
+        let now = SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        let until = now + Duration::from_secs(120);
+        let mut cache = ReplayCache::default();
+        assert!(cache.insert("proxy1/jti1", until, now));
+        assert!(cache.insert("proxy1/jti2", until, now));
+        assert!(cache.insert("proxy2/jti1", until, now));
+        assert!(!cache.insert("proxy1/jti1", until, now + Duration::from_secs(60)));
+        // Once the Date header is no longer accepted, the ID is forgotten
+        let later = until + Duration::from_secs(1);
+        assert!(cache.insert("proxy1/jti3", later, later));
+        assert!(!cache.seen.contains_key("proxy1/jti1"));
+    }
+}
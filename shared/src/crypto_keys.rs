@@ -0,0 +1,739 @@
+//! Key material for the key types supported in Samply.PKI certificates: RSA, EC (P-256, P-384) and Ed25519.
+//! The algorithms used for signing (JWT) and for wrapping the symmetric content keys are derived from the key type,
+//! so proxies with RSA and EC/Ed25519 certificates can communicate with each other.
+
+use std::fmt::{Debug, Display};
+
+use chacha20poly1305::{
+    aead::{Aead, AeadCore, KeyInit, OsRng},
+    XChaCha20Poly1305, XNonce,
+};
+use ed25519_compact::x25519;
+use hkdf::Hkdf;
+use jwt_simple::{
+    claims::JWTClaims,
+    prelude::{
+        ECDSAP256KeyPairLike, ECDSAP256PublicKeyLike, ECDSAP384KeyPairLike, ECDSAP384PublicKeyLike,
+        ES256KeyPair, ES256PublicKey, ES384KeyPair, ES384PublicKey, Ed25519KeyPair,
+        Ed25519PublicKey, EdDSAKeyPairLike, EdDSAPublicKeyLike, RS256KeyPair, RS256PublicKey,
+        RSAKeyPairLike, RSAPublicKeyLike, VerificationOptions,
+    },
+    reexports::ct_codecs::{Base64UrlSafeNoPadding, Decoder},
+};
+use openssl::{
+    bn::{BigNum, BigNumContext},
+    derive::Deriver,
+    ec::{EcGroup, EcGroupRef, EcKey, EcPoint, PointConversionForm},
+    error::ErrorStack,
+    nid::Nid,
+    pkey::{HasPublic, Id, PKey, PKeyRef, Private, Public},
+    rsa::Rsa,
+    x509::X509,
+};
+use rsa::{
+    pkcs8::{DecodePrivateKey, DecodePublicKey},
+    PaddingScheme, PublicKey as _, RsaPrivateKey, RsaPublicKey,
+};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use sha2::Sha256;
+
+use crate::errors::SamplyBeamError;
+
+/// Domain separation for the key encryption keys derived from (EC)DH shared secrets
+const KEY_WRAP_INFO: &[u8] = b"samply.beam key wrap";
+const NONCE_LEN: usize = 24;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyType {
+    Rsa,
+    P256,
+    P384,
+    Ed25519,
+}
+
+impl KeyType {
+    pub fn of<T: HasPublic>(pkey: &PKeyRef<T>) -> Result<Self, SamplyBeamError> {
+        match pkey.id() {
+            Id::RSA => Ok(Self::Rsa),
+            Id::EC => match pkey.ec_key()?.group().curve_name() {
+                Some(Nid::X9_62_PRIME256V1) => Ok(Self::P256),
+                Some(Nid::SECP384R1) => Ok(Self::P384),
+                other => Err(SamplyBeamError::SignEncryptError(format!(
+                    "Unsupported elliptic curve {:?}; only P-256 and P-384 are supported",
+                    other
+                ))),
+            },
+            Id::ED25519 => Ok(Self::Ed25519),
+            other => Err(SamplyBeamError::SignEncryptError(format!(
+                "Unsupported key type {:?}; supported are RSA, EC (P-256, P-384) and Ed25519",
+                other
+            ))),
+        }
+    }
+
+    /// The JWT signature algorithm used with this key type
+    pub fn jwt_algorithm(&self) -> &'static str {
+        match self {
+            KeyType::Rsa => "RS256",
+            KeyType::P256 => "ES256",
+            KeyType::P384 => "ES384",
+            KeyType::Ed25519 => "EdDSA",
+        }
+    }
+
+    /// The algorithm used to wrap content keys for this key type
+    pub fn key_wrap_algorithm(&self) -> KeyWrapAlgorithm {
+        match self {
+            KeyType::Rsa => KeyWrapAlgorithm::RsaOaep256,
+            KeyType::P256 => KeyWrapAlgorithm::EcdhEsP256,
+            KeyType::P384 => KeyWrapAlgorithm::EcdhEsP384,
+            KeyType::Ed25519 => KeyWrapAlgorithm::EcdhEsX25519,
+        }
+    }
+}
+
+/// Algorithm used to wrap a message's content key for one recipient, as announced in the encryption envelope.
+/// Algorithms unknown to this version are kept as-is, so messages using them can still be passed on.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(from = "String", into = "String")]
+pub enum KeyWrapAlgorithm {
+    RsaOaep256,
+    EcdhEsP256,
+    EcdhEsP384,
+    EcdhEsX25519,
+    Other(String),
+}
+
+impl KeyWrapAlgorithm {
+    pub fn as_str(&self) -> &str {
+        match self {
+            KeyWrapAlgorithm::RsaOaep256 => "rsa-oaep-256",
+            KeyWrapAlgorithm::EcdhEsP256 => "ecdh-es-p256",
+            KeyWrapAlgorithm::EcdhEsP384 => "ecdh-es-p384",
+            KeyWrapAlgorithm::EcdhEsX25519 => "ecdh-es-x25519",
+            KeyWrapAlgorithm::Other(other) => other,
+        }
+    }
+}
+
+impl From<String> for KeyWrapAlgorithm {
+    fn from(value: String) -> Self {
+        match value.as_str() {
+            "rsa-oaep-256" => KeyWrapAlgorithm::RsaOaep256,
+            "ecdh-es-p256" => KeyWrapAlgorithm::EcdhEsP256,
+            "ecdh-es-p384" => KeyWrapAlgorithm::EcdhEsP384,
+            "ecdh-es-x25519" => KeyWrapAlgorithm::EcdhEsX25519,
+            _ => KeyWrapAlgorithm::Other(value),
+        }
+    }
+}
+
+impl From<KeyWrapAlgorithm> for String {
+    fn from(value: KeyWrapAlgorithm) -> Self {
+        value.as_str().to_string()
+    }
+}
+
+impl Display for KeyWrapAlgorithm {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl Display for KeyType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            KeyType::Rsa => "RSA",
+            KeyType::P256 => "EC P-256",
+            KeyType::P384 => "EC P-384",
+            KeyType::Ed25519 => "Ed25519",
+        };
+        f.write_str(name)
+    }
+}
+
+enum SigningKey {
+    RS256(RS256KeyPair),
+    ES256(ES256KeyPair),
+    ES384(ES384KeyPair),
+    EdDSA(Ed25519KeyPair),
+}
+
+// The ECDSA key pairs of jwt_simple do not implement Clone
+impl Clone for SigningKey {
+    fn clone(&self) -> Self {
+        fn with_key_id<K>(key: K, key_id: &Option<String>, f: fn(K, &str) -> K) -> K {
+            match key_id {
+                Some(kid) => f(key, kid),
+                None => key,
+            }
+        }
+        match self {
+            Self::RS256(key) => Self::RS256(key.clone()),
+            Self::ES256(key) => Self::ES256(with_key_id(
+                ES256KeyPair::from_bytes(&key.to_bytes()).expect("Key has been parsed before"),
+                key.key_id(),
+                ES256KeyPair::with_key_id,
+            )),
+            Self::ES384(key) => Self::ES384(with_key_id(
+                ES384KeyPair::from_bytes(&key.to_bytes()).expect("Key has been parsed before"),
+                key.key_id(),
+                ES384KeyPair::with_key_id,
+            )),
+            Self::EdDSA(key) => Self::EdDSA(key.clone()),
+        }
+    }
+}
+
+#[derive(Clone)]
+enum DecryptionKey {
+    RsaOaep(Box<RsaPrivateKey>),
+    /// ECDH with the private key itself
+    Ecdh,
+    /// X25519 key derived from the Ed25519 key
+    X25519(x25519::SecretKey),
+}
+
+/// A proxy's private key, used for signing and to unwrap the content keys of messages encrypted for this proxy.
+#[derive(Clone)]
+pub struct PrivateKey {
+    key_type: KeyType,
+    key_id: Option<String>,
+    pkey: PKey<Private>,
+    signing: SigningKey,
+    decryption: DecryptionKey,
+}
+
+impl Debug for PrivateKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("PrivateKey")
+            .field("key_type", &self.key_type)
+            .field("key_id", &self.key_id)
+            .finish_non_exhaustive()
+    }
+}
+
+impl PrivateKey {
+    /// Reads a PEM-encoded private key (PKCS#1, PKCS#8 or SEC1)
+    pub fn from_pem(pem: &str) -> Result<Self, SamplyBeamError> {
+        Self::from_pkey(PKey::private_key_from_pem(pem.as_bytes())?)
+    }
+
+    pub fn from_pkey(pkey: PKey<Private>) -> Result<Self, SamplyBeamError> {
+        let key_type = KeyType::of(&pkey)?;
+        let pkcs8 = pkey.private_key_to_pkcs8()?;
+        let (signing, decryption) = match key_type {
+            KeyType::Rsa => (
+                SigningKey::RS256(RS256KeyPair::from_der(&pkcs8).map_err(jwt_err)?),
+                DecryptionKey::RsaOaep(Box::new(RsaPrivateKey::from_pkcs8_der(&pkcs8).map_err(
+                    |e| {
+                        SamplyBeamError::SignEncryptError(format!("Invalid RSA private key: {}", e))
+                    },
+                )?)),
+            ),
+            KeyType::P256 => (
+                SigningKey::ES256(ES256KeyPair::from_der(&pkcs8).map_err(jwt_err)?),
+                DecryptionKey::Ecdh,
+            ),
+            KeyType::P384 => (
+                SigningKey::ES384(ES384KeyPair::from_der(&pkcs8).map_err(jwt_err)?),
+                DecryptionKey::Ecdh,
+            ),
+            KeyType::Ed25519 => {
+                let seed = ed25519_compact::Seed::from_slice(&pkey.raw_private_key()?)
+                    .map_err(ed25519_err)?;
+                let key_pair = ed25519_compact::KeyPair::from_seed(seed);
+                (
+                    SigningKey::EdDSA(Ed25519KeyPair::from_der(&pkcs8).map_err(jwt_err)?),
+                    DecryptionKey::X25519(
+                        x25519::SecretKey::from_ed25519(&key_pair.sk).map_err(ed25519_err)?,
+                    ),
+                )
+            }
+        };
+        Ok(Self {
+            key_type,
+            key_id: None,
+            pkey,
+            signing,
+            decryption,
+        })
+    }
+
+    pub fn key_type(&self) -> KeyType {
+        self.key_type
+    }
+
+    /// Sets the key ID (certificate serial) announced in the header of signed JWTs and used to find keys wrapped for this key
+    pub fn with_key_id(mut self, key_id: &str) -> Self {
+        self.key_id = Some(key_id.to_string());
+        self.signing = match self.signing {
+            SigningKey::RS256(key) => SigningKey::RS256(key.with_key_id(key_id)),
+            SigningKey::ES256(key) => SigningKey::ES256(key.with_key_id(key_id)),
+            SigningKey::ES384(key) => SigningKey::ES384(key.with_key_id(key_id)),
+            SigningKey::EdDSA(key) => SigningKey::EdDSA(key.with_key_id(key_id)),
+        };
+        self
+    }
+
+    pub fn key_id(&self) -> Option<&str> {
+        self.key_id.as_deref()
+    }
+
+    pub fn sign<T: Serialize + DeserializeOwned>(
+        &self,
+        claims: JWTClaims<T>,
+    ) -> Result<String, SamplyBeamError> {
+        match &self.signing {
+            SigningKey::RS256(key) => key.sign(claims),
+            SigningKey::ES256(key) => key.sign(claims),
+            SigningKey::ES384(key) => key.sign(claims),
+            SigningKey::EdDSA(key) => key.sign(claims),
+        }
+        .map_err(|e| SamplyBeamError::SignEncryptError(format!("Unable to sign JWT: {}", e)))
+    }
+
+    /// Checks whether the certificate has been issued for this key
+    pub fn matches_cert(&self, cert: &X509) -> Result<bool, ErrorStack> {
+        Ok(cert.public_key()?.public_eq(&self.pkey))
+    }
+
+    pub fn public_key(&self) -> Result<PublicKey, SamplyBeamError> {
+        PublicKey::from_pkey(PKey::public_key_from_der(&self.pkey.public_key_to_der()?)?)
+    }
+
+    /// Unwraps a content key that has been wrapped with [`PublicKey::wrap_key`] for this key
+    pub fn unwrap_key(&self, wrapped: &[u8]) -> Result<Vec<u8>, SamplyBeamError> {
+        match &self.decryption {
+            DecryptionKey::RsaOaep(key) => {
+                Ok(key.decrypt(PaddingScheme::new_oaep::<Sha256>(), wrapped)?)
+            }
+            DecryptionKey::Ecdh => {
+                let own = self.pkey.ec_key()?;
+                let (ephemeral, sealed) = split_checked(wrapped, ec_point_len(own.group()))?;
+                let mut ctx = BigNumContext::new()?;
+                let point = EcPoint::from_bytes(own.group(), ephemeral, &mut ctx)?;
+                let peer = PKey::from_ec_key(EcKey::from_public_key(own.group(), &point)?)?;
+                let mut deriver = Deriver::new(&self.pkey)?;
+                deriver.set_peer(&peer)?;
+                open(&deriver.derive_to_vec()?, ephemeral, sealed)
+            }
+            DecryptionKey::X25519(key) => {
+                let (ephemeral, sealed) = split_checked(wrapped, x25519::PublicKey::BYTES)?;
+                let shared = x25519::PublicKey::from_slice(ephemeral)
+                    .and_then(|peer| peer.dh(key))
+                    .map_err(ed25519_err)?;
+                open(shared.as_slice(), ephemeral, sealed)
+            }
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+enum VerifyingKey {
+    RS256(RS256PublicKey),
+    ES256(ES256PublicKey),
+    ES384(ES384PublicKey),
+    EdDSA(Ed25519PublicKey),
+}
+
+#[derive(Clone, Debug)]
+enum EncryptionKey {
+    RsaOaep(RsaPublicKey),
+    Ecdh(PKey<Public>),
+    X25519(x25519::PublicKey),
+}
+
+/// Another proxy's public key (from its certificate), used to verify its signatures and to wrap content keys for it.
+#[derive(Clone, Debug)]
+pub struct PublicKey {
+    key_type: KeyType,
+    key_id: Option<String>,
+    verifying: VerifyingKey,
+    encryption: EncryptionKey,
+}
+
+impl PublicKey {
+    /// Reads a PEM-encoded public key (SubjectPublicKeyInfo)
+    pub fn from_pem(pem: &str) -> Result<Self, SamplyBeamError> {
+        Self::from_pkey(PKey::public_key_from_pem(pem.as_bytes())?)
+    }
+
+    pub fn from_x509(cert: &X509) -> Result<Self, SamplyBeamError> {
+        Self::from_pkey(cert.public_key()?)
+    }
+
+    pub fn from_pkey(pkey: PKey<Public>) -> Result<Self, SamplyBeamError> {
+        let key_type = KeyType::of(&pkey)?;
+        let spki = pkey.public_key_to_der()?;
+        let (verifying, encryption) = match key_type {
+            KeyType::Rsa => (
+                VerifyingKey::RS256(RS256PublicKey::from_der(&spki).map_err(jwt_err)?),
+                EncryptionKey::RsaOaep(RsaPublicKey::from_public_key_der(&spki).map_err(|e| {
+                    SamplyBeamError::SignEncryptError(format!("Invalid RSA public key: {}", e))
+                })?),
+            ),
+            KeyType::P256 => (
+                VerifyingKey::ES256(ES256PublicKey::from_der(&spki).map_err(jwt_err)?),
+                EncryptionKey::Ecdh(pkey),
+            ),
+            KeyType::P384 => (
+                VerifyingKey::ES384(ES384PublicKey::from_der(&spki).map_err(jwt_err)?),
+                EncryptionKey::Ecdh(pkey),
+            ),
+            KeyType::Ed25519 => {
+                let raw = pkey.raw_public_key()?;
+                let ed25519 = ed25519_compact::PublicKey::from_slice(&raw).map_err(ed25519_err)?;
+                (
+                    VerifyingKey::EdDSA(Ed25519PublicKey::from_bytes(&raw).map_err(jwt_err)?),
+                    EncryptionKey::X25519(
+                        x25519::PublicKey::from_ed25519(&ed25519).map_err(ed25519_err)?,
+                    ),
+                )
+            }
+        };
+        Ok(Self {
+            key_type,
+            key_id: None,
+            verifying,
+            encryption,
+        })
+    }
+
+    /// Reads a public key from a JWK, e.g. from an OAuth2/OIDC identity provider's JWKS.
+    /// Supported are RSA, EC (P-256, P-384) and Ed25519 keys.
+    pub fn from_jwk(jwk: &Jwk) -> Result<Self, SamplyBeamError> {
+        let param = |name: &str, value: &Option<String>| {
+            let value = value.as_deref().ok_or_else(|| {
+                SamplyBeamError::SignEncryptError(format!("JWK lacks parameter \"{name}\""))
+            })?;
+            Base64UrlSafeNoPadding::decode_to_vec(value, None).map_err(|e| {
+                SamplyBeamError::SignEncryptError(format!("Invalid JWK parameter \"{name}\": {e}"))
+            })
+        };
+        let bignum = |name: &str, value: &Option<String>| -> Result<BigNum, SamplyBeamError> {
+            Ok(BigNum::from_slice(&param(name, value)?)?)
+        };
+        let pkey = match (jwk.kty.as_str(), jwk.crv.as_deref()) {
+            ("RSA", _) => PKey::from_rsa(Rsa::from_public_components(
+                bignum("n", &jwk.n)?,
+                bignum("e", &jwk.e)?,
+            )?)?,
+            ("EC", Some(crv @ ("P-256" | "P-384"))) => {
+                let nid = match crv {
+                    "P-256" => Nid::X9_62_PRIME256V1,
+                    _ => Nid::SECP384R1,
+                };
+                let group = EcGroup::from_curve_name(nid)?;
+                let (x, y) = (bignum("x", &jwk.x)?, bignum("y", &jwk.y)?);
+                PKey::from_ec_key(EcKey::from_public_key_affine_coordinates(&group, &x, &y)?)?
+            }
+            ("OKP", Some("Ed25519")) => {
+                PKey::public_key_from_raw_bytes(&param("x", &jwk.x)?, Id::ED25519)?
+            }
+            (kty, crv) => {
+                return Err(SamplyBeamError::SignEncryptError(format!(
+                    "Unsupported JWK key type {kty} (curve {})",
+                    crv.unwrap_or("none")
+                )))
+            }
+        };
+        let key = Self::from_pkey(pkey)?;
+        Ok(match &jwk.kid {
+            Some(kid) => key.with_key_id(kid),
+            None => key,
+        })
+    }
+
+    pub fn key_type(&self) -> KeyType {
+        self.key_type
+    }
+
+    /// Sets the key ID (certificate serial) under which keys wrapped for this key are announced
+    pub fn with_key_id(mut self, key_id: &str) -> Self {
+        self.key_id = Some(key_id.to_string());
+        self
+    }
+
+    pub fn key_id(&self) -> Option<&str> {
+        self.key_id.as_deref()
+    }
+
+    /// Verifies a JWT signed by the corresponding private key. Tokens with a signature algorithm not matching the key type are rejected.
+    pub fn verify_token<T: Serialize + DeserializeOwned>(
+        &self,
+        token: &str,
+        options: Option<VerificationOptions>,
+    ) -> Result<JWTClaims<T>, jwt_simple::Error> {
+        match &self.verifying {
+            VerifyingKey::RS256(key) => key.verify_token(token, options),
+            VerifyingKey::ES256(key) => key.verify_token(token, options),
+            VerifyingKey::ES384(key) => key.verify_token(token, options),
+            VerifyingKey::EdDSA(key) => key.verify_token(token, options),
+        }
+    }
+
+    /// Wraps a (symmetric) content key for this key's owner.
+    /// RSA keys use RSA-OAEP (SHA-256). EC and Ed25519 keys use ECDH (X25519 for Ed25519) with an ephemeral key; the
+    /// shared secret is run through HKDF-SHA256 to encrypt the content key with XChaCha20Poly1305.
+    /// The result is `ephemeral public key || nonce || ciphertext`.
+    pub fn wrap_key(&self, key: &[u8]) -> Result<Vec<u8>, SamplyBeamError> {
+        match &self.encryption {
+            EncryptionKey::RsaOaep(peer) => Ok(peer.encrypt(
+                &mut rand::thread_rng(),
+                PaddingScheme::new_oaep::<Sha256>(),
+                key,
+            )?),
+            EncryptionKey::Ecdh(peer) => {
+                let peer_ec = peer.ec_key()?;
+                let group = peer_ec.group();
+                let ephemeral = EcKey::generate(group)?;
+                let mut ctx = BigNumContext::new()?;
+                let ephemeral_public = ephemeral.public_key().to_bytes(
+                    group,
+                    PointConversionForm::UNCOMPRESSED,
+                    &mut ctx,
+                )?;
+                let ephemeral = PKey::from_ec_key(ephemeral)?;
+                let mut deriver = Deriver::new(&ephemeral)?;
+                deriver.set_peer(peer)?;
+                seal(&deriver.derive_to_vec()?, ephemeral_public, key)
+            }
+            EncryptionKey::X25519(peer) => {
+                let ephemeral = x25519::KeyPair::generate();
+                let shared = peer.dh(&ephemeral.sk).map_err(ed25519_err)?;
+                seal(shared.as_slice(), ephemeral.pk.to_vec(), key)
+            }
+        }
+    }
+}
+
+fn key_encryption_cipher(
+    shared_secret: &[u8],
+    ephemeral_public: &[u8],
+) -> Result<XChaCha20Poly1305, SamplyBeamError> {
+    let mut kek = [0u8; 32];
+    Hkdf::<Sha256>::new(None, shared_secret)
+        .expand_multi_info(&[KEY_WRAP_INFO, ephemeral_public], &mut kek)
+        .map_err(|e| SamplyBeamError::SignEncryptError(format!("Key derivation failed: {}", e)))?;
+    XChaCha20Poly1305::new_from_slice(&kek).map_err(|e| {
+        SamplyBeamError::SignEncryptError(format!("Cannot initialize key wrap cipher: {}", e))
+    })
+}
+
+fn seal(
+    shared_secret: &[u8],
+    mut ephemeral_public: Vec<u8>,
+    key: &[u8],
+) -> Result<Vec<u8>, SamplyBeamError> {
+    let cipher = key_encryption_cipher(shared_secret, &ephemeral_public)?;
+    let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+    let ciphertext = cipher.encrypt(&nonce, key).map_err(|_| {
+        SamplyBeamError::SignEncryptError("Encryption error: Cannot wrap symmetric key".into())
+    })?;
+    ephemeral_public.extend_from_slice(&nonce);
+    ephemeral_public.extend_from_slice(&ciphertext);
+    Ok(ephemeral_public)
+}
+
+fn open(
+    shared_secret: &[u8],
+    ephemeral_public: &[u8],
+    sealed: &[u8],
+) -> Result<Vec<u8>, SamplyBeamError> {
+    let (nonce, ciphertext) = split_checked(sealed, NONCE_LEN)?;
+    key_encryption_cipher(shared_secret, ephemeral_public)?
+        .decrypt(XNonce::from_slice(nonce), ciphertext)
+        .map_err(|_| {
+            SamplyBeamError::SignEncryptError(
+                "Decryption error: Cannot unwrap symmetric key".into(),
+            )
+        })
+}
+
+fn split_checked(data: &[u8], at: usize) -> Result<(&[u8], &[u8]), SamplyBeamError> {
+    if data.len() < at {
+        return Err(SamplyBeamError::SignEncryptError(
+            "Decryption error: Wrapped key is too short".into(),
+        ));
+    }
+    Ok(data.split_at(at))
+}
+
+/// Length of an uncompressed point on the given curve
+fn ec_point_len(group: &EcGroupRef) -> usize {
+    1 + 2 * (group.degree() as usize).div_ceil(8)
+}
+
+/// A JSON Web Key (RFC 7517), limited to the parameters of the supported public key types
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Jwk {
+    pub kty: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub kid: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub crv: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub n: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub e: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub x: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub y: Option<String>,
+}
+
+/// A JSON Web Key Set, as published by OAuth2/OIDC identity providers
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Jwks {
+    pub keys: Vec<Jwk>,
+}
+
+fn jwt_err(e: jwt_simple::Error) -> SamplyBeamError {
+    SamplyBeamError::SignEncryptError(format!("Unable to initialize key: {}", e))
+}
+
+fn ed25519_err(e: ed25519_compact::Error) -> SamplyBeamError {
+    SamplyBeamError::SignEncryptError(format!("Invalid Ed25519/X25519 key: {}", e))
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use jwt_simple::{
+        prelude::{Claims, Duration},
+        reexports::ct_codecs::Encoder,
+    };
+    use serde_json::{json, Value};
+
+    use super::*;
+
+    pub(crate) fn generate_key(key_type: KeyType) -> PrivateKey {
+        let pkey = match key_type {
+            KeyType::Rsa => PKey::from_rsa(Rsa::generate(2048).unwrap()),
+            KeyType::P256 => PKey::from_ec_key(
+                EcKey::generate(&EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).unwrap()).unwrap(),
+            ),
+            KeyType::P384 => PKey::from_ec_key(
+                EcKey::generate(&EcGroup::from_curve_name(Nid::SECP384R1).unwrap()).unwrap(),
+            ),
+            KeyType::Ed25519 => PKey::generate_ed25519(),
+        }
+        .unwrap();
+        PrivateKey::from_pkey(pkey).unwrap()
+    }
+
+    const ALL_KEY_TYPES: [KeyType; 4] =
+        [KeyType::Rsa, KeyType::P256, KeyType::P384, KeyType::Ed25519];
+
+    #[test]
+    fn public_keys_from_jwk() {
+        let b64 = |bytes: &[u8]| Some(Base64UrlSafeNoPadding::encode_to_string(bytes).unwrap());
+        let jwk = |kty: &str, crv: Option<&str>| Jwk {
+            kty: kty.to_string(),
+            kid: Some(format!("{kty}-key")),
+            crv: crv.map(str::to_string),
+            n: None,
+            e: None,
+            x: None,
+            y: None,
+        };
+        let rsa = Rsa::generate(2048).unwrap();
+        let rsa_jwk = Jwk {
+            n: b64(&rsa.n().to_vec()),
+            e: b64(&rsa.e().to_vec()),
+            ..jwk("RSA", None)
+        };
+        let ec =
+            EcKey::generate(&EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).unwrap()).unwrap();
+        let (mut x, mut y) = (BigNum::new().unwrap(), BigNum::new().unwrap());
+        ec.public_key()
+            .affine_coordinates(
+                ec.group(),
+                &mut x,
+                &mut y,
+                &mut BigNumContext::new().unwrap(),
+            )
+            .unwrap();
+        let ec_jwk = Jwk {
+            x: b64(&x.to_vec()),
+            y: b64(&y.to_vec()),
+            ..jwk("EC", Some("P-256"))
+        };
+        let ed25519 = PKey::generate_ed25519().unwrap();
+        let ed25519_jwk = Jwk {
+            x: b64(&ed25519.raw_public_key().unwrap()),
+            ..jwk("OKP", Some("Ed25519"))
+        };
+        for (pkey, jwk) in [
+            (PKey::from_rsa(rsa).unwrap(), rsa_jwk),
+            (PKey::from_ec_key(ec).unwrap(), ec_jwk),
+            (ed25519, ed25519_jwk),
+        ] {
+            let token = PrivateKey::from_pkey(pkey)
+                .unwrap()
+                .sign(Claims::with_custom_claims(
+                    json!({}),
+                    Duration::from_mins(1),
+                ))
+                .unwrap();
+            let public = PublicKey::from_jwk(&jwk).unwrap();
+            assert_eq!(public.key_id(), jwk.kid.as_deref());
+            public.verify_token::<Value>(&token, None).unwrap();
+        }
+        assert!(PublicKey::from_jwk(&jwk("oct", None)).is_err());
+        assert!(PublicKey::from_jwk(&jwk("RSA", None)).is_err());
+    }
+
+    #[test]
+    fn sign_and_verify() {
+        for key_type in ALL_KEY_TYPES {
+            let key = generate_key(key_type).with_key_id("0a:0b");
+            let token = key
+                .sign(Claims::with_custom_claims(
+                    json!({ "body": "payload" }),
+                    Duration::from_mins(1),
+                ))
+                .unwrap();
+            let claims = key
+                .public_key()
+                .unwrap()
+                .verify_token::<Value>(&token, None)
+                .unwrap();
+            assert_eq!(claims.custom["body"], "payload", "{key_type}");
+            // Signatures by other keys must not verify
+            let other = generate_key(key_type).public_key().unwrap();
+            assert!(
+                other.verify_token::<Value>(&token, None).is_err(),
+                "{key_type}"
+            );
+        }
+    }
+
+    #[test]
+    fn wrap_and_unwrap() {
+        for key_type in ALL_KEY_TYPES {
+            let key = generate_key(key_type);
+            let content_key = [42u8; 32];
+            let wrapped = key.public_key().unwrap().wrap_key(&content_key).unwrap();
+            assert_eq!(key.unwrap_key(&wrapped).unwrap(), content_key, "{key_type}");
+            assert!(
+                generate_key(key_type).unwrap_key(&wrapped).is_err(),
+                "{key_type}"
+            );
+        }
+    }
+
+    #[test]
+    fn reads_pem_keys() {
+        let ec =
+            EcKey::generate(&EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).unwrap()).unwrap();
+        let sec1 = String::from_utf8(ec.private_key_to_pem().unwrap()).unwrap();
+        assert_eq!(
+            PrivateKey::from_pem(&sec1).unwrap().key_type(),
+            KeyType::P256
+        );
+        let pkcs1 = Rsa::generate(2048).unwrap().private_key_to_pem().unwrap();
+        let key = PrivateKey::from_pem(std::str::from_utf8(&pkcs1).unwrap()).unwrap();
+        assert_eq!(key.key_type(), KeyType::Rsa);
+    }
+}
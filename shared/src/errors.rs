@@ -92,6 +92,8 @@ pub enum CertificateInvalidReason {
     InvalidPublicKey,
     #[error("Internal error: {0}")]
     InternalError(String),
+    #[error("Certificate has been revoked")]
+    Revoked,
     #[error("Not disclosed: Broker consideres this certificate invalid")]
     NotDisclosedByBroker,
     #[error("Other problem: {0}")]
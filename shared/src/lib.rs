@@ -7,11 +7,11 @@ use chacha20poly1305::{
     XChaCha20Poly1305, XNonce,
 };
 use crypto_jwt::extract_jwt;
+use crypto_keys::{KeyWrapAlgorithm, PrivateKey, PublicKey};
 use errors::SamplyBeamError;
 use itertools::Itertools;
 use jwt_simple::prelude::{RS256PublicKey, RSAPublicKeyLike};
 use openssl::base64;
-use rsa::{PaddingScheme, PublicKey, RsaPrivateKey, RsaPublicKey};
 use serde_json::{json, Value};
 use sha2::Sha256;
 use static_init::dynamic;
@@ -37,8 +37,12 @@ pub type MsgId = MyUuid;
 pub type MsgType = String;
 pub type TaskResponse = String;
 
+pub mod api_keys;
+pub mod blobs;
+pub mod compression;
 pub mod crypto;
 pub mod crypto_jwt;
+pub mod crypto_keys;
 pub mod errors;
 pub mod logger;
 mod traits;
@@ -54,6 +58,12 @@ pub mod beam_id;
 pub mod graceful_shutdown;
 pub mod http_client;
 pub mod middleware;
+pub mod policy;
+pub mod reload;
+pub mod sockets;
+pub mod tls_server;
+pub mod websocket;
+pub mod wire;
 
 pub mod examples;
 
@@ -102,15 +112,53 @@ impl Display for MyUuid {
 #[serde(rename_all = "lowercase", tag = "status")]
 pub enum WorkStatus {
     Claimed,
+    /// The task is being worked on; may be reported repeatedly to keep the task's creator informed
+    Running {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        progress: Option<Progress>,
+    },
     TempFailed,
     PermFailed,
     Succeeded,
 }
 
+impl WorkStatus {
+    /// Whether the work is done, i.e. the result will not change anymore
+    pub fn is_final(&self) -> bool {
+        matches!(self, WorkStatus::Succeeded | WorkStatus::PermFailed)
+    }
+}
+
+/// Progress of a running task as reported by the worker
+#[derive(PartialEq, Eq, Serialize, Deserialize, Debug, Clone, Default)]
+pub struct Progress {
+    /// Percentage of the work done, from 0 to 100
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub percent: Option<u8>,
+    /// Current stage of the work, e.g. "querying" or "aggregating"
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub stage: Option<String>,
+    /// Human-readable description of what is going on
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub message: Option<String>,
+}
+
 impl Display for WorkStatus {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         let str = match self {
             WorkStatus::Claimed => String::from("Claimed"),
+            WorkStatus::Running { progress: None } => String::from("Running"),
+            WorkStatus::Running {
+                progress: Some(Progress { percent, stage, .. }),
+            } => {
+                let percent = percent.map(|percent| format!(" {percent}%"));
+                let stage = stage.as_ref().map(|stage| format!(" ({stage})"));
+                format!(
+                    "Running{}{}",
+                    percent.unwrap_or_default(),
+                    stage.unwrap_or_default()
+                )
+            }
             WorkStatus::TempFailed => String::from("Temporary failure"),
             WorkStatus::PermFailed => String::from("Permanent failure"),
             WorkStatus::Succeeded => String::from("Success"),
@@ -133,6 +181,8 @@ pub enum FailureStrategy {
 pub struct HowLongToBlock {
     pub wait_time: Option<Duration>,
     pub wait_count: Option<u16>,
+    /// Only count results that are final (see [`WorkStatus::is_final`]) towards `wait_count`
+    pub wait_final: bool,
 }
 
 #[derive(Clone, Debug, PartialEq, Serialize)]
@@ -186,12 +236,25 @@ where
     // Maybe add MessageSigned and Encrypted versions
     MsgTaskRequest(MsgTaskRequest<State>),
     MsgTaskResult(MsgTaskResult<State>),
+    MsgSocketRequest(MsgSocketRequest<State>),
     MsgEmpty(MsgEmpty),
 }
 
 pub type PlainMessage = MessageType<Plain>;
 pub type EncryptedMessage = MessageType<Encrypted>;
 
+impl PlainMessage {
+    /// The message's body, e.g. for setting a body that has been sent as raw bytes
+    pub fn body_mut(&mut self) -> Option<&mut Plain> {
+        match self {
+            Self::MsgTaskRequest(m) => Some(&mut m.body),
+            Self::MsgTaskResult(m) => Some(&mut m.body),
+            Self::MsgSocketRequest(m) => Some(&mut m.secret),
+            Self::MsgEmpty(_) => None,
+        }
+    }
+}
+
 impl EncryptableMsg for PlainMessage {
     type Output = EncryptedMessage;
 
@@ -199,6 +262,7 @@ impl EncryptableMsg for PlainMessage {
         match self {
             Self::MsgTaskRequest(m) => Self::Output::MsgTaskRequest(m.convert_self(body)),
             Self::MsgTaskResult(m) => Self::Output::MsgTaskResult(m.convert_self(body)),
+            Self::MsgSocketRequest(m) => Self::Output::MsgSocketRequest(m.convert_self(body)),
             Self::MsgEmpty(m) => Self::Output::MsgEmpty(m),
         }
     }
@@ -207,23 +271,28 @@ impl EncryptableMsg for PlainMessage {
         match self {
             Self::MsgTaskRequest(m) => m.get_plain(),
             Self::MsgTaskResult(m) => m.get_plain(),
-            Self::MsgEmpty(_) => &Plain { body: None },
+            Self::MsgSocketRequest(m) => m.get_plain(),
+            Self::MsgEmpty(_) => &EMPTY_PLAIN,
         }
     }
 }
 
 const MESSAGE_EMPTY_ENCRYPTION: &Encrypted = &Encrypted {
+    envelope: ENVELOPE_VERSION,
+    aead: AeadAlgorithm::XChaCha20Poly1305,
     encrypted: Vec::new(),
     encryption_keys: Vec::new(),
+    compression: None,
 };
 
 impl DecryptableMsg for EncryptedMessage {
     type Output = PlainMessage;
 
-    fn convert_self(self, body: String) -> Self::Output {
+    fn convert_self(self, body: Plain) -> Self::Output {
         match self {
             Self::MsgTaskRequest(m) => Self::Output::MsgTaskRequest(m.convert_self(body)),
             Self::MsgTaskResult(m) => Self::Output::MsgTaskResult(m.convert_self(body)),
+            Self::MsgSocketRequest(m) => Self::Output::MsgSocketRequest(m.convert_self(body)),
             Self::MsgEmpty(m) => Self::Output::MsgEmpty(m),
         }
     }
@@ -232,6 +301,7 @@ impl DecryptableMsg for EncryptedMessage {
         match self {
             Self::MsgTaskRequest(m) => m.get_encryption(),
             Self::MsgTaskResult(m) => m.get_encryption(),
+            Self::MsgSocketRequest(m) => m.get_encryption(),
             Self::MsgEmpty(_) => MESSAGE_EMPTY_ENCRYPTION,
         }
     }
@@ -243,6 +313,7 @@ impl<T: MsgState> Msg for MessageType<T> {
         match self {
             MsgTaskRequest(m) => m.get_from(),
             MsgTaskResult(m) => m.get_from(),
+            MsgSocketRequest(m) => m.get_from(),
             MsgEmpty(m) => m.get_from(),
         }
     }
@@ -252,6 +323,7 @@ impl<T: MsgState> Msg for MessageType<T> {
         match self {
             MsgTaskRequest(m) => m.get_to(),
             MsgTaskResult(m) => m.get_to(),
+            MsgSocketRequest(m) => m.get_to(),
             MsgEmpty(m) => m.get_to(),
         }
     }
@@ -261,6 +333,7 @@ impl<T: MsgState> Msg for MessageType<T> {
         match self {
             MsgTaskRequest(m) => m.get_metadata(),
             MsgTaskResult(m) => m.get_metadata(),
+            MsgSocketRequest(m) => m.get_metadata(),
             MsgEmpty(m) => m.get_metadata(),
         }
     }
@@ -270,19 +343,16 @@ pub trait DecryptableMsg: Msg + Serialize + Sized {
     type Output: Msg + DeserializeOwned;
 
     fn get_encryption(&self) -> &Encrypted;
-    fn convert_self(self, body: String) -> Self::Output;
+    fn convert_self(self, body: Plain) -> Self::Output;
 
     /// Decrypts an encrypted message. Caution: can panic.
     #[allow(clippy::or_fun_call)]
     fn decrypt(
         self,
         my_id: &AppOrProxyId,
-        my_priv_key: &RsaPrivateKey,
+        my_priv_key: &PrivateKey,
     ) -> Result<Self::Output, SamplyBeamError> {
-        let Encrypted {
-            encrypted,
-            encryption_keys,
-        } = self.get_encryption();
+        let encryption = self.get_encryption();
         let to_array_index: usize = self
             .get_to()
             .iter()
@@ -300,40 +370,26 @@ pub trait DecryptableMsg: Msg + Serialize + Sized {
             .ok_or(SamplyBeamError::SignEncryptError(
                 "Decryption error: This client cannot be found in 'to' list".into(),
             ))?;
-        let encrypted_decryption_key = &encryption_keys[to_array_index];
 
         // Cryptographic Operations
-        let cipher_engine = XChaCha20Poly1305::new_from_slice(&my_priv_key.decrypt(
-            rsa::PaddingScheme::new_oaep::<sha2::Sha256>(),
-            &encrypted_decryption_key,
-        )?)
-        .map_err(|e| {
-            SamplyBeamError::SignEncryptError(format!(
-                "Decryption error: Cannot initialize stream cipher because {}",
-                e
-            ))
-        })?;
-        let nonce: XNonce = XNonce::clone_from_slice(&encrypted[0..24]);
-        let ciphertext = &encrypted[24..];
-        let plaintext = String::from_utf8(
-            cipher_engine
-                .decrypt(&nonce, ciphertext.as_ref())
-                .map_err(|e| {
-                    SamplyBeamError::SignEncryptError(format!(
-                        "Decryption error: Cannot decrypt payload because {}",
-                        e
-                    ))
-                })?,
-        )
-        .map_err(|e| {
-            SamplyBeamError::SignEncryptError(format!(
-                "Decryption error: Invalid UTF8 text in decrypted ciphertext {}",
-                e
-            ))
-        })?;
-
-        // self.set_body(plaintext);
-        Ok(self.convert_self(plaintext))
+        let symmetric_key = if encryption.envelope == 0 {
+            encryption.unwrap_legacy_key(to_array_index, my_priv_key)?
+        } else {
+            encryption.unwrap_recipient_key(my_priv_key)?
+        };
+        let plaintext = encryption.decrypt_content(&symmetric_key)?;
+        let plaintext = match &encryption.compression {
+            Some(compression) => compression::decompress(
+                compression,
+                &plaintext,
+                compression::MAX_DECOMPRESSED_SIZE,
+            )?,
+            None => plaintext,
+        };
+
+        Ok(self.convert_self(Plain {
+            body: Some(plaintext),
+        }))
     }
 }
 
@@ -343,52 +399,12 @@ pub trait EncryptableMsg: Msg + Serialize + Sized {
     fn convert_self(self, body: Encrypted) -> Self::Output;
     fn get_plain(&self) -> &Plain;
 
-    #[allow(clippy::or_fun_call)]
     fn encrypt(
         self,
-        receivers_public_keys: &Vec<RsaPublicKey>,
+        receivers_public_keys: &[PublicKey],
     ) -> Result<Self::Output, SamplyBeamError> {
-        // Generate Symmetric Key and Nonce
-        let mut rng = rand::thread_rng();
-        let symmetric_key = XChaCha20Poly1305::generate_key(&mut rng);
-        let nonce = XChaCha20Poly1305::generate_nonce(&mut rng);
-
-        // Encrypt symmetric key with receivers' public keys
-        let (encrypted_keys, err): (Vec<_>, Vec<_>) = receivers_public_keys
-            .iter()
-            .map(|key| {
-                key.encrypt(
-                    &mut rng,
-                    PaddingScheme::new_oaep::<Sha256>(),
-                    symmetric_key.as_slice(),
-                )
-            })
-            .partition_result();
-        if !err.is_empty() {
-            return Err(SamplyBeamError::SignEncryptError(
-                "Encryption error: Cannot encrypt symmetric key".into(),
-            ));
-        }
-
-        // Encrypt fields content
-        let cipher = XChaCha20Poly1305::new(&symmetric_key);
-
-        // I cant belive there is no better way
-        let default = String::new();
-        let plaintext = self.get_plain().body.as_ref().unwrap_or(&default);
-
-        let mut ciphertext = cipher.encrypt(&nonce, plaintext.as_ref()).or(Err(
-            SamplyBeamError::SignEncryptError("Encryption error: Can not encrypt data.".into()),
-        ))?;
-
-        // Prepend Nonce to ciphertext
-        let mut nonce_and_ciphertext = nonce.to_vec();
-        nonce_and_ciphertext.append(&mut ciphertext);
-
-        Ok(self.convert_self(Encrypted {
-            encrypted: nonce_and_ciphertext,
-            encryption_keys: encrypted_keys,
-        }))
+        let encrypted = Encrypted::new(self.get_plain(), receivers_public_keys)?;
+        Ok(self.convert_self(encrypted))
     }
 }
 
@@ -440,6 +456,20 @@ impl<T: MsgState> Msg for MsgTaskResult<T> {
     }
 }
 
+impl<T: MsgState> Msg for MsgSocketRequest<T> {
+    fn get_from(&self) -> &AppOrProxyId {
+        &self.from
+    }
+
+    fn get_to(&self) -> &Vec<AppOrProxyId> {
+        &self.to
+    }
+
+    fn get_metadata(&self) -> &Value {
+        &self.metadata
+    }
+}
+
 mod serialize_time {
     use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
@@ -475,25 +505,281 @@ mod serialize_time {
 
 pub trait MsgState: Serialize + Eq + PartialEq + Default {}
 
+/// Version of the encryption envelope written by this version of Samply.Beam
+pub const ENVELOPE_VERSION: u8 = 1;
+
+/// Encryption envelope of a message's body.
+/// Version 0 (Beam before 0.7, without the `envelope` field): XChaCha20Poly1305 and RSA-OAEP wrapped keys without identifiers.
+/// Version 1: announces the AEAD algorithm and, for each wrapped key, the key wrapping algorithm and the recipient's key ID (certificate serial).
+/// Either version may carry `compression`, the codec the plaintext was compressed with before encryption.
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Default)]
 pub struct Encrypted {
+    #[serde(default)]
+    pub envelope: u8,
+    #[serde(default)]
+    pub aead: AeadAlgorithm,
+    /// The ciphertext; for XChaCha20Poly1305, prepended by the 24-byte nonce
     pub encrypted: Vec<u8>,
-    pub encryption_keys: Vec<Vec<u8>>,
+    pub encryption_keys: Vec<WrappedKey>,
+    /// Absent if the body is not compressed, see [`compression`]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub compression: Option<compression::Compression>,
 }
 
 impl MsgState for Encrypted {}
 
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(untagged)]
+pub enum WrappedKey {
+    Recipient {
+        kid: Option<String>,
+        alg: KeyWrapAlgorithm,
+        key: Vec<u8>,
+    },
+    Legacy(Vec<u8>),
+}
+
+/// AEAD algorithm used to encrypt the message body. Unknown algorithms are kept as-is, see [`KeyWrapAlgorithm`].
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Default)]
+#[serde(from = "String", into = "String")]
+pub enum AeadAlgorithm {
+    #[default]
+    XChaCha20Poly1305,
+    Other(String),
+}
+
+impl AeadAlgorithm {
+    pub fn as_str(&self) -> &str {
+        match self {
+            AeadAlgorithm::XChaCha20Poly1305 => "xchacha20poly1305",
+            AeadAlgorithm::Other(other) => other,
+        }
+    }
+}
+
+impl From<String> for AeadAlgorithm {
+    fn from(value: String) -> Self {
+        match value.as_str() {
+            "xchacha20poly1305" => AeadAlgorithm::XChaCha20Poly1305,
+            _ => AeadAlgorithm::Other(value),
+        }
+    }
+}
+
+impl From<AeadAlgorithm> for String {
+    fn from(value: AeadAlgorithm) -> Self {
+        value.as_str().to_string()
+    }
+}
+
+impl Encrypted {
+    /// Encrypts a body for the given recipients' keys
+    #[allow(clippy::or_fun_call)]
+    pub fn new(
+        plain: &Plain,
+        receivers_public_keys: &[PublicKey],
+    ) -> Result<Self, SamplyBeamError> {
+        // Generate Symmetric Key and Nonce
+        let mut rng = rand::thread_rng();
+        let symmetric_key = XChaCha20Poly1305::generate_key(&mut rng);
+        let nonce = XChaCha20Poly1305::generate_nonce(&mut rng);
+
+        // Encrypt symmetric key with receivers' public keys
+        let (encrypted_keys, err): (Vec<_>, Vec<_>) = receivers_public_keys
+            .iter()
+            .map(|key| {
+                key.wrap_key(symmetric_key.as_slice())
+                    .map(|wrapped| WrappedKey::Recipient {
+                        kid: key.key_id().map(ToOwned::to_owned),
+                        alg: key.key_type().key_wrap_algorithm(),
+                        key: wrapped,
+                    })
+            })
+            .partition_result();
+        if !err.is_empty() {
+            return Err(SamplyBeamError::SignEncryptError(
+                "Encryption error: Cannot encrypt symmetric key".into(),
+            ));
+        }
+
+        // Encrypt fields content
+        let cipher = XChaCha20Poly1305::new(&symmetric_key);
+
+        let plaintext = plain.body.as_deref().unwrap_or_default();
+        let compressed = compression::compress(plaintext);
+        let (compression, plaintext) = match &compressed {
+            Some((compression, compressed)) => (Some(compression.clone()), compressed.as_slice()),
+            None => (None, plaintext),
+        };
+
+        let mut ciphertext =
+            cipher
+                .encrypt(&nonce, plaintext)
+                .or(Err(SamplyBeamError::SignEncryptError(
+                    "Encryption error: Can not encrypt data.".into(),
+                )))?;
+
+        // Prepend Nonce to ciphertext
+        let mut nonce_and_ciphertext = nonce.to_vec();
+        nonce_and_ciphertext.append(&mut ciphertext);
+
+        Ok(Encrypted {
+            envelope: ENVELOPE_VERSION,
+            aead: AeadAlgorithm::XChaCha20Poly1305,
+            encrypted: nonce_and_ciphertext,
+            encryption_keys: encrypted_keys,
+            compression,
+        })
+    }
+
+    /// Unwraps the content key at the given position in the `to` list (envelope version 0)
+    fn unwrap_legacy_key(
+        &self,
+        index: usize,
+        my_priv_key: &PrivateKey,
+    ) -> Result<Vec<u8>, SamplyBeamError> {
+        match self.encryption_keys.get(index) {
+            Some(WrappedKey::Legacy(key)) => my_priv_key.unwrap_key(key),
+            Some(WrappedKey::Recipient { .. }) => Err(SamplyBeamError::SignEncryptError(
+                "Decryption error: Malformed encryption envelope (version 0)".into(),
+            )),
+            None => Err(SamplyBeamError::SignEncryptError(
+                "Decryption error: No encrypted key for this client".into(),
+            )),
+        }
+    }
+
+    /// Unwraps the content key wrapped for this proxy's certificate, identified by its serial.
+    /// If there is none (e.g. the certificate has been renewed for the same key), all keys wrapped with this key's algorithm are tried.
+    fn unwrap_recipient_key(&self, my_priv_key: &PrivateKey) -> Result<Vec<u8>, SamplyBeamError> {
+        if self.envelope != ENVELOPE_VERSION {
+            return Err(SamplyBeamError::SignEncryptError(format!(
+                "Decryption error: Unsupported encryption envelope version {}; please update this Beam.Proxy",
+                self.envelope
+            )));
+        }
+        let own_alg = my_priv_key.key_type().key_wrap_algorithm();
+        let mut candidates = Vec::new();
+        for wrapped in &self.encryption_keys {
+            let WrappedKey::Recipient { kid, alg, key } = wrapped else {
+                return Err(SamplyBeamError::SignEncryptError(format!(
+                    "Decryption error: Malformed encryption envelope (version {})",
+                    self.envelope
+                )));
+            };
+            let own_kid = kid.is_some() && kid.as_deref() == my_priv_key.key_id();
+            match (own_kid, *alg == own_alg) {
+                (true, true) => return my_priv_key.unwrap_key(key),
+                (true, false) => {
+                    return Err(SamplyBeamError::SignEncryptError(format!(
+                        "Decryption error: Key has been wrapped using {alg}, but this client's {} key requires {own_alg}",
+                        my_priv_key.key_type()
+                    )))
+                }
+                (false, true) => candidates.push(key),
+                (false, false) => {}
+            }
+        }
+        candidates
+            .into_iter()
+            .find_map(|key| my_priv_key.unwrap_key(key).ok())
+            .ok_or_else(|| {
+                SamplyBeamError::SignEncryptError(format!(
+                    "Decryption error: No encrypted key for this client's certificate (serial {})",
+                    my_priv_key.key_id().unwrap_or("unknown")
+                ))
+            })
+    }
+
+    fn decrypt_content(&self, symmetric_key: &[u8]) -> Result<Vec<u8>, SamplyBeamError> {
+        match &self.aead {
+            AeadAlgorithm::XChaCha20Poly1305 => {
+                let cipher_engine =
+                    XChaCha20Poly1305::new_from_slice(symmetric_key).map_err(|e| {
+                        SamplyBeamError::SignEncryptError(format!(
+                            "Decryption error: Cannot initialize stream cipher because {}",
+                            e
+                        ))
+                    })?;
+                if self.encrypted.len() < 24 {
+                    return Err(SamplyBeamError::SignEncryptError(
+                        "Decryption error: Ciphertext is too short".into(),
+                    ));
+                }
+                let (nonce, ciphertext) = self.encrypted.split_at(24);
+                cipher_engine
+                    .decrypt(XNonce::from_slice(nonce), ciphertext)
+                    .map_err(|e| {
+                        SamplyBeamError::SignEncryptError(format!(
+                            "Decryption error: Cannot decrypt payload because {}",
+                            e
+                        ))
+                    })
+            }
+            AeadAlgorithm::Other(other) => Err(SamplyBeamError::SignEncryptError(format!(
+                "Decryption error: Unsupported AEAD algorithm {other}; please update this Beam.Proxy"
+            ))),
+        }
+    }
+}
+
+/// The decrypted body of a message, which may be text or binary data
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Default)]
+#[serde(try_from = "PlainJson", into = "PlainJson")]
 pub struct Plain {
-    pub body: Option<String>,
+    pub body: Option<Vec<u8>>,
+}
+
+/// JSON representation of [`Plain`]: Bodies that are not valid UTF-8 are given base64-encoded
+#[derive(Serialize, Deserialize)]
+struct PlainJson {
+    body: Option<String>,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    binary: bool,
+}
+
+impl TryFrom<PlainJson> for Plain {
+    type Error = String;
+
+    fn try_from(json: PlainJson) -> Result<Self, Self::Error> {
+        let body = match json.body {
+            Some(body) if json.binary => Some(
+                openssl::base64::decode_block(&body)
+                    .map_err(|_| "Binary body is not valid base64".to_string())?,
+            ),
+            body => body.map(String::into_bytes),
+        };
+        Ok(Plain { body })
+    }
+}
+
+impl From<Plain> for PlainJson {
+    fn from(plain: Plain) -> Self {
+        match plain.body.map(String::from_utf8) {
+            Some(Ok(text)) => PlainJson {
+                body: Some(text),
+                binary: false,
+            },
+            Some(Err(e)) => PlainJson {
+                body: Some(openssl::base64::encode_block(e.as_bytes())),
+                binary: true,
+            },
+            None => PlainJson {
+                body: None,
+                binary: false,
+            },
+        }
+    }
 }
 
+static EMPTY_PLAIN: Plain = Plain { body: None };
+
 impl MsgState for Plain {}
 
 impl<T: Into<String>> From<T> for Plain {
     fn from(val: T) -> Self {
         Plain {
-            body: Some(val.into()),
+            body: Some(val.into().into_bytes()),
         }
     }
 }
@@ -509,6 +795,10 @@ where
     pub to: Vec<AppOrProxyId>,
     #[serde(flatten)]
     pub body: State,
+    /// Bodies for individual recipients, each encrypted only for the recipient's proxy. Recipients
+    /// receive their own body in place of `body`.
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub bodies: HashMap<AppOrProxyId, State>,
     #[serde(with = "serialize_time", rename = "ttl")]
     pub expire: SystemTime,
     pub failure_strategy: FailureStrategy,
@@ -533,6 +823,7 @@ impl EncryptableMsg for MsgTaskRequest {
         } = self;
         Self::Output {
             body,
+            bodies: HashMap::new(),
             id,
             from,
             to,
@@ -551,7 +842,7 @@ impl EncryptableMsg for MsgTaskRequest {
 impl DecryptableMsg for MsgTaskRequest<Encrypted> {
     type Output = MsgTaskRequest;
 
-    fn convert_self(self, body: String) -> Self::Output {
+    fn convert_self(self, body: Plain) -> Self::Output {
         let Self {
             id,
             from,
@@ -562,7 +853,8 @@ impl DecryptableMsg for MsgTaskRequest<Encrypted> {
             ..
         } = self;
         Self::Output {
-            body: Plain::from(body),
+            body,
+            bodies: HashMap::new(),
             id,
             from,
             to,
@@ -599,7 +891,7 @@ where
 impl DecryptableMsg for MsgTaskResult<Encrypted> {
     type Output = MsgTaskResult;
 
-    fn convert_self(self, body: String) -> Self::Output {
+    fn convert_self(self, body: Plain) -> Self::Output {
         let Self {
             from,
             to,
@@ -609,7 +901,7 @@ impl DecryptableMsg for MsgTaskResult<Encrypted> {
             ..
         } = self;
         Self::Output {
-            body: Plain::from(body),
+            body,
             from,
             to,
             task,
@@ -650,6 +942,79 @@ impl EncryptableMsg for MsgTaskResult<Plain> {
     }
 }
 
+/// A request to open a socket connection to another app, see [`sockets`]. Its secret is the key
+/// for encrypting the connection, which is encrypted for the recipient like a task's body.
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct MsgSocketRequest<State = Plain>
+where
+    State: MsgState,
+{
+    pub id: MsgId,
+    pub from: AppOrProxyId,
+    pub to: Vec<AppOrProxyId>,
+    #[serde(flatten)]
+    pub secret: State,
+    #[serde(with = "serialize_time", rename = "ttl")]
+    pub expire: SystemTime,
+    pub metadata: Value,
+}
+
+pub type EncryptedMsgSocketRequest = MsgSocketRequest<Encrypted>;
+
+impl EncryptableMsg for MsgSocketRequest {
+    type Output = EncryptedMsgSocketRequest;
+
+    fn convert_self(self, secret: Encrypted) -> Self::Output {
+        let Self {
+            id,
+            from,
+            to,
+            expire,
+            metadata,
+            ..
+        } = self;
+        Self::Output {
+            id,
+            from,
+            to,
+            secret,
+            expire,
+            metadata,
+        }
+    }
+
+    fn get_plain(&self) -> &Plain {
+        &self.secret
+    }
+}
+
+impl DecryptableMsg for EncryptedMsgSocketRequest {
+    type Output = MsgSocketRequest;
+
+    fn convert_self(self, secret: Plain) -> Self::Output {
+        let Self {
+            id,
+            from,
+            to,
+            expire,
+            metadata,
+            ..
+        } = self;
+        Self::Output {
+            id,
+            from,
+            to,
+            secret,
+            expire,
+            metadata,
+        }
+    }
+
+    fn get_encryption(&self) -> &Encrypted {
+        &self.secret
+    }
+}
+
 pub trait HasWaitId<I: PartialEq> {
     fn wait_id(&self) -> I;
 }
@@ -666,6 +1031,17 @@ impl HasWaitId<String> for MsgTaskResult {
     }
 }
 
+impl EncryptedMsgTaskRequest {
+    /// Prepares the task for decryption on behalf of `recipient`: Its own body (see `bodies`), if
+    /// there is one, replaces the common body, and the other recipients' bodies are dropped.
+    pub fn select_body(&mut self, recipient: &AppOrProxyId) {
+        if let Some(body) = self.bodies.remove(recipient) {
+            self.body = body;
+        }
+        self.bodies.clear();
+    }
+}
+
 impl HasWaitId<MsgId> for EncryptedMsgTaskRequest {
     fn wait_id(&self) -> MsgId {
         self.id
@@ -694,6 +1070,26 @@ impl<T: MsgState> MsgTaskRequest<T> {
     }
 }
 impl MsgTaskRequest {
+    /// Encrypts the task like [`EncryptableMsg::encrypt`] and each of its `bodies` only for its
+    /// recipient, using the keys given per recipient
+    pub fn encrypt_with_bodies(
+        mut self,
+        receivers_public_keys: &[PublicKey],
+        recipients_public_keys: &HashMap<AppOrProxyId, Vec<PublicKey>>,
+    ) -> Result<EncryptedMsgTaskRequest, SamplyBeamError> {
+        let bodies = std::mem::take(&mut self.bodies);
+        let mut task = self.encrypt(receivers_public_keys)?;
+        for (recipient, body) in bodies {
+            let keys = recipients_public_keys.get(&recipient).ok_or_else(|| {
+                SamplyBeamError::SignEncryptError(format!(
+                    "Encryption error: No key for the body of {recipient}"
+                ))
+            })?;
+            task.bodies.insert(recipient, Encrypted::new(&body, keys)?);
+        }
+        Ok(task)
+    }
+
     pub fn new(
         from: AppOrProxyId,
         to: Vec<AppOrProxyId>,
@@ -706,6 +1102,7 @@ impl MsgTaskRequest {
             from,
             to,
             body: body.into(),
+            bodies: HashMap::new(),
             failure_strategy,
             results: HashMap::new(),
             metadata,
@@ -722,6 +1119,7 @@ impl<T: MsgState> PartialEq for MsgTaskRequest<T> {
             && self.from == other.from
             && self.to == other.to
             && self.body == other.body
+            && self.bodies == other.bodies
             && self.failure_strategy == other.failure_strategy
             && self.results == other.results
             && self.metadata == other.metadata
@@ -779,7 +1177,10 @@ where
 
 #[cfg(test)]
 mod tests {
-    use crate::beam_id::BrokerId;
+    use crate::{
+        beam_id::BrokerId,
+        crypto_keys::{tests::generate_key, KeyType},
+    };
 
     use super::*;
 
@@ -798,6 +1199,7 @@ mod tests {
             from,
             to,
             body: "Testbody".into(),
+            bodies: HashMap::new(),
             expire: expiry,
             failure_strategy: failure,
             results: HashMap::new(),
@@ -805,14 +1207,10 @@ mod tests {
         };
 
         //Setup Keypairs
-        let mut rng = rand::thread_rng();
-        let rsa_length: usize = 2048;
-        let p1_private = RsaPrivateKey::new(&mut rng, rsa_length)
-            .expect("Failed to generate private key for proxy 1");
-        let p2_private = RsaPrivateKey::new(&mut rng, rsa_length)
-            .expect("Failed to generate private key for proxy 2");
-        let p1_public = RsaPublicKey::from(&p1_private);
-        let p2_public = RsaPublicKey::from(&p2_private);
+        let p1_private = generate_key(KeyType::Rsa);
+        let p2_private = generate_key(KeyType::Rsa);
+        let p1_public = p1_private.public_key().unwrap();
+        let p2_public = p2_private.public_key().unwrap();
 
         // Encrypt Message
         let receivers_public_keys = vec![p1_public, p2_public];
@@ -833,6 +1231,88 @@ mod tests {
         assert_eq!(msg, msg_p1_decr);
     }
 
+    #[test]
+    fn recipients_only_decrypt_their_own_bodies() {
+        AppId::set_broker_id("broker.samply.de".to_string());
+        let p1_id = AppOrProxyId::AppId(AppId::new("app.proxy1.broker.samply.de").unwrap());
+        let p2_id = AppOrProxyId::AppId(AppId::new("app.proxy2.broker.samply.de").unwrap());
+        let p3_id = AppOrProxyId::AppId(AppId::new("app.proxy3.broker.samply.de").unwrap());
+        let mut msg = MsgTaskRequest::new(
+            p1_id.clone(),
+            vec![p1_id.clone(), p2_id.clone(), p3_id.clone()],
+            "Common".into(),
+            FailureStrategy::Discard,
+            Value::Null,
+        );
+        msg.bodies.insert(p1_id.clone(), "Partition 1".into());
+        msg.bodies.insert(p2_id.clone(), "Partition 2".into());
+        let keys = [
+            generate_key(KeyType::P256),
+            generate_key(KeyType::Rsa),
+            generate_key(KeyType::Ed25519),
+        ];
+        let public = |key: &PrivateKey| key.public_key().unwrap();
+        let recipients_keys = HashMap::from([
+            (p1_id.clone(), vec![public(&keys[0])]),
+            (p2_id.clone(), vec![public(&keys[1])]),
+        ]);
+        let msg_encr = msg
+            .encrypt_with_bodies(&keys.iter().map(public).collect::<Vec<_>>(), &recipients_keys)
+            .unwrap();
+        // The bodies survive signing and verification as JSON
+        let msg_encr: EncryptedMsgTaskRequest =
+            serde_json::from_value(serde_json::to_value(&msg_encr).unwrap()).unwrap();
+        assert_eq!(msg_encr.bodies.len(), 2);
+
+        for (id, key, expected) in [
+            (&p1_id, &keys[0], "Partition 1"),
+            (&p2_id, &keys[1], "Partition 2"),
+            (&p3_id, &keys[2], "Common"),
+        ] {
+            let mut task = msg_encr.clone();
+            task.select_body(id);
+            let task = task.decrypt(id, key).unwrap();
+            assert_eq!(task.body.body.as_deref(), Some(expected.as_bytes()));
+            assert!(task.bodies.is_empty());
+        }
+        // Other recipients cannot decrypt a recipient's body
+        let mut task = msg_encr;
+        task.select_body(&p2_id);
+        assert!(task.decrypt(&p1_id, &keys[0]).is_err());
+    }
+
+    #[test]
+    fn socket_request_is_parsed_as_such() {
+        AppId::set_broker_id("broker.samply.de".to_string());
+        let p1_id = AppOrProxyId::AppId(AppId::new("app.proxy1.broker.samply.de").unwrap());
+        let p2_id = AppOrProxyId::AppId(AppId::new("app.proxy2.broker.samply.de").unwrap());
+        let msg = MsgSocketRequest {
+            id: MsgId::new(),
+            from: p1_id,
+            to: vec![p2_id.clone()],
+            secret: "Secret".into(),
+            expire: SystemTime::now() + Duration::from_secs(60),
+            metadata: Value::Null,
+        };
+        let p2_private = generate_key(KeyType::Ed25519);
+        let msg_encr = EncryptedMessage::MsgSocketRequest(
+            msg.clone()
+                .encrypt(&[p2_private.public_key().unwrap()])
+                .expect("Could not encrypt message"),
+        );
+
+        let json = serde_json::to_string(&msg_encr).unwrap();
+        let EncryptedMessage::MsgSocketRequest(parsed) = serde_json::from_str(&json).unwrap()
+        else {
+            panic!("Socket request has been parsed as another message type");
+        };
+        let decrypted = parsed
+            .decrypt(&p2_id, &p2_private)
+            .expect("Cannot decrypt message");
+        assert_eq!(decrypted.id, msg.id);
+        assert_eq!(decrypted.secret, msg.secret);
+    }
+
     #[test]
     fn encrypt_decrypt_result() {
         AppId::set_broker_id("broker.samply.de".to_string());
@@ -851,14 +1331,10 @@ mod tests {
         };
 
         //Setup Keypairs
-        let mut rng = rand::thread_rng();
-        let rsa_length: usize = 2048;
-        let p1_private = RsaPrivateKey::new(&mut rng, rsa_length)
-            .expect("Failed to generate private key for proxy 1");
-        let p2_private = RsaPrivateKey::new(&mut rng, rsa_length)
-            .expect("Failed to generate private key for proxy 2");
-        let p1_public = RsaPublicKey::from(&p1_private);
-        let p2_public = RsaPublicKey::from(&p2_private);
+        let p1_private = generate_key(KeyType::Rsa);
+        let p2_private = generate_key(KeyType::Rsa);
+        let p1_public = p1_private.public_key().unwrap();
+        let p2_public = p2_private.public_key().unwrap();
 
         // Encrypt Message
         let receivers_public_keys = vec![p1_public, p2_public];
@@ -879,6 +1355,244 @@ mod tests {
         assert_eq!(msg_p1_decr, msg_p2_decr);
         assert_eq!(msg, msg_p1_decr);
     }
+
+    fn encrypted_test_result(
+        key_type: KeyType,
+    ) -> (
+        AppOrProxyId,
+        PrivateKey,
+        MsgTaskResult,
+        MsgTaskResult<Encrypted>,
+    ) {
+        AppId::set_broker_id("broker.samply.de".to_string());
+        let id = AppOrProxyId::AppId(AppId::new("app.proxy1.broker.samply.de").unwrap());
+        let private_key = generate_key(key_type);
+        let msg = MsgTaskResult {
+            from: id.clone(),
+            to: vec![id.clone()],
+            task: MsgId::new(),
+            status: WorkStatus::Succeeded,
+            body: "The result is 55!".into(),
+            metadata: "".into(),
+        };
+        let msg_encr = msg
+            .clone()
+            .encrypt(&[private_key.public_key().unwrap()])
+            .expect("Could not encrypt message");
+        (id, private_key, msg, msg_encr)
+    }
+
+    #[test]
+    fn decrypt_legacy_envelope() {
+        let (id, private_key, msg, msg_encr) = encrypted_test_result(KeyType::Rsa);
+        // Rewrite into the format used before envelope versioning
+        let mut json = serde_json::to_value(msg_encr).unwrap();
+        let fields = json.as_object_mut().unwrap();
+        fields.remove("envelope");
+        fields.remove("aead");
+        let keys = fields["encryption_keys"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|wrapped| wrapped["key"].clone())
+            .collect();
+        fields["encryption_keys"] = Value::Array(keys);
+
+        let legacy: MsgTaskResult<Encrypted> = serde_json::from_value(json).unwrap();
+        assert_eq!(legacy.body.envelope, 0);
+        assert!(matches!(
+            legacy.body.encryption_keys[0],
+            WrappedKey::Legacy(_)
+        ));
+        let msg_decr = legacy
+            .decrypt(&id, &private_key)
+            .expect("Cannot decrypt legacy message");
+        assert_eq!(msg, msg_decr);
+    }
+
+    #[test]
+    fn unknown_envelope_is_passed_on_but_not_decrypted() {
+        let (id, private_key, _, msg_encr) = encrypted_test_result(KeyType::P256);
+        let mut json = serde_json::to_value(msg_encr).unwrap();
+        json["envelope"] = json!(99);
+        json["aead"] = json!("some-future-aead");
+        json["encryption_keys"][0]["alg"] = json!("some-future-kem");
+
+        let future: MsgTaskResult<Encrypted> = serde_json::from_value(json.clone()).unwrap();
+        assert_eq!(serde_json::to_value(&future).unwrap(), json);
+        assert!(future.decrypt(&id, &private_key).is_err());
+    }
+
+    #[test]
+    fn decrypt_selects_key_by_kid() {
+        AppId::set_broker_id("broker.samply.de".to_string());
+        let app1_proxy1 = AppOrProxyId::AppId(AppId::new("app1.proxy1.broker.samply.de").unwrap());
+        let app2_proxy1 = AppOrProxyId::AppId(AppId::new("app2.proxy1.broker.samply.de").unwrap());
+        let app_proxy2 = AppOrProxyId::AppId(AppId::new("app.proxy2.broker.samply.de").unwrap());
+        let proxy1_key = generate_key(KeyType::P256).with_key_id("01");
+        let proxy2_key = generate_key(KeyType::P256).with_key_id("02");
+        // One wrapped key per proxy, although proxy1 hosts two recipients
+        let public_keys = vec![
+            proxy1_key.public_key().unwrap().with_key_id("01"),
+            proxy2_key.public_key().unwrap().with_key_id("02"),
+        ];
+        let msg = MsgTaskResult {
+            from: app_proxy2.clone(),
+            to: vec![app1_proxy1.clone(), app2_proxy1.clone(), app_proxy2.clone()],
+            task: MsgId::new(),
+            status: WorkStatus::Succeeded,
+            body: "The result is 55!".into(),
+            metadata: "".into(),
+        };
+        let msg_encr = msg.clone().encrypt(&public_keys).unwrap();
+        assert_eq!(msg_encr.body.encryption_keys.len(), 2);
+
+        for (id, key) in [
+            (&app1_proxy1, &proxy1_key),
+            (&app2_proxy1, &proxy1_key),
+            (&app_proxy2, &proxy2_key),
+        ] {
+            let msg_decr = msg_encr
+                .clone()
+                .decrypt(id, key)
+                .expect("Cannot decrypt message");
+            assert_eq!(msg, msg_decr);
+        }
+    }
+
+    #[test]
+    fn encrypt_decrypt_mixed_key_types() {
+        AppId::set_broker_id("broker.samply.de".to_string());
+        let key_types = [KeyType::Rsa, KeyType::P256, KeyType::P384, KeyType::Ed25519];
+        let ids: Vec<_> = (1..=key_types.len())
+            .map(|i| {
+                AppOrProxyId::AppId(AppId::new(&format!("app.proxy{i}.broker.samply.de")).unwrap())
+            })
+            .collect();
+        let private_keys: Vec<_> = key_types.into_iter().map(generate_key).collect();
+        let public_keys: Vec<_> = private_keys
+            .iter()
+            .map(|key| key.public_key().unwrap())
+            .collect();
+        let msg = MsgTaskResult {
+            from: ids[0].clone(),
+            to: ids.clone(),
+            task: MsgId::new(),
+            status: WorkStatus::Succeeded,
+            body: "The result is 55!".into(),
+            metadata: "".into(),
+        };
+
+        let msg_encr = msg
+            .clone()
+            .encrypt(&public_keys)
+            .expect("Could not encrypt message");
+        for (id, private_key) in ids.iter().zip(&private_keys) {
+            let msg_decr = msg_encr
+                .clone()
+                .decrypt(id, private_key)
+                .expect("Cannot decrypt message");
+            assert_eq!(msg, msg_decr);
+        }
+    }
+
+    #[test]
+    fn binary_body_passes_unchanged() {
+        AppId::set_broker_id("broker.samply.de".to_string());
+        let id = AppOrProxyId::AppId(AppId::new("app.proxy1.broker.samply.de").unwrap());
+        let private_key = generate_key(KeyType::P256);
+        let binary = vec![0x00, 0xff, 0xfe, 0x80, b'a'];
+        let msg = MsgTaskResult {
+            from: id.clone(),
+            to: vec![id.clone()],
+            task: MsgId::new(),
+            status: WorkStatus::Succeeded,
+            body: Plain {
+                body: Some(binary.clone()),
+            },
+            metadata: Value::Null,
+        };
+        let msg_decr = msg
+            .clone()
+            .encrypt(&[private_key.public_key().unwrap()])
+            .unwrap()
+            .decrypt(&id, &private_key)
+            .expect("Cannot decrypt binary body");
+        assert_eq!(msg_decr.body.body.as_deref(), Some(binary.as_slice()));
+
+        // Binary bodies are given base64-encoded in JSON, text bodies as they are
+        let json = serde_json::to_value(&msg_decr).unwrap();
+        assert_eq!(json["binary"], json!(true));
+        let parsed: MsgTaskResult = serde_json::from_value(json).unwrap();
+        assert_eq!(parsed, msg);
+        let text = serde_json::to_value(MsgTaskResult {
+            body: "Text".into(),
+            ..msg
+        })
+        .unwrap();
+        assert_eq!(text["body"], json!("Text"));
+        assert!(text.get("binary").is_none());
+    }
+
+    #[test]
+    fn large_bodies_are_compressed() {
+        AppId::set_broker_id("broker.samply.de".to_string());
+        let id = AppOrProxyId::AppId(AppId::new("app.proxy1.broker.samply.de").unwrap());
+        let private_key = generate_key(KeyType::P256);
+        let body = r#"{"resourceType":"Observation","status":"final"}"#.repeat(200);
+        let msg = MsgTaskResult {
+            from: id.clone(),
+            to: vec![id.clone()],
+            task: MsgId::new(),
+            status: WorkStatus::Succeeded,
+            body: body.as_str().into(),
+            metadata: Value::Null,
+        };
+        let msg_encr = msg
+            .encrypt(&[private_key.public_key().unwrap()])
+            .unwrap();
+        assert_eq!(
+            msg_encr.body.compression,
+            Some(compression::Compression::Zstd)
+        );
+        assert!(msg_encr.body.encrypted.len() < body.len() / 4);
+        let msg_decr = msg_encr.decrypt(&id, &private_key).unwrap();
+        assert_eq!(msg_decr.body.body.as_deref(), Some(body.as_bytes()));
+    }
+
+    #[test]
+    fn running_status_carries_progress() {
+        AppId::set_broker_id("broker.samply.de".to_string());
+        let id = AppOrProxyId::AppId(AppId::new("app.proxy1.broker.samply.de").unwrap());
+        let msg = MsgTaskResult {
+            from: id.clone(),
+            to: vec![id],
+            task: MsgId::new(),
+            status: WorkStatus::Running {
+                progress: Some(Progress {
+                    percent: Some(42),
+                    stage: Some("querying".into()),
+                    message: None,
+                }),
+            },
+            body: Plain::from(""),
+            metadata: Value::Null,
+        };
+        let json = serde_json::to_value(&msg).unwrap();
+        assert_eq!(json["status"], "running");
+        assert_eq!(
+            json["progress"],
+            json!({"percent": 42, "stage": "querying"})
+        );
+        let parsed: MsgTaskResult = serde_json::from_value(json).unwrap();
+        assert_eq!(parsed.status, msg.status);
+        assert!(!parsed.status.is_final());
+        assert_eq!(parsed.status.to_string(), "Running 42% (querying)");
+
+        let parsed: WorkStatus = serde_json::from_value(json!({"status": "running"})).unwrap();
+        assert_eq!(parsed, WorkStatus::Running { progress: None });
+        assert!(WorkStatus::PermFailed.is_final());
+    }
 }
 
 impl<T: MsgState + Debug> Debug for MsgTaskRequest<T> {
@@ -888,6 +1602,7 @@ impl<T: MsgState + Debug> Debug for MsgTaskRequest<T> {
             .field("from", &self.from)
             .field("to", &self.to)
             .field("body", &self.body)
+            .field("bodies", &self.bodies)
             .field("expire", &self.expire)
             .field("failure_strategy", &self.failure_strategy)
             .field("metadata", &self.metadata)
@@ -895,6 +1610,19 @@ impl<T: MsgState + Debug> Debug for MsgTaskRequest<T> {
     }
 }
 
+// The secret is left out, as it is the key of the socket connection once decrypted
+impl<T: MsgState> Debug for MsgSocketRequest<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("MsgSocketRequest")
+            .field("id", &self.id)
+            .field("from", &self.from)
+            .field("to", &self.to)
+            .field("expire", &self.expire)
+            .field("metadata", &self.metadata)
+            .finish()
+    }
+}
+
 impl<T: MsgState + Debug> Debug for MsgTaskResult<T> {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         f.debug_struct("EncryptedMsgTaskResult")
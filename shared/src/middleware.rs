@@ -1,6 +1,8 @@
 use std::{
     cell::RefCell,
+    fmt::Display,
     net::{IpAddr, SocketAddr},
+    str::FromStr,
     sync::Arc,
 };
 
@@ -18,7 +20,7 @@ use hyper::Body;
 use tokio::sync::{oneshot, Mutex};
 use tracing::{info, instrument, span, warn, Level};
 
-use crate::beam_id::AppOrProxyId;
+use crate::{beam_id::AppOrProxyId, config, errors::SamplyBeamError};
 
 const X_FORWARDED_FOR: HeaderName = HeaderName::from_static("x-forwarded-for");
 
@@ -80,7 +82,7 @@ pub async fn log(
 ) -> Response {
     let method = req.method().clone();
     let uri = req.uri().clone();
-    let ip = get_ip(&req, &info);
+    let ip = get_ip(&req, &info, &config::CONFIG_SHARED.trusted_proxies);
 
     let mut info = LoggingInfo::new(method, uri, ip);
     // This channel may or may not recieve an AppOrProxyId from verify_with_extended_header
@@ -103,11 +105,143 @@ pub async fn log(
     resp
 }
 
-fn get_ip(req: &Request<Body>, info: &SocketAddr) -> IpAddr {
-    req.headers()
-        .get(X_FORWARDED_FOR)
-        .and_then(|v| v.to_str().ok())
-        .and_then(|v| v.split(',').next())
-        .and_then(|v| v.parse().ok())
-        .unwrap_or(info.ip())
+/// Determines the client's IP address. X-Forwarded-For is only followed as long as the
+/// connecting peer (and the hops it reports) are trusted reverse proxies.
+fn get_ip(req: &Request<Body>, info: &SocketAddr, trusted_proxies: &[IpNet]) -> IpAddr {
+    let mut ip = info.ip().to_canonical();
+    let forwarded: Vec<&str> = req
+        .headers()
+        .get_all(X_FORWARDED_FOR)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .map(str::trim)
+        .collect();
+    for hop in forwarded.into_iter().rev() {
+        if !trusted_proxies.iter().any(|net| net.contains(&ip)) {
+            break;
+        }
+        match hop.parse::<IpAddr>() {
+            Ok(hop) => ip = hop.to_canonical(),
+            Err(_) => break,
+        }
+    }
+    ip
+}
+
+/// An IP address or network in CIDR notation, e.g. `10.0.0.0/8`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IpNet {
+    addr: IpAddr,
+    prefix_len: u8,
+}
+
+impl IpNet {
+    pub fn contains(&self, ip: &IpAddr) -> bool {
+        match (self.addr, ip) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                let mask = u32::MAX
+                    .checked_shl(32 - self.prefix_len as u32)
+                    .unwrap_or(0);
+                u32::from(net) & mask == u32::from(*ip) & mask
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                let mask = u128::MAX
+                    .checked_shl(128 - self.prefix_len as u32)
+                    .unwrap_or(0);
+                u128::from(net) & mask == u128::from(*ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for IpNet {
+    type Err = SamplyBeamError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let err =
+            || SamplyBeamError::ConfigurationFailed(format!("Invalid IP address or network: {s}"));
+        let (addr, prefix_len) = match s.split_once('/') {
+            Some((addr, prefix_len)) => (addr, Some(prefix_len)),
+            None => (s, None),
+        };
+        let addr: IpAddr = addr.trim().parse().map_err(|_| err())?;
+        let max_len = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix_len = match prefix_len {
+            Some(len) => len.trim().parse().map_err(|_| err())?,
+            None => max_len,
+        };
+        if prefix_len > max_len {
+            return Err(err());
+        }
+        Ok(Self {
+            addr: addr.to_canonical(),
+            prefix_len,
+        })
+    }
+}
+
+impl Display for IpNet {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix_len)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn forwarded_for_is_only_trusted_from_trusted_proxies() {
+        let trusted: Vec<IpNet> = ["10.0.0.0/8", "192.168.1.1", "fd00::/8"]
+            .iter()
+            .map(|net| net.parse().unwrap())
+            .collect();
+        let ip = |ip: &str| ip.parse::<IpAddr>().unwrap();
+        let peer = |ip: &str| SocketAddr::new(ip.parse().unwrap(), 443);
+        let req = |forwarded_for: &str| {
+            Request::builder()
+                .header(X_FORWARDED_FOR, forwarded_for)
+                .body(Body::empty())
+                .unwrap()
+        };
+
+        // Untrusted peers cannot fake their address
+        assert_eq!(
+            get_ip(&req("1.2.3.4"), &peer("5.6.7.8"), &trusted),
+            ip("5.6.7.8")
+        );
+        // Trusted proxies report the client
+        assert_eq!(
+            get_ip(&req("1.2.3.4"), &peer("10.1.2.3"), &trusted),
+            ip("1.2.3.4")
+        );
+        assert_eq!(
+            get_ip(&req("1.2.3.4"), &peer("fd12::1"), &trusted),
+            ip("1.2.3.4")
+        );
+        // Only hops added by trusted proxies count
+        assert_eq!(
+            get_ip(
+                &req("6.6.6.6, 1.2.3.4, 192.168.1.1"),
+                &peer("10.1.2.3"),
+                &trusted
+            ),
+            ip("1.2.3.4")
+        );
+        assert_eq!(
+            get_ip(&req("1.2.3.4"), &peer("192.168.1.2"), &trusted),
+            ip("192.168.1.2")
+        );
+        assert_eq!(
+            get_ip(&req("1.2.3.4"), &peer("10.1.2.3"), &[]),
+            ip("10.1.2.3")
+        );
+        assert!("10.0.0.0/33".parse::<IpNet>().is_err());
+        assert!("0.0.0.0/0"
+            .parse::<IpNet>()
+            .unwrap()
+            .contains(&ip("1.2.3.4")));
+    }
 }
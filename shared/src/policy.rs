@@ -0,0 +1,268 @@
+//! Authorization policies, specifying which Beam IDs may communicate with each other
+
+use std::{collections::HashMap, fmt::Display, path::Path};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    beam_id::{AppId, AppOrProxyId, BeamId, ProxyId},
+    errors::SamplyBeamError,
+};
+
+/// A pattern matching Beam IDs label by label, where `*` matches any single label.
+/// E.g. `*.proxy2.broker.example.de` matches all apps of proxy2, and `*` matches any Beam ID.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct BeamIdPattern(String);
+
+impl BeamIdPattern {
+    const WILDCARD: &'static str = "*";
+
+    pub fn matches(&self, id: &AppOrProxyId) -> bool {
+        if self.0 == Self::WILDCARD {
+            return true;
+        }
+        let mut labels = id.value().split('.');
+        let mut pattern = self.0.split('.');
+        loop {
+            match (pattern.next(), labels.next()) {
+                (None, None) => return true,
+                (Some(p), Some(l)) if p == Self::WILDCARD || p == l => continue,
+                _ => return false,
+            }
+        }
+    }
+
+    /// Whether any of the patterns matches the given Beam ID
+    pub fn any_matches(patterns: &[BeamIdPattern], id: &AppOrProxyId) -> bool {
+        patterns.iter().any(|p| p.matches(id))
+    }
+}
+
+impl TryFrom<String> for BeamIdPattern {
+    type Error = SamplyBeamError;
+
+    fn try_from(pattern: String) -> Result<Self, Self::Error> {
+        if pattern.split('.').any(str::is_empty) {
+            return Err(SamplyBeamError::ConfigurationFailed(format!(
+                "Invalid Beam ID pattern \"{pattern}\": Contains empty labels"
+            )));
+        }
+        Ok(Self(pattern))
+    }
+}
+
+impl From<BeamIdPattern> for String {
+    fn from(pattern: BeamIdPattern) -> Self {
+        pattern.0
+    }
+}
+
+impl Display for BeamIdPattern {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+/// Which remote apps/proxies a local app may communicate with
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AppPolicy {
+    /// Recipients the app may send tasks to
+    #[serde(default)]
+    pub send_to: Vec<BeamIdPattern>,
+    /// Senders the app accepts tasks from (and may return results to)
+    #[serde(default)]
+    pub receive_from: Vec<BeamIdPattern>,
+}
+
+/// On-disk representation of the proxy's policy file, with apps identified by their short name
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ProxyPolicyFile {
+    #[serde(default)]
+    apps: HashMap<String, AppPolicy>,
+    default: Option<AppPolicy>,
+}
+
+/// Per-app authorization policy of a proxy. Apps not listed in the policy fall back to the
+/// default policy; without a default policy, they may neither send nor receive tasks.
+#[derive(Debug, Clone, Default)]
+pub struct ProxyPolicy {
+    apps: HashMap<AppId, AppPolicy>,
+    default: AppPolicy,
+}
+
+impl ProxyPolicy {
+    pub fn load(file: &Path, proxy_id: &ProxyId) -> Result<Self, SamplyBeamError> {
+        load_policy_file(file, |content| Self::parse(content, proxy_id))
+    }
+
+    fn parse(content: &str, proxy_id: &ProxyId) -> Result<Self, SamplyBeamError> {
+        let file: ProxyPolicyFile = serde_json::from_str(content)
+            .map_err(|e| SamplyBeamError::ConfigurationFailed(e.to_string()))?;
+        let apps = file
+            .apps
+            .into_iter()
+            .map(|(app, policy)| Ok((AppId::new(&format!("{app}.{proxy_id}"))?, policy)))
+            .collect::<Result<_, SamplyBeamError>>()?;
+        Ok(Self {
+            apps,
+            default: file.default.unwrap_or_default(),
+        })
+    }
+
+    fn policy_for(&self, app: &AppId) -> &AppPolicy {
+        self.apps.get(app).unwrap_or(&self.default)
+    }
+
+    /// Whether the local `app` may send tasks to `recipient`
+    pub fn may_send_to(&self, app: &AppId, recipient: &AppOrProxyId) -> bool {
+        BeamIdPattern::any_matches(&self.policy_for(app).send_to, recipient)
+    }
+
+    /// Whether the local `app` accepts tasks from (and may return results to) `sender`
+    pub fn may_receive_from(&self, app: &AppId, sender: &AppOrProxyId) -> bool {
+        BeamIdPattern::any_matches(&self.policy_for(app).receive_from, sender)
+    }
+}
+
+/// A rule of the [`RoutingPolicy`], allowing the senders matching `from` to address the recipients matching `to`
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RoutingRule {
+    pub from: Vec<BeamIdPattern>,
+    pub to: Vec<BeamIdPattern>,
+}
+
+/// Broker policy specifying which proxies/apps may address which others.
+/// Anything not allowed by one of its rules is denied.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RoutingPolicy {
+    #[serde(default)]
+    pub rules: Vec<RoutingRule>,
+}
+
+impl RoutingPolicy {
+    pub fn load(file: &Path) -> Result<Self, SamplyBeamError> {
+        load_policy_file(file, |content| {
+            serde_json::from_str(content)
+                .map_err(|e| SamplyBeamError::ConfigurationFailed(e.to_string()))
+        })
+    }
+
+    pub fn may_address(&self, from: &AppOrProxyId, to: &AppOrProxyId) -> bool {
+        self.rules.iter().any(|rule| {
+            BeamIdPattern::any_matches(&rule.from, from) && BeamIdPattern::any_matches(&rule.to, to)
+        })
+    }
+
+    /// The recipients that `from` may not address
+    pub fn denied_recipients<'a>(
+        &self,
+        from: &AppOrProxyId,
+        to: impl IntoIterator<Item = &'a AppOrProxyId>,
+    ) -> Vec<AppOrProxyId> {
+        to.into_iter()
+            .filter(|recipient| !self.may_address(from, recipient))
+            .cloned()
+            .collect()
+    }
+}
+
+fn load_policy_file<T>(
+    file: &Path,
+    parse: impl FnOnce(&str) -> Result<T, SamplyBeamError>,
+) -> Result<T, SamplyBeamError> {
+    let content = std::fs::read_to_string(file).map_err(|e| {
+        SamplyBeamError::ConfigurationFailed(format!(
+            "Unable to read policy file {}: {e}",
+            file.to_string_lossy()
+        ))
+    })?;
+    parse(&content).map_err(|e| {
+        SamplyBeamError::ConfigurationFailed(format!(
+            "Unable to parse policy file {}: {e}",
+            file.to_string_lossy()
+        ))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::beam_id::BrokerId;
+
+    const BROKER_ID: &str = "broker.samply.de";
+
+    fn id(id: &str) -> AppOrProxyId {
+        AppOrProxyId::new(&format!("{id}.{BROKER_ID}")).unwrap()
+    }
+
+    fn pattern(pattern: &str) -> BeamIdPattern {
+        BeamIdPattern::try_from(pattern.to_string()).unwrap()
+    }
+
+    #[test]
+    fn patterns_match_by_label() {
+        BrokerId::set_broker_id(BROKER_ID.to_string());
+        assert!(pattern("*").matches(&id("app1.proxy1")));
+        assert!(pattern("*").matches(&id("proxy1")));
+        assert!(pattern(&format!("*.proxy1.{BROKER_ID}")).matches(&id("app1.proxy1")));
+        assert!(!pattern(&format!("*.proxy1.{BROKER_ID}")).matches(&id("app1.proxy2")));
+        assert!(!pattern(&format!("*.proxy1.{BROKER_ID}")).matches(&id("proxy1")));
+        assert!(pattern(&format!("app1.*.{BROKER_ID}")).matches(&id("app1.proxy2")));
+        assert!(pattern(&format!("proxy1.{BROKER_ID}")).matches(&id("proxy1")));
+        assert!(!pattern(&format!("proxy1.{BROKER_ID}")).matches(&id("app1.proxy1")));
+        assert!(BeamIdPattern::try_from("app1..broker".to_string()).is_err());
+    }
+
+    #[test]
+    fn proxy_policy_falls_back_to_default() {
+        BrokerId::set_broker_id(BROKER_ID.to_string());
+        let proxy_id = ProxyId::new(&format!("proxy1.{BROKER_ID}")).unwrap();
+        let app = |name: &str| AppId::new(&format!("{name}.{proxy_id}")).unwrap();
+        let policy = ProxyPolicy::parse(
+            &format!(
+                r#"{{
+                    "apps": {{ "app1": {{ "send_to": ["*.proxy2.{BROKER_ID}"], "receive_from": ["*"] }} }},
+                    "default": {{ "receive_from": ["app9.proxy3.{BROKER_ID}"] }}
+                }}"#
+            ),
+            &proxy_id,
+        )
+        .unwrap();
+        assert!(policy.may_send_to(&app("app1"), &id("app7.proxy2")));
+        assert!(!policy.may_send_to(&app("app1"), &id("app7.proxy3")));
+        assert!(policy.may_receive_from(&app("app1"), &id("app7.proxy3")));
+        assert!(!policy.may_send_to(&app("app2"), &id("app7.proxy2")));
+        assert!(policy.may_receive_from(&app("app2"), &id("app9.proxy3")));
+        assert!(!policy.may_receive_from(&app("app2"), &id("app7.proxy3")));
+        assert!(!ProxyPolicy::default().may_send_to(&app("app1"), &id("app7.proxy2")));
+    }
+
+    #[test]
+    fn routing_policy_allows_listed_routes_only() {
+        BrokerId::set_broker_id(BROKER_ID.to_string());
+        let policy: RoutingPolicy = serde_json::from_str(&format!(
+            r#"{{ "rules": [
+                {{ "from": ["*.proxy1.{BROKER_ID}", "proxy1.{BROKER_ID}"], "to": ["*.proxy2.{BROKER_ID}"] }},
+                {{ "from": ["*"], "to": ["app1.proxy3.{BROKER_ID}"] }}
+            ] }}"#
+        ))
+        .unwrap();
+        assert!(policy.may_address(&id("app1.proxy1"), &id("app5.proxy2")));
+        assert!(policy.may_address(&id("proxy1"), &id("app5.proxy2")));
+        assert!(!policy.may_address(&id("app5.proxy2"), &id("app1.proxy1")));
+        assert!(policy.may_address(&id("app5.proxy2"), &id("app1.proxy3")));
+        assert_eq!(
+            policy.denied_recipients(
+                &id("app1.proxy1"),
+                &[id("app5.proxy2"), id("app2.proxy3"), id("app1.proxy3")]
+            ),
+            vec![id("app2.proxy3")]
+        );
+        assert!(!RoutingPolicy::default().may_address(&id("app1.proxy1"), &id("app5.proxy2")));
+    }
+}
@@ -0,0 +1,83 @@
+use std::{
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+
+use tokio::sync::RwLock;
+use tracing::{error, info};
+
+use crate::errors::SamplyBeamError;
+
+/// How often [`reload_on_file_change`] checks the file for modifications
+const FILE_CHECK_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Reloads `target` using `load` (e.g. from a configuration file) whenever the process receives SIGHUP.
+/// If loading fails, the previous value is kept.
+#[cfg(unix)]
+pub fn reload_on_sighup<T, F>(what: &'static str, target: Arc<RwLock<T>>, load: F)
+where
+    T: Send + Sync + 'static,
+    F: Fn() -> Result<T, SamplyBeamError> + Send + Sync + 'static,
+{
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut sighup = signal(SignalKind::hangup())
+        .expect("Unable to register reload handler; are you running a Unix-based OS?");
+    tokio::task::spawn(async move {
+        while sighup.recv().await.is_some() {
+            reload(what, "Received SIGHUP", &target, &load).await;
+        }
+    });
+}
+
+#[cfg(windows)]
+pub fn reload_on_sighup<T, F>(what: &'static str, _target: Arc<RwLock<T>>, _load: F)
+where
+    T: Send + Sync + 'static,
+    F: Fn() -> Result<T, SamplyBeamError> + Send + Sync + 'static,
+{
+    info!("Reloading {what} on SIGHUP is not supported on this platform.");
+}
+
+/// Reloads `target` using `load` whenever the modification time of `file` changes.
+/// If loading fails, the previous value is kept.
+pub fn reload_on_file_change<T, F>(
+    what: &'static str,
+    file: PathBuf,
+    target: Arc<RwLock<T>>,
+    load: F,
+) where
+    T: Send + Sync + 'static,
+    F: Fn() -> Result<T, SamplyBeamError> + Send + Sync + 'static,
+{
+    tokio::task::spawn(async move {
+        let mut last_modified = modified(&file);
+        let mut interval = tokio::time::interval(FILE_CHECK_INTERVAL);
+        loop {
+            interval.tick().await;
+            let modified = modified(&file);
+            if modified != last_modified {
+                last_modified = modified;
+                reload(what, "File changed", &target, &load).await;
+            }
+        }
+    });
+}
+
+fn modified(file: &Path) -> Option<SystemTime> {
+    std::fs::metadata(file).and_then(|m| m.modified()).ok()
+}
+
+async fn reload<T, F>(what: &str, trigger: &str, target: &RwLock<T>, load: &F)
+where
+    F: Fn() -> Result<T, SamplyBeamError>,
+{
+    match load() {
+        Ok(value) => {
+            *target.write().await = value;
+            info!("{trigger}: Reloaded {what}.");
+        }
+        Err(e) => error!("{trigger}: Unable to reload {what}, keeping the previous one: {e}"),
+    }
+}
@@ -0,0 +1,297 @@
+//! Direct socket connections between apps, tunneled through the Broker.
+//!
+//! The initiating Proxy announces a connection with a [`MsgSocketRequest`](crate::MsgSocketRequest)
+//! carrying a random key, which is encrypted for the recipient's Proxy like a task's body. Both
+//! Proxies then connect to the Broker, which relays the bytes between them. As all connections are
+//! outbound from the Proxies' point of view, no ports need to be opened at the sites.
+//!
+//! Through the tunnel, data is sent in chunks, each prefixed by its length and encrypted with
+//! XChaCha20Poly1305 using a counter as nonce, so chunks cannot be reordered or replayed. An
+//! encrypted empty chunk marks the end of the stream, so truncation is detected. Chunks of length
+//! 0 are keep-alives, which prevent idle connections from being closed by read timeouts.
+
+use std::future::Future;
+
+use axum::{
+    async_trait,
+    extract::FromRequestParts,
+    http::request::Parts,
+    response::{IntoResponse, Response as AxumResponse},
+};
+use chacha20poly1305::{aead::Aead, KeyInit, XChaCha20Poly1305, XNonce};
+use hkdf::Hkdf;
+use hyper::{
+    header::{self, HeaderValue},
+    upgrade::{OnUpgrade, Upgraded},
+    Body, Request, StatusCode,
+};
+use openssl::base64;
+use rand::Rng;
+use sha2::Sha256;
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    time,
+};
+use tracing::{debug, warn};
+
+use crate::{errors::SamplyBeamError, http_client::SamplyHttpClient, websocket::PING_INTERVAL};
+
+/// Protocol name in the `Upgrade` header of requests opening a socket connection
+pub const UPGRADE_PROTOCOL: &str = "tcp";
+/// Plaintext is sent in chunks of at most this size
+const MAX_CHUNK_SIZE: usize = 64 * 1024;
+const TAG_SIZE: usize = 16;
+const KEY_SIZE: usize = 32;
+
+/// The end of a socket connection
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Side {
+    Initiator,
+    Recipient,
+}
+
+impl Side {
+    /// Each direction is encrypted with its own key, so nonces never repeat for a key
+    fn key_info(&self) -> &'static [u8] {
+        match self {
+            Side::Initiator => b"samply.beam socket initiator",
+            Side::Recipient => b"samply.beam socket recipient",
+        }
+    }
+
+    fn other(&self) -> Side {
+        match self {
+            Side::Initiator => Side::Recipient,
+            Side::Recipient => Side::Initiator,
+        }
+    }
+}
+
+/// Generates the secret of a new socket request
+pub fn generate_key() -> String {
+    base64::encode_block(&rand::thread_rng().gen::<[u8; KEY_SIZE]>())
+}
+
+fn cipher(key: &[u8], sending: Side) -> XChaCha20Poly1305 {
+    let mut derived = [0; KEY_SIZE];
+    Hkdf::<Sha256>::new(None, key)
+        .expand(sending.key_info(), &mut derived)
+        .expect("Output length to be valid for HKDF-SHA256");
+    XChaCha20Poly1305::new(&derived.into())
+}
+
+fn nonce(counter: u64) -> XNonce {
+    let mut nonce = XNonce::default();
+    nonce[16..].copy_from_slice(&counter.to_be_bytes());
+    nonce
+}
+
+fn invalid_data(msg: &'static str) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, msg)
+}
+
+/// Sends a keep-alive through a tunnel, e.g. while the Broker waits for its other end
+pub async fn keep_alive(io: &mut (impl AsyncWrite + Unpin)) -> std::io::Result<()> {
+    io.write_u32(0).await?;
+    io.flush().await
+}
+
+/// Relays the app's connection through the tunnel to the other Proxy, encrypting and decrypting
+/// with the socket request's secret, until both directions have ended
+pub async fn relay(
+    app: impl AsyncRead + AsyncWrite,
+    tunnel: impl AsyncRead + AsyncWrite,
+    secret: &str,
+    side: Side,
+) -> std::io::Result<()> {
+    let key = base64::decode_block(secret)
+        .ok()
+        .filter(|key| key.len() == KEY_SIZE)
+        .ok_or_else(|| invalid_data("Invalid socket key"))?;
+    let (app_rx, app_tx) = tokio::io::split(app);
+    let (tunnel_rx, tunnel_tx) = tokio::io::split(tunnel);
+    tokio::try_join!(
+        encrypt(app_rx, tunnel_tx, cipher(&key, side)),
+        decrypt(tunnel_rx, app_tx, cipher(&key, side.other()))
+    )?;
+    Ok(())
+}
+
+async fn encrypt(
+    mut plain: impl AsyncRead + Unpin,
+    mut tunnel: impl AsyncWrite + Unpin,
+    cipher: XChaCha20Poly1305,
+) -> std::io::Result<()> {
+    let mut buf = vec![0; MAX_CHUNK_SIZE];
+    let mut keep_alive = time::interval(PING_INTERVAL);
+    let mut counter = 0u64;
+    loop {
+        let n = tokio::select! {
+            n = plain.read(&mut buf) => n?,
+            _ = keep_alive.tick() => {
+                self::keep_alive(&mut tunnel).await?;
+                continue;
+            }
+        };
+        let chunk = cipher
+            .encrypt(&nonce(counter), &buf[..n])
+            .map_err(|_| invalid_data("Unable to encrypt chunk"))?;
+        counter += 1;
+        tunnel.write_u32(chunk.len() as u32).await?;
+        tunnel.write_all(&chunk).await?;
+        tunnel.flush().await?;
+        if n == 0 {
+            return tunnel.shutdown().await;
+        }
+    }
+}
+
+async fn decrypt(
+    mut tunnel: impl AsyncRead + Unpin,
+    mut plain: impl AsyncWrite + Unpin,
+    cipher: XChaCha20Poly1305,
+) -> std::io::Result<()> {
+    let mut counter = 0u64;
+    loop {
+        let len = tunnel.read_u32().await? as usize;
+        if len == 0 {
+            continue;
+        }
+        if !(TAG_SIZE..=MAX_CHUNK_SIZE + TAG_SIZE).contains(&len) {
+            return Err(invalid_data("Invalid chunk length"));
+        }
+        let mut chunk = vec![0; len];
+        tunnel.read_exact(&mut chunk).await?;
+        let data = cipher
+            .decrypt(&nonce(counter), chunk.as_slice())
+            .map_err(|_| invalid_data("Unable to decrypt chunk"))?;
+        counter += 1;
+        if data.is_empty() {
+            return plain.shutdown().await;
+        }
+        plain.write_all(&data).await?;
+        plain.flush().await?;
+    }
+}
+
+/// Whether the request asks to upgrade the connection to a socket connection
+fn is_upgrade_request(headers: &hyper::HeaderMap) -> bool {
+    headers.get(header::UPGRADE).is_some_and(|v| {
+        v.as_bytes()
+            .eq_ignore_ascii_case(UPGRADE_PROTOCOL.as_bytes())
+    })
+}
+
+/// The pending upgrade of a request opening a socket connection. Like
+/// [`websocket::Upgrade`](crate::websocket::Upgrade), it leaves the rest of the request to other
+/// extractors.
+pub struct SocketUpgrade(OnUpgrade);
+
+#[async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for SocketUpgrade {
+    type Rejection = AxumResponse;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        match parts.extensions.remove::<OnUpgrade>() {
+            Some(on_upgrade) if is_upgrade_request(&parts.headers) => Ok(Self(on_upgrade)),
+            _ => Err((
+                StatusCode::UPGRADE_REQUIRED,
+                [(header::UPGRADE, UPGRADE_PROTOCOL)],
+                "Please request a connection upgrade to open a socket.",
+            )
+                .into_response()),
+        }
+    }
+}
+
+impl SocketUpgrade {
+    /// Switches protocols and runs `handle` on the connection once it has been upgraded
+    pub fn accept<F, Fut>(self, handle: F) -> AxumResponse
+    where
+        F: FnOnce(Upgraded) -> Fut + Send + 'static,
+        Fut: Future<Output = ()> + Send,
+    {
+        tokio::task::spawn(async move {
+            match self.0.await {
+                Ok(io) => handle(io).await,
+                Err(e) => warn!("Unable to upgrade connection to socket: {e}"),
+            }
+        });
+        (
+            StatusCode::SWITCHING_PROTOCOLS,
+            [
+                (header::CONNECTION, "upgrade"),
+                (header::UPGRADE, UPGRADE_PROTOCOL),
+            ],
+        )
+            .into_response()
+    }
+}
+
+/// Opens a socket connection with the given request, e.g. a signed `GET` request to the Broker
+pub async fn connect(
+    client: &SamplyHttpClient,
+    mut req: Request<Body>,
+) -> Result<Upgraded, SamplyBeamError> {
+    let headers = req.headers_mut();
+    headers.insert(header::CONNECTION, HeaderValue::from_static("upgrade"));
+    headers.insert(header::UPGRADE, HeaderValue::from_static(UPGRADE_PROTOCOL));
+    let resp = client.request(req).await?;
+    if resp.status() != StatusCode::SWITCHING_PROTOCOLS {
+        return Err(SamplyBeamError::RequestValidationFailed(format!(
+            "Opening socket answered with status {}",
+            resp.status()
+        )));
+    }
+    let io = hyper::upgrade::on(resp).await?;
+    debug!("Socket connection established");
+    Ok(io)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn data_passes_encrypted_in_both_directions() {
+        let secret = generate_key();
+        let (app1, mut app1_peer) = tokio::io::duplex(1024);
+        let (app2, mut app2_peer) = tokio::io::duplex(1024);
+        let (tunnel1, tunnel2) = tokio::io::duplex(1024);
+        let secret2 = secret.clone();
+        let relay1 =
+            tokio::spawn(async move { relay(app1, tunnel1, &secret, Side::Initiator).await });
+        let relay2 =
+            tokio::spawn(async move { relay(app2, tunnel2, &secret2, Side::Recipient).await });
+
+        let long = vec![42; 3 * MAX_CHUNK_SIZE + 1];
+        let writer = tokio::spawn(async move {
+            app1_peer.write_all(&long).await.unwrap();
+            app1_peer.shutdown().await.unwrap();
+            let mut answer = Vec::new();
+            app1_peer.read_to_end(&mut answer).await.unwrap();
+            answer
+        });
+        let mut received = Vec::new();
+        app2_peer.read_to_end(&mut received).await.unwrap();
+        assert_eq!(received.len(), 3 * MAX_CHUNK_SIZE + 1);
+        app2_peer.write_all(b"thanks").await.unwrap();
+        app2_peer.shutdown().await.unwrap();
+
+        assert_eq!(writer.await.unwrap(), b"thanks");
+        relay1.await.unwrap().unwrap();
+        relay2.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn tampering_is_detected() {
+        let (app, _app_peer) = tokio::io::duplex(1024);
+        let (tunnel, mut tunnel_peer) = tokio::io::duplex(1024);
+        let relay =
+            tokio::spawn(async move { relay(app, tunnel, &generate_key(), Side::Recipient).await });
+        tunnel_peer.write_u32(TAG_SIZE as u32 + 3).await.unwrap();
+        tunnel_peer.write_all(&[0; TAG_SIZE + 3]).await.unwrap();
+        let err = relay.await.unwrap().unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+    }
+}
@@ -0,0 +1,299 @@
+//! Serving HTTPS, optionally authenticating clients by their certificates (mutual TLS)
+
+use std::{
+    convert::Infallible,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    pin::Pin,
+    sync::Arc,
+    time::Duration,
+};
+
+use axum::{extract::ConnectInfo, Extension, Router};
+use hyper::{server::accept, service::make_service_fn};
+use openssl::{
+    ssl::{Ssl, SslAcceptor, SslFiletype, SslMethod, SslVerifyMode},
+    x509::{X509Name, X509},
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    net::{TcpListener, TcpStream},
+    sync::{mpsc, RwLock},
+};
+use tokio_openssl::SslStream;
+use tower_layer::Layer;
+use tracing::debug;
+
+use crate::{errors::SamplyBeamError, reload};
+
+/// Clients taking longer to complete the TLS handshake are disconnected
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Clone, Debug)]
+pub struct TlsServerConfig {
+    /// Server certificate (chain), PEM-encoded
+    pub cert_file: PathBuf,
+    /// Server private key, PEM-encoded
+    pub key_file: PathBuf,
+    /// If set, clients may authenticate with a certificate issued by one of these CAs
+    pub client_ca_file: Option<PathBuf>,
+}
+
+impl TlsServerConfig {
+    pub fn acceptor(&self) -> Result<SslAcceptor, SamplyBeamError> {
+        let err = |file: &Path, e| {
+            SamplyBeamError::ConfigurationFailed(format!(
+                "Unable to load TLS configuration from {}: {e}",
+                file.to_string_lossy()
+            ))
+        };
+        let mut acceptor = SslAcceptor::mozilla_intermediate_v5(SslMethod::tls_server())?;
+        acceptor
+            .set_certificate_chain_file(&self.cert_file)
+            .map_err(|e| err(&self.cert_file, e))?;
+        acceptor
+            .set_private_key_file(&self.key_file, SslFiletype::PEM)
+            .map_err(|e| err(&self.key_file, e))?;
+        acceptor
+            .check_private_key()
+            .map_err(|e| err(&self.key_file, e))?;
+        if let Some(ca_file) = &self.client_ca_file {
+            acceptor.set_ca_file(ca_file).map_err(|e| err(ca_file, e))?;
+            acceptor.set_client_ca_list(
+                X509Name::load_client_ca_file(ca_file).map_err(|e| err(ca_file, e))?,
+            );
+            // Client certificates are optional, so clients can authenticate by other means
+            acceptor.set_verify(SslVerifyMode::PEER);
+        }
+        Ok(acceptor.build())
+    }
+
+    /// Like [`Self::acceptor`], but reloads the certificate and key when their files change or on SIGHUP
+    pub fn reloadable_acceptor(&self) -> Result<Arc<RwLock<SslAcceptor>>, SamplyBeamError> {
+        let acceptor = Arc::new(RwLock::new(self.acceptor()?));
+        let config = self.clone();
+        let load = move || config.acceptor();
+        reload::reload_on_sighup("TLS certificate", acceptor.clone(), load.clone());
+        for file in [&self.cert_file, &self.key_file] {
+            reload::reload_on_file_change(
+                "TLS certificate",
+                file.clone(),
+                acceptor.clone(),
+                load.clone(),
+            );
+        }
+        Ok(acceptor)
+    }
+}
+
+/// The verified certificate of a client, available as a request extension
+#[derive(Clone, Debug)]
+pub struct ClientCertificate(pub X509);
+
+impl ClientCertificate {
+    pub fn common_name(&self) -> Option<String> {
+        self.0
+            .subject_name()
+            .entries_by_nid(openssl::nid::Nid::COMMONNAME)
+            .next()
+            .and_then(|cn| cn.data().to_string().ok())
+    }
+}
+
+/// A TLS connection that has completed the handshake
+pub struct TlsConnection {
+    stream: SslStream<TcpStream>,
+    remote_addr: SocketAddr,
+}
+
+impl TlsConnection {
+    fn client_certificate(&self) -> Option<ClientCertificate> {
+        self.stream.ssl().peer_certificate().map(ClientCertificate)
+    }
+}
+
+impl AsyncRead for TlsConnection {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> std::task::Poll<std::io::Result<()>> {
+        Pin::new(&mut self.stream).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for TlsConnection {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        buf: &[u8],
+    ) -> std::task::Poll<std::io::Result<usize>> {
+        Pin::new(&mut self.stream).poll_write(cx, buf)
+    }
+
+    fn poll_flush(
+        mut self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<std::io::Result<()>> {
+        Pin::new(&mut self.stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(
+        mut self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<std::io::Result<()>> {
+        Pin::new(&mut self.stream).poll_shutdown(cx)
+    }
+}
+
+async fn handshake(
+    acceptor: &RwLock<SslAcceptor>,
+    tcp: TcpStream,
+    remote_addr: SocketAddr,
+) -> Result<TlsConnection, String> {
+    let ssl = Ssl::new(acceptor.read().await.context()).map_err(|e| e.to_string())?;
+    let mut stream = SslStream::new(ssl, tcp).map_err(|e| e.to_string())?;
+    tokio::time::timeout(HANDSHAKE_TIMEOUT, Pin::new(&mut stream).accept())
+        .await
+        .map_err(|_| "Timeout".to_string())?
+        .map_err(|e| e.to_string())?;
+    Ok(TlsConnection {
+        stream,
+        remote_addr,
+    })
+}
+
+/// Serves `app` via HTTPS until a shutdown signal is received. Like with
+/// [`Router::into_make_service_with_connect_info`], the client's address is available as
+/// `ConnectInfo<SocketAddr>`; a verified client certificate as `Option<ClientCertificate>`.
+pub async fn serve(
+    bind_addr: SocketAddr,
+    acceptor: Arc<RwLock<SslAcceptor>>,
+    app: Router,
+) -> anyhow::Result<()> {
+    let listener = TcpListener::bind(bind_addr).await?;
+    serve_on(listener, acceptor, app).await
+}
+
+async fn serve_on(
+    listener: TcpListener,
+    acceptor: Arc<RwLock<SslAcceptor>>,
+    app: Router,
+) -> anyhow::Result<()> {
+    let (tx, mut rx) = mpsc::channel(32);
+    tokio::task::spawn(async move {
+        loop {
+            let (tcp, remote_addr) = match listener.accept().await {
+                Ok(conn) => conn,
+                Err(e) => {
+                    debug!("Unable to accept connection: {e}");
+                    continue;
+                }
+            };
+            let acceptor = acceptor.clone();
+            let tx = tx.clone();
+            // Handshake in the background so slow clients do not hold up others
+            tokio::task::spawn(async move {
+                match handshake(&acceptor, tcp, remote_addr).await {
+                    Ok(conn) => {
+                        let _ = tx.send(Ok::<_, Infallible>(conn)).await;
+                    }
+                    Err(e) => debug!("TLS handshake with {remote_addr} failed: {e}"),
+                }
+            });
+        }
+    });
+    let incoming = accept::poll_fn(move |cx| rx.poll_recv(cx));
+    let make_service = make_service_fn(move |conn: &TlsConnection| {
+        let service = Extension(ConnectInfo(conn.remote_addr)).layer(app.clone());
+        let service = Extension(conn.client_certificate()).layer(service);
+        async move { Ok::<_, Infallible>(service) }
+    });
+    hyper::Server::builder(incoming)
+        .serve(make_service)
+        .with_graceful_shutdown(crate::graceful_shutdown::wait_for_signal())
+        .await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::routing::get;
+    use openssl::ssl::SslConnector;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use super::*;
+    use crate::crypto::tests::{issue_cert, rsa_key};
+
+    #[tokio::test]
+    async fn client_certificates_are_passed_to_handlers() {
+        let (ca_key, server_key, client_key) = (rsa_key(), rsa_key(), rsa_key());
+        let ca = issue_cert("Test CA", &ca_key, None, 1);
+        let server = issue_cert("localhost", &server_key, Some((&ca, &ca_key)), 1);
+        let client = issue_cert("app1", &client_key, Some((&ca, &ca_key)), 1);
+
+        let dir = std::env::temp_dir().join(format!("beam-tls-{}", crate::MyUuid::new()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let ca_file = dir.join("ca.pem");
+        std::fs::write(&ca_file, ca.to_pem().unwrap()).unwrap();
+        let config = TlsServerConfig {
+            cert_file: dir.join("cert.pem"),
+            key_file: dir.join("key.pem"),
+            client_ca_file: Some(ca_file.clone()),
+        };
+        std::fs::write(&config.cert_file, server.to_pem().unwrap()).unwrap();
+        std::fs::write(
+            &config.key_file,
+            server_key.private_key_to_pem_pkcs8().unwrap(),
+        )
+        .unwrap();
+        let acceptor = Arc::new(RwLock::new(config.acceptor().unwrap()));
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        let app = Router::new().route(
+            "/",
+            get(
+                |ConnectInfo(addr): ConnectInfo<SocketAddr>,
+                 Extension(cert): Extension<Option<ClientCertificate>>| async move {
+                    format!(
+                        "{} {}",
+                        addr.ip(),
+                        cert.and_then(|c| c.common_name()).unwrap_or_default()
+                    )
+                },
+            ),
+        );
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::task::spawn(serve_on(listener, acceptor, app));
+
+        let request = |with_cert: bool| {
+            let mut connector = SslConnector::builder(SslMethod::tls_client()).unwrap();
+            connector.cert_store_mut().add_cert(ca.clone()).unwrap();
+            if with_cert {
+                connector.set_certificate(&client).unwrap();
+                connector.set_private_key(&client_key).unwrap();
+            }
+            let ssl = connector
+                .build()
+                .configure()
+                .unwrap()
+                .into_ssl("localhost")
+                .unwrap();
+            async move {
+                let tcp = TcpStream::connect(addr).await.unwrap();
+                let mut stream = SslStream::new(ssl, tcp).unwrap();
+                Pin::new(&mut stream).connect().await.unwrap();
+                stream
+                    .write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+                    .await
+                    .unwrap();
+                let mut response = String::new();
+                stream.read_to_string(&mut response).await.unwrap();
+                response
+            }
+        };
+        assert!(request(true).await.ends_with("127.0.0.1 app1"));
+        assert!(request(false).await.ends_with("127.0.0.1 "));
+    }
+}
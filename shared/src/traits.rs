@@ -13,6 +13,8 @@ use crate::*;
 struct HowLongToBlockQueryExtractor {
     wait_time: Option<String>,
     wait_count: Option<u16>,
+    #[serde(default)]
+    wait_final: bool,
 }
 
 #[test]
@@ -32,15 +34,15 @@ where
 
     async fn from_request_parts(req: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
         match req.extract::<Query<HowLongToBlockQueryExtractor>>().await {
-            Ok(Query(HowLongToBlockQueryExtractor { wait_time, wait_count })) => {
+            Ok(Query(HowLongToBlockQueryExtractor { wait_time, wait_count, wait_final })) => {
                 if let Some(wait_time_str) = wait_time {
                     let wait_time = DurationParser::default()
                         .default_unit(fundu::TimeUnit::MilliSecond)
                         .parse(&wait_time_str)
                         .map_err(|_| (StatusCode::BAD_REQUEST, "For long-polling, please define &wait_time=<duration with unit> (e.g. 1000ms) and &wait_count=<count>."))?;
-                    Ok(Self { wait_time: Some(wait_time), wait_count})
+                    Ok(Self { wait_time: Some(wait_time), wait_count, wait_final })
                 } else {
-                    Ok(Self { wait_time: None, wait_count })
+                    Ok(Self { wait_time: None, wait_count, wait_final })
                 }
             },
             Err(_) => Err((StatusCode::BAD_REQUEST, "For long-polling, please define &wait_time=<duration with unit> (e.g. 1000ms) and &wait_count=<count>.")),
@@ -0,0 +1,454 @@
+//! WebSockets on top of hyper's connection upgrades, using [`tokio_tungstenite`] for the protocol.
+//!
+//! Beam does not define new message formats for WebSockets: Requests are tunneled as [`Frame`]s
+//! carrying the same method, path, headers and body as their HTTP counterparts, so signed requests
+//! from a Proxy are verified by the Broker exactly like those sent via HTTP.
+
+use std::{collections::BTreeMap, future::Future, sync::Arc, time::Duration};
+
+use axum::{
+    async_trait,
+    extract::FromRequestParts,
+    http::request::Parts,
+    response::{IntoResponse, Response as AxumResponse},
+    Router,
+};
+use futures_util::{
+    stream::{SplitSink, SplitStream},
+    SinkExt, StreamExt,
+};
+use hyper::{
+    body::HttpBody,
+    header::{self, HeaderName, HeaderValue},
+    upgrade::{OnUpgrade, Upgraded},
+    Body, HeaderMap, Method, Request, Response, StatusCode, Uri,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    sync::Mutex,
+};
+use tokio_tungstenite::{
+    tungstenite::{
+        self,
+        error::ProtocolError,
+        handshake::{client::generate_key, derive_accept_key},
+        protocol::{Role, WebSocketConfig},
+    },
+    WebSocketStream,
+};
+use tracing::{debug, warn};
+
+use crate::{crypto_jwt::MAX_TOKEN_LENGTH, errors::SamplyBeamError, http_client::SamplyHttpClient};
+
+/// Signed messages are limited to 10 MB (see [`MAX_TOKEN_LENGTH`]), so larger messages are
+/// rejected; the rest leaves room for the method, path and headers of a tunneled request
+const MAX_MESSAGE_SIZE: usize = MAX_TOKEN_LENGTH + 64 * 1024;
+/// Servers ping their clients this often, so idle connections are not closed by timeouts, e.g.
+/// the Proxy's 30 second read timeout
+pub const PING_INTERVAL: Duration = Duration::from_secs(15);
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Message {
+    Text(String),
+    Binary(Vec<u8>),
+}
+
+/// The sending half of a WebSocket; clones send on the same connection
+pub struct WsSender<S> {
+    sink: Arc<Mutex<SplitSink<WebSocketStream<S>, tungstenite::Message>>>,
+}
+
+impl<S> Clone for WsSender<S> {
+    fn clone(&self) -> Self {
+        Self {
+            sink: self.sink.clone(),
+        }
+    }
+}
+
+/// The receiving half of a WebSocket, which also answers the peer's pings
+pub struct WsReceiver<S> {
+    stream: SplitStream<WebSocketStream<S>>,
+    sender: WsSender<S>,
+}
+
+/// Frames are masked by clients and not by servers; frames violating this are rejected
+async fn split<S: AsyncRead + AsyncWrite + Unpin>(
+    io: S,
+    role: Role,
+) -> (WsSender<S>, WsReceiver<S>) {
+    let config = WebSocketConfig {
+        max_message_size: Some(MAX_MESSAGE_SIZE),
+        max_frame_size: Some(MAX_MESSAGE_SIZE),
+        ..Default::default()
+    };
+    let (sink, stream) = WebSocketStream::from_raw_socket(io, role, Some(config))
+        .await
+        .split();
+    let sender = WsSender {
+        sink: Arc::new(Mutex::new(sink)),
+    };
+    let receiver = WsReceiver {
+        stream,
+        sender: sender.clone(),
+    };
+    (sender, receiver)
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> WsSender<S> {
+    pub async fn send(&self, msg: Message) -> std::io::Result<()> {
+        match msg {
+            Message::Text(text) => self.send_raw(tungstenite::Message::Text(text)).await,
+            Message::Binary(bytes) => self.send_raw(tungstenite::Message::Binary(bytes)).await,
+        }
+    }
+
+    pub async fn send_json(&self, json: &impl Serialize) -> std::io::Result<()> {
+        let text = serde_json::to_string(json).expect("Serialization to succeed");
+        self.send_raw(tungstenite::Message::Text(text)).await
+    }
+
+    /// Keeps the connection alive; the peer answers with a pong
+    pub async fn ping(&self) -> std::io::Result<()> {
+        self.send_raw(tungstenite::Message::Ping(Vec::new())).await
+    }
+
+    pub async fn close(&self) -> std::io::Result<()> {
+        self.send_raw(tungstenite::Message::Close(None)).await
+    }
+
+    async fn send_raw(&self, msg: tungstenite::Message) -> std::io::Result<()> {
+        self.sink.lock().await.send(msg).await.map_err(to_io_error)
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> WsReceiver<S> {
+    /// Receives the next message, or `None` once the connection has been closed
+    pub async fn recv(&mut self) -> std::io::Result<Option<Message>> {
+        loop {
+            match self.stream.next().await {
+                Some(Ok(tungstenite::Message::Text(text))) => return Ok(Some(Message::Text(text))),
+                Some(Ok(tungstenite::Message::Binary(bytes))) => {
+                    return Ok(Some(Message::Binary(bytes)))
+                }
+                Some(Ok(tungstenite::Message::Close(_))) => {
+                    // Confirms the peer's close; the peer may have gone already
+                    let _ = self.sender.sink.lock().await.flush().await;
+                    return Ok(None);
+                }
+                // Pings are answered while reading on
+                Some(Ok(_)) => continue,
+                None
+                | Some(Err(
+                    tungstenite::Error::ConnectionClosed
+                    | tungstenite::Error::AlreadyClosed
+                    | tungstenite::Error::Protocol(ProtocolError::ResetWithoutClosingHandshake),
+                )) => return Ok(None),
+                Some(Err(e)) => return Err(to_io_error(e)),
+            }
+        }
+    }
+}
+
+fn to_io_error(e: tungstenite::Error) -> std::io::Error {
+    match e {
+        tungstenite::Error::Io(e) => e,
+        e => std::io::Error::new(std::io::ErrorKind::InvalidData, e),
+    }
+}
+
+/// Whether the request asks to upgrade the connection to a WebSocket
+pub fn is_upgrade_request(headers: &HeaderMap) -> bool {
+    headers
+        .get(header::UPGRADE)
+        .is_some_and(|v| v.as_bytes().eq_ignore_ascii_case(b"websocket"))
+}
+
+/// The pending upgrade of a WebSocket handshake request. As an extractor, it leaves the rest of
+/// the request to other extractors, e.g. for verifying its signature.
+pub struct Upgrade {
+    accept_key: String,
+    on_upgrade: OnUpgrade,
+}
+
+#[async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for Upgrade {
+    type Rejection = AxumResponse;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        let key = parts
+            .headers
+            .get(header::SEC_WEBSOCKET_KEY)
+            .filter(|_| is_upgrade_request(&parts.headers));
+        match (key, parts.extensions.remove::<OnUpgrade>()) {
+            (Some(key), Some(on_upgrade)) => Ok(Self {
+                accept_key: derive_accept_key(key.as_bytes()),
+                on_upgrade,
+            }),
+            _ => Err((
+                StatusCode::UPGRADE_REQUIRED,
+                [(header::UPGRADE, "websocket")],
+                "This endpoint only speaks WebSocket.",
+            )
+                .into_response()),
+        }
+    }
+}
+
+impl Upgrade {
+    /// Accepts the handshake and runs `handle` on the connection once it has been upgraded
+    pub fn accept<F, Fut>(self, handle: F) -> AxumResponse
+    where
+        F: FnOnce(WsSender<Upgraded>, WsReceiver<Upgraded>) -> Fut + Send + 'static,
+        Fut: Future<Output = ()> + Send,
+    {
+        tokio::task::spawn(async move {
+            match self.on_upgrade.await {
+                Ok(io) => {
+                    let (sender, receiver) = split(io, Role::Server).await;
+                    handle(sender, receiver).await
+                }
+                Err(e) => warn!("Unable to upgrade connection to WebSocket: {e}"),
+            }
+        });
+        (
+            StatusCode::SWITCHING_PROTOCOLS,
+            [
+                (header::CONNECTION, "upgrade".to_string()),
+                (header::UPGRADE, "websocket".to_string()),
+                (header::SEC_WEBSOCKET_ACCEPT, self.accept_key),
+            ],
+        )
+            .into_response()
+    }
+}
+
+/// The API serving requests tunneled via WebSockets
+#[derive(Clone)]
+pub struct Api(Arc<std::sync::Mutex<Router>>);
+
+impl Api {
+    pub fn new(router: Router) -> Self {
+        Self(Arc::new(std::sync::Mutex::new(router)))
+    }
+
+    pub fn router(&self) -> Router {
+        self.0.lock().unwrap().clone()
+    }
+}
+
+/// Opens a WebSocket with the given request, e.g. a signed `GET` request to the Broker
+pub async fn connect(
+    client: &SamplyHttpClient,
+    mut req: Request<Body>,
+) -> Result<(WsSender<Upgraded>, WsReceiver<Upgraded>), SamplyBeamError> {
+    let key = generate_key();
+    let headers = req.headers_mut();
+    headers.insert(header::CONNECTION, HeaderValue::from_static("upgrade"));
+    headers.insert(header::UPGRADE, HeaderValue::from_static("websocket"));
+    headers.insert(
+        header::SEC_WEBSOCKET_VERSION,
+        HeaderValue::from_static("13"),
+    );
+    headers.insert(
+        header::SEC_WEBSOCKET_KEY,
+        HeaderValue::from_str(&key).expect("Base64 to be a valid header value"),
+    );
+    let resp = client.request(req).await?;
+    if resp.status() != StatusCode::SWITCHING_PROTOCOLS {
+        return Err(SamplyBeamError::RequestValidationFailed(format!(
+            "WebSocket handshake answered with status {}",
+            resp.status()
+        )));
+    }
+    let expected = derive_accept_key(key.as_bytes());
+    if resp
+        .headers()
+        .get(header::SEC_WEBSOCKET_ACCEPT)
+        .map(HeaderValue::as_bytes)
+        != Some(expected.as_bytes())
+    {
+        return Err(SamplyBeamError::RequestValidationFailed(
+            "WebSocket handshake answered with invalid Sec-WebSocket-Accept".into(),
+        ));
+    }
+    let io = hyper::upgrade::on(resp).await?;
+    debug!("WebSocket connection established");
+    Ok(split(io, Role::Client).await)
+}
+
+/// A message exchanged via a Beam WebSocket
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Frame {
+    /// A request as it would be sent via HTTP, including any signature headers
+    Request {
+        id: u64,
+        method: String,
+        uri: String,
+        #[serde(default)]
+        headers: BTreeMap<String, String>,
+        #[serde(default)]
+        body: String,
+    },
+    /// The answer to the request with the same `id`
+    Response {
+        id: u64,
+        status: u16,
+        #[serde(default)]
+        headers: BTreeMap<String, String>,
+        #[serde(default)]
+        body: String,
+    },
+    /// Asks for events about the tasks or results matching `uri`, e.g. `/v1/tasks?filter=todo`
+    Subscribe { id: u64, uri: String },
+    /// Ends the subscription with the given `id`
+    Unsubscribe { id: u64 },
+    /// A new task or result, or an error; `subscription` is the `id` of the matching subscription
+    Event {
+        event: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        subscription: Option<u64>,
+        data: Value,
+    },
+}
+
+impl Frame {
+    /// Tunnels an HTTP request with the given `id`
+    pub async fn from_request(id: u64, req: Request<Body>) -> Result<Self, SamplyBeamError> {
+        let (parts, body) = req.into_parts();
+        let body = hyper::body::to_bytes(body).await?;
+        Ok(Self::Request {
+            id,
+            method: parts.method.to_string(),
+            uri: parts
+                .uri
+                .path_and_query()
+                .map(|pq| pq.to_string())
+                .unwrap_or_else(|| parts.uri.path().to_string()),
+            headers: to_map(&parts.headers),
+            body: String::from_utf8(body.to_vec()).map_err(SamplyBeamError::HttpParseError)?,
+        })
+    }
+
+    /// Tunnels the answer to the request with the given `id`
+    pub async fn from_response<B: HttpBody>(id: u64, resp: Response<B>) -> Self {
+        let (parts, body) = resp.into_parts();
+        let body = hyper::body::to_bytes(body).await.unwrap_or_default();
+        Self::Response {
+            id,
+            status: parts.status.as_u16(),
+            headers: to_map(&parts.headers),
+            body: String::from_utf8_lossy(&body).into_owned(),
+        }
+    }
+}
+
+/// Rebuilds a tunneled request
+pub fn to_request(
+    method: &str,
+    uri: &str,
+    headers: &BTreeMap<String, String>,
+    body: String,
+) -> Result<Request<Body>, SamplyBeamError> {
+    let mut req = Request::builder()
+        .method(Method::from_bytes(method.as_bytes()).map_err(|_| SamplyBeamError::InvalidPath)?)
+        .uri(Uri::try_from(uri).map_err(|_| SamplyBeamError::InvalidPath)?)
+        .body(Body::from(body))?;
+    *req.headers_mut() = from_map(headers);
+    Ok(req)
+}
+
+/// Rebuilds a tunneled response
+pub fn to_response(
+    status: u16,
+    headers: &BTreeMap<String, String>,
+    body: String,
+) -> Result<Response<Body>, SamplyBeamError> {
+    let mut resp = Response::builder().status(status).body(Body::from(body))?;
+    *resp.headers_mut() = from_map(headers);
+    Ok(resp)
+}
+
+fn to_map(headers: &HeaderMap) -> BTreeMap<String, String> {
+    headers
+        .iter()
+        .filter_map(|(name, value)| Some((name.to_string(), value.to_str().ok()?.to_string())))
+        .collect()
+}
+
+fn from_map(map: &BTreeMap<String, String>) -> HeaderMap {
+    map.iter()
+        .filter_map(|(name, value)| {
+            Some((
+                HeaderName::try_from(name).ok()?,
+                HeaderValue::try_from(value).ok()?,
+            ))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::AsyncWriteExt;
+
+    use super::*;
+
+    #[test]
+    fn handshake_key_is_accepted() {
+        // Example from RFC 6455, section 1.3
+        assert_eq!(
+            derive_accept_key(b"dGhlIHNhbXBsZSBub25jZQ=="),
+            "s3pPLMBiTxaQ9kYGzzhZRbK+xOo="
+        );
+    }
+
+    #[tokio::test]
+    async fn messages_pass_in_both_directions() {
+        let (client, server) = tokio::io::duplex(1024 * 1024);
+        let (client_tx, mut client_rx) = split(client, Role::Client).await;
+        let (server_tx, mut server_rx) = split(server, Role::Server).await;
+
+        let long = "x".repeat(70000);
+        client_tx.ping().await.unwrap();
+        client_tx.send(Message::Text(long.clone())).await.unwrap();
+        assert_eq!(server_rx.recv().await.unwrap(), Some(Message::Text(long)));
+        server_tx
+            .send(Message::Binary(vec![1, 2, 3]))
+            .await
+            .unwrap();
+        // The client first receives the pong answering its ping
+        assert_eq!(
+            client_rx.recv().await.unwrap(),
+            Some(Message::Binary(vec![1, 2, 3]))
+        );
+        client_tx.close().await.unwrap();
+        assert_eq!(server_rx.recv().await.unwrap(), None);
+        assert_eq!(client_rx.recv().await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn unmasked_client_frames_are_rejected() {
+        let (mut client, server) = tokio::io::duplex(1024);
+        let (_server_tx, mut server_rx) = split(server, Role::Server).await;
+        // A final text frame "Hi" without the mask bit
+        client.write_all(&[0x81, 0x02, b'H', b'i']).await.unwrap();
+        assert!(server_rx.recv().await.is_err());
+    }
+
+    #[tokio::test]
+    async fn oversized_messages_are_rejected() {
+        let (client, server) = tokio::io::duplex(1024 * 1024);
+        let (client_tx, _client_rx) = split(client, Role::Client).await;
+        let (_server_tx, mut server_rx) = split(server, Role::Server).await;
+        let sending = tokio::spawn(async move {
+            let _ = client_tx
+                .send(Message::Binary(vec![0; MAX_MESSAGE_SIZE + 1]))
+                .await;
+        });
+        assert!(server_rx.recv().await.is_err());
+        sending.abort();
+    }
+}
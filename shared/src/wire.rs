@@ -0,0 +1,245 @@
+//! Compact wire format between Beam.Proxy and Beam.Broker. By default, signed messages are sent as
+//! JWTs and returned as JSON arrays of `{"jwt": ...}` objects, whose claims are base64-encoded JSON.
+//! If negotiated via `Content-Type`/`Accept` ([`COMPACT`]), they are exchanged in CBOR instead, with
+//! the JWT's claims and signature as raw bytes (i.e. a JWS with detached payload) and the claims
+//! compressed (see [`compression`]). The receiver reassembles the exact JWT, so signatures, the
+//! messages stored by the Broker and the provenance passed on to apps are the same in both formats.
+//! As messages are decoded before their signature is verified, neither the compact body nor the
+//! decompressed claims may exceed the maximum length of a JWT ([`MAX_TOKEN_LENGTH`]).
+
+use axum::{
+    response::{IntoResponse, Response},
+    Json,
+};
+use hyper::{
+    header::{self, HeaderValue},
+    HeaderMap,
+};
+use jwt_simple::reexports::ct_codecs::{Base64UrlSafeNoPadding, Decoder, Encoder};
+use serde::{Deserialize, Serialize};
+use tracing::warn;
+
+use crate::{
+    compression::{self, Compression},
+    crypto_jwt::MAX_TOKEN_LENGTH,
+    errors::SamplyBeamError,
+    Msg, MsgSigned,
+};
+
+/// Media type of signed messages (requests) and lists of signed messages (responses) in the compact format
+pub const COMPACT: &str = "application/vnd.samply.beam+cbor";
+
+/// A JWT taken apart for sending it in CBOR
+#[derive(Serialize, Deserialize)]
+struct CompactToken {
+    /// The JWT's header as encoded in the token, as it is covered by the signature
+    h: String,
+    /// The JWT's claims, compressed with `c` (if given)
+    #[serde(with = "serde_bytes")]
+    p: Vec<u8>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    c: Option<Compression>,
+    /// The JWT's signature
+    #[serde(with = "serde_bytes")]
+    s: Vec<u8>,
+}
+
+fn invalid(e: impl std::fmt::Display) -> SamplyBeamError {
+    SamplyBeamError::RequestValidationFailed(format!("Invalid compact message: {e}"))
+}
+
+impl CompactToken {
+    fn from_jwt(jwt: &str) -> Result<Self, SamplyBeamError> {
+        let mut parts = jwt.split('.');
+        let (Some(header), Some(claims), Some(sig), None) =
+            (parts.next(), parts.next(), parts.next(), parts.next())
+        else {
+            return Err(invalid("Not a JWT"));
+        };
+        let claims = Base64UrlSafeNoPadding::decode_to_vec(claims, None).map_err(invalid)?;
+        let (c, p) = match compression::compress(&claims) {
+            Some((compression, compressed)) => (Some(compression), compressed),
+            None => (None, claims),
+        };
+        Ok(Self {
+            h: header.to_string(),
+            p,
+            c,
+            s: Base64UrlSafeNoPadding::decode_to_vec(sig, None).map_err(invalid)?,
+        })
+    }
+
+    fn into_jwt(self) -> Result<String, SamplyBeamError> {
+        let claims = match &self.c {
+            Some(compression) => compression::decompress(compression, &self.p, MAX_TOKEN_LENGTH)?,
+            None => self.p,
+        };
+        let claims = Base64UrlSafeNoPadding::encode_to_string(claims).map_err(invalid)?;
+        let sig = Base64UrlSafeNoPadding::encode_to_string(self.s).map_err(invalid)?;
+        Ok(format!("{}.{claims}.{sig}", self.h))
+    }
+}
+
+fn to_cbor(value: &impl Serialize) -> Vec<u8> {
+    let mut cbor = Vec::new();
+    ciborium::ser::into_writer(value, &mut cbor).expect("Should serialize fine");
+    cbor
+}
+
+/// Encodes a signed message (JWT) in the compact format
+pub fn encode(jwt: &str) -> Result<Vec<u8>, SamplyBeamError> {
+    Ok(to_cbor(&CompactToken::from_jwt(jwt)?))
+}
+
+/// Decodes a signed message in the compact format to its JWT
+pub fn decode(cbor: &[u8]) -> Result<String, SamplyBeamError> {
+    if cbor.len() > MAX_TOKEN_LENGTH {
+        return Err(invalid("Message is too large"));
+    }
+    ciborium::de::from_reader::<CompactToken, _>(cbor)
+        .map_err(invalid)?
+        .into_jwt()
+}
+
+/// Encodes a list of signed messages (JWTs) in the compact format
+pub fn encode_list<'a>(
+    jwts: impl IntoIterator<Item = &'a str>,
+) -> Result<Vec<u8>, SamplyBeamError> {
+    let tokens = jwts
+        .into_iter()
+        .map(CompactToken::from_jwt)
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok(to_cbor(&tokens))
+}
+
+/// Decodes a list of signed messages in the compact format to their JWTs
+pub fn decode_list(cbor: &[u8]) -> Result<Vec<String>, SamplyBeamError> {
+    ciborium::de::from_reader::<Vec<CompactToken>, _>(cbor)
+        .map_err(invalid)?
+        .into_iter()
+        .map(CompactToken::into_jwt)
+        .collect()
+}
+
+/// Whether the body is in the compact format according to the `Content-Type` header
+pub fn is_compact(headers: &HeaderMap) -> bool {
+    headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v.split(';').next().unwrap_or_default().trim() == COMPACT)
+}
+
+/// Whether the client accepts the compact format according to the `Accept` header
+pub fn accepts_compact(headers: &HeaderMap) -> bool {
+    headers
+        .get(header::ACCEPT)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| {
+            v.split(',')
+                .any(|part| part.split(';').next().unwrap_or_default().trim() == COMPACT)
+        })
+}
+
+/// Reads the signed message (JWT) from a request body, which is either the JWT itself or, according
+/// to the `Content-Type` header, in the compact format
+pub fn token_from_body(headers: &HeaderMap, body: &[u8]) -> Result<String, SamplyBeamError> {
+    if is_compact(headers) {
+        decode(body)
+    } else {
+        std::str::from_utf8(body)
+            .map(ToOwned::to_owned)
+            .map_err(|e| SamplyBeamError::RequestValidationFailed(format!("Invalid JWT: {e}")))
+    }
+}
+
+/// Answers with signed messages in the compact format if the client accepts it, or as JSON otherwise
+pub fn signed_response<M: Msg>(headers: &HeaderMap, msgs: &[MsgSigned<M>]) -> Response {
+    if accepts_compact(headers) {
+        match encode_list(msgs.iter().map(|msg| msg.jwt.as_str())) {
+            Ok(cbor) => {
+                return (
+                    [(header::CONTENT_TYPE, HeaderValue::from_static(COMPACT))],
+                    cbor,
+                )
+                    .into_response()
+            }
+            Err(e) => warn!("Unable to encode messages in the compact format, sending JSON: {e}"),
+        }
+    }
+    Json(msgs).into_response()
+}
+
+/// Like [`signed_response`], but with a single signed message
+pub fn signed_message_response<M: Msg>(headers: &HeaderMap, msg: &MsgSigned<M>) -> Response {
+    if accepts_compact(headers) {
+        match encode(&msg.jwt) {
+            Ok(cbor) => {
+                return (
+                    [(header::CONTENT_TYPE, HeaderValue::from_static(COMPACT))],
+                    cbor,
+                )
+                    .into_response()
+            }
+            Err(e) => warn!("Unable to encode message in the compact format, sending JSON: {e}"),
+        }
+    }
+    Json(msg).into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn compact_tokens_are_reassembled() {
+        // Encrypted bodies are serialized as JSON arrays of numbers, which the compact format shrinks
+        let claims = json!({
+            "from": "app1.proxy1.broker",
+            "to": ["app2.proxy2.broker"],
+            "body": { "encrypted": (0..20_000).map(|i| (i * 7 % 256) as u8).collect::<Vec<_>>() },
+        });
+        let jwt = format!(
+            "eyJhbGciOiJFUzI1NiIsImtpZCI6IjEyIn0.{}.{}",
+            Base64UrlSafeNoPadding::encode_to_string(claims.to_string()).unwrap(),
+            Base64UrlSafeNoPadding::encode_to_string([42; 64]).unwrap(),
+        );
+        let compact = encode(&jwt).unwrap();
+        assert!(compact.len() < jwt.len() / 4);
+        assert_eq!(decode(&compact).unwrap(), jwt);
+
+        let list = encode_list([jwt.as_str(), jwt.as_str()]).unwrap();
+        assert_eq!(decode_list(&list).unwrap(), vec![jwt.clone(), jwt]);
+        assert!(encode("no.jwt").is_err());
+        assert!(decode(b"garbage").is_err());
+    }
+
+    #[test]
+    fn oversized_claims_are_rejected() {
+        // Compresses to a few kilobytes, but would be expanded beyond the limit of JWTs
+        let (c, p) = compression::compress(&vec![b'a'; MAX_TOKEN_LENGTH + 1]).unwrap();
+        let token = CompactToken {
+            h: "eyJhbGciOiJFUzI1NiIsImtpZCI6IjEyIn0".to_string(),
+            p,
+            c: Some(c),
+            s: vec![42; 64],
+        };
+        let cbor = to_cbor(&token);
+        assert!(cbor.len() < 100 * 1024);
+        assert!(decode(&cbor).is_err());
+    }
+
+    #[test]
+    fn compact_format_is_negotiated() {
+        let mut headers = HeaderMap::new();
+        assert!(!accepts_compact(&headers));
+        headers.insert(
+            header::ACCEPT,
+            HeaderValue::from_static("application/json, application/vnd.samply.beam+cbor;q=0.9"),
+        );
+        assert!(accepts_compact(&headers));
+        assert!(!is_compact(&headers));
+        headers.insert(header::CONTENT_TYPE, HeaderValue::from_static(COMPACT));
+        assert!(is_compact(&headers));
+    }
+}